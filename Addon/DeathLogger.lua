@@ -10,9 +10,13 @@ SavedVariables:
 - DeathLoggerDB.screenshotOn (default true)
 - DeathLoggerDB.screenshotDelay (default 0.5)
 - DeathLoggerDB.maxEntries (default 200)
+- DeathLoggerDB.levels["Name-Realm"] = { player, realm, level, at, timePlayed } (current level per character)
+- DeathLoggerDB.version (addon version that last wrote the file)
 --]]
 
+local addonName = ...
 local frame = CreateFrame("Frame")
+local GetAddOnVersion = (C_AddOns and C_AddOns.GetAddOnMetadata) or GetAddOnMetadata
 
 -- --------------------- SavedVariables bootstrap ---------------------
 local function EnsureDB()
@@ -21,6 +25,8 @@ local function EnsureDB()
     if DeathLoggerDB.maxEntries == nil then DeathLoggerDB.maxEntries = 200 end
     if DeathLoggerDB.screenshotOn == nil then DeathLoggerDB.screenshotOn = true end
     if DeathLoggerDB.screenshotDelay == nil then DeathLoggerDB.screenshotDelay = 0.5 end
+    if not DeathLoggerDB.levels then DeathLoggerDB.levels = {} end
+    if GetAddOnVersion then DeathLoggerDB.version = GetAddOnVersion(addonName, "Version") end
 end
 
 -- --------------------- Utilities ---------------------
@@ -137,7 +143,7 @@ local function SnapshotBags()
     local bags = {}
     for _, bagID in ipairs(BAG_IDS) do
         local numSlots = ContainerNumSlots(bagID)
-        local bag = { bagID = bagID, slots = {} }
+        local bag = { bagID = bagID, numSlots = numSlots, slots = {} }
         for slot = 1, numSlots do
             local link, itemID, count, icon, quality = ContainerItemQuick(bagID, slot)
             if link or itemID then
@@ -255,8 +261,11 @@ local function DetermineKiller()
 
     return {
         sourceName = src,
+        sourceGUID = e.sourceGUID,
         subevent = e.subevent,
+        spellID = e.spellID,
         spellName = spell,
+        environmentalType = e.environmentalType,
         amount = e.amount,
         overkill = e.overkill,
         timestamp = e.timestamp,
@@ -327,6 +336,28 @@ local function RecordDeath()
     end
 end
 
+-- --------------------- Level tracking ---------------------
+local function LevelRecord()
+    local name, realm = GetPlayerNameRealm()
+    local key = name .. "-" .. realm
+    local rec = DeathLoggerDB.levels[key]
+    if not rec then
+        rec = { player = name, realm = realm }
+        DeathLoggerDB.levels[key] = rec
+    end
+    return rec
+end
+
+-- `at` only moves when the level changes, so it records when a level was reached.
+local function RecordLevel(level)
+    local rec = LevelRecord()
+    level = level or UnitLevel("player")
+    if rec.level ~= level then
+        rec.level = level
+        rec.at = time()
+    end
+end
+
 -- --------------------- Slash commands ---------------------
 SLASH_DEATHLOGGER1 = "/deathlog"
 SlashCmdList["DEATHLOGGER"] = function(msg)
@@ -434,11 +465,14 @@ end
 frame:RegisterEvent("PLAYER_LOGIN")
 frame:RegisterEvent("PLAYER_DEAD")
 frame:RegisterEvent("COMBAT_LOG_EVENT_UNFILTERED")
+frame:RegisterEvent("PLAYER_LEVEL_UP")
+frame:RegisterEvent("TIME_PLAYED_MSG")
 
-frame:SetScript("OnEvent", function(_, event)
+frame:SetScript("OnEvent", function(_, event, ...)
     if event == "PLAYER_LOGIN" then
         EnsureDB()
         playerGUID = UnitGUID("player")
+        RecordLevel()
         print("|cffff5555DeathLogger loaded.|r Use /deathlog help for commands.")
         return
     end
@@ -451,4 +485,17 @@ frame:SetScript("OnEvent", function(_, event)
         recentDamage = {}
         return
     end
+    if event == "PLAYER_LEVEL_UP" then
+        EnsureDB()
+        RecordLevel((...))
+        if type(RequestTimePlayed) == "function" then
+            RequestTimePlayed()
+        end
+        return
+    end
+    if event == "TIME_PLAYED_MSG" then
+        EnsureDB()
+        LevelRecord().timePlayed = (...)
+        return
+    end
 end)
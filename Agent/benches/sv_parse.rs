@@ -0,0 +1,102 @@
+//! Parsing a large SavedVariables file: time per parse, and how much memory a
+//! parse takes at its peak and still holds once it has returned.
+//!
+//! `cargo bench --bench sv_parse`
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use deathlogger_agent::parse_deaths;
+
+/// The system allocator, counting live bytes and their high-water mark. Lua
+/// allocates through Rust's allocator, so its states are counted too.
+struct Counting;
+
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let p = System.alloc(layout);
+        if !p.is_null() {
+            let live = LIVE.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(live, Ordering::Relaxed);
+        }
+        p
+    }
+
+    unsafe fn dealloc(&self, p: *mut u8, layout: Layout) {
+        System.dealloc(p, layout);
+        LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOC: Counting = Counting;
+
+const DEATHS: usize = 1_000;
+
+/// A DeathLogger file with `n` deaths, each with gear, bags and a killer;
+/// 1000 of them come to about a megabyte, like a long-lived account's file.
+fn sv_text(n: usize) -> String {
+    let mut sv = String::from("DeathLoggerDB = {\n\t[\"version\"] = \"1.3.0\",\n\t[\"deaths\"] = {\n");
+    for i in 0..n {
+        write!(
+            sv,
+            r#"		{{
+			["player"] = "Bench{i}", ["realm"] = "Silvermoon", ["class"] = "WARRIOR", ["level"] = {level},
+			["at"] = {at}, ["moneyCopper"] = 34217,
+			["killer"] = {{ ["sourceName"] = "Hogger", ["subevent"] = "SWING_DAMAGE", ["amount"] = 312, ["overkill"] = 87 }},
+			["location"] = {{ ["zone"] = "Elwynn Forest", ["subzone"] = "Forest's Edge", ["mapID"] = 37, ["x"] = 26.53, ["y"] = 78.41 }},
+			["equipped"] = {{
+				{{ ["slot"] = 4, ["hyperlink"] = "|cff1eff00|Hitem:2575::::::::24:::::::::|h[Red Linen Shirt]|h|r" }},
+				{{ ["slot"] = 16, ["hyperlink"] = "|cffffffff|Hitem:25::::::::24:::::::::|h[Worn Shortsword]|h|r" }},
+			}},
+			["bags"] = {{
+				{{ ["bagID"] = 0, ["slots"] = {{
+					{{ ["slot"] = 1, ["itemID"] = 117, ["stackCount"] = 6, ["hyperlink"] = "|cffffffff|Hitem:117::::::::24:::::::::|h[Tough Jerky]|h|r" }},
+				}} }},
+			}},
+		}}, -- [{index}]
+"#,
+            level = 10 + i % 60,
+            at = 1_700_000_000 + i,
+            index = i + 1,
+        )
+        .unwrap();
+    }
+    sv.push_str("\t},\n}\n");
+    sv
+}
+
+fn footprint(text: &str) {
+    let before = LIVE.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    let deaths = parse_deaths(text).unwrap();
+    let peak = PEAK.load(Ordering::Relaxed) - before;
+    let held = LIVE.load(Ordering::Relaxed) - before;
+    drop(deaths);
+    let leaked = LIVE.load(Ordering::Relaxed).saturating_sub(before);
+    println!(
+        "sv_parse: {:.1} MiB of SavedVariables; peak {:.1} MiB during the parse, {:.1} MiB held by the result, {} bytes left once it is dropped",
+        text.len() as f64 / 1048576.0,
+        peak as f64 / 1048576.0,
+        held as f64 / 1048576.0,
+        leaked
+    );
+}
+
+fn bench(c: &mut Criterion) {
+    let text = sv_text(DEATHS);
+    footprint(&text);
+    c.bench_function("parse_deaths 1000 entries", |b| b.iter(|| parse_deaths(&text).unwrap()));
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = bench
+}
+criterion_main!(benches);
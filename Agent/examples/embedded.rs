@@ -0,0 +1,44 @@
+//! Runs the agent inside another program and prints what it does.
+//!
+//!     cargo run --example embedded -- [path/to/config.toml]
+//!
+//! Without an argument it uses the installed agent's config and state.
+
+use deathlogger_agent::{AgentBuilder, AgentEvent};
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let mut builder = AgentBuilder::new().on_event(|event| {
+        if let AgentEvent::UploadFailed { will_retry: false, character, error, .. } = event {
+            eprintln!("rejected for good: {character}: {error}");
+        }
+    });
+    if let Some(path) = std::env::args().nth(1) {
+        builder = builder.config_file(path);
+    }
+    let mut agent = builder.build()?;
+    let mut events = agent.subscribe();
+    agent.start()?;
+
+    let status = agent.status();
+    println!("watching {} ({} death(s) waiting for a retry)", status.wow_dir.display(), status.retry_queue);
+
+    let consume = async {
+        while let Some(event) = events.next().await {
+            match event {
+                AgentEvent::DeathDetected { character, death } => {
+                    println!("{character} died at level {}", death.level().unwrap_or_default())
+                }
+                AgentEvent::ScreenshotPaired { screenshot, .. } => println!("  with {}", screenshot.display()),
+                AgentEvent::Uploaded { character, target, .. } => println!("  {character} sent to {target}"),
+                AgentEvent::UploadFailed { target, error, .. } => println!("  {target} failed: {error}"),
+                _ => {}
+            }
+        }
+    };
+    tokio::select! {
+        _ = consume => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    agent.shutdown()
+}
@@ -0,0 +1,715 @@
+//! Command handlers behind the `doctor`, `preview`, `status`, `stats`,
+//! `debug`, `replay` and `parse` subcommands.
+
+use crate::*;
+
+// ---------- Diagnostics ----------
+
+/// Every host the current configuration could contact, with the feature that would do so.
+pub(crate) fn contactable_hosts(cfg: &Config) -> Vec<(NetFeature, String)> {
+    let mut hosts = vec![];
+    let endpoint_urls = api_endpoints(cfg).into_iter().map(|e| (NetFeature::Upload, e.url));
+    let others = [
+        (NetFeature::Milestones, milestones_url(cfg)),
+        (NetFeature::Challenge, cfg.screenshot_challenge_url.clone()),
+        (NetFeature::Media, cfg.media_url.clone()),
+        (NetFeature::Heartbeat, cfg.heartbeat_url.clone()),
+        (NetFeature::Bulk, cfg.bulk_api_url.clone()),
+    ];
+    for (feature, url) in endpoint_urls.chain(others) {
+        if let Some(h) = url_host(&url) {
+            hosts.push((feature, h));
+        }
+    }
+    if let Some(h) = cfg.oauth.as_ref().and_then(|o| url_host(&o.token_url)) {
+        hosts.push((NetFeature::OAuth, h));
+    }
+    if cfg.update_addon_on_start {
+        for url in [RAW_TOC, RAW_LUA] {
+            if let Some(h) = url_host(url) {
+                hosts.push((NetFeature::AddonDownload, h));
+            }
+        }
+    }
+    if cfg.telemetry {
+        if let Some(h) = url_host(&cfg.telemetry_url) {
+            hosts.push((NetFeature::Telemetry, h));
+        }
+    }
+    if cfg.upload_mode != UploadMode::Api {
+        if let Some(h) = url_host(&cfg.discord_webhook_url) {
+            hosts.push((NetFeature::Discord, h));
+        }
+    }
+    hosts.sort();
+    hosts.dedup();
+    hosts
+}
+
+/// Doctor's lines on the addon settings last seen per character.
+pub(crate) fn addon_settings_report(cfg: &Config, state: &State) -> Vec<String> {
+    let mut lines = vec![];
+    for (owner, settings) in &state.addon_settings {
+        lines.push(format!(
+            "[doctor] Addon settings for {}: auto-screenshot {}{}",
+            owner,
+            match settings.auto_screenshot {
+                Some(true) => "ON",
+                Some(false) => "OFF (pairing relies on manual screenshots)",
+                None => "unknown",
+            },
+            settings.max_entries.map(|n| format!(", keeps {} deaths", n)).unwrap_or_default()
+        ));
+        if let Some(delay) = settings.screenshot_delay.filter(|d| *d > cfg.pair_window_after() as f64) {
+            lines.push(format!(
+                "      warning: the addon waits {}s before its screenshot but {} is {}",
+                delay,
+                cfg.pair_window_key(true),
+                cfg.pair_window_after()
+            ));
+        }
+    }
+    lines
+}
+
+pub(crate) fn doctor() -> Result<()> {
+    let cfg_path = config_path()?;
+    if !cfg_path.exists() {
+        println!("[doctor] No config found at {}. Run the agent once to create it.", cfg_path.display());
+        return Ok(());
+    }
+    let cfg = load_config(&cfg_path)?;
+    println!("[doctor] Config: {}", cfg_path.display());
+    print_config_findings(&validate_config(&cfg));
+    let wow = WowPaths::from_config(&cfg);
+    if !wow.root.exists() {
+        if let Some(serial) = volumes::parse_serial(&cfg.wow_volume_serial) {
+            if !SystemVolumes.volumes().iter().any(|v| v.serial == Some(serial)) {
+                println!(
+                    "[doctor] Install previously seen on volume serial {} not currently mounted",
+                    cfg.wow_volume_serial
+                );
+            } else if let Some(moved) = find_moved_wow_root(&cfg, &SystemVolumes) {
+                println!("[doctor] WoW: {} is missing; the install is now at {} (the agent switches on start)", cfg.wow_root, moved.display());
+            }
+        }
+    }
+    if !wow.branch_root().is_dir() {
+        println!("[doctor] WoW: {} does not exist", wow.branch_root().display());
+    } else if wow.is_pre_first_launch() {
+        println!("[doctor] WoW: waiting for first game launch");
+        println!("      {}", PRE_FIRST_LAUNCH_HINT);
+    } else {
+        println!(
+            "[doctor] WoW: {} ({} SavedVariables file(s), Screenshots folder {})",
+            wow.branch_root().display(),
+            account_sv_paths(&wow).len(),
+            if wow.screenshots_dir().is_dir() { "present" } else { "not created yet" }
+        );
+    }
+    for dir in find_legacy_addon_dirs(&wow.addons_dir()) {
+        println!(
+            "[doctor] Legacy addon copy at {}: it clobbers DeathLoggerDB. Restart the agent to migrate it.",
+            dir.display()
+        );
+    }
+    match compile_killer_rules(&cfg) {
+        Ok(rules) => println!("[doctor] Killer remap rules: {} active", rules.len()),
+        Err(e) => println!("[doctor] Killer remap rules: INVALID: {e:#}"),
+    }
+    let state = match load_state() {
+        Ok(state) => state,
+        Err(e) => {
+            println!("[doctor] State: {e:#}");
+            State::default()
+        }
+    };
+    if state.quarantine.is_empty() {
+        println!("[doctor] Quarantine: empty");
+    } else {
+        println!("[doctor] Quarantine: {} death(s) waiting for player/realm to be resolved:", state.quarantine.len());
+        for q in &state.quarantine {
+            println!("      death at {} in {} (held since {})", format_epoch(q.death.at), q.sv_path, format_epoch(q.since));
+        }
+    }
+    for line in addon_settings_report(&cfg, &state) {
+        println!("{line}");
+    }
+    println!("[doctor] Architecture: {}", arch_summary());
+    println!("[doctor] SavedVariables parser: {} first", if cfg.sv_parser == SvParser::Data { "data" } else { "lua" });
+    println!("[doctor] Uploads: {}", if cfg.uploads_enabled { "enabled" } else { "DISABLED" });
+    if cfg.event_log {
+        println!("[doctor] Event log: warnings and errors go to the Application log (source {})", eventlog::SOURCE);
+    }
+    if !cfg.encrypt_to_public_key.is_empty() {
+        println!(
+            "[doctor] Encryption: deaths{} are sealed to the configured public key",
+            if cfg.encrypt_screenshot { " and screenshots" } else { "" }
+        );
+    }
+
+    let http = Http::new(&cfg)?;
+    println!("[doctor] Proxy: {}", proxy_summary(&cfg));
+    if cfg.network_allowlist.is_empty() {
+        println!("[doctor] network_allowlist is empty: all hosts are allowed");
+    } else {
+        println!("[doctor] network_allowlist: {}", cfg.network_allowlist.join(", "));
+    }
+    println!("[doctor] Hosts this configuration may contact:");
+    for (feature, host) in contactable_hosts(&cfg) {
+        let verdict = if http.host_allowed(&host) { "allowed" } else { "BLOCKED" };
+        println!("      {:<16} {} ({})", feature.name(), host, verdict);
+    }
+    Ok(())
+}
+
+// ---------- Upload preview ----------
+
+pub(crate) const PREVIEW_MAX_STRING: usize = 80;
+pub(crate) const PREVIEW_MAX_ITEMS: usize = 3;
+
+/// Shorten long strings and arrays so a payload fits on screen.
+pub(crate) fn elide_json(v: &serde_json::Value) -> serde_json::Value {
+    match v {
+        serde_json::Value::String(s) if s.chars().count() > PREVIEW_MAX_STRING => {
+            let head: String = s.chars().take(PREVIEW_MAX_STRING).collect();
+            json!(format!("{}… ({} chars)", head, s.chars().count()))
+        }
+        serde_json::Value::Array(a) if a.len() > PREVIEW_MAX_ITEMS => {
+            let mut out: Vec<_> = a.iter().take(PREVIEW_MAX_ITEMS).map(elide_json).collect();
+            out.push(json!(format!("… {} more item(s), use --full to show", a.len() - PREVIEW_MAX_ITEMS)));
+            serde_json::Value::Array(out)
+        }
+        serde_json::Value::Array(a) => serde_json::Value::Array(a.iter().map(elide_json).collect()),
+        serde_json::Value::Object(m) => {
+            serde_json::Value::Object(m.iter().map(|(k, v)| (k.clone(), elide_json(v))).collect())
+        }
+        other => other.clone(),
+    }
+}
+
+/// Most recent death across all SavedVariables files. Read-only.
+pub(crate) fn latest_recorded_death(wow: &WowPaths) -> Option<DeathPayload> {
+    account_sv_paths(wow)
+        .iter()
+        .filter_map(|p| {
+            let snapshot = wow.parse_sv(p, None).ok()?;
+            let mut death = snapshot.latest?;
+            resolve_identity(&mut death, p, snapshot.recent_identity.as_ref()).then_some(death)
+        })
+        .max_by_key(|d| d.at)
+}
+
+/// Show exactly what would be uploaded for the most recent death, without
+/// touching state. Returns false when there is nothing to show yet.
+pub(crate) fn print_preview(cfg: &Config, full: bool) -> Result<bool> {
+    match preview_text(cfg, full)? {
+        Some(text) => {
+            print!("{text}");
+            Ok(true)
+        }
+        None => {
+            println!("[preview] No recorded deaths found yet, nothing to preview.");
+            Ok(false)
+        }
+    }
+}
+
+/// The text `print_preview` shows; None without a recorded death.
+pub(crate) fn preview_text(cfg: &Config, full: bool) -> Result<Option<String>> {
+    use std::fmt::Write as _;
+    let wow = WowPaths::from_config(cfg);
+    let Some(mut death) = latest_recorded_death(&wow) else { return Ok(None) };
+    prepare_payload(cfg, &mut death)?;
+    let state = load_state().unwrap_or_default();
+    let shots = find_screenshots(cfg, &state, death.at, &[]);
+
+    let body = serde_json::to_value(&death)?;
+    let body = if full { body } else { elide_json(&body) };
+
+    let mut out = String::new();
+    writeln!(out, "[preview] Most recent death: {} at {}", to_key(&death.player, &death.realm), format_epoch(death.at))?;
+    for ep in api_endpoints(cfg) {
+        writeln!(out, "[preview] Target: {} ({})", ep.url, ep.name)?;
+    }
+    writeln!(out, "{}", serde_json::to_string_pretty(&body)?)?;
+    for s in &shots {
+        let path = Path::new(&s.path);
+        let size = path.metadata().map(|m| m.len()).unwrap_or(0);
+        let dims = image::image_dimensions(path)
+            .map(|(w, h)| format!("{}x{}", w, h))
+            .unwrap_or_else(|_| "unknown size".into());
+        writeln!(out, "[preview] Screenshot: {} ({}, {} bytes)", path.display(), dims, size)?;
+    }
+    if shots.is_empty() {
+        writeln!(out, "[preview] Screenshot: none would be attached")?;
+    }
+    Ok(Some(out))
+}
+
+pub(crate) fn preview_command(full: bool) -> Result<()> {
+    let cfg_path = config_path()?;
+    if !cfg_path.exists() {
+        println!("[preview] No config found at {}. Run the agent once to create it.", cfg_path.display());
+        return Ok(());
+    }
+    let mut cfg = load_config(&cfg_path)?;
+    print_preview(&cfg, full)?;
+
+    let enable = Confirm::new()
+        .with_prompt("Enable uploads now?")
+        .default(cfg.uploads_enabled)
+        .interact()
+        .unwrap_or(cfg.uploads_enabled);
+    if enable != cfg.uploads_enabled {
+        cfg.uploads_enabled = enable;
+        fs::write(&cfg_path, toml::to_string_pretty(&cfg)?)?;
+    }
+    println!("[preview] Uploads are {}", if cfg.uploads_enabled { "enabled" } else { "disabled" });
+    Ok(())
+}
+
+pub(crate) fn status() -> Result<()> {
+    let cfg_path = config_path()?;
+    if !cfg_path.exists() {
+        println!("[status] No config found at {}. Run the agent once to create it.", cfg_path.display());
+        return Ok(());
+    }
+    let cfg = load_config(&cfg_path)?;
+    let wow = WowPaths::from_config(&cfg);
+    let state = load_state().unwrap_or_default();
+
+    println!("[status] WoW: {}", wow.branch_root().display());
+    println!("[status] Uploads: {}", if cfg.uploads_enabled { "enabled" } else { "DISABLED" });
+    println!("[status] Last uploaded deaths:");
+    if state.last_uploaded.is_empty() {
+        println!("      (none yet)");
+    }
+    for (key, at) in &state.last_uploaded {
+        println!("      {} at {}", key, format_epoch(*at));
+    }
+    if !state.upload_history.is_empty() {
+        println!("[status] Recent uploads with a server id or page:");
+        let mut recent: Vec<(&String, &UploadRecord)> =
+            state.upload_history.iter().flat_map(|(death_ref, records)| records.iter().map(move |r| (death_ref, r))).collect();
+        recent.sort_by_key(|(_, r)| std::cmp::Reverse(r.uploaded_at));
+        for (death_ref, r) in recent.into_iter().take(10) {
+            let shown = r.url.as_deref().or(r.id.as_deref()).unwrap_or_default();
+            println!("      {} on {}: {}", death_ref, r.target, shown);
+        }
+    }
+    println!("[status] Pending screenshots: {}", state.pending_screens.len());
+    let end = Utc::now().date_naive() + chrono::Duration::days(1);
+    let week = state.metrics.totals(end - chrono::Duration::days(7), end);
+    println!("[status] Malformed death entries skipped in the last 7 days: {}", week.entries_skipped);
+    for (file, entries) in &state.skipped_entries {
+        for e in entries {
+            println!("      {}: {}", file.display(), e);
+        }
+    }
+    let notes = load_pending_notes();
+    if !notes.is_empty() {
+        println!("[status] Notes waiting for their death to be sent: {}", notes.len());
+        for (death_ref, note) in &notes {
+            println!("      {}: {:?}", death_ref, note);
+        }
+    }
+    let resends = load_note_resends();
+    if !resends.is_empty() {
+        println!("[status] Uploaded deaths to send again with a note: {}", resends.len());
+        for r in &resends {
+            println!("      {} at {} to {}", r.death.key(), format_epoch(r.death.at), r.targets.join(", "));
+        }
+    }
+    println!("[status] Retry queue: {}", state.retry_queue.len());
+    for r in &state.retry_queue {
+        let targets = if r.targets.is_empty() { "all targets".to_string() } else { r.targets.join(", ") };
+        println!(
+            "      {} at {} for {}: {} failed attempt(s), next at {} ({})",
+            r.key(),
+            format_epoch(r.death.at),
+            targets,
+            r.attempts,
+            format_epoch(r.next_at),
+            r.last_error
+        );
+    }
+    if !state.failed_uploads.is_empty() {
+        println!("[status] Rejected by the server (not retried): {}", state.failed_uploads.len());
+        for f in &state.failed_uploads {
+            let target = if f.target.is_empty() { DEFAULT_ENDPOINT } else { f.target.as_str() };
+            println!("      {} at {} by {}: {}", to_key(&f.death.player, &f.death.realm), format_epoch(f.death.at), target, f.error);
+        }
+    }
+
+    if !cfg.network_allowlist.is_empty() || !week.requests_blocked.is_empty() {
+        let per_feature: Vec<String> = week.requests_blocked.iter().map(|(f, n)| format!("{} {}", f, n)).collect();
+        println!(
+            "[status] Requests blocked by network_allowlist in the last 7 days: {}{}",
+            week.requests_blocked.values().sum::<u64>(),
+            if per_feature.is_empty() { String::new() } else { format!(" ({})", per_feature.join(", ")) }
+        );
+    }
+
+    let (indexed, total) = ScreenshotIndex::load().progress(&wow.screenshots_dir());
+    println!("[status] Screenshot index: {}/{} file(s) hashed", indexed, total);
+    if let Ok(path) = filter_journal_path() {
+        let filtered = read_filter_journal(&path, cfg.filter_journal_days);
+        if !filtered.is_empty() {
+            println!("[status] Deaths kept back by [filters] (last {} days): {}", cfg.filter_journal_days, filtered.len());
+        }
+    }
+    if let Ok(dir) = config_dir() {
+        let (entries, bytes) = shotcache::usage_on_disk(&dir.join("screenshot_cache"));
+        println!(
+            "[status] Screenshot cache: {} file(s), {:.1} of {} MB",
+            entries,
+            bytes as f64 / (1024.0 * 1024.0),
+            cfg.screenshot_cache_max_mb
+        );
+    }
+    Ok(())
+}
+
+pub(crate) fn stats(args: &[String]) -> Result<()> {
+    let cfg_path = config_path()?;
+    let cfg = if cfg_path.exists() { load_config(&cfg_path)? } else { Config::default() };
+    let state = load_state().unwrap_or_default();
+    let only_reliability = args.iter().any(|a| a == "--reliability");
+
+    if !only_reliability {
+        println!("Uploaded deaths per character:");
+        for (key, at) in &state.last_uploaded {
+            println!("  {} (latest {})", key, format_epoch(*at));
+        }
+    }
+    let days = if cfg.reliability_summary_days > 0 { cfg.reliability_summary_days } else { 7 };
+    // Include today in the current period.
+    let end = Utc::now().date_naive() + chrono::Duration::days(1);
+    for line in state.metrics.reliability_summary(end, days).lines() {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+pub(crate) fn debug_command(args: &[String]) -> Result<()> {
+    match args.first().map(String::as_str) {
+        Some("last-requests") => {
+            let path = last_requests_path()?;
+            let captured: Vec<CapturedRequest> = if path.exists() {
+                serde_json::from_str(&fs::read_to_string(&path)?)?
+            } else {
+                Vec::new()
+            };
+            if args.iter().any(|a| a == "--json") {
+                println!("{}", serde_json::to_string_pretty(&captured)?);
+                return Ok(());
+            }
+            if captured.is_empty() {
+                println!("No requests captured yet (capture_last_requests = 0 disables capture).");
+            }
+            for (i, r) in captured.iter().enumerate() {
+                let outcome = match (&r.status, &r.error) {
+                    (Some(s), _) => s.to_string(),
+                    (None, Some(e)) => format!("error: {}", e),
+                    (None, None) => "no response".into(),
+                };
+                println!("#{} {} [{}] {} {} -> {}", i + 1, format_epoch(r.at), r.feature, r.method, r.url, outcome);
+                for (k, v) in &r.headers {
+                    println!("    {}: {}", k, v);
+                }
+                for p in &r.parts {
+                    match (&p.text, &p.file_name) {
+                        (Some(t), _) => println!("    part {:?}: {} bytes of text", p.name, t.len()),
+                        (None, Some(f)) => println!(
+                            "    part {:?}: file {} ({} bytes, {}, sha256 {})",
+                            p.name,
+                            f,
+                            p.size.unwrap_or(0),
+                            p.content_type.as_deref().unwrap_or("?"),
+                            p.sha256.as_deref().unwrap_or("?")
+                        ),
+                        _ => println!("    part {:?}", p.name),
+                    }
+                }
+            }
+            Ok(())
+        }
+        _ => Err(anyhow!("Usage: deathlogger-agent debug last-requests [--json]")),
+    }
+}
+
+// ---------- Event trace and replay ----------
+//
+// With `event_trace_max` set, the run loop appends every input it acts on to
+// trace.jsonl and keeps a copy of each SavedVariables version it parsed, so
+// `replay` can feed the same sequence through identity, pairing and watermark
+// decisions offline, with uploads stubbed out.
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum TraceEvent {
+    /// A SavedVariables write; the content is kept as `trace/<sha256>.lua`
+    SvChange { at: i64, path: String, sha256: String },
+    /// A screenshot queued for pairing, with the timestamp pairing used
+    Screenshot { at: i64, path: String, ts: i64 },
+    /// The periodic re-scan of every SV file
+    Poll { at: i64 },
+}
+
+pub(crate) fn trace_path() -> Result<PathBuf> {
+    Ok(config_dir()?.join("trace.jsonl"))
+}
+
+pub(crate) fn trace_files_dir() -> Result<PathBuf> {
+    Ok(config_dir()?.join("trace"))
+}
+
+pub(crate) fn read_trace(path: &Path) -> Result<Vec<TraceEvent>> {
+    let text = fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    text.lines()
+        .filter(|l| !l.trim().is_empty())
+        .enumerate()
+        .map(|(i, l)| serde_json::from_str(l).with_context(|| format!("{} line {}", path.display(), i + 1)))
+        .collect()
+}
+
+/// Path as stored in the trace: relative to the WoW install (`<wow>/...`) or the
+/// home directory (`~/...`), so a trace can be shared without the local layout.
+/// The part below the install is kept because identity comes from it.
+pub(crate) fn redact_trace_path(wow: &WowPaths, p: &Path) -> String {
+    let relative = |base: &Path, label: &str| {
+        p.strip_prefix(base).ok().map(|rest| {
+            let parts: Vec<String> = rest.components().map(|c| c.as_os_str().to_string_lossy().to_string()).collect();
+            format!("{}/{}", label, parts.join("/"))
+        })
+    };
+    relative(&wow.root, "<wow>")
+        .or_else(|| home_dir().and_then(|h| relative(&h, "~")))
+        .unwrap_or_else(|| p.file_name().map(|f| f.to_string_lossy().to_string()).unwrap_or_default())
+}
+
+/// Appends to trace.jsonl and compacts it to the newest `event_trace_max`
+/// events once it holds twice that many.
+pub(crate) struct TraceRecorder {
+    pub(crate) max: usize,
+    /// Events in the file right now
+    pub(crate) lines: usize,
+}
+
+impl TraceRecorder {
+    pub(crate) fn new(max: usize) -> Option<Self> {
+        let lines = trace_path().and_then(|p| Ok(fs::read_to_string(p)?.lines().count())).unwrap_or(0);
+        (max > 0).then_some(Self { max, lines })
+    }
+
+    pub(crate) fn sv_change(&mut self, wow: &WowPaths, sv: &Path) {
+        let copied = (|| -> Result<String> {
+            let content = fs::read(sv)?;
+            let sha256 = format!("{:x}", Sha256::digest(&content));
+            let copy = trace_files_dir()?.join(format!("{}.lua", sha256));
+            if !copy.exists() {
+                fs::create_dir_all(trace_files_dir()?)?;
+                write_atomic(&copy, &content)?;
+            }
+            Ok(sha256)
+        })();
+        match copied {
+            Ok(sha256) => self.record(TraceEvent::SvChange { at: Utc::now().timestamp(), path: redact_trace_path(wow, sv), sha256 }),
+            Err(e) => eprintln!("[warn] event trace: {e:#}"),
+        }
+    }
+
+    pub(crate) fn record(&mut self, ev: TraceEvent) {
+        if let Err(e) = self.append(&ev) {
+            eprintln!("[warn] event trace: {e:#}");
+        }
+    }
+
+    pub(crate) fn append(&mut self, ev: &TraceEvent) -> Result<()> {
+        fs::create_dir_all(config_dir()?)?;
+        let mut f = fs::OpenOptions::new().create(true).append(true).open(trace_path()?)?;
+        std::io::Write::write_all(&mut f, format!("{}\n", serde_json::to_string(ev)?).as_bytes())?;
+        self.lines += 1;
+        if self.lines >= self.max * 2 {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Keep the newest `max` events and the SV copies they still reference.
+    pub(crate) fn compact(&mut self) -> Result<()> {
+        let events = read_trace(&trace_path()?)?;
+        let keep = &events[events.len().saturating_sub(self.max)..];
+        let mut text = String::new();
+        for ev in keep {
+            text.push_str(&serde_json::to_string(ev)?);
+            text.push('\n');
+        }
+        write_atomic(&trace_path()?, text.as_bytes())?;
+        self.lines = keep.len();
+        let referenced: std::collections::BTreeSet<String> = keep
+            .iter()
+            .filter_map(|ev| match ev {
+                TraceEvent::SvChange { sha256, .. } => Some(format!("{}.lua", sha256)),
+                _ => None,
+            })
+            .collect();
+        for entry in fs::read_dir(trace_files_dir()?).into_iter().flatten().flatten() {
+            if !referenced.contains(&*entry.file_name().to_string_lossy()) {
+                fs::remove_file(entry.path()).ok();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `replay <trace> [--against <dir>]`: run a recorded trace through the pipeline
+/// and print the decisions. SV contents come from `<dir>/<sha256>.lua`
+/// (default: the `trace` folder next to the trace file).
+pub(crate) fn replay_command(args: &[String]) -> Result<()> {
+    const USAGE: &str = "Usage: deathlogger-agent replay <trace.jsonl> [--against <fixture-dir>]";
+    let trace = args.first().filter(|a| !a.starts_with("--")).map(PathBuf::from).ok_or_else(|| anyhow!(USAGE))?;
+    let fixtures = match args.iter().position(|a| a == "--against") {
+        Some(i) => PathBuf::from(args.get(i + 1).ok_or_else(|| anyhow!(USAGE))?),
+        None => trace.parent().unwrap_or(Path::new(".")).join("trace"),
+    };
+    let cfg_path = config_path()?;
+    let cfg = if cfg_path.exists() { load_config(&cfg_path)? } else { Config::default() };
+    let events = read_trace(&trace)?;
+    if !cfg.uploads_enabled {
+        println!("[replay] uploads_enabled = false in the config, so nothing will be submitted");
+    }
+
+    // Whatever the pipeline persists (state, notes, locks) lands in a scratch directory.
+    let scratch = std::env::temp_dir().join(format!("deathlogger-replay-{}", std::process::id()));
+    fs::create_dir_all(&scratch)?;
+    CONFIG_DIR_OVERRIDE.set(scratch.clone()).map_err(|_| anyhow!("replay already running in this process"))?;
+    let result = replay_events(&cfg, &events, &fixtures);
+    fs::remove_dir_all(&scratch).ok();
+    for line in result? {
+        println!("[replay] {}", line);
+    }
+    Ok(())
+}
+
+/// The decision log of a trace: screenshots queued, deaths submitted with what
+/// they were paired with, and watermark moves, one line each.
+pub(crate) fn replay_events(cfg: &Config, events: &[TraceEvent], fixtures: &Path) -> Result<Vec<String>> {
+    let mut state = State::default();
+    let mut log = vec![];
+    // Latest version of each SV file, for poll ticks
+    let mut sv_files: BTreeMap<String, String> = BTreeMap::new();
+    for ev in events {
+        match ev {
+            TraceEvent::Screenshot { path, ts, .. } => {
+                log.push(format!("screenshot {} taken at {}", path, ts));
+                queue_screenshot(&mut state, path.clone(), *ts);
+            }
+            TraceEvent::SvChange { path, sha256, .. } => {
+                sv_files.insert(path.clone(), sha256.clone());
+                replay_sv(cfg, &mut state, path, sha256, fixtures, &mut log)?;
+            }
+            TraceEvent::Poll { .. } => {
+                log.push("poll".to_string());
+                for (path, sha256) in &sv_files {
+                    replay_sv(cfg, &mut state, path, sha256, fixtures, &mut log)?;
+                }
+            }
+        }
+    }
+    Ok(log)
+}
+
+pub(crate) fn replay_sv(cfg: &Config, state: &mut State, path: &str, sha256: &str, fixtures: &Path, log: &mut Vec<String>) -> Result<()> {
+    let file = fixtures.join(format!("{}.lua", sha256));
+    if !file.exists() {
+        return Err(anyhow!("{} is missing; the trace refers to it for {}", file.display(), path));
+    }
+    let marks = SvMarks { uploaded: account_marks(state, account_from_sv_path(Path::new(path)).as_deref()), cursor: None };
+    // The adapter goes by the recorded path; the fixture is what gets read.
+    let wow = WowPaths::from_config(cfg);
+    let parsed = match wow.adapter_for(Path::new(path)) {
+        Some(adapter) => adapter.parse(&file, Some(&marks)),
+        None => Err(anyhow!("no enabled sv_adapters entry reads it")),
+    };
+    let mut snapshot = match parsed {
+        Ok(s) => s,
+        Err(e) => {
+            log.push(format!("{} did not parse: {e:#}", path));
+            return Ok(());
+        }
+    };
+    // Uploads are stubbed: every target accepts.
+    let ready = ready_deaths(state, Path::new(path), &mut snapshot);
+    let times: Vec<i64> = ready.iter().map(|d| d.at).collect();
+    for (i, death) in ready.into_iter().enumerate() {
+        let Some(staged) = stage_death(cfg, state, death, &times[i + 1..])? else { continue };
+        let targets = upload_targets(cfg);
+        log.push(format!(
+            "submit {} at {} to [{}] key {} screenshot {}",
+            staged.key,
+            staged.death.at,
+            targets.join(", "),
+            staged.idempotency_key,
+            if staged.screenshot.is_some() { staged.screenshots().join(", ") } else { "none".to_string() }
+        ));
+        let key = staged.key.clone();
+        let results = targets.into_iter().map(|t| (t, Ok(None))).collect();
+        finish_staged(state, staged, results, std::time::Instant::now());
+        log.push(format!("watermark {} = {}", key, state.last_uploaded.get(&key).copied().unwrap_or(0)));
+    }
+    Ok(())
+}
+
+// ---------- Parse command ----------
+
+/// `parse <path> [--all] [--json]`: run the SavedVariables parser on any file,
+/// such as one a user sent in, and print the deaths in it as they would be
+/// sent. A file that doesn't parse is an error carrying the parser's message.
+pub(crate) fn parse_command(args: &[String]) -> Result<()> {
+    const USAGE: &str = "Usage: deathlogger-agent parse <path> [--all] [--json]";
+    let path = args.first().filter(|a| !a.starts_with("--")).map(PathBuf::from).ok_or_else(|| anyhow!(USAGE))?;
+    if let Some(flag) = args[1..].iter().find(|a| !matches!(a.as_str(), "--all" | "--json")) {
+        return Err(anyhow!("Unknown option {}\n{}", flag, USAGE));
+    }
+    let all = args.iter().any(|a| a == "--all");
+    let cfg_path = config_path()?;
+    let cfg = if cfg_path.exists() { load_config(&cfg_path)? } else { Config::default() };
+    let (count, deaths) = parse_file(&cfg, &path, all)?;
+    if args.iter().any(|a| a == "--json") {
+        // Only the JSON on stdout, so it can be diffed or piped.
+        let out = if all { serde_json::to_string_pretty(&deaths)? } else { serde_json::to_string_pretty(&deaths.last())? };
+        println!("{}", out);
+        return Ok(());
+    }
+    println!("[parse] {}: {} entries, showing {}", path.display(), count, if all { "all" } else { "the newest" });
+    if deaths.is_empty() {
+        println!("No deaths in the file.");
+    }
+    for death in &deaths {
+        println!("{}", serde_json::to_string_pretty(death)?);
+    }
+    Ok(())
+}
+
+/// The number of entries in `path` and its deaths as the agent would send them,
+/// oldest first: every one with `all`, otherwise the newest. The adapter goes by
+/// the file name; one that was renamed is read as DeathLogger's.
+pub(crate) fn parse_file(cfg: &Config, path: &Path, all: bool) -> Result<(usize, Vec<DeathPayload>)> {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    let adapter = sv_adapters(cfg).into_iter().find(|a| a.file_name().eq_ignore_ascii_case(name)).unwrap_or_else(|| {
+        Arc::new(DeathLoggerAdapter { file_name: cfg.sv_file_name.clone(), fmt: SvFormat::from_config(cfg) })
+    });
+    let marks = all.then(SvMarks::default);
+    let mut snapshot = adapter.parse(path, marks.as_ref()).with_context(|| format!("parsing {}", path.display()))?;
+    let account = account_from_sv_path(path);
+    let mut deaths: Vec<DeathPayload> = std::mem::take(&mut snapshot.history).into_iter().chain(snapshot.latest.take()).collect();
+    for death in &mut deaths {
+        death.addon = snapshot.addon_info();
+        death.account = account.clone();
+        resolve_identity(death, path, snapshot.recent_identity.as_ref());
+        prepare_payload(cfg, death)?;
+    }
+    Ok((snapshot.count, deaths))
+}
@@ -0,0 +1,1219 @@
+//! Agent configuration: the `config.toml` schema, its defaults and validation.
+
+use crate::*;
+
+// ---------- Configuration ----------
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct Config {
+    /// Full path to the WoW root folder; e.g.
+    ///   C:\Program Files (x86)\World of Warcraft
+    pub(crate) wow_root: String,
+    /// Which branch inside WoW to use: one of "_retail_", "_classic_", "_classic_era_", "_classic_ptr_"
+    pub(crate) wow_branch: String,
+    /// Serial of the volume wow_root was last seen on ("1A2B-3C4D"); filled in automatically
+    pub(crate) wow_volume_serial: String,
+    /// Branch and .build.info products of the install, to recognize it on another drive letter
+    pub(crate) wow_fingerprint: String,
+    /// Random id sent with every upload so servers can tell agents apart; filled in automatically
+    pub(crate) agent_id: String,
+
+    /// Server endpoint to upload to (e.g., https://example.com/api/death)
+    pub(crate) api_url: String,
+    /// Optional API token (sent as header "Authorization: Bearer <token>" if not empty)
+    pub(crate) api_token: String,
+    /// Upload to several servers; when non-empty this replaces api_url/api_token
+    pub(crate) endpoints: Vec<Endpoint>,
+    /// Fetch short-lived bearer tokens for servers without a static token (see config.example.toml)
+    pub(crate) oauth: Option<OAuthConfig>,
+    /// Extra headers on every request to the upload servers; values may use `${ENV_VAR}`
+    pub(crate) headers: BTreeMap<String, String>,
+    /// Shared secret for signing uploads (X-DeathLogger-Signature); empty sends no signature
+    pub(crate) hmac_secret: String,
+
+    /// Whether agent starts with Windows
+    pub(crate) start_with_windows: bool,
+    /// Seconds running work may take to finish after Ctrl+C before it is abandoned
+    pub(crate) shutdown_grace_secs: u64,
+    /// Seconds after a clean stop within which a start resumes the stopped agent's timers (0 = never)
+    pub(crate) handoff_max_age_secs: u64,
+
+    /// Shorthand for both pairing windows below, for configs from before they were split
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) pair_window_secs: Option<i64>,
+    /// Seconds before the death a screenshot may be taken and still pair (default 10)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) pair_window_before_secs: Option<i64>,
+    /// Seconds after the death a screenshot may be taken and still pair (default 120)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) pair_window_after_secs: Option<i64>,
+    /// Seconds an unpaired screenshot stays queued; 0 = four times the longer pairing window
+    pub(crate) screenshot_ttl_secs: i64,
+    /// Attach every screenshot in the pairing window instead of only the nearest
+    pub(crate) attach_all_screenshots_in_window: bool,
+    /// Most screenshots attached to one death with `attach_all_screenshots_in_window`
+    pub(crate) max_screenshots_per_death: usize,
+    /// Send a screenshot taken after its death was uploaded without one in a follow-up request
+    pub(crate) late_screenshots: bool,
+
+    /// Whether to auto-update addon files from GitHub at launch
+    pub(crate) update_addon_on_start: bool,
+
+    /// Screenshots hashed per poll tick while building the screenshot index (0 disables)
+    pub(crate) screenshot_index_batch: usize,
+
+    /// Days covered by the periodic reliability summary written to the log (0 disables)
+    pub(crate) reliability_summary_days: i64,
+    /// Also show the periodic reliability summary as a desktop notification (Windows)
+    pub(crate) reliability_summary_toast: bool,
+
+    /// How many recent outgoing requests to keep (redacted) for `debug last-requests`; 0 disables
+    pub(crate) capture_last_requests: usize,
+
+    /// Ordered killer name cleanup rules (see config.example.toml)
+    pub(crate) killer_remap: Vec<KillerRule>,
+    /// Run the built-in killer rules (strip pet owner decoration and realm suffix) before `killer_remap`
+    pub(crate) killer_remap_builtins: bool,
+
+    /// "full" sends every bag snapshot; "diff" sends changes against the last full one
+    pub(crate) bags_mode: BagsMode,
+    /// In diff mode, send a full snapshot every N deaths per character
+    pub(crate) bags_keyframe_every: u32,
+    /// "full" sends `bags` and `equipped` as recorded, "summary" item counts and ids, "none" neither
+    pub(crate) inventory_detail: InventoryDetail,
+    /// Leave out death entry fields the agent doesn't know instead of passing them through
+    pub(crate) drop_unknown_fields: bool,
+    /// Death payload shape to send; the previous version for servers that don't take the current one yet
+    pub(crate) payload_schema_version: u32,
+
+    /// Master switch for sending deaths anywhere. When false the agent still watches
+    /// and parses, but nothing leaves the machine.
+    pub(crate) uploads_enabled: bool,
+    /// Deaths that are not uploaded (`[filters]`, see config.example.toml)
+    pub(crate) filters: DeathFilters,
+    /// When the filters change, upload journaled deaths they now let through without asking
+    pub(crate) refilter_on_change: bool,
+    /// Days a filtered death stays in filtered.jsonl
+    pub(crate) filter_journal_days: i64,
+    /// Fields left out of every upload (`[privacy]`, see config.example.toml)
+    pub(crate) privacy: Privacy,
+
+    /// PEM bundle of extra CA certificates trusted for the upload servers (not for addon downloads)
+    pub(crate) tls_ca_file: String,
+    /// Skip certificate checks for the upload servers. Dangerous; for testing only
+    pub(crate) tls_accept_invalid_certs: bool,
+    /// Client certificate presented to the upload servers: a PEM chain or a `.p12`/`.pfx`
+    /// bundle. Empty disables mutual TLS
+    pub(crate) tls_client_cert: String,
+    /// PKCS#8 PEM private key for a PEM tls_client_cert; empty when the key is in the certificate file
+    pub(crate) tls_client_key: String,
+    /// Password protecting a PKCS#12 tls_client_cert
+    pub(crate) tls_client_key_password: String,
+
+    /// Proxy for all requests ("http://[user:pass@]host:port" or "socks5://..."); empty uses HTTP(S)_PROXY
+    pub(crate) proxy_url: String,
+
+    /// Seconds to wait for a TCP/TLS connection to a server
+    pub(crate) http_connect_timeout_secs: u64,
+    /// Seconds a whole request (including the upload body) may take
+    pub(crate) http_request_timeout_secs: u64,
+
+    /// Hosts the agent may contact; empty means unrestricted.
+    /// Entries are exact hosts ("example.com") or wildcard subdomains ("*.example.com").
+    pub(crate) network_allowlist: Vec<String>,
+
+    /// Mirror warnings and errors into the Windows Application event log
+    pub(crate) event_log: bool,
+
+    /// Opt-in anonymous daily usage ping (see `deathlogger-agent telemetry preview`)
+    pub(crate) telemetry: bool,
+    /// Where the usage ping is sent
+    pub(crate) telemetry_url: String,
+
+    /// Levels announced as survivor milestones; empty means the branch's level cap
+    pub(crate) milestone_levels: Vec<i64>,
+    /// Where milestones are posted; empty means `<api_url>/milestones`
+    pub(crate) milestones_url: String,
+    /// Also announce milestones characters had already passed when the agent first saw them
+    pub(crate) announce_historical_milestones: bool,
+
+    /// Where to post a liveness heartbeat (with the upload auth); empty disables it
+    pub(crate) heartbeat_url: String,
+    /// Seconds between heartbeats
+    pub(crate) heartbeat_interval_secs: u64,
+
+    /// Endpoint issuing screenshot freshness nonces; empty disables the challenge
+    pub(crate) screenshot_challenge_url: String,
+    /// Drop screenshots older than the challenge's `max_age_secs` instead of attaching them
+    pub(crate) enforce_screenshot_max_age: bool,
+
+    /// Seconds to offer a note prompt in the console after a live death (0 disables)
+    pub(crate) annotation_prompt_secs: u64,
+
+    /// Pixelate `blur_regions` of screenshots before they leave the machine
+    pub(crate) blur_chat_region: bool,
+    /// Areas to pixelate, in percent of the image (default: the standard chat frame)
+    pub(crate) blur_regions: Vec<BlurRegion>,
+    /// Re-encode screenshots as JPEG at `screenshot_quality` before they are sent
+    pub(crate) screenshot_recompress: bool,
+    /// JPEG quality (1-100) for recompressed and blurred screenshots
+    pub(crate) screenshot_quality: u8,
+    /// Shrink screenshots whose longer side exceeds this many pixels before they are sent; 0 = never
+    pub(crate) screenshot_max_dimension: u32,
+    /// Size limit for the cache of re-encoded screenshots, least recently used dropped first; 0 = no cache
+    pub(crate) screenshot_cache_max_mb: u64,
+    /// Also send a small JPEG of the nearest screenshot as the `thumbnail` part
+    pub(crate) screenshot_thumbnail: bool,
+    /// Longer side of the thumbnail in pixels
+    pub(crate) thumbnail_max_dimension: u32,
+
+    /// Media endpoint for uploading screenshots once and referencing them by id; empty sends them inline
+    pub(crate) media_url: String,
+
+    /// Discord webhook that also gets each death as an embed; empty disables it
+    pub(crate) discord_webhook_url: String,
+    /// Where deaths go: "api", "discord", or "both"
+    pub(crate) upload_mode: UploadMode,
+    /// Request body of a death upload: "multipart" or "json"
+    pub(crate) upload_format: UploadFormat,
+    /// In json format, send the screenshot base64-encoded in the body
+    pub(crate) embed_screenshot_base64: bool,
+    /// How screenshots reach the server: "inline" with the death, or "presigned" (PUT to a URL the server hands out)
+    pub(crate) upload_flow: UploadFlow,
+
+    /// Gzip the JSON part of uploads (falls back to plain JSON if the server refuses it)
+    pub(crate) compress_uploads: bool,
+
+    /// Send the death first, then PUT the screenshot in resumable chunks (the server must support it)
+    pub(crate) resumable_uploads: bool,
+    /// Chunk size for resumable screenshot uploads
+    pub(crate) upload_chunk_bytes: usize,
+
+    /// Send several ready deaths in one request (`deaths` array) instead of one request each
+    pub(crate) batch_uploads: bool,
+    /// Most deaths per batch request
+    pub(crate) max_batch_size: usize,
+    /// NDJSON endpoint that gets a file's earlier unsent deaths in one streamed request; empty uploads them one by one
+    pub(crate) bulk_api_url: String,
+    /// Upload requests allowed per minute, across all endpoints; 0 means no limit
+    pub(crate) max_uploads_per_minute: u32,
+
+    /// Events kept in trace.jsonl for `replay`; 0 disables recording
+    pub(crate) event_trace_max: usize,
+    /// Parse SavedVariables files on every event and poll, even when their size and mtime haven't changed
+    pub(crate) reparse_unchanged_sv: bool,
+    /// Addons whose SavedVariables are read for deaths (see SV_ADAPTER_NAMES)
+    pub(crate) sv_adapters: Vec<String>,
+    /// SavedVariables file the addon writes, for forks that rename it
+    pub(crate) sv_file_name: String,
+    /// Globals read from that file; the first one holding a table is used
+    pub(crate) sv_global_names: Vec<String>,
+    /// Deepest table nesting converted from a death entry; deeper tables and
+    /// tables that contain themselves are sent as "<recursion>"
+    pub(crate) sv_max_table_depth: usize,
+    /// How the addon's `at` values relate to UTC
+    pub(crate) timestamp_mode: TimestampMode,
+    /// Parser tried first on SavedVariables; the other one reads files it refuses
+    pub(crate) sv_parser: SvParser,
+
+    /// Deaths of one character within surge_window_secs that switch it to grouped uploads; 0 disables
+    pub(crate) surge_deaths: usize,
+    pub(crate) surge_window_secs: i64,
+    /// Grouping stays on this long after the last death over the threshold
+    pub(crate) surge_cooldown_secs: i64,
+    /// Longest a death waits in a group before the group is sent
+    pub(crate) surge_flush_secs: i64,
+
+    /// Base64 X25519 public key; when set, deaths are sealed to it before upload
+    pub(crate) encrypt_to_public_key: String,
+    /// Also seal the screenshot (otherwise it is sent as-is alongside the sealed death)
+    pub(crate) encrypt_screenshot: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            wow_root: String::new(),
+            wow_branch: "_retail_".into(),
+            wow_volume_serial: String::new(),
+            wow_fingerprint: String::new(),
+            agent_id: String::new(),
+            api_url: "https://your-server.example/upload".into(),
+            api_token: String::new(),
+            endpoints: Vec::new(),
+            oauth: None,
+            headers: BTreeMap::new(),
+            hmac_secret: String::new(),
+            start_with_windows: false,
+            shutdown_grace_secs: 10,
+            handoff_max_age_secs: 300,
+            pair_window_secs: None,
+            pair_window_before_secs: None,
+            pair_window_after_secs: None,
+            screenshot_ttl_secs: 0,
+            attach_all_screenshots_in_window: false,
+            max_screenshots_per_death: 3,
+            late_screenshots: true,
+            update_addon_on_start: true,
+            screenshot_index_batch: 50,
+            reliability_summary_days: 7,
+            reliability_summary_toast: false,
+            capture_last_requests: 5,
+            killer_remap: Vec::new(),
+            killer_remap_builtins: true,
+            bags_mode: BagsMode::Full,
+            bags_keyframe_every: 10,
+            inventory_detail: InventoryDetail::Full,
+            drop_unknown_fields: false,
+            payload_schema_version: SCHEMA_VERSION,
+            uploads_enabled: true,
+            filters: DeathFilters::default(),
+            refilter_on_change: false,
+            filter_journal_days: 30,
+            privacy: Privacy::default(),
+            tls_ca_file: String::new(),
+            tls_accept_invalid_certs: false,
+            tls_client_cert: String::new(),
+            tls_client_key: String::new(),
+            tls_client_key_password: String::new(),
+            proxy_url: String::new(),
+            http_connect_timeout_secs: 10,
+            http_request_timeout_secs: 60,
+            network_allowlist: Vec::new(),
+            event_log: false,
+            telemetry: false,
+            telemetry_url: String::new(),
+            milestone_levels: Vec::new(),
+            milestones_url: String::new(),
+            announce_historical_milestones: false,
+            heartbeat_url: String::new(),
+            heartbeat_interval_secs: 300,
+            screenshot_challenge_url: String::new(),
+            annotation_prompt_secs: 0,
+            blur_chat_region: false,
+            blur_regions: default_blur_regions(),
+            screenshot_recompress: false,
+            screenshot_quality: 90,
+            screenshot_max_dimension: 0,
+            screenshot_cache_max_mb: 200,
+            screenshot_thumbnail: false,
+            thumbnail_max_dimension: 320,
+            media_url: String::new(),
+            discord_webhook_url: String::new(),
+            upload_mode: UploadMode::Both,
+            upload_format: UploadFormat::Multipart,
+            embed_screenshot_base64: false,
+            upload_flow: UploadFlow::Inline,
+            enforce_screenshot_max_age: true,
+            compress_uploads: false,
+            resumable_uploads: false,
+            upload_chunk_bytes: 1024 * 1024,
+            batch_uploads: false,
+            max_batch_size: 20,
+            bulk_api_url: String::new(),
+            max_uploads_per_minute: 0,
+            event_trace_max: 0,
+            reparse_unchanged_sv: false,
+            sv_adapters: vec!["deathlogger".into()],
+            sv_file_name: SV_FILE_NAME.into(),
+            sv_global_names: vec![SV_GLOBAL_NAME.into()],
+            sv_max_table_depth: 64,
+            timestamp_mode: TimestampMode::AsIs,
+            sv_parser: SvParser::default(),
+            surge_deaths: 10,
+            surge_window_secs: 300,
+            surge_cooldown_secs: 300,
+            surge_flush_secs: 60,
+            encrypt_to_public_key: String::new(),
+            encrypt_screenshot: true,
+        }
+    }
+}
+
+/// Stands in for the data directory during `replay`, so a replay never touches the real state.
+pub(crate) static CONFIG_DIR_OVERRIDE: once_cell::sync::OnceCell<PathBuf> = once_cell::sync::OnceCell::new();
+
+pub(crate) fn config_dir() -> Result<PathBuf> {
+    if let Some(d) = CONFIG_DIR_OVERRIDE.get() {
+        return Ok(d.clone());
+    }
+    let d = data_dir()
+        .or_else(|| home_dir().map(|h| h.join("AppData/Roaming")))
+        .ok_or_else(|| anyhow!("Cannot determine writable config directory"))?;
+    Ok(d.join("DeathLoggerAgent"))
+}
+
+pub(crate) fn config_path() -> Result<PathBuf> {
+    Ok(config_dir()?.join("config.toml"))
+}
+
+pub(crate) fn load_config(path: &Path) -> Result<Config> {
+    let s = fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    let cfg: Config = toml::from_str(&s)?;
+    encryption_key(&cfg).context("encrypt_to_public_key")?;
+    extra_headers(&cfg).context("[headers]")?;
+    Ok(cfg)
+}
+
+pub(crate) fn state_path() -> Result<PathBuf> {
+    Ok(config_dir()?.join("state.json"))
+}
+
+pub(crate) fn handoff_path() -> Result<PathBuf> {
+    Ok(config_dir()?.join("handoff.json"))
+}
+
+pub(crate) fn filter_journal_path() -> Result<PathBuf> {
+    Ok(config_dir()?.join("filtered.jsonl"))
+}
+
+pub(crate) fn notes_path() -> Result<PathBuf> {
+    Ok(config_dir()?.join("notes.json"))
+}
+
+pub(crate) fn note_resends_path() -> Result<PathBuf> {
+    Ok(config_dir()?.join("note_resends.json"))
+}
+
+pub(crate) fn screenshot_index_path() -> Result<PathBuf> {
+    Ok(config_dir()?.join("screenshot_index.json"))
+}
+
+// ---------- Config validation ----------
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Severity {
+    /// The combination can never work
+    Error,
+    /// Probably not what the user meant
+    Warning,
+}
+
+/// One upload server from `[[endpoints]]`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct Endpoint {
+    /// Shown in logs and `status`; also names the endpoint in the retry queue
+    pub(crate) name: String,
+    pub(crate) url: String,
+    pub(crate) token: String,
+    pub(crate) enabled: bool,
+}
+
+impl Default for Endpoint {
+    fn default() -> Self {
+        Self { name: String::new(), url: String::new(), token: String::new(), enabled: true }
+    }
+}
+
+/// Name the legacy api_url/api_token pair goes by.
+pub(crate) const DEFAULT_ENDPOINT: &str = "default";
+
+/// Enabled upload servers: `[[endpoints]]`, or api_url/api_token when there are none.
+pub(crate) fn api_endpoints(cfg: &Config) -> Vec<Endpoint> {
+    if cfg.endpoints.is_empty() {
+        return vec![Endpoint {
+            name: DEFAULT_ENDPOINT.into(),
+            url: cfg.api_url.clone(),
+            token: cfg.api_token.clone(),
+            enabled: true,
+        }];
+    }
+    cfg.endpoints.iter().filter(|e| e.enabled).cloned().collect()
+}
+
+/// The first enabled server; milestones and the setup checks use it.
+pub(crate) fn primary_endpoint(cfg: &Config) -> Endpoint {
+    api_endpoints(cfg).into_iter().next().unwrap_or_default()
+}
+
+impl Config {
+    /// Seconds before the death a screenshot still pairs.
+    pub(crate) fn pair_window_before(&self) -> i64 {
+        self.pair_window_before_secs.or(self.pair_window_secs).unwrap_or(DEFAULT_PAIR_WINDOW_BEFORE_SECS)
+    }
+
+    /// Seconds after the death a screenshot still pairs.
+    pub(crate) fn pair_window_after(&self) -> i64 {
+        self.pair_window_after_secs.or(self.pair_window_secs).unwrap_or(DEFAULT_PAIR_WINDOW_AFTER_SECS)
+    }
+
+    /// The key a pairing window comes from, for messages about it.
+    pub(crate) fn pair_window_key(&self, after: bool) -> &'static str {
+        match (after, self.pair_window_secs) {
+            (true, Some(_)) if self.pair_window_after_secs.is_none() => "pair_window_secs",
+            (false, Some(_)) if self.pair_window_before_secs.is_none() => "pair_window_secs",
+            (true, _) => "pair_window_after_secs",
+            (false, _) => "pair_window_before_secs",
+        }
+    }
+
+    /// This config with api_url/api_token pointing at `ep`, so the upload code
+    /// can stay unaware of how many servers there are.
+    pub(crate) fn for_endpoint(&self, ep: &Endpoint) -> Config {
+        Config { api_url: ep.url.clone(), api_token: ep.token.clone(), ..self.clone() }
+    }
+}
+
+/// One known interdependency between config options. `check` returns the
+/// explanation (including a suggested value) when the rule is violated.
+pub(crate) struct ConfigRule {
+    pub(crate) area: &'static str,
+    pub(crate) severity: Severity,
+    pub(crate) check: fn(&Config) -> Option<String>,
+}
+
+/// The addon's default delay between a death and its screenshot.
+pub(crate) const ADDON_SCREENSHOT_DELAY_SECS: f64 = 0.5;
+
+/// Pairing windows when neither they nor `pair_window_secs` are set. Death
+/// screenshots come after the death; one just before is usually from the pull.
+pub(crate) const DEFAULT_PAIR_WINDOW_BEFORE_SECS: i64 = 10;
+pub(crate) const DEFAULT_PAIR_WINDOW_AFTER_SECS: i64 = 120;
+
+pub(crate) const CONFIG_RULES: &[ConfigRule] = &[
+    ConfigRule {
+        area: "screenshots",
+        severity: Severity::Error,
+        check: |c| {
+            (!(1..=100).contains(&c.screenshot_quality))
+                .then(|| format!("screenshot_quality must be between 1 and 100, not {}", c.screenshot_quality))
+        },
+    },
+    ConfigRule {
+        area: "screenshots",
+        severity: Severity::Warning,
+        check: |c| (c.screenshot_max_dimension > 0 && c.screenshot_max_dimension < 480).then(|| {
+            format!(
+                "screenshot_max_dimension = {} leaves screenshots too small to read; use 1920, or 0 to keep full size",
+                c.screenshot_max_dimension
+            )
+        }),
+    },
+    ConfigRule {
+        area: "screenshots",
+        severity: Severity::Error,
+        check: |c| {
+            let windows = [(c.pair_window_key(true), c.pair_window_after()), (c.pair_window_key(false), c.pair_window_before())];
+            let (key, secs) = windows.into_iter().find(|(_, secs)| *secs < 0)?;
+            Some(format!("{key} = {secs} can never match a screenshot; use {}", if key.contains("before") { 10 } else { 120 }))
+        },
+    },
+    ConfigRule {
+        area: "screenshots",
+        severity: Severity::Error,
+        check: |c| (c.attach_all_screenshots_in_window && c.max_screenshots_per_death == 0).then(|| {
+            "max_screenshots_per_death = 0 attaches nothing; use 3, or turn attach_all_screenshots_in_window off".to_string()
+        }),
+    },
+    ConfigRule {
+        area: "screenshots",
+        severity: Severity::Warning,
+        check: |c| (c.pair_window_after() == 0).then(|| {
+            format!(
+                "{} = 0 only pairs screenshots taken in the same second as the death, but the addon \
+                 takes its screenshot {}s later by default; use 120",
+                c.pair_window_key(true),
+                ADDON_SCREENSHOT_DELAY_SECS
+            )
+        }),
+    },
+    ConfigRule {
+        area: "screenshots",
+        severity: Severity::Warning,
+        check: |c| {
+            let windows = [(c.pair_window_key(true), c.pair_window_after()), (c.pair_window_key(false), c.pair_window_before())];
+            let (key, secs) = windows.into_iter().find(|(_, secs)| *secs > 3600)?;
+            Some(format!("{key} = {secs} will attach unrelated screenshots taken up to an hour away; use 120"))
+        },
+    },
+    ConfigRule {
+        area: "bags",
+        severity: Severity::Warning,
+        check: |c| (c.bags_mode == BagsMode::Diff && c.bags_keyframe_every <= 1).then(|| {
+            format!(
+                "bags_mode = \"diff\" with bags_keyframe_every = {} sends a full snapshot every time; use 10",
+                c.bags_keyframe_every
+            )
+        }),
+    },
+    ConfigRule {
+        area: "upload",
+        severity: Severity::Error,
+        check: |c| {
+            let bad: Vec<String> = api_endpoints(c)
+                .into_iter()
+                .filter(|e| !reqwest::Url::parse(&e.url).map(|u| matches!(u.scheme(), "http" | "https")).unwrap_or(false))
+                .map(|e| format!("{:?} ({})", e.url, e.name))
+                .collect();
+            (c.uploads_enabled && c.upload_mode != UploadMode::Discord && !bad.is_empty())
+                .then(|| format!("upload URL {} is not an http(s) URL, so no upload can succeed", bad.join(", ")))
+        },
+    },
+    ConfigRule {
+        area: "upload",
+        severity: Severity::Warning,
+        check: |c| {
+            let plain: Vec<String> = api_endpoints(c)
+                .into_iter()
+                .filter(|e| e.url.starts_with("http://") && !e.token.is_empty())
+                .map(|e| e.name)
+                .collect();
+            (!plain.is_empty() && c.encrypt_to_public_key.is_empty()).then(|| {
+                format!("the API token of {} is sent over plain http://; switch the URL to https://", plain.join(", "))
+            })
+        },
+    },
+    ConfigRule {
+        area: "upload",
+        severity: Severity::Error,
+        check: |c| {
+            let o = c.oauth.as_ref()?;
+            (o.token_url.is_empty() || o.client_id.is_empty()).then(|| "[oauth] needs token_url and client_id".to_string())
+        },
+    },
+    ConfigRule {
+        area: "upload",
+        severity: Severity::Warning,
+        check: |c| {
+            let o = c.oauth.as_ref()?;
+            o.token_url
+                .starts_with("http://")
+                .then(|| "[oauth] token_url is plain http://, so the client secret travels unencrypted".to_string())
+        },
+    },
+    ConfigRule {
+        area: "upload",
+        severity: Severity::Warning,
+        check: |c| {
+            (c.oauth.is_some() && api_endpoints(c).iter().all(|e| !e.token.is_empty()))
+                .then(|| "[oauth] is unused: every upload server has its own token".to_string())
+        },
+    },
+    ConfigRule {
+        area: "upload",
+        severity: Severity::Error,
+        check: |c| {
+            let mut names: Vec<&str> = c.endpoints.iter().map(|e| e.name.as_str()).collect();
+            if names.iter().any(|n| n.is_empty() || *n == DISCORD_TARGET) {
+                return Some(format!("every [[endpoints]] entry needs a name other than {:?}", DISCORD_TARGET));
+            }
+            names.sort();
+            let before = names.len();
+            names.dedup();
+            (names.len() != before).then(|| "[[endpoints]] names must be unique".to_string())
+        },
+    },
+    ConfigRule {
+        area: "upload",
+        severity: Severity::Error,
+        check: |c| {
+            (!c.endpoints.is_empty() && c.endpoints.iter().all(|e| !e.enabled) && c.upload_mode != UploadMode::Discord)
+                .then(|| "every [[endpoints]] entry is disabled, so nothing is uploaded".to_string())
+        },
+    },
+    ConfigRule {
+        area: "discord",
+        severity: Severity::Error,
+        check: |c| {
+            (c.upload_mode == UploadMode::Discord && url_host(&c.discord_webhook_url).is_none())
+                .then(|| "upload_mode = \"discord\" needs a valid discord_webhook_url".to_string())
+        },
+    },
+    ConfigRule {
+        area: "telemetry",
+        severity: Severity::Warning,
+        check: |c| {
+            (c.telemetry && url_host(&c.telemetry_url).is_none())
+                .then(|| "telemetry is on but telemetry_url is not a valid URL; no ping will be sent".to_string())
+        },
+    },
+    ConfigRule {
+        area: "heartbeat",
+        severity: Severity::Error,
+        check: |c| {
+            (!c.heartbeat_url.is_empty() && url_host(&c.heartbeat_url).is_none())
+                .then(|| format!("heartbeat_url {:?} is not a valid URL", c.heartbeat_url))
+        },
+    },
+    ConfigRule {
+        area: "heartbeat",
+        severity: Severity::Warning,
+        check: |c| (!c.heartbeat_url.is_empty() && c.heartbeat_interval_secs < HEARTBEAT_MIN_SECS).then(|| {
+            format!(
+                "heartbeat_interval_secs = {} is below the minimum; heartbeats go out every {}s",
+                c.heartbeat_interval_secs, HEARTBEAT_MIN_SECS
+            )
+        }),
+    },
+    ConfigRule {
+        area: "network",
+        severity: Severity::Error,
+        check: |c| {
+            (!c.tls_ca_file.is_empty() && !Path::new(&c.tls_ca_file).is_file())
+                .then(|| format!("tls_ca_file {} does not exist", c.tls_ca_file))
+        },
+    },
+    ConfigRule {
+        area: "network",
+        severity: Severity::Error,
+        check: |c| {
+            [("tls_client_cert", &c.tls_client_cert), ("tls_client_key", &c.tls_client_key)]
+                .into_iter()
+                .find(|(_, path)| !path.is_empty() && !Path::new(path.as_str()).is_file())
+                .map(|(name, path)| format!("{} {} does not exist", name, path))
+        },
+    },
+    ConfigRule {
+        area: "network",
+        severity: Severity::Error,
+        check: |c| {
+            (c.tls_client_cert.is_empty() && !c.tls_client_key.is_empty())
+                .then(|| "tls_client_key is set without tls_client_cert; set both or neither".to_string())
+        },
+    },
+    ConfigRule {
+        area: "network",
+        severity: Severity::Warning,
+        check: |c| {
+            c.tls_accept_invalid_certs.then(|| {
+                "tls_accept_invalid_certs is on: upload servers are not authenticated and anyone on the network \
+                 can read or alter uploads; use tls_ca_file instead"
+                    .to_string()
+            })
+        },
+    },
+    ConfigRule {
+        area: "network",
+        severity: Severity::Error,
+        check: |c| {
+            if c.proxy_url.is_empty() {
+                return None;
+            }
+            let ok = reqwest::Url::parse(&c.proxy_url)
+                .map(|u| matches!(u.scheme(), "http" | "https" | "socks5" | "socks5h"))
+                .unwrap_or(false);
+            (!ok).then(|| "proxy_url must look like http://host:port or socks5://host:port".to_string())
+        },
+    },
+    ConfigRule {
+        area: "network",
+        severity: Severity::Warning,
+        check: |c| (c.http_request_timeout_secs < c.http_connect_timeout_secs).then(|| {
+            format!(
+                "http_request_timeout_secs ({}) is shorter than http_connect_timeout_secs ({}), so the connect timeout never applies; use 60",
+                c.http_request_timeout_secs, c.http_connect_timeout_secs
+            )
+        }),
+    },
+    ConfigRule {
+        area: "network",
+        severity: Severity::Error,
+        check: |c| {
+            let blocked: Vec<String> = api_endpoints(c)
+                .iter()
+                .filter_map(|e| url_host(&e.url))
+                .filter(|h| !(c.network_allowlist.is_empty() || c.network_allowlist.iter().any(|p| host_matches(p, h))))
+                .collect();
+            (c.uploads_enabled && !blocked.is_empty()).then(|| {
+                format!("network_allowlist does not include the upload host(s) {}; their uploads would be blocked", blocked.join(", "))
+            })
+        },
+    },
+    ConfigRule {
+        area: "upload",
+        severity: Severity::Warning,
+        check: |c| (c.batch_uploads && !c.encrypt_to_public_key.is_empty()).then(|| {
+            "batch_uploads is ignored while encrypt_to_public_key is set; deaths are sent one per request".to_string()
+        }),
+    },
+    ConfigRule {
+        area: "upload",
+        severity: Severity::Warning,
+        check: |c| (c.batch_uploads && c.max_batch_size <= 1).then(|| {
+            "max_batch_size below 2 sends one death per request, which defeats batch_uploads; use 20".to_string()
+        }),
+    },
+    ConfigRule {
+        area: "upload",
+        severity: Severity::Error,
+        check: |c| {
+            (!c.bulk_api_url.is_empty() && url_host(&c.bulk_api_url).is_none())
+                .then(|| format!("bulk_api_url {:?} is not a valid URL", c.bulk_api_url))
+        },
+    },
+    ConfigRule {
+        area: "upload",
+        severity: Severity::Error,
+        check: |c| (!c.bulk_api_url.is_empty() && !c.encrypt_to_public_key.is_empty()).then(|| {
+            "bulk_api_url sends deaths as plain JSON; clear encrypt_to_public_key or bulk_api_url".to_string()
+        }),
+    },
+    ConfigRule {
+        area: "upload",
+        severity: Severity::Warning,
+        check: |c| (!c.bulk_api_url.is_empty() && c.upload_mode == UploadMode::Discord).then(|| {
+            "bulk_api_url is ignored with upload_mode = \"discord\"; earlier deaths are not sent".to_string()
+        }),
+    },
+    ConfigRule {
+        area: "upload",
+        severity: Severity::Warning,
+        check: |c| (c.resumable_uploads && (!c.encrypt_to_public_key.is_empty() || !c.screenshot_challenge_url.is_empty())).then(|| {
+            "resumable_uploads is ignored while encryption or screenshot_challenge_url is on; screenshots go inline".to_string()
+        }),
+    },
+    ConfigRule {
+        area: "upload",
+        severity: Severity::Error,
+        check: |c| (c.upload_flow == UploadFlow::Presigned && !c.encrypt_to_public_key.is_empty()).then(|| {
+            "upload_flow = \"presigned\" sends deaths as plain JSON; clear encrypt_to_public_key or use \"inline\"".to_string()
+        }),
+    },
+    ConfigRule {
+        area: "upload",
+        severity: Severity::Warning,
+        check: |c| {
+            let ignored: Vec<&str> = [
+                ("upload_format = \"multipart\"", c.upload_format == UploadFormat::Multipart),
+                ("batch_uploads", c.batch_uploads),
+                ("resumable_uploads", c.resumable_uploads),
+                ("media_url", !c.media_url.is_empty()),
+                ("embed_screenshot_base64", c.embed_screenshot_base64),
+                ("screenshot_challenge_url", !c.screenshot_challenge_url.is_empty()),
+            ]
+            .into_iter()
+            .filter_map(|(name, on)| on.then_some(name))
+            .collect();
+            (c.upload_flow == UploadFlow::Presigned && !ignored.is_empty())
+                .then(|| format!("{} do not apply with upload_flow = \"presigned\" and are ignored", ignored.join(", ")))
+        },
+    },
+    ConfigRule {
+        area: "upload",
+        severity: Severity::Error,
+        check: |c| (c.upload_format == UploadFormat::Json && !c.encrypt_to_public_key.is_empty()).then(|| {
+            "encrypted uploads need upload_format = \"multipart\"; clear encrypt_to_public_key or switch back".to_string()
+        }),
+    },
+    ConfigRule {
+        area: "upload",
+        severity: Severity::Warning,
+        check: |c| {
+            let ignored: Vec<&str> = [
+                ("batch_uploads", c.batch_uploads),
+                ("resumable_uploads", c.resumable_uploads),
+                ("screenshot_challenge_url", !c.screenshot_challenge_url.is_empty()),
+                ("compress_uploads", c.compress_uploads),
+            ]
+            .into_iter()
+            .filter_map(|(name, on)| on.then_some(name))
+            .collect();
+            (c.upload_format == UploadFormat::Json && !ignored.is_empty()).then(|| {
+                format!("{} only apply to upload_format = \"multipart\" and are ignored", ignored.join(", "))
+            })
+        },
+    },
+    ConfigRule {
+        area: "upload",
+        severity: Severity::Error,
+        check: |c| (c.resumable_uploads && c.upload_chunk_bytes < 64 * 1024).then(|| {
+            format!("upload_chunk_bytes = {} is too small to make progress; use 1048576", c.upload_chunk_bytes)
+        }),
+    },
+    ConfigRule {
+        area: "encryption",
+        severity: Severity::Warning,
+        check: |c| (c.encrypt_to_public_key.is_empty() && !c.encrypt_screenshot).then(|| {
+            "encrypt_screenshot = false has no effect without encrypt_to_public_key".to_string()
+        }),
+    },
+    ConfigRule {
+        area: "savedvariables",
+        severity: Severity::Error,
+        check: |c| (c.sv_file_name.is_empty() || c.sv_file_name.contains(['/', '\\'])).then(|| {
+            format!("sv_file_name = \"{}\" must be a file name like \"{SV_FILE_NAME}\", not a path", c.sv_file_name)
+        }),
+    },
+    ConfigRule {
+        area: "savedvariables",
+        severity: Severity::Error,
+        check: |c| {
+            let valid = |n: &String| {
+                n.chars().next().is_some_and(|ch| ch.is_ascii_alphabetic() || ch == '_')
+                    && n.chars().all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+            };
+            if c.sv_global_names.is_empty() {
+                Some(format!("sv_global_names is empty, so no deaths are read; use [\"{SV_GLOBAL_NAME}\"]"))
+            } else {
+                c.sv_global_names.iter().find(|n| !valid(n)).map(|n| format!("sv_global_names entry \"{n}\" is not a Lua global name"))
+            }
+        },
+    },
+    ConfigRule {
+        area: "savedvariables",
+        severity: Severity::Error,
+        check: |c| {
+            if c.sv_adapters.is_empty() {
+                Some("sv_adapters is empty, so no deaths are read; use [\"deathlogger\"]".to_string())
+            } else {
+                c.sv_adapters.iter().find(|a| !SV_ADAPTER_NAMES.contains(&a.as_str())).map(|a| {
+                    format!("sv_adapters entry \"{a}\" is not one of {}", SV_ADAPTER_NAMES.join(", "))
+                })
+            }
+        },
+    },
+    ConfigRule {
+        area: "savedvariables",
+        severity: Severity::Warning,
+        check: |c| (c.sv_max_table_depth < 8).then(|| {
+            format!(
+                "sv_max_table_depth = {} cuts off bags and equipped items as \"{RECURSION_MARKER}\"; use 64",
+                c.sv_max_table_depth
+            )
+        }),
+    },
+    ConfigRule {
+        area: "savedvariables",
+        severity: Severity::Warning,
+        check: |c| match c.timestamp_mode {
+            TimestampMode::OffsetSecs(secs) if secs.abs() > 14 * 3600 => Some(format!(
+                "timestamp_mode offset_secs = {secs} is more than any time zone is from UTC; deaths will show at the wrong time"
+            )),
+            _ => None,
+        },
+    },
+    ConfigRule {
+        area: "filters",
+        severity: Severity::Error,
+        check: |c| (c.filter_journal_days < 1).then(|| {
+            format!("filter_journal_days = {} keeps no filtered deaths to re-check; use 30", c.filter_journal_days)
+        }),
+    },
+    ConfigRule {
+        area: "filters",
+        severity: Severity::Warning,
+        check: |c| {
+            let bad: Vec<&str> = c.filters.skip_characters.iter().filter(|n| !n.contains('@')).map(String::as_str).collect();
+            (!bad.is_empty()).then(|| format!("filters.skip_characters need \"Player@Realm\"; {} match no character", bad.join(", ")))
+        },
+    },
+    ConfigRule {
+        area: "upload",
+        severity: Severity::Error,
+        check: |c| (!(1..=SCHEMA_VERSION).contains(&c.payload_schema_version)).then(|| {
+            format!(
+                "payload_schema_version must be between 1 and {SCHEMA_VERSION}, not {}",
+                c.payload_schema_version
+            )
+        }),
+    },
+    ConfigRule {
+        area: "bags",
+        severity: Severity::Warning,
+        check: |c| (c.bags_mode == BagsMode::Diff && c.inventory_detail != InventoryDetail::Full).then(|| {
+            "bags_mode = \"diff\" only applies with inventory_detail = \"full\"; bags are sent whole in the chosen detail".to_string()
+        }),
+    },
+    ConfigRule {
+        area: "screenshots",
+        severity: Severity::Warning,
+        check: |c| (c.screenshot_ttl_secs > 0 && c.screenshot_ttl_secs < c.pair_window_after()).then(|| {
+            format!(
+                "screenshot_ttl_secs = {} is shorter than {} = {}; screenshots can expire before their death is seen",
+                c.screenshot_ttl_secs,
+                c.pair_window_key(true),
+                c.pair_window_after()
+            )
+        }),
+    },
+    ConfigRule {
+        area: "screenshots",
+        severity: Severity::Error,
+        check: |c| (c.screenshot_thumbnail && c.thumbnail_max_dimension < 16).then(|| {
+            format!("thumbnail_max_dimension = {} is too small for a thumbnail; use 320", c.thumbnail_max_dimension)
+        }),
+    },
+];
+
+pub(crate) struct ConfigFinding {
+    pub(crate) area: &'static str,
+    pub(crate) severity: Severity,
+    pub(crate) message: String,
+}
+
+pub(crate) fn validate_config(cfg: &Config) -> Vec<ConfigFinding> {
+    let mut out: Vec<ConfigFinding> = CONFIG_RULES
+        .iter()
+        .filter_map(|r| (r.check)(cfg).map(|message| ConfigFinding { area: r.area, severity: r.severity, message }))
+        .collect();
+    out.sort_by_key(|f| f.area);
+    out
+}
+
+/// Print findings grouped by area. Returns the number of hard errors.
+pub(crate) fn print_config_findings(findings: &[ConfigFinding]) -> usize {
+    let mut area = "";
+    for f in findings {
+        if f.area != area {
+            area = f.area;
+            println!("[config] {}:", area);
+        }
+        let tag = if f.severity == Severity::Error { "error" } else { "warning" };
+        println!("      {}: {}", tag, f.message);
+    }
+    findings.iter().filter(|f| f.severity == Severity::Error).count()
+}
+
+/// `deathlogger-agent config validate`
+pub(crate) fn config_command(args: &[String]) -> Result<()> {
+    match args.first().map(String::as_str) {
+        Some("validate") => {}
+        _ => return Err(anyhow!("Usage: deathlogger-agent config validate")),
+    }
+    let cfg_path = config_path()?;
+    let cfg = load_config(&cfg_path)?;
+    let findings = validate_config(&cfg);
+    if findings.is_empty() {
+        println!("[config] {}: no problems found", cfg_path.display());
+        return Ok(());
+    }
+    match print_config_findings(&findings) {
+        0 => Ok(()),
+        n => Err(anyhow!("{} config error(s)", n)),
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum UploadMode {
+    Api,
+    Discord,
+    /// The API, then Discord when a webhook is configured
+    #[default]
+    Both,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum UploadFormat {
+    /// `multipart/form-data` with a `death` part and file parts
+    #[default]
+    Multipart,
+    /// The death itself as an `application/json` body
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum UploadFlow {
+    /// Screenshots travel with the death, as `upload_format` describes
+    #[default]
+    Inline,
+    /// The death goes as JSON; its screenshot is PUT to a presigned URL from the response
+    Presigned,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum BagsMode {
+    #[default]
+    Full,
+    Diff,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum InventoryDetail {
+    #[default]
+    Full,
+    Summary,
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SvParser {
+    /// The Lua interpreter, which runs whatever the file contains
+    Lua,
+    /// The pure-Rust data parser, which reads table assignments only
+    Data,
+}
+
+impl Default for SvParser {
+    /// The data parser on ARM, where it is the quicker of the two and the
+    /// vendored Lua build gets the least use; Lua elsewhere.
+    fn default() -> Self {
+        if cfg!(target_arch = "aarch64") {
+            SvParser::Data
+        } else {
+            SvParser::Lua
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TimestampMode {
+    /// Unix time already, as `time()` gives on a correctly set clock
+    #[default]
+    AsIs,
+    /// The local wall-clock time, written as if it were UTC
+    AssumeLocal,
+    /// Off by a fixed amount, which is added to every `at`
+    OffsetSecs(i64),
+}
+
+impl TimestampMode {
+    pub(crate) fn to_utc(self, at: i64) -> i64 {
+        match self {
+            TimestampMode::AsIs => at,
+            TimestampMode::OffsetSecs(secs) => at.saturating_add(secs),
+            TimestampMode::AssumeLocal => DateTime::from_timestamp(at, 0)
+                .and_then(|d| Local.from_local_datetime(&d.naive_utc()).earliest())
+                .map(|d| d.timestamp())
+                .unwrap_or(at),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A config with no findings, for each case to change one thing in.
+    fn base() -> Config {
+        Config { api_url: "https://deaths.example.com/upload".into(), ..Config::default() }
+    }
+
+    fn findings(cfg: &Config) -> Vec<(&'static str, Severity, String)> {
+        validate_config(cfg).into_iter().map(|f| (f.area, f.severity, f.message)).collect()
+    }
+
+    #[test]
+    fn base_config_has_no_findings() {
+        assert_eq!(findings(&base()), []);
+    }
+
+    #[test]
+    fn rules_trigger_only_on_their_combination() {
+        let key = BASE64.encode([7u8; 32]);
+        // (trigger, a near miss that must stay quiet, area, severity, part of the message)
+        let cases: Vec<(Config, Config, &str, Severity, &str)> = vec![
+            (
+                Config { pair_window_secs: Some(0), ..base() },
+                Config { pair_window_secs: Some(1), ..base() },
+                "screenshots",
+                Severity::Warning,
+                "takes its screenshot 0.5s later",
+            ),
+            (
+                Config { pair_window_secs: Some(-1), ..base() },
+                Config { pair_window_secs: Some(3600), ..base() },
+                "screenshots",
+                Severity::Error,
+                "pair_window_secs = -1 can never match",
+            ),
+            (
+                Config { attach_all_screenshots_in_window: true, max_screenshots_per_death: 0, ..base() },
+                Config { attach_all_screenshots_in_window: false, max_screenshots_per_death: 0, ..base() },
+                "screenshots",
+                Severity::Error,
+                "max_screenshots_per_death = 0 attaches nothing",
+            ),
+            (
+                Config { bags_mode: BagsMode::Diff, bags_keyframe_every: 1, ..base() },
+                Config { bags_mode: BagsMode::Full, bags_keyframe_every: 1, ..base() },
+                "bags",
+                Severity::Warning,
+                "sends a full snapshot every time",
+            ),
+            (
+                Config { bags_mode: BagsMode::Diff, inventory_detail: InventoryDetail::Summary, ..base() },
+                Config { bags_mode: BagsMode::Diff, ..base() },
+                "bags",
+                Severity::Warning,
+                "only applies with inventory_detail = \"full\"",
+            ),
+            (
+                Config { screenshot_ttl_secs: 60, ..base() },
+                Config { screenshot_ttl_secs: 0, ..base() },
+                "screenshots",
+                Severity::Warning,
+                "can expire before their death is seen",
+            ),
+            (
+                Config { screenshot_thumbnail: true, thumbnail_max_dimension: 0, ..base() },
+                Config { screenshot_thumbnail: false, thumbnail_max_dimension: 0, ..base() },
+                "screenshots",
+                Severity::Error,
+                "is too small for a thumbnail",
+            ),
+            (
+                Config { api_url: "http://deaths.example.com/upload".into(), api_token: "t".into(), ..base() },
+                Config {
+                    api_url: "http://deaths.example.com/upload".into(),
+                    api_token: "t".into(),
+                    encrypt_to_public_key: key.clone(),
+                    ..base()
+                },
+                "upload",
+                Severity::Warning,
+                "sent over plain http://",
+            ),
+            (
+                Config { api_url: "ftp://deaths.example.com".into(), ..base() },
+                Config { api_url: "ftp://deaths.example.com".into(), uploads_enabled: false, ..base() },
+                "upload",
+                Severity::Error,
+                "is not an http(s) URL",
+            ),
+            (
+                Config { batch_uploads: true, encrypt_to_public_key: key.clone(), ..base() },
+                Config { batch_uploads: false, encrypt_to_public_key: key.clone(), ..base() },
+                "upload",
+                Severity::Warning,
+                "batch_uploads is ignored while encrypt_to_public_key is set",
+            ),
+            (
+                Config { bulk_api_url: "https://deaths.example.com/bulk".into(), encrypt_to_public_key: key.clone(), ..base() },
+                Config { bulk_api_url: "https://deaths.example.com/bulk".into(), ..base() },
+                "upload",
+                Severity::Error,
+                "bulk_api_url sends deaths as plain JSON",
+            ),
+            (
+                Config { upload_flow: UploadFlow::Presigned, encrypt_to_public_key: key, ..base() },
+                Config { upload_flow: UploadFlow::Presigned, ..base() },
+                "upload",
+                Severity::Error,
+                "upload_flow = \"presigned\" sends deaths as plain JSON",
+            ),
+        ];
+        for (trigger, quiet, area, severity, message) in cases {
+            let found = findings(&trigger);
+            assert!(
+                found.iter().any(|(a, s, m)| *a == area && *s == severity && m.contains(message)),
+                "{message:?} not in {found:#?}"
+            );
+            let quiet = findings(&quiet);
+            assert!(!quiet.iter().any(|(_, _, m)| m.contains(message)), "{message:?} fired on the near miss: {quiet:#?}");
+        }
+    }
+
+    #[test]
+    fn findings_are_grouped_by_area() {
+        let cfg = Config {
+            bags_mode: BagsMode::Diff,
+            bags_keyframe_every: 0,
+            pair_window_secs: Some(-5),
+            batch_uploads: true,
+            max_batch_size: 1,
+            ..base()
+        };
+        let areas: Vec<&str> = findings(&cfg).into_iter().map(|(a, _, _)| a).collect();
+        assert_eq!(areas, ["bags", "screenshots", "upload"]);
+        assert_eq!(print_config_findings(&validate_config(&cfg)), 1, "one hard error");
+    }
+}
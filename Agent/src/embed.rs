@@ -0,0 +1,400 @@
+//! Embedding API: run the agent inside another application.
+//!
+//! [`AgentBuilder`] reads the configuration and applies overrides,
+//! [`AgentHandle::start`] runs the watch loop on a thread of its own, and
+//! [`AgentHandle::subscribe`] hands out a stream of [`AgentEvent`]s. The
+//! `deathlogger-agent` binary runs on this same API.
+//!
+//! The agent keeps its state in one directory per process, so one process runs
+//! at most one agent at a time.
+
+use crate::*;
+use tokio::sync::broadcast;
+
+// ---------- Events ----------
+
+/// Events buffered per subscriber; a subscriber that falls further behind
+/// misses the oldest ones.
+const EVENT_BUFFER: usize = 256;
+
+/// Something the running agent did.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum AgentEvent {
+    /// A death not seen before was read from a SavedVariables file.
+    DeathDetected { character: String, death: Box<DeathPayload> },
+    /// A screenshot was matched to a death and goes out with it.
+    ScreenshotPaired { character: String, at: i64, screenshot: PathBuf },
+    /// An upload target accepted a death.
+    Uploaded { character: String, at: i64, target: String },
+    /// An upload target did not take a death. `will_retry` is false when the
+    /// server rejected it for good.
+    UploadFailed { character: String, at: i64, target: String, error: String, will_retry: bool },
+}
+
+type Callback = Arc<dyn Fn(&AgentEvent) + Send + Sync>;
+
+/// Where the running agent's events go; set while an agent runs.
+struct EventSink {
+    tx: broadcast::Sender<AgentEvent>,
+    callbacks: Vec<Callback>,
+    /// Emitted since the last flush
+    pending: Vec<AgentEvent>,
+}
+
+static EVENT_SINK: Mutex<Option<EventSink>> = Mutex::new(None);
+
+/// Note an event for the running agent's callbacks and subscribers, if any. It
+/// goes out on the next `flush_events`, so what it reports is in the saved
+/// state by the time anyone sees it.
+pub(crate) fn emit(event: AgentEvent) {
+    if let Some(sink) = EVENT_SINK.lock().unwrap().as_mut() {
+        sink.pending.push(event);
+    }
+}
+
+/// Deliver the events emitted so far. Called after each state save and after
+/// each main-loop step.
+pub(crate) fn flush_events() {
+    let Some((pending, tx, callbacks)) = EVENT_SINK
+        .lock()
+        .unwrap()
+        .as_mut()
+        .map(|sink| (std::mem::take(&mut sink.pending), sink.tx.clone(), sink.callbacks.clone()))
+    else {
+        return;
+    };
+    for event in pending {
+        for callback in &callbacks {
+            callback(&event);
+        }
+        // No subscribers is fine.
+        let _ = tx.send(event);
+    }
+}
+
+/// Events from one agent, in the order they happened. Ends when the agent stops.
+pub struct Events {
+    rx: broadcast::Receiver<AgentEvent>,
+}
+
+impl Events {
+    /// The next event; None once the agent has stopped.
+    pub async fn next(&mut self) -> Option<AgentEvent> {
+        loop {
+            match self.rx.recv().await {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// `next` for callers outside an async runtime.
+    pub fn blocking_next(&mut self) -> Option<AgentEvent> {
+        loop {
+            match self.rx.blocking_recv() {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// An event that already happened, without waiting for one.
+    pub fn try_next(&mut self) -> Option<AgentEvent> {
+        loop {
+            match self.rx.try_recv() {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::TryRecvError::Lagged(_)) => continue,
+                Err(_) => return None,
+            }
+        }
+    }
+}
+
+// ---------- Status ----------
+
+/// What `deathlogger-agent status` shows, as data.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct AgentStatus {
+    /// Whether this handle's agent is running
+    pub running: bool,
+    pub uploads_enabled: bool,
+    /// The watched game branch, e.g. `.../World of Warcraft/_retail_`
+    pub wow_dir: PathBuf,
+    /// Newest uploaded death per character
+    pub last_uploaded: BTreeMap<String, i64>,
+    pub pending_screenshots: usize,
+    /// Deaths waiting for another upload attempt
+    pub retry_queue: usize,
+    /// Deaths the server rejected for good
+    pub rejected: usize,
+    /// Deaths held back until their player and realm are known
+    pub waiting_for_identity: usize,
+    /// The game hasn't been launched from this branch yet, so there is nothing to watch
+    pub waiting_for_first_launch: bool,
+}
+
+// ---------- Builder ----------
+
+#[derive(Debug, Clone)]
+enum ConfigSource {
+    /// `config.toml` in the data directory
+    Default,
+    File(PathBuf),
+    Toml(String),
+}
+
+/// Sets up an agent. Nothing runs until [`AgentHandle::start`].
+#[must_use]
+pub struct AgentBuilder {
+    config: ConfigSource,
+    data_dir: Option<PathBuf>,
+    wow_root: Option<PathBuf>,
+    wow_branch: Option<String>,
+    api_base: Option<String>,
+    callbacks: Vec<Callback>,
+    force_downgrade_state: bool,
+    /// Set by the CLI: prompts are allowed and it already holds the run lock
+    cli_locks: Option<Vec<ProcessLock>>,
+}
+
+impl Default for AgentBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AgentBuilder {
+    /// An agent using the `config.toml` and state of the installed agent.
+    pub fn new() -> Self {
+        Self {
+            config: ConfigSource::Default,
+            data_dir: None,
+            wow_root: None,
+            wow_branch: None,
+            api_base: None,
+            callbacks: Vec::new(),
+            force_downgrade_state: false,
+            cli_locks: None,
+        }
+    }
+
+    /// Read the configuration from `path`. Changes the agent makes to its
+    /// config (a moved install, a new agent id) are written back there.
+    pub fn config_file(mut self, path: impl AsRef<Path>) -> Self {
+        self.config = ConfigSource::File(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Use this `config.toml` text; nothing is written back.
+    pub fn config_toml(mut self, toml: impl Into<String>) -> Self {
+        self.config = ConfigSource::Toml(toml.into());
+        self
+    }
+
+    /// Keep the state, screenshot index and logs in `dir` instead of the
+    /// installed agent's data directory. Once per process.
+    pub fn data_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        self.data_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Watch this World of Warcraft folder and branch (`_retail_`,
+    /// `_classic_era_`, ...) instead of the configured ones.
+    pub fn wow_install(mut self, root: impl AsRef<Path>, branch: impl Into<String>) -> Self {
+        self.wow_root = Some(root.as_ref().to_path_buf());
+        self.wow_branch = Some(branch.into());
+        self
+    }
+
+    /// Send uploads to `url` instead of the configured servers.
+    pub fn api_base(mut self, url: impl Into<String>) -> Self {
+        self.api_base = Some(url.into());
+        self
+    }
+
+    /// Call `f` for every event, on the agent's thread; keep it short.
+    pub fn on_event(mut self, f: impl Fn(&AgentEvent) + Send + Sync + 'static) -> Self {
+        self.callbacks.push(Arc::new(f));
+        self
+    }
+
+    /// Open a state file written by a newer agent, dropping what this version
+    /// doesn't know.
+    pub fn force_downgrade_state(mut self, force: bool) -> Self {
+        self.force_downgrade_state = force;
+        self
+    }
+
+    /// The CLI: prompts may be shown, and `locks` are held for the run.
+    pub(crate) fn interactive(mut self, locks: Vec<ProcessLock>) -> Self {
+        self.cli_locks = Some(locks);
+        self
+    }
+
+    /// Read and check the configuration.
+    pub fn build(self) -> Result<AgentHandle> {
+        if let Some(dir) = &self.data_dir {
+            fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+            if CONFIG_DIR_OVERRIDE.get_or_init(|| dir.clone()) != dir {
+                return Err(anyhow!("this process already uses {}", CONFIG_DIR_OVERRIDE.get().unwrap().display()));
+            }
+        }
+        let (mut cfg, cfg_path) = match &self.config {
+            ConfigSource::Default => {
+                let path = config_path()?;
+                (load_config(&path)?, Some(path))
+            }
+            ConfigSource::File(path) => (load_config(path)?, Some(path.clone())),
+            ConfigSource::Toml(text) => (toml::from_str(text).context("parsing the config")?, None),
+        };
+        let overridden = self.wow_root.is_some() || self.api_base.is_some();
+        if let (Some(root), Some(branch)) = (&self.wow_root, &self.wow_branch) {
+            cfg.wow_root = root.to_string_lossy().to_string();
+            cfg.wow_branch = branch.clone();
+        }
+        if let Some(url) = &self.api_base {
+            cfg.api_url = url.clone();
+            cfg.endpoints.clear();
+        }
+        eventlog::init(cfg.event_log);
+
+        // Reject bad rules now rather than on the first death.
+        compile_killer_rules(&cfg)?;
+        match print_config_findings(&validate_config(&cfg)) {
+            0 => {}
+            n => {
+                let msg = match &cfg_path {
+                    Some(path) => format!("{} config error(s); fix {} and restart", n, path.display()),
+                    None => format!("{} config error(s)", n),
+                };
+                eventlog::report(Level::Error, EventClass::Config, &msg);
+                return Err(anyhow!(msg));
+            }
+        }
+
+        // Overrides stay out of the user's config file.
+        let persist = cfg_path.filter(|_| !overridden);
+        // External drives come back under a different letter; find the install again
+        // before any path is derived from wow_root.
+        if self.wow_root.is_none() {
+            rebind_wow_root(&mut cfg, persist.as_deref(), &SystemVolumes, self.cli_locks.is_some())?;
+        }
+        if cfg.agent_id.is_empty() {
+            cfg.agent_id = random_uuid();
+            if let Some(path) = &persist {
+                fs::write(path, toml::to_string_pretty(&cfg)?)?;
+            }
+        }
+
+        Ok(AgentHandle {
+            cfg,
+            events: broadcast::channel(EVENT_BUFFER).0,
+            callbacks: self.callbacks,
+            force_downgrade_state: self.force_downgrade_state,
+            cli_locks: self.cli_locks,
+            thread: None,
+        })
+    }
+}
+
+// ---------- Handle ----------
+
+/// A configured agent; see [`AgentBuilder`]. Dropping a running handle shuts it down.
+pub struct AgentHandle {
+    pub(crate) cfg: Config,
+    events: broadcast::Sender<AgentEvent>,
+    callbacks: Vec<Callback>,
+    force_downgrade_state: bool,
+    cli_locks: Option<Vec<ProcessLock>>,
+    thread: Option<std::thread::JoinHandle<Result<()>>>,
+}
+
+impl AgentHandle {
+    /// Start watching and uploading on a new thread. Fails when another agent
+    /// uses the same data directory.
+    pub fn start(&mut self) -> Result<()> {
+        if self.thread.is_some() {
+            return Err(anyhow!("the agent is already started"));
+        }
+        let interactive = self.cli_locks.is_some();
+        let locks = match self.cli_locks.take() {
+            Some(locks) => locks,
+            None => ProcessLock::acquire_for("run", Duration::ZERO).context("another agent is running with this data directory")?,
+        };
+        SHUTDOWN.store(false, Ordering::Relaxed);
+        *EVENT_SINK.lock().unwrap() = Some(EventSink { tx: self.events.clone(), callbacks: self.callbacks.clone(), pending: vec![] });
+
+        let cfg = self.cfg.clone();
+        let force_downgrade_state = self.force_downgrade_state;
+        let thread = std::thread::Builder::new().name("deathlogger-agent".into()).spawn(move || {
+            let _locks = locks;
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+            let result = runtime.block_on(watch(cfg, force_downgrade_state, interactive));
+            flush_events();
+            EVENT_SINK.lock().unwrap().take();
+            result
+        })?;
+        self.thread = Some(thread);
+        Ok(())
+    }
+
+    /// Whether the agent was started and hasn't stopped.
+    pub fn is_running(&self) -> bool {
+        self.thread.as_ref().is_some_and(|t| !t.is_finished())
+    }
+
+    /// Stop the agent and wait for it: running work gets `shutdown_grace_secs`
+    /// to finish, then state is saved. Returns the error the agent stopped on, if any.
+    pub fn shutdown(&mut self) -> Result<()> {
+        let Some(thread) = self.thread.take() else { return Ok(()) };
+        SHUTDOWN.store(true, Ordering::Relaxed);
+        let result = thread.join().map_err(|_| anyhow!("the agent thread panicked"))?;
+        EVENT_SINK.lock().unwrap().take();
+        // Ends the streams handed out so far; later subscribers get the next run.
+        self.events = broadcast::channel(EVENT_BUFFER).0;
+        result
+    }
+
+    /// Events from now on. Subscribe before `start` to see every one.
+    pub fn subscribe(&self) -> Events {
+        Events { rx: self.events.subscribe() }
+    }
+
+    /// What the agent has done so far, read from its saved state.
+    pub fn status(&self) -> AgentStatus {
+        let state = load_state().unwrap_or_default();
+        AgentStatus {
+            running: self.is_running(),
+            uploads_enabled: self.cfg.uploads_enabled,
+            wow_dir: WowPaths::from_config(&self.cfg).branch_root(),
+            last_uploaded: state.last_uploaded,
+            pending_screenshots: state.pending_screens.len(),
+            retry_queue: state.retry_queue.len(),
+            rejected: state.failed_uploads.len(),
+            waiting_for_identity: state.quarantine.len(),
+            waiting_for_first_launch: WowPaths::from_config(&self.cfg).is_pre_first_launch(),
+        }
+    }
+
+    /// Whether deaths are uploaded (`uploads_enabled`).
+    pub fn uploads_enabled(&self) -> bool {
+        self.cfg.uploads_enabled
+    }
+
+    /// The id this agent sends with heartbeats and telemetry.
+    pub fn agent_id(&self) -> &str {
+        &self.cfg.agent_id
+    }
+}
+
+impl Drop for AgentHandle {
+    fn drop(&mut self) {
+        if let Err(e) = self.shutdown() {
+            eprintln!("[error] agent stopped with: {e:#}");
+        }
+    }
+}
@@ -0,0 +1,296 @@
+//! Optional mirror of warnings and errors into the Windows Application event log.
+//!
+//! Writes go through a bounded channel to a background thread, so reporting never
+//! blocks the caller; when the queue is full or the event source can't be opened,
+//! events are dropped silently.
+//!
+//! Turning on start with Windows registers the event source (which needs an
+//! administrator) so the viewer shows the messages; turning it off removes it.
+
+use once_cell::sync::OnceCell;
+use std::sync::mpsc::{sync_channel, SyncSender};
+
+/// Event source name. Windows accepts writes from unregistered sources, but the
+/// viewer only shows clean messages once the source is registered.
+pub const SOURCE: &str = "DeathLoggerAgent";
+
+/// Registry key of the source under HKEY_LOCAL_MACHINE.
+#[cfg(windows)]
+const SOURCE_KEY: &str = "SYSTEM\\CurrentControlSet\\Services\\EventLog\\Application\\DeathLoggerAgent";
+/// Message file with a bare "%1" for every event ID, shipped with the .NET
+/// Framework; it lets the viewer show our text without a message DLL of our own.
+#[cfg(windows)]
+const MESSAGE_FILE: &str = "%SystemRoot%\\Microsoft.NET\\Framework\\v4.0.30319\\EventLogMessages.dll";
+
+/// Longest string ReportEvent accepts.
+const MAX_MESSAGE_CHARS: usize = 31_839;
+const QUEUE_DEPTH: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Info,
+    Warning,
+    Error,
+}
+
+/// Area an event comes from. Each has its own event ID so alerts can filter on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventClass {
+    Lifecycle,
+    Config,
+    Upload,
+    Parse,
+    Watch,
+}
+
+impl EventClass {
+    pub fn event_id(self) -> u32 {
+        match self {
+            EventClass::Lifecycle => 1000,
+            EventClass::Config => 1100,
+            EventClass::Upload => 1200,
+            EventClass::Parse => 1300,
+            EventClass::Watch => 1400,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            EventClass::Lifecycle => "lifecycle",
+            EventClass::Config => "config",
+            EventClass::Upload => "upload",
+            EventClass::Parse => "parse",
+            EventClass::Watch => "watch",
+        }
+    }
+}
+
+/// Message text as it appears in the event viewer.
+pub fn format_message(class: EventClass, message: &str) -> String {
+    let text = format!("[{}] {}", class.name(), message.trim());
+    match text.char_indices().nth(MAX_MESSAGE_CHARS) {
+        Some((cut, _)) => text[..cut].to_string(),
+        None => text,
+    }
+}
+
+/// Destination for events; the Windows implementation wraps ReportEventW.
+pub trait EventSink {
+    fn write(&self, level: Level, event_id: u32, message: &str);
+}
+
+struct Event {
+    level: Level,
+    event_id: u32,
+    message: String,
+}
+
+static QUEUE: OnceCell<SyncSender<Event>> = OnceCell::new();
+
+/// Start the writer thread. Does nothing when disabled or off Windows.
+pub fn init(enabled: bool) {
+    if !enabled {
+        return;
+    }
+    if let Some(sink) = platform_sink() {
+        start(sink);
+    }
+}
+
+fn start(sink: Box<dyn EventSink + Send>) {
+    if QUEUE.get().is_none() {
+        let _ = QUEUE.set(spawn_writer(sink, QUEUE_DEPTH));
+    }
+}
+
+/// A writer thread feeding `sink` from a queue of `depth` events.
+fn spawn_writer(sink: Box<dyn EventSink + Send>, depth: usize) -> SyncSender<Event> {
+    let (tx, rx) = sync_channel::<Event>(depth);
+    std::thread::spawn(move || {
+        for ev in rx {
+            sink.write(ev.level, ev.event_id, &ev.message);
+        }
+    });
+    tx
+}
+
+/// Hand an event to the writer; false when the queue was full and it was dropped.
+fn enqueue(tx: &SyncSender<Event>, level: Level, class: EventClass, message: &str) -> bool {
+    tx.try_send(Event { level, event_id: class.event_id(), message: format_message(class, message) }).is_ok()
+}
+
+/// Queue an event. Never blocks; a no-op unless `init` enabled the event log.
+pub fn report(level: Level, class: EventClass, message: &str) {
+    if let Some(tx) = QUEUE.get() {
+        enqueue(tx, level, class, message);
+    }
+}
+
+/// Register the event source. Needs an administrator.
+#[cfg(windows)]
+pub fn register_source() -> anyhow::Result<()> {
+    use winreg::enums::HKEY_LOCAL_MACHINE;
+    let (key, _) = winreg::RegKey::predef(HKEY_LOCAL_MACHINE).create_subkey(SOURCE_KEY)?;
+    key.set_raw_value(
+        "EventMessageFile",
+        &winreg::RegValue { bytes: wide_bytes(MESSAGE_FILE), vtype: winreg::enums::RegType::REG_EXPAND_SZ },
+    )?;
+    // Error, warning and information.
+    key.set_value("TypesSupported", &7u32)?;
+    Ok(())
+}
+
+#[cfg(windows)]
+fn wide_bytes(s: &str) -> Vec<u8> {
+    s.encode_utf16().chain(std::iter::once(0)).flat_map(u16::to_le_bytes).collect()
+}
+
+/// Remove the event source; not registered counts as done.
+#[cfg(windows)]
+pub fn unregister_source() -> anyhow::Result<()> {
+    use winreg::enums::HKEY_LOCAL_MACHINE;
+    match winreg::RegKey::predef(HKEY_LOCAL_MACHINE).delete_subkey_all(SOURCE_KEY) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(windows)]
+fn platform_sink() -> Option<Box<dyn EventSink + Send>> {
+    windows::WindowsSink::open().map(|s| Box::new(s) as Box<dyn EventSink + Send>)
+}
+
+#[cfg(not(windows))]
+fn platform_sink() -> Option<Box<dyn EventSink + Send>> {
+    None
+}
+
+#[cfg(windows)]
+mod windows {
+    use super::{EventSink, Level, SOURCE};
+    use windows_sys::Win32::Foundation::HANDLE;
+    use windows_sys::Win32::System::EventLog::{
+        DeregisterEventSource, RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE,
+        EVENTLOG_WARNING_TYPE,
+    };
+
+    fn wide(s: &str) -> Vec<u16> {
+        s.encode_utf16().chain(std::iter::once(0)).collect()
+    }
+
+    pub struct WindowsSink(HANDLE);
+
+    // The handle is only used from the writer thread.
+    unsafe impl Send for WindowsSink {}
+
+    impl WindowsSink {
+        pub fn open() -> Option<Self> {
+            let name = wide(SOURCE);
+            let handle = unsafe { RegisterEventSourceW(std::ptr::null(), name.as_ptr()) };
+            (!handle.is_null()).then_some(Self(handle))
+        }
+    }
+
+    impl EventSink for WindowsSink {
+        fn write(&self, level: Level, event_id: u32, message: &str) {
+            let kind = match level {
+                Level::Info => EVENTLOG_INFORMATION_TYPE,
+                Level::Warning => EVENTLOG_WARNING_TYPE,
+                Level::Error => EVENTLOG_ERROR_TYPE,
+            };
+            let text = wide(message);
+            let strings = [text.as_ptr()];
+            unsafe {
+                ReportEventW(self.0, kind, 0, event_id, std::ptr::null_mut(), 1, 0, strings.as_ptr(), std::ptr::null());
+            }
+        }
+    }
+
+    impl Drop for WindowsSink {
+        fn drop(&mut self) {
+            unsafe {
+                DeregisterEventSource(self.0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    type Written = Arc<Mutex<Vec<(Level, u32, String)>>>;
+
+    /// Records what would have gone to ReportEventW, optionally holding the
+    /// writer thread until `release` is unlocked.
+    struct MockSink {
+        written: Written,
+        release: Option<Arc<Mutex<()>>>,
+    }
+
+    impl EventSink for MockSink {
+        fn write(&self, level: Level, event_id: u32, message: &str) {
+            let _held = self.release.as_ref().map(|r| r.lock().unwrap());
+            self.written.lock().unwrap().push((level, event_id, message.to_string()));
+        }
+    }
+
+    fn wait_for(written: &Written, n: usize) {
+        for _ in 0..200 {
+            if written.lock().unwrap().len() >= n {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("only {} of {} events written", written.lock().unwrap().len(), n);
+    }
+
+    #[test]
+    fn messages_carry_the_class_and_fit_report_event() {
+        assert_eq!(format_message(EventClass::Upload, "  upload failed: 503\n"), "[upload] upload failed: 503");
+        assert_eq!(format_message(EventClass::Lifecycle, "started"), "[lifecycle] started");
+        let long = format_message(EventClass::Parse, &"é".repeat(MAX_MESSAGE_CHARS + 10));
+        assert_eq!(long.chars().count(), MAX_MESSAGE_CHARS);
+        assert!(long.starts_with("[parse] é"));
+    }
+
+    #[test]
+    fn each_class_has_its_own_stable_event_id() {
+        let classes = [EventClass::Lifecycle, EventClass::Config, EventClass::Upload, EventClass::Parse, EventClass::Watch];
+        let ids: Vec<u32> = classes.iter().map(|c| c.event_id()).collect();
+        // Alert rules filter on these; changing one breaks them.
+        assert_eq!(ids, [1000, 1100, 1200, 1300, 1400]);
+    }
+
+    #[test]
+    fn events_reach_the_sink_with_level_and_id() {
+        let written = Written::default();
+        let tx = spawn_writer(Box::new(MockSink { written: written.clone(), release: None }), 8);
+        assert!(enqueue(&tx, Level::Error, EventClass::Upload, "upload to main failed"));
+        assert!(enqueue(&tx, Level::Warning, EventClass::Config, "unknown key"));
+        wait_for(&written, 2);
+        assert_eq!(
+            *written.lock().unwrap(),
+            [
+                (Level::Error, 1200, "[upload] upload to main failed".to_string()),
+                (Level::Warning, 1100, "[config] unknown key".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_stalled_sink_drops_events_instead_of_blocking() {
+        let written = Written::default();
+        let release = Arc::new(Mutex::new(()));
+        let held = release.lock().unwrap();
+        let tx = spawn_writer(Box::new(MockSink { written: written.clone(), release: Some(release.clone()) }), 2);
+        // One event sits in the stalled write, two fill the queue, the rest are dropped.
+        let accepted = (0..10).filter(|n| enqueue(&tx, Level::Warning, EventClass::Watch, &format!("event {n}"))).count();
+        assert!((2..=3).contains(&accepted), "{accepted} accepted");
+        drop(held);
+        wait_for(&written, accepted);
+        assert_eq!(written.lock().unwrap()[0].2, "[watch] event 0");
+    }
+}
@@ -0,0 +1,8232 @@
+//! The DeathLogger agent: watches a WoW install's SavedVariables and uploads the
+//! deaths the addon records. The binary is a thin wrapper around [`run`].
+
+mod commands;
+mod config;
+mod embed;
+mod eventlog;
+mod metrics;
+mod pairing;
+mod payload;
+mod state;
+mod sv;
+mod shotcache;
+mod svdata;
+mod upload;
+mod volumes;
+
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::{DateTime, Local, TimeZone, Utc};
+use dialoguer::{Confirm, Input, Password, Select};
+use dirs::{data_dir, home_dir};
+use glob::glob;
+use hmac::{Hmac, Mac};
+use mlua::{Lua, Value as LuaValue};
+use notify::{Config as NotifyConfig, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use reqwest::header::{AUTHORIZATION, CONTENT_DISPOSITION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, LOCATION};
+use reqwest::{multipart, Method, StatusCode};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use walkdir::WalkDir;
+#[cfg(windows)]
+use winreg::enums::HKEY_CURRENT_USER;
+#[cfg(windows)]
+use winreg::RegKey;
+
+use eventlog::{EventClass, Level};
+use volumes::{SystemVolumes, VolumeSource};
+use metrics::Metrics;
+use svdata::Value as SvValue;
+
+pub use embed::{AgentBuilder, AgentEvent, AgentHandle, AgentStatus, Events};
+pub use payload::DeathPayload;
+pub use sv::{parse_deaths, parse_deaths_file};
+
+pub(crate) use commands::*;
+pub(crate) use config::*;
+pub(crate) use embed::{emit, flush_events};
+pub(crate) use pairing::*;
+pub(crate) use payload::*;
+pub(crate) use state::*;
+pub(crate) use sv::*;
+pub(crate) use upload::*;
+
+// ---------- WoW layout helpers ----------
+
+/// "<branch>|<products>" for an install, e.g. "_retail_|wow,wow_classic". None
+/// unless the branch folder is there.
+fn install_fingerprint(root: &Path, branch: &str) -> Option<String> {
+    if !root.join(branch).is_dir() {
+        return None;
+    }
+    let mut products: Vec<String> = read_build_info(root)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|mut row| row.remove("Product"))
+        .collect();
+    products.sort();
+    products.dedup();
+    Some(format!("{}|{}", branch, products.join(",")))
+}
+
+/// Where a missing wow_root went when its drive came back under another letter:
+/// the same folder on the volume with the recorded serial, or failing that on a
+/// volume whose copy has the recorded fingerprint.
+fn find_moved_wow_root(cfg: &Config, volumes: &dyn VolumeSource) -> Option<PathBuf> {
+    let root = PathBuf::from(&cfg.wow_root);
+    if root.exists() {
+        return None;
+    }
+    let (old_volume, rest) = volumes.split(&root)?;
+    let serial = volumes::parse_serial(&cfg.wow_volume_serial);
+    let candidates: Vec<(Option<u32>, PathBuf)> = volumes
+        .volumes()
+        .into_iter()
+        .filter(|v| v.root != old_volume)
+        .map(|v| (v.serial, v.root.join(&rest)))
+        .filter(|(_, p)| p.join(&cfg.wow_branch).is_dir())
+        .collect();
+    if let Some((_, p)) = candidates.iter().find(|(s, _)| serial.is_some() && *s == serial) {
+        return Some(p.clone());
+    }
+    if cfg.wow_fingerprint.is_empty() {
+        return None;
+    }
+    candidates
+        .into_iter()
+        .find(|(_, p)| install_fingerprint(p, &cfg.wow_branch).as_deref() == Some(cfg.wow_fingerprint.as_str()))
+        .map(|(_, p)| p)
+}
+
+/// Follow a WoW install whose drive letter changed, then record where it lives
+/// now. With `ask`, asks before rewriting wow_root; without it or a terminal it
+/// goes ahead and logs it. Written back to `cfg_path`, when there is one.
+fn rebind_wow_root(cfg: &mut Config, cfg_path: Option<&Path>, volumes: &dyn VolumeSource, ask: bool) -> Result<()> {
+    let mut changed = false;
+    if let Some(new_root) = find_moved_wow_root(cfg, volumes) {
+        println!("[drive] {} is missing, but the same install is at {}", cfg.wow_root, new_root.display());
+        let accept = if ask && std::io::IsTerminal::is_terminal(&std::io::stdin()) {
+            Confirm::new()
+                .with_prompt(format!("Use {} as the WoW folder from now on?", new_root.display()))
+                .default(true)
+                .interact()
+                .unwrap_or(false)
+        } else {
+            true
+        };
+        if accept {
+            let msg = format!("wow_root moved from {} to {}", cfg.wow_root, new_root.display());
+            println!("[drive] {}", msg);
+            eventlog::report(Level::Warning, EventClass::Config, &msg);
+            cfg.wow_root = new_root.to_string_lossy().to_string();
+            changed = true;
+        }
+    }
+    let root = PathBuf::from(&cfg.wow_root);
+    if let Some(serial) = volumes.serial_for(&root).map(volumes::format_serial) {
+        if serial != cfg.wow_volume_serial {
+            cfg.wow_volume_serial = serial;
+            changed = true;
+        }
+    }
+    if let Some(fingerprint) = install_fingerprint(&root, &cfg.wow_branch) {
+        if fingerprint != cfg.wow_fingerprint {
+            cfg.wow_fingerprint = fingerprint;
+            changed = true;
+        }
+    }
+    if let Some(cfg_path) = cfg_path.filter(|_| changed) {
+        fs::write(cfg_path, toml::to_string_pretty(cfg)?)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+struct WowPaths {
+    root: PathBuf,     // e.g. C:\Program Files (x86)\World of Warcraft
+    branch: String,    // _retail_ / _classic_ / _classic_era_ / _classic_ptr_
+    /// Enabled `sv_adapters`, one per addon whose SavedVariables are read
+    adapters: Vec<Arc<dyn SvAdapter>>,
+}
+
+impl WowPaths {
+    fn from_config(cfg: &Config) -> Self {
+        Self { root: PathBuf::from(&cfg.wow_root), branch: cfg.wow_branch.clone(), adapters: sv_adapters(cfg) }
+    }
+    fn branch_root(&self) -> PathBuf {
+        self.root.join(&self.branch)
+    }
+    fn addons_dir(&self) -> PathBuf {
+        self.branch_root().join("Interface").join("AddOns")
+    }
+    fn screenshots_dir(&self) -> PathBuf {
+        self.branch_root().join("Screenshots")
+    }
+    fn wtf_account_dir(&self) -> PathBuf {
+        self.branch_root().join("WTF").join("Account")
+    }
+    /// The branch folder exists but the game has never been launched from it:
+    /// no WTF yet. The game creates WTF and Screenshots itself on first run.
+    fn is_pre_first_launch(&self) -> bool {
+        self.branch_root().is_dir() && !self.branch_root().join("WTF").exists()
+    }
+    /// Account-level and per-character SavedVariables files of every adapter:
+    /// WTF/Account/<ACCOUNT>[/<ServerName>/<CharName>]/SavedVariables/<file name>
+    fn wtf_savedvariables_globs(&self) -> Vec<String> {
+        let account = self.wtf_account_dir().join("*");
+        let character = account.join("*").join("*");
+        let mut globs = vec![];
+        for adapter in &self.adapters {
+            let file = glob::Pattern::escape(adapter.file_name());
+            for dir in [&account, &character] {
+                globs.push(dir.join("SavedVariables").join(&file).to_string_lossy().to_string());
+            }
+        }
+        globs
+    }
+    /// True for a SavedVariables file some adapter reads, in either layout under WTF/Account.
+    fn is_sv_file(&self, path: &Path) -> bool {
+        self.adapter_for(path).is_some()
+    }
+    fn adapter_for(&self, path: &Path) -> Option<&dyn SvAdapter> {
+        self.adapters.iter().find(|a| a.matches(path)).map(|a| a.as_ref())
+    }
+    /// Parse `path` with the adapter for its addon, or its `.bak` when it is
+    /// corrupt. `history_after` as for `parse_sv`.
+    fn parse_sv(&self, path: &Path, history_after: Option<&SvMarks>) -> Result<SvSnapshot> {
+        let adapter = self.adapter_for(path).ok_or_else(|| anyhow!("no enabled sv_adapters entry reads {}", path.display()))?;
+        parse_sv_or_backup(adapter, path, history_after)
+    }
+}
+
+/// Directory watches that are attached as soon as the game creates the folders.
+#[derive(Default)]
+struct GameWatches {
+    wtf: bool,
+    screenshots: bool,
+}
+
+impl GameWatches {
+    /// Attach watches for folders that exist now. Returns true if anything new was attached.
+    fn attach(&mut self, watcher: &mut RecommendedWatcher, wow: &WowPaths) -> bool {
+        let mut attached = false;
+        if !self.wtf && wow.wtf_account_dir().is_dir() {
+            match watcher.watch(&wow.wtf_account_dir(), RecursiveMode::Recursive) {
+                Ok(()) => {
+                    self.wtf = true;
+                    attached = true;
+                    println!("[watch] Watching {}", wow.wtf_account_dir().display());
+                }
+                Err(e) => {
+                    eprintln!("[warn] cannot watch {}: {e}", wow.wtf_account_dir().display());
+                    eventlog::report(Level::Warning, EventClass::Watch, &format!("cannot watch {}: {e}", wow.wtf_account_dir().display()));
+                }
+            }
+        }
+        if !self.screenshots && wow.screenshots_dir().is_dir() {
+            match watcher.watch(&wow.screenshots_dir(), RecursiveMode::NonRecursive) {
+                Ok(()) => {
+                    self.screenshots = true;
+                    attached = true;
+                    println!("[watch] Watching {}", wow.screenshots_dir().display());
+                }
+                Err(e) => {
+                    eprintln!("[warn] cannot watch {}: {e}", wow.screenshots_dir().display());
+                    eventlog::report(Level::Warning, EventClass::Watch, &format!("cannot watch {}: {e}", wow.screenshots_dir().display()));
+                }
+            }
+        }
+        attached
+    }
+}
+
+const PRE_FIRST_LAUNCH_HINT: &str =
+    "This WoW install has never been launched. Launch the game once and log in; the agent will pick things up automatically.";
+
+// ---------- Startup registration (Windows) ----------
+
+/// Add or remove the Run entry. With the event log on, also registers or
+/// removes its event source; that needs an administrator, so failing there
+/// only warns.
+#[cfg(windows)]
+fn set_startup(enable: bool, event_log: bool) -> Result<()> {
+    let exe = std::env::current_exe()?.to_string_lossy().to_string();
+    let hkcu = RegKey::predef(HKEY_CURRENT_USER);
+    let (key, _) = hkcu.create_subkey("Software\\Microsoft\\Windows\\CurrentVersion\\Run")?;
+    if enable {
+        key.set_value("DeathLoggerAgent", &exe)?;
+    } else {
+        let _ = key.delete_value("DeathLoggerAgent");
+    }
+    if event_log {
+        let (done, action) = match enable {
+            true => (eventlog::register_source(), "register"),
+            false => (eventlog::unregister_source(), "remove"),
+        };
+        if let Err(e) = done {
+            eprintln!("[warn] cannot {action} event source {} ({e:#}); run the agent once as administrator", eventlog::SOURCE);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn set_startup(_enable: bool, _event_log: bool) -> Result<()> {
+    Err(anyhow!("Start with Windows is only available on Windows"))
+}
+
+// ---------- Platform ----------
+
+/// Architecture this binary was built for.
+fn build_arch() -> &'static str {
+    std::env::consts::ARCH
+}
+
+/// Native machine architecture and whether this process runs under emulation
+/// (x64 on ARM64, or 32-bit WOW64). `None` when it can't be determined.
+#[cfg(windows)]
+fn native_arch() -> Option<(&'static str, bool)> {
+    use windows_sys::Win32::System::SystemInformation::{
+        IMAGE_FILE_MACHINE_AMD64, IMAGE_FILE_MACHINE_ARM64, IMAGE_FILE_MACHINE_I386, IMAGE_FILE_MACHINE_UNKNOWN,
+    };
+    use windows_sys::Win32::System::Threading::{GetCurrentProcess, IsWow64Process2};
+
+    let mut process: u16 = 0;
+    let mut native: u16 = 0;
+    // SAFETY: both out-pointers are valid for the duration of the call.
+    let ok = unsafe { IsWow64Process2(GetCurrentProcess(), &mut process, &mut native) };
+    if ok == 0 {
+        return None;
+    }
+    let native_name = match native {
+        IMAGE_FILE_MACHINE_ARM64 => "aarch64",
+        IMAGE_FILE_MACHINE_AMD64 => "x86_64",
+        IMAGE_FILE_MACHINE_I386 => "x86",
+        _ => "unknown",
+    };
+    // WOW64 reports the process machine; x64-on-ARM64 emulation is not WOW64
+    // and shows up as a build/native mismatch instead.
+    let emulated = process != IMAGE_FILE_MACHINE_UNKNOWN || native_name != build_arch();
+    Some((native_name, emulated))
+}
+
+#[cfg(not(windows))]
+fn native_arch() -> Option<(&'static str, bool)> {
+    Some((build_arch(), false))
+}
+
+/// Show a desktop notification. Windows only; a toast needs no window of ours,
+/// so it also works when the agent runs in the background.
+#[cfg(windows)]
+fn show_toast(title: &str, body: &str) -> Result<()> {
+    use std::os::windows::process::CommandExt;
+    const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+    // Single-quoted PowerShell strings only need their quotes doubled.
+    let quote = |s: &str| format!("'{}'", s.replace('\'', "''"));
+    let script = format!(
+        "$t = [Windows.UI.Notifications.ToastNotificationManager, Windows.UI.Notifications, ContentType = WindowsRuntime]::GetTemplateContent(1); \
+         $x = $t.GetElementsByTagName('text'); \
+         [void]$x.Item(0).AppendChild($t.CreateTextNode({})); \
+         [void]$x.Item(1).AppendChild($t.CreateTextNode({})); \
+         [Windows.UI.Notifications.ToastNotificationManager]::CreateToastNotifier('DeathLogger Agent').Show([Windows.UI.Notifications.ToastNotification]::new($t))",
+        quote(title),
+        quote(body)
+    );
+    let status = std::process::Command::new("powershell")
+        .args(["-NoProfile", "-NonInteractive", "-Command", &script])
+        .creation_flags(CREATE_NO_WINDOW)
+        .status()?;
+    if !status.success() {
+        return Err(anyhow!("powershell exited with {}", status));
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn show_toast(_title: &str, _body: &str) -> Result<()> {
+    Err(anyhow!("desktop notifications are only available on Windows"))
+}
+
+fn arch_summary() -> String {
+    match native_arch() {
+        Some((native, true)) => format!("{} build running under emulation on {}", build_arch(), native),
+        Some((_, false)) => format!("{} (native)", build_arch()),
+        None => format!("{} (emulation unknown)", build_arch()),
+    }
+}
+
+// ---------- HTTP ----------
+
+/// Sent on every request, uploads and addon downloads alike.
+const USER_AGENT: &str = concat!("DeathLoggerAgent/", env!("CARGO_PKG_VERSION"));
+
+/// Which part of the agent is making an outbound request.
+/// Used to attribute allowlist violations in logs and counters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum NetFeature {
+    /// Deaths to the endpoints, including resumable chunks and presigned confirmations
+    Upload,
+    /// Screenshots sent ahead to `media_url` or PUT to a presigned storage URL
+    Media,
+    /// Nonces from `screenshot_challenge_url`
+    Challenge,
+    /// Earlier unsent deaths to `bulk_api_url`
+    Bulk,
+    Milestones,
+    Heartbeat,
+    /// `annotate` PATCHing a note onto an uploaded death
+    Notes,
+    AddonDownload,
+    Telemetry,
+    Discord,
+    OAuth,
+}
+
+impl NetFeature {
+    fn name(self) -> &'static str {
+        match self {
+            NetFeature::Upload => "upload",
+            NetFeature::Media => "media",
+            NetFeature::Challenge => "challenge",
+            NetFeature::Bulk => "bulk",
+            NetFeature::Milestones => "milestones",
+            NetFeature::Heartbeat => "heartbeat",
+            NetFeature::Notes => "notes",
+            NetFeature::AddonDownload => "addon-download",
+            NetFeature::Telemetry => "telemetry",
+            NetFeature::Discord => "discord",
+            NetFeature::OAuth => "oauth",
+        }
+    }
+}
+
+const MAX_REDIRECTS: usize = 10;
+
+/// One multipart form field. Forms are described with these instead of
+/// `reqwest::multipart::Form` so the wrapper can record what was sent.
+#[derive(Clone)]
+struct FormPart {
+    name: String,
+    kind: FormPartKind,
+}
+
+#[derive(Clone)]
+enum FormPartKind {
+    Text(String),
+    File {
+        file_name: String,
+        bytes: Vec<u8>,
+        content_type: String,
+    },
+    /// JSON sent gzipped with a per-part `Content-Encoding: gzip`
+    GzipJson {
+        raw_len: usize,
+        bytes: Vec<u8>,
+    },
+}
+
+impl FormPart {
+    fn text(name: &str, value: String) -> Self {
+        Self { name: name.into(), kind: FormPartKind::Text(value) }
+    }
+
+    fn file(name: &str, file_name: String, bytes: Vec<u8>, content_type: &str) -> Self {
+        Self {
+            name: name.into(),
+            kind: FormPartKind::File { file_name, bytes, content_type: content_type.into() },
+        }
+    }
+
+    fn gzip_json(name: &str, json: &str) -> Result<Self> {
+        let mut enc = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut enc, json.as_bytes())?;
+        Ok(Self { name: name.into(), kind: FormPartKind::GzipJson { raw_len: json.len(), bytes: enc.finish()? } })
+    }
+
+    fn describe(&self, secrets: &[String]) -> CapturedPart {
+        match &self.kind {
+            FormPartKind::Text(v) => CapturedPart {
+                name: self.name.clone(),
+                text: Some(redact_secrets(v, secrets)),
+                ..Default::default()
+            },
+            FormPartKind::File { file_name, bytes, content_type } => CapturedPart {
+                name: self.name.clone(),
+                file_name: Some(redact_secrets(file_name, secrets)),
+                size: Some(bytes.len()),
+                content_type: Some(content_type.clone()),
+                sha256: Some(format!("{:x}", Sha256::digest(bytes))),
+                ..Default::default()
+            },
+            FormPartKind::GzipJson { raw_len, bytes } => CapturedPart {
+                name: self.name.clone(),
+                text: Some(format!("<{} bytes of JSON, gzipped>", raw_len)),
+                size: Some(bytes.len()),
+                content_type: Some("application/json".into()),
+                content_encoding: Some("gzip".into()),
+                sha256: Some(format!("{:x}", Sha256::digest(bytes))),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn into_part(self) -> Result<multipart::Part> {
+        Ok(match self.kind {
+            FormPartKind::Text(v) => multipart::Part::text(v),
+            FormPartKind::File { file_name, bytes, content_type } => {
+                multipart::Part::bytes(bytes).file_name(file_name).mime_str(&content_type)?
+            }
+            FormPartKind::GzipJson { bytes, .. } => {
+                let mut headers = reqwest::header::HeaderMap::new();
+                headers.insert(CONTENT_ENCODING, reqwest::header::HeaderValue::from_static("gzip"));
+                multipart::Part::bytes(bytes).mime_str("application/json")?.headers(headers)
+            }
+        })
+    }
+}
+
+/// Redacted record of one outgoing request, for `debug last-requests`.
+/// Never contains file contents.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct CapturedRequest {
+    at: i64,
+    feature: String,
+    method: String,
+    url: String,
+    headers: BTreeMap<String, String>,
+    parts: Vec<CapturedPart>,
+    status: Option<u16>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct CapturedPart {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_encoding: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+}
+
+fn last_requests_path() -> Result<PathBuf> {
+    Ok(config_dir()?.join("last_requests.json"))
+}
+
+/// Token bucket in front of upload requests (`max_uploads_per_minute`). It holds
+/// a minute's worth of tokens, so a few deaths go out at once while a backfill
+/// settles to the configured rate.
+struct RateLimiter {
+    per_minute: u32,
+    /// Tokens available and when they were last topped up
+    bucket: Mutex<(f64, std::time::Instant)>,
+}
+
+impl RateLimiter {
+    fn new(per_minute: u32) -> Self {
+        Self { per_minute, bucket: Mutex::new((per_minute as f64, std::time::Instant::now())) }
+    }
+
+    /// Time until a token is free, zero when one is free now. `take` uses up a free token.
+    fn check(&self, take: bool) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let per_sec = self.per_minute as f64 / 60.0;
+        let now = std::time::Instant::now();
+        bucket.0 = (bucket.0 + now.duration_since(bucket.1).as_secs_f64() * per_sec).min(self.per_minute as f64);
+        bucket.1 = now;
+        if bucket.0 >= 1.0 {
+            if take {
+                bucket.0 -= 1.0;
+            }
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - bucket.0) / per_sec)
+        }
+    }
+
+    /// Tokens free now.
+    fn tokens(&self) -> f64 {
+        self.check(false);
+        self.bucket.lock().unwrap().0
+    }
+
+    /// Start from `tokens` as they were `ago`; the time since refills the bucket.
+    fn resume(&self, tokens: f64, ago: Duration) {
+        let now = std::time::Instant::now();
+        *self.bucket.lock().unwrap() = (tokens.clamp(0.0, self.per_minute as f64), now.checked_sub(ago).unwrap_or(now));
+    }
+}
+
+/// Why an upload was held back without being sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HoldCause {
+    /// max_uploads_per_minute
+    RateLimit,
+    /// An earlier Retry-After from that server
+    RetryAfter,
+    /// The server was unreachable; see "Offline mode"
+    Offline,
+}
+
+/// An upload held back by our own rate limit, by an earlier Retry-After from
+/// that server or because it is offline; it goes to the retry queue without
+/// counting as a failed attempt.
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error("waiting {}s for {}", self.wait_secs(), match self.cause {
+    HoldCause::RateLimit => "the upload rate limit",
+    HoldCause::RetryAfter => "the server's Retry-After",
+    HoldCause::Offline => "the upload server to be reachable again",
+})]
+struct RateLimited {
+    wait: Duration,
+    cause: HoldCause,
+}
+
+impl RateLimited {
+    fn wait_secs(&self) -> i64 {
+        self.wait.as_secs_f64().ceil().max(1.0) as i64
+    }
+}
+
+/// Headers the agent sets itself, which `[headers]` may not replace.
+const RESERVED_HEADERS: &[&str] = &[
+    "authorization",
+    "content-type",
+    "content-length",
+    "content-encoding",
+    "content-range",
+    "host",
+    "idempotency-key",
+];
+
+/// `[headers]` with `${ENV_VAR}` expanded, checked as HTTP headers so a bad entry
+/// is reported when the config loads rather than failing inside the request.
+fn extra_headers(cfg: &Config) -> Result<reqwest::header::HeaderMap> {
+    let mut map = reqwest::header::HeaderMap::new();
+    for (name, value) in &cfg.headers {
+        let header = reqwest::header::HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| anyhow!("{:?} is not a valid header name", name))?;
+        let lower = header.as_str();
+        if RESERVED_HEADERS.contains(&lower) || lower.starts_with("x-deathlogger-") {
+            return Err(anyhow!("{} is set by the agent and can't be overridden", name));
+        }
+        let value = expand_env(value).with_context(|| format!("header {}", name))?;
+        // The value may be a secret: say what's wrong without echoing it.
+        let value = reqwest::header::HeaderValue::from_str(&value)
+            .map_err(|_| anyhow!("the value of {} contains characters not allowed in a header", name))?;
+        map.insert(header, value);
+    }
+    Ok(map)
+}
+
+/// Replace every `${NAME}` with the environment variable NAME; an unset variable is an error.
+fn expand_env(s: &str) -> Result<String> {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let end = rest[start..].find('}').ok_or_else(|| anyhow!("unterminated ${{ in {:?}", rest))? + start;
+        let var = &rest[start + 2..end];
+        out.push_str(&std::env::var(var).map_err(|_| anyhow!("environment variable {} is not set", var))?);
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// The client certificate for mutual TLS: a PKCS#12 bundle (`.p12`/`.pfx`,
+/// unlocked with `tls_client_key_password`) or a PEM chain with its PKCS#8 key
+/// in `tls_client_key` or the same file. Loaded when the client is built, so a
+/// bad file stops the agent at startup rather than at the first death.
+fn client_identity(cfg: &Config) -> Result<Option<reqwest::Identity>> {
+    if cfg.tls_client_cert.is_empty() {
+        return Ok(None);
+    }
+    let cert = fs::read(&cfg.tls_client_cert).with_context(|| format!("reading tls_client_cert {}", cfg.tls_client_cert))?;
+    let ext = Path::new(&cfg.tls_client_cert).extension().and_then(|e| e.to_str()).unwrap_or("").to_ascii_lowercase();
+    if ext == "p12" || ext == "pfx" {
+        let identity = reqwest::Identity::from_pkcs12_der(&cert, &cfg.tls_client_key_password).with_context(|| {
+            format!("tls_client_cert {} is not a PKCS#12 bundle this tls_client_key_password opens", cfg.tls_client_cert)
+        })?;
+        return Ok(Some(identity));
+    }
+    let (cert, key) = if cfg.tls_client_key.is_empty() {
+        split_pem_key(&String::from_utf8_lossy(&cert))
+    } else {
+        (cert, fs::read(&cfg.tls_client_key).with_context(|| format!("reading tls_client_key {}", cfg.tls_client_key))?)
+    };
+    let identity = reqwest::Identity::from_pkcs8_pem(&cert, &key).context(
+        "tls_client_cert/tls_client_key must be a PEM certificate and an unencrypted PKCS#8 key \
+         (`openssl pkcs8 -topk8 -nocrypt -in key.pem -out key.pk8.pem` converts older RSA/EC keys)",
+    )?;
+    Ok(Some(identity))
+}
+
+/// Separate a combined PEM file into its certificate blocks and its key block.
+fn split_pem_key(pem: &str) -> (Vec<u8>, Vec<u8>) {
+    let (mut certs, mut key) = (String::new(), String::new());
+    let mut in_key = false;
+    for line in pem.lines() {
+        if line.starts_with("-----BEGIN") {
+            in_key = line.contains("PRIVATE KEY");
+        }
+        let out = if in_key { &mut key } else { &mut certs };
+        out.push_str(line);
+        out.push('\n');
+    }
+    (certs.into_bytes(), key.into_bytes())
+}
+
+/// Shared HTTP client. Every outbound request must go through `Http::send` so the
+/// network allowlist is enforced in one place (including on redirect hops).
+struct Http {
+    /// For the upload servers; trusts `tls_ca_file` and honors `tls_accept_invalid_certs`
+    client: reqwest::Client,
+    /// For public hosts (addon downloads): the built-in web PKI roots only
+    public_client: reqwest::Client,
+    allowlist: Vec<String>,
+    /// Blocked attempts per feature this session
+    blocked: Mutex<BTreeMap<NetFeature, u64>>,
+    /// Blocked attempts not yet added to the persisted metrics (`take_blocked`)
+    blocked_unrecorded: Mutex<BTreeMap<NetFeature, u64>>,
+    /// Ring of the last requests; `None` when capture is disabled
+    captured: Option<Arc<Mutex<VecDeque<CapturedRequest>>>>,
+    /// A write of `captured` to last_requests.json is scheduled
+    capture_flush_pending: Arc<AtomicBool>,
+    capture_limit: usize,
+    /// Values that must never appear in anything we log or persist
+    secrets: Vec<String>,
+    /// The server refused a gzipped upload this session
+    gzip_refused: AtomicBool,
+    /// The server accepted a resumable death without an upload URL this session
+    resumable_refused: AtomicBool,
+    /// `None` when max_uploads_per_minute is 0
+    upload_limiter: Option<RateLimiter>,
+    /// Upload URLs that answered with Retry-After, and until when
+    throttled: Mutex<BTreeMap<String, std::time::Instant>>,
+    /// Last token from `[oauth]`
+    oauth_token: Mutex<Option<OAuthToken>>,
+    /// `[headers]`, expanded
+    extra_headers: reqwest::header::HeaderMap,
+    /// Upload URLs that could not be reached
+    offline: Mutex<BTreeMap<String, OfflineSince>>,
+    /// Presigned screenshot uploads still owed for accepted deaths
+    presigned: Mutex<BTreeMap<String, PresignedUpload>>,
+}
+
+impl Http {
+    fn new(cfg: &Config) -> Result<Self> {
+        // Redirects are followed manually in `send` so each hop is checked.
+        // Timeouts surface as ordinary request errors, which the retry queue treats as transient.
+        let base = || -> Result<reqwest::ClientBuilder> {
+            let mut builder = reqwest::Client::builder()
+                .user_agent(USER_AGENT)
+                .redirect(reqwest::redirect::Policy::none())
+                .connect_timeout(Duration::from_secs(cfg.http_connect_timeout_secs.max(1)))
+                .timeout(Duration::from_secs(cfg.http_request_timeout_secs.max(1)));
+            // Without proxy_url reqwest picks up HTTP(S)_PROXY from the environment;
+            // an explicit proxy replaces those.
+            if !cfg.proxy_url.is_empty() {
+                builder = builder.proxy(reqwest::Proxy::all(&cfg.proxy_url).context("proxy_url")?);
+            }
+            Ok(builder)
+        };
+        let public_client = base()?.build()?;
+        let mut builder = base()?;
+        if !cfg.tls_ca_file.is_empty() {
+            let pem = fs::read(&cfg.tls_ca_file).with_context(|| format!("reading tls_ca_file {}", cfg.tls_ca_file))?;
+            for cert in reqwest::Certificate::from_pem_bundle(&pem).context("tls_ca_file is not a PEM bundle")? {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        if cfg.tls_accept_invalid_certs {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        if let Some(identity) = client_identity(cfg)? {
+            builder = builder.identity(identity);
+        }
+        let client = builder.build()?;
+        let captured = (cfg.capture_last_requests > 0).then(|| {
+            let previous: VecDeque<CapturedRequest> = last_requests_path()
+                .and_then(|p| Ok(serde_json::from_str(&fs::read_to_string(p)?)?))
+                .unwrap_or_default();
+            Arc::new(Mutex::new(previous))
+        });
+        Ok(Self {
+            client,
+            public_client,
+            allowlist: cfg.network_allowlist.clone(),
+            blocked: Mutex::new(BTreeMap::new()),
+            blocked_unrecorded: Mutex::new(BTreeMap::new()),
+            captured,
+            capture_flush_pending: Arc::new(AtomicBool::new(false)),
+            capture_limit: cfg.capture_last_requests,
+            secrets: config_secrets(cfg),
+            gzip_refused: AtomicBool::new(false),
+            resumable_refused: AtomicBool::new(false),
+            upload_limiter: (cfg.max_uploads_per_minute > 0).then(|| RateLimiter::new(cfg.max_uploads_per_minute)),
+            throttled: Mutex::new(BTreeMap::new()),
+            oauth_token: Mutex::new(None),
+            extra_headers: extra_headers(cfg).context("[headers]")?,
+            offline: Mutex::new(BTreeMap::new()),
+            presigned: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Fail with `RateLimited` while `url` is inside a Retry-After window.
+    fn check_throttle(&self, url: &str) -> Result<()> {
+        let mut throttled = self.throttled.lock().unwrap();
+        let now = std::time::Instant::now();
+        throttled.retain(|_, until| *until > now);
+        match throttled.get(url) {
+            Some(until) => Err(RateLimited { wait: *until - now, cause: HoldCause::RetryAfter }.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Remember the Retry-After carried by a failed upload to `url`.
+    fn note_throttle<T>(&self, url: &str, result: &Result<T>) {
+        let Some(secs) = result.as_ref().err().and_then(|e| e.downcast_ref::<UploadError>()).and_then(|u| u.retry_after) else {
+            return;
+        };
+        let until = std::time::Instant::now() + Duration::from_secs(secs as u64);
+        self.throttled.lock().unwrap().insert(url.to_string(), until);
+    }
+
+    /// Use up one upload slot, or fail with `RateLimited` when none is free.
+    fn take_upload_slot(&self) -> Result<()> {
+        match self.upload_limiter.as_ref().map(|l| l.check(true)) {
+            Some(wait) if !wait.is_zero() => Err(RateLimited { wait, cause: HoldCause::RateLimit }.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// How long until an upload slot is free, if one isn't now.
+    fn upload_wait(&self) -> Option<RateLimited> {
+        let wait = self.upload_limiter.as_ref()?.check(false);
+        (!wait.is_zero()).then_some(RateLimited { wait, cause: HoldCause::RateLimit })
+    }
+
+    fn get(&self, url: &str) -> reqwest::RequestBuilder {
+        self.client.get(url)
+    }
+
+    fn post(&self, url: &str) -> reqwest::RequestBuilder {
+        self.client.post(url)
+    }
+
+    fn host_allowed(&self, host: &str) -> bool {
+        self.allowlist.is_empty() || self.allowlist.iter().any(|p| host_matches(p, host))
+    }
+
+    fn check(&self, feature: NetFeature, url: &reqwest::Url) -> Result<()> {
+        let host = url.host_str().unwrap_or_default();
+        if self.host_allowed(host) {
+            return Ok(());
+        }
+        let count = {
+            let mut blocked = self.blocked.lock().unwrap();
+            let c = blocked.entry(feature).or_insert(0);
+            *c += 1;
+            *c
+        };
+        *self.blocked_unrecorded.lock().unwrap().entry(feature).or_insert(0) += 1;
+        eprintln!(
+            "[blocked] {} tried to contact {} (not in network_allowlist; {} blocked so far)",
+            feature.name(),
+            host,
+            count
+        );
+        Err(anyhow!("{} request to {} blocked by network_allowlist", feature.name(), host))
+    }
+
+    /// Blocked attempts since the last call, for the persisted metrics.
+    fn take_blocked(&self) -> BTreeMap<NetFeature, u64> {
+        std::mem::take(&mut *self.blocked_unrecorded.lock().unwrap())
+    }
+
+    async fn send(&self, feature: NetFeature, req: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+        self.send_described(feature, req, Vec::new()).await
+    }
+
+    async fn send_multipart(
+        &self,
+        feature: NetFeature,
+        req: reqwest::RequestBuilder,
+        parts: Vec<FormPart>,
+    ) -> Result<reqwest::Response> {
+        let described = if self.captured.is_some() {
+            parts.iter().map(|p| p.describe(&self.secrets)).collect()
+        } else {
+            Vec::new()
+        };
+        let mut form = multipart::Form::new();
+        for part in parts {
+            let name = part.name.clone();
+            form = form.part(name, part.into_part()?);
+        }
+        self.send_described(feature, req.multipart(form), described).await
+    }
+
+    async fn send_described(
+        &self,
+        feature: NetFeature,
+        req: reqwest::RequestBuilder,
+        parts: Vec<CapturedPart>,
+    ) -> Result<reqwest::Response> {
+        let req = req.build()?;
+        if self.captured.is_none() {
+            return self.execute(feature, req).await;
+        }
+
+        let mut record = CapturedRequest {
+            at: Utc::now().timestamp(),
+            feature: feature.name().into(),
+            method: req.method().to_string(),
+            url: redact_secrets(req.url().as_str(), &self.secrets),
+            headers: req
+                .headers()
+                .iter()
+                .map(|(k, v)| {
+                    let value = if k == AUTHORIZATION {
+                        "<redacted>".to_string()
+                    } else {
+                        redact_secrets(v.to_str().unwrap_or("<binary>"), &self.secrets)
+                    };
+                    (k.to_string(), value)
+                })
+                .collect(),
+            parts,
+            ..Default::default()
+        };
+        let result = self.execute(feature, req).await;
+        match &result {
+            Ok(resp) => record.status = Some(resp.status().as_u16()),
+            Err(e) => record.error = Some(redact_secrets(&format!("{e:#}"), &self.secrets)),
+        }
+        self.capture(record);
+        result
+    }
+
+    fn capture(&self, record: CapturedRequest) {
+        let Some(captured) = &self.captured else { return };
+        {
+            let mut ring = captured.lock().unwrap();
+            ring.push_back(record);
+            while ring.len() > self.capture_limit {
+                ring.pop_front();
+            }
+        }
+        // Written off the request path, at most once per CAPTURE_FLUSH_DELAY;
+        // `Drop` writes whatever is still pending.
+        if self.capture_flush_pending.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let (ring, pending) = (captured.clone(), self.capture_flush_pending.clone());
+        tokio::spawn(async move {
+            tokio::time::sleep(CAPTURE_FLUSH_DELAY).await;
+            // Cleared before the snapshot so a capture made meanwhile schedules another write.
+            pending.store(false, Ordering::SeqCst);
+            let json = serde_json::to_string_pretty(&*ring.lock().unwrap());
+            if let (Ok(p), Ok(dir), Ok(json)) = (last_requests_path(), config_dir(), json) {
+                tokio::fs::create_dir_all(dir).await.ok();
+                tokio::fs::write(p, json).await.ok();
+            }
+        });
+    }
+
+    async fn execute(&self, feature: NetFeature, mut req: reqwest::Request) -> Result<reqwest::Response> {
+        for _ in 0..=MAX_REDIRECTS {
+            self.check(feature, req.url())?;
+            let replay = req.try_clone();
+            let method = req.method().clone();
+            let headers = req.headers().clone();
+            let from = req.url().clone();
+
+            let client = match feature {
+                NetFeature::AddonDownload => &self.public_client,
+                _ => &self.client,
+            };
+            let resp = match client.execute(req).await {
+                Ok(resp) => resp,
+                Err(e) => {
+                    let err = anyhow::Error::from(e).context(format!("{} {}", method, from));
+                    if is_certificate_error(&err) {
+                        return Err(err.context(format!(
+                            "the TLS certificate of {} is not trusted; for a private CA set tls_ca_file",
+                            from.host_str().unwrap_or("")
+                        )));
+                    }
+                    return Err(err);
+                }
+            };
+            if !resp.status().is_redirection() {
+                return Ok(resp);
+            }
+            let location = match resp.headers().get(LOCATION).and_then(|v| v.to_str().ok()) {
+                Some(l) => l.to_string(),
+                None => return Ok(resp),
+            };
+            let to = from.join(&location)?;
+            let cross_host = to.host_str() != from.host_str();
+
+            // 307/308 must replay the same method and body; the rest become a plain GET.
+            let mut next = match resp.status() {
+                StatusCode::TEMPORARY_REDIRECT | StatusCode::PERMANENT_REDIRECT => {
+                    let mut r = replay
+                        .ok_or_else(|| anyhow!("cannot replay request body for redirect to {}", to))?;
+                    // The body is the death itself: with an allowlist in use, only hand
+                    // it to a host the user listed.
+                    let unlisted = !self.allowlist.is_empty() && !self.host_allowed(to.host_str().unwrap_or_default());
+                    if cross_host && r.body().is_some() && unlisted {
+                        return Err(anyhow!(
+                            "{} {} redirected to {}, which is not in network_allowlist; not resending the body there",
+                            method,
+                            from,
+                            to.host_str().unwrap_or_default()
+                        ));
+                    }
+                    *r.url_mut() = to.clone();
+                    r
+                }
+                _ => {
+                    let mut r = reqwest::Request::new(Method::GET, to.clone());
+                    *r.headers_mut() = headers;
+                    r.headers_mut().remove(CONTENT_TYPE);
+                    r.headers_mut().remove(CONTENT_LENGTH);
+                    r
+                }
+            };
+            // Never forward credentials to a different host.
+            if cross_host {
+                self.strip_credentials(next.headers_mut());
+            }
+            req = next;
+        }
+        Err(anyhow!("{} request exceeded {} redirects", feature.name(), MAX_REDIRECTS))
+    }
+
+    /// Drop everything that authenticates or identifies us to the original host:
+    /// Authorization, `[headers]`, the signature and idempotency headers.
+    fn strip_credentials(&self, headers: &mut reqwest::header::HeaderMap) {
+        headers.remove(AUTHORIZATION);
+        for name in self.extra_headers.keys() {
+            headers.remove(name);
+        }
+        let ours: Vec<reqwest::header::HeaderName> = headers
+            .keys()
+            .filter(|k| k.as_str() == "idempotency-key" || k.as_str().starts_with("x-deathlogger-"))
+            .cloned()
+            .collect();
+        for name in ours {
+            headers.remove(name);
+        }
+    }
+}
+
+impl Drop for Http {
+    fn drop(&mut self) {
+        let Some(ring) = &self.captured else { return };
+        if !self.capture_flush_pending.load(Ordering::SeqCst) {
+            return;
+        }
+        if let (Ok(p), Ok(json)) = (last_requests_path(), serde_json::to_string_pretty(&*ring.lock().unwrap())) {
+            fs::create_dir_all(config_dir().unwrap_or_default()).ok();
+            fs::write(p, json).ok();
+        }
+    }
+}
+
+/// How long captured requests wait before last_requests.json is rewritten.
+const CAPTURE_FLUSH_DELAY: Duration = Duration::from_secs(2);
+
+/// Match a host against an allowlist pattern: exact ("example.com") or
+/// wildcard subdomain ("*.example.com", which does not match the bare domain).
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.trim().trim_end_matches('.').to_ascii_lowercase();
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => host
+            .strip_suffix(suffix)
+            .map(|prefix| prefix.len() > 1 && prefix.ends_with('.'))
+            .unwrap_or(false),
+        None => host == pattern,
+    }
+}
+
+/// Move the allowlist violations counted by `http` into the daily metrics.
+fn record_blocked(http: &Http, state: &mut State) {
+    let blocked = http.take_blocked();
+    if blocked.is_empty() {
+        return;
+    }
+    let day = state.metrics.today();
+    for (feature, n) in blocked {
+        *day.requests_blocked.entry(feature.name().to_string()).or_insert(0) += n;
+    }
+    save_state(state).ok();
+}
+
+fn url_host(url: &str) -> Option<String> {
+    reqwest::Url::parse(url).ok()?.host_str().map(|h| h.to_string())
+}
+
+// ---------- OAuth tokens ----------
+//
+// With `[oauth]`, upload servers that have no static token get a bearer token
+// from the client-credentials grant. It is fetched before the first request that
+// needs it, reused until a minute before it expires, and dropped on a 401 so the
+// next request fetches a fresh one. Refresh failures are `OAuthError`s and logged
+// as such, so a broken client secret doesn't read like a broken upload server.
+
+/// `[oauth]`: client-credentials grant (RFC 6749 section 4.4).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct OAuthConfig {
+    token_url: String,
+    client_id: String,
+    client_secret: String,
+    /// Space-separated; empty asks for the server's default scope
+    scope: String,
+}
+
+/// Tokens this close to expiry are refreshed before use.
+const OAUTH_REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+struct OAuthToken {
+    value: String,
+    /// None when the server didn't say; the token is then used until a 401
+    expires: Option<std::time::Instant>,
+}
+
+#[derive(Deserialize)]
+struct OAuthTokenResponse {
+    access_token: String,
+    #[serde(default)]
+    expires_in: Option<u64>,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("fetching an OAuth token from {url} failed: {reason}")]
+struct OAuthError {
+    url: String,
+    reason: String,
+}
+
+impl Http {
+    /// Bearer token for a request to `cfg.api_url`: its own token, else one from `[oauth]`.
+    async fn bearer(&self, cfg: &Config) -> Result<Option<String>> {
+        if !cfg.api_token.is_empty() {
+            return Ok(Some(cfg.api_token.clone()));
+        }
+        let Some(oauth) = &cfg.oauth else { return Ok(None) };
+        let now = std::time::Instant::now();
+        if let Some(token) = self.oauth_token.lock().unwrap().as_ref() {
+            if token.expires.map(|e| e > now + OAUTH_REFRESH_MARGIN).unwrap_or(true) {
+                return Ok(Some(token.value.clone()));
+            }
+        }
+        let token = self
+            .fetch_oauth_token(oauth)
+            .await
+            .map_err(|e| OAuthError { url: oauth.token_url.clone(), reason: format!("{e:#}") })?;
+        let value = token.access_token.clone();
+        let expires = token.expires_in.map(|secs| now + Duration::from_secs(secs));
+        *self.oauth_token.lock().unwrap() = Some(OAuthToken { value: value.clone(), expires });
+        Ok(Some(value))
+    }
+
+    async fn fetch_oauth_token(&self, oauth: &OAuthConfig) -> Result<OAuthTokenResponse> {
+        let mut form = vec![("grant_type", "client_credentials")];
+        if !oauth.scope.is_empty() {
+            form.push(("scope", oauth.scope.as_str()));
+        }
+        let req = self.post(&oauth.token_url).basic_auth(&oauth.client_id, Some(&oauth.client_secret)).form(&form);
+        let resp = self.send(NetFeature::OAuth, req).await?;
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.text().await.unwrap_or_default();
+            return Err(anyhow!("HTTP {}: {}", status, redact_secrets(body.trim(), &self.secrets)));
+        }
+        let token: OAuthTokenResponse = resp.json().await.context("the token response has no access_token")?;
+        println!(
+            "[oauth] Got a token{}",
+            token.expires_in.map(|s| format!(" valid for {}s", s)).unwrap_or_default()
+        );
+        Ok(token)
+    }
+
+    /// Add `[headers]` and the bearer token for `cfg.api_url`, if there is one.
+    /// Every request to an upload server goes through here.
+    async fn authorize(&self, cfg: &Config, req: reqwest::RequestBuilder) -> Result<reqwest::RequestBuilder> {
+        let req = req.headers(self.extra_headers.clone());
+        Ok(match self.bearer(cfg).await? {
+            Some(token) => req.bearer_auth(token),
+            None => req,
+        })
+    }
+
+    /// After a 401: drop the `[oauth]` token so the next request fetches a new one.
+    /// True when there was one to drop, i.e. the request is worth repeating.
+    fn forget_oauth_token(&self, cfg: &Config) -> bool {
+        cfg.api_token.is_empty() && self.oauth_token.lock().unwrap().take().is_some()
+    }
+}
+
+// ---------- Redaction ----------
+
+/// Secret values from the config that must never be logged or persisted.
+fn config_secrets(cfg: &Config) -> Vec<String> {
+    let proxy_password = reqwest::Url::parse(&cfg.proxy_url)
+        .ok()
+        .and_then(|u| u.password().map(|p| p.to_string()))
+        .unwrap_or_default();
+    // The webhook URL carries its own token.
+    [
+        cfg.api_token.clone(),
+        cfg.hmac_secret.clone(),
+        cfg.discord_webhook_url.clone(),
+        cfg.tls_client_key_password.clone(),
+        cfg.oauth.as_ref().map(|o| o.client_secret.clone()).unwrap_or_default(),
+        proxy_password,
+    ]
+        .into_iter()
+        .chain(cfg.endpoints.iter().map(|e| e.token.clone()))
+        .chain(cfg.headers.values().map(|v| expand_env(v).unwrap_or_default()))
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// Which proxy requests go through, for doctor. Credentials are redacted.
+fn proxy_summary(cfg: &Config) -> String {
+    if !cfg.proxy_url.is_empty() {
+        return format!("{} (proxy_url)", redact_secrets(&cfg.proxy_url, &config_secrets(cfg)));
+    }
+    for var in ["HTTPS_PROXY", "https_proxy", "HTTP_PROXY", "http_proxy", "ALL_PROXY", "all_proxy"] {
+        if let Ok(v) = std::env::var(var) {
+            if !v.is_empty() {
+                let password = reqwest::Url::parse(&v).ok().and_then(|u| u.password().map(|p| p.to_string()));
+                return format!("{} (from {})", redact_secrets(&v, &password.into_iter().collect::<Vec<_>>()), var);
+            }
+        }
+    }
+    "none".into()
+}
+
+fn redact_secrets(text: &str, secrets: &[String]) -> String {
+    let mut out = text.to_string();
+    for s in secrets {
+        out = out.replace(s.as_str(), "<redacted>");
+    }
+    out
+}
+
+// ---------- Installer / updater ----------
+
+const RAW_TOC: &str = "https://raw.githubusercontent.com/2Lynk/DeathLogger/main/Addon/DeathLogger.toc";
+const RAW_LUA: &str = "https://raw.githubusercontent.com/2Lynk/DeathLogger/main/Addon/DeathLogger.lua";
+
+async fn download_to(http: &Http, url: &str, dest: &Path) -> Result<()> {
+    let bytes = http
+        .send(NetFeature::AddonDownload, http.get(url))
+        .await
+        .with_context(|| format!("GET {}", url))?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    if let Some(pdir) = dest.parent() {
+        fs::create_dir_all(pdir)?;
+    }
+    fs::write(dest, &bytes)?;
+    Ok(())
+}
+
+async fn install_or_update_addon(http: &Http, paths: &WowPaths) -> Result<()> {
+    let addon_dir = paths.addons_dir().join("DeathLogger");
+    fs::create_dir_all(&addon_dir)?;
+    download_to(http, RAW_TOC, &addon_dir.join("DeathLogger.toc")).await?;
+    download_to(http, RAW_LUA, &addon_dir.join("DeathLogger.lua")).await?;
+    println!("[install] Updated addon in {}", addon_dir.display());
+    Ok(())
+}
+
+// ---------- Legacy addon folders ----------
+
+/// Suffix given to legacy addon folders once migrated; WoW ignores folders
+/// whose TOC name doesn't match, so the copy stays on disk but never loads.
+const MIGRATED_SUFFIX: &str = ".migrated";
+
+/// True when `dir` holds a copy of our addon: a TOC declaring our title or
+/// SavedVariables, or Lua referencing DeathLoggerDB. Looks one level deep to
+/// catch GitHub archives that keep the addon under `Addon/`.
+fn is_deathlogger_addon_dir(dir: &Path) -> bool {
+    WalkDir::new(dir)
+        .max_depth(2)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .any(|e| {
+            let ext = e.path().extension().and_then(|x| x.to_str()).unwrap_or("").to_ascii_lowercase();
+            let text = match ext.as_str() {
+                "toc" | "lua" => fs::read_to_string(e.path()).unwrap_or_default(),
+                _ => return false,
+            };
+            if ext == "toc" {
+                text.lines().any(|l| {
+                    let l = l.trim();
+                    l.eq_ignore_ascii_case("## Title: Death Logger") || l.eq_ignore_ascii_case("## SavedVariables: DeathLoggerDB")
+                })
+            } else {
+                text.contains("DeathLoggerDB")
+            }
+        })
+}
+
+/// Other AddOns folders (e.g. "DeathLogger-main" from a GitHub zip) that carry our addon.
+fn find_legacy_addon_dirs(addons_dir: &Path) -> Vec<PathBuf> {
+    let mut out: Vec<PathBuf> = fs::read_dir(addons_dir)
+        .map(|rd| rd.filter_map(|e| e.ok()).map(|e| e.path()).collect())
+        .unwrap_or_default();
+    out.retain(|p| {
+        let name = p.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        p.is_dir() && name != "DeathLogger" && !name.ends_with(MIGRATED_SUFFIX) && is_deathlogger_addon_dir(p)
+    });
+    out.sort();
+    out
+}
+
+/// Rewrite an AddOns.txt so entries for `legacy` point at `current` instead.
+/// Returns None when the file doesn't mention `legacy`.
+fn rewrite_addons_txt(content: &str, legacy: &str, current: &str) -> Option<String> {
+    let entry = |line: &str| line.split_once(':').map(|(name, state)| (name.trim().to_string(), state.trim().to_string()));
+    let mut legacy_state = None;
+    let mut has_current = false;
+    let mut lines = vec![];
+    for line in content.lines() {
+        match entry(line) {
+            Some((name, state)) if name.eq_ignore_ascii_case(legacy) => legacy_state = Some(state),
+            Some((name, _)) if name.eq_ignore_ascii_case(current) => {
+                has_current = true;
+                lines.push(line.to_string());
+            }
+            _ => lines.push(line.to_string()),
+        }
+    }
+    let legacy_state = legacy_state?;
+    if !has_current {
+        lines.push(format!("{}: {}", current, legacy_state));
+    }
+    let mut out = lines.join("\n");
+    if content.ends_with('\n') {
+        out.push('\n');
+    }
+    Some(out)
+}
+
+/// Every per-character AddOns.txt under WTF/Account.
+fn addons_txt_files(wow: &WowPaths) -> Vec<PathBuf> {
+    WalkDir::new(wow.wtf_account_dir())
+        .max_depth(4)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file() && e.file_name().eq_ignore_ascii_case("AddOns.txt"))
+        .map(|e| e.into_path())
+        .collect()
+}
+
+#[cfg(windows)]
+fn game_running() -> bool {
+    std::process::Command::new("tasklist")
+        .args(["/NH", "/FO", "CSV"])
+        .output()
+        .map(|o| {
+            let list = String::from_utf8_lossy(&o.stdout).to_ascii_lowercase();
+            ["\"wow.exe\"", "\"wowclassic.exe\"", "\"wow-64.exe\""].iter().any(|exe| list.contains(exe))
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(not(windows))]
+fn game_running() -> bool {
+    false
+}
+
+/// Disable one legacy copy: rename the folder and repoint AddOns.txt entries,
+/// logging each change with how to undo it.
+fn migrate_legacy_addon_dir(wow: &WowPaths, legacy: &Path) -> Result<()> {
+    let legacy_name = legacy.file_name().and_then(|n| n.to_str()).unwrap_or_default().to_string();
+    let target = legacy.with_file_name(format!("{}{}", legacy_name, MIGRATED_SUFFIX));
+    if target.exists() {
+        return Err(anyhow!("{} already exists; move it away and retry", target.display()));
+    }
+    fs::rename(legacy, &target).with_context(|| format!("renaming {}", legacy.display()))?;
+    println!("[migrate] Renamed {} -> {} (undo: rename it back)", legacy.display(), target.display());
+
+    for txt in addons_txt_files(wow) {
+        let Ok(content) = fs::read_to_string(&txt) else { continue };
+        if let Some(updated) = rewrite_addons_txt(&content, &legacy_name, "DeathLogger") {
+            let backup = txt.with_extension("txt.bak");
+            fs::copy(&txt, &backup)?;
+            fs::write(&txt, updated)?;
+            println!("[migrate] Updated {} (undo: restore {})", txt.display(), backup.display());
+        }
+    }
+    Ok(())
+}
+
+/// Find legacy copies and, with the user's OK, migrate them.
+fn offer_legacy_migration(wow: &WowPaths) {
+    let legacy = find_legacy_addon_dirs(&wow.addons_dir());
+    if legacy.is_empty() {
+        return;
+    }
+    for dir in &legacy {
+        println!("[warn] Another copy of the addon is installed at {}; both write DeathLoggerDB.", dir.display());
+    }
+    if game_running() {
+        println!("[warn] WoW is running. Exit the game and restart the agent to migrate the old copy.");
+        return;
+    }
+    let go = Confirm::new()
+        .with_prompt("Disable the old copy (renamed with a .migrated suffix, nothing is deleted)?")
+        .default(true)
+        .interact()
+        .unwrap_or(false);
+    if !go {
+        return;
+    }
+    for dir in &legacy {
+        if let Err(e) = migrate_legacy_addon_dir(wow, dir) {
+            eprintln!("[warn] migrating {} failed: {e:#}", dir.display());
+        }
+    }
+}
+
+// ---------- First-run setup ----------
+
+fn try_detect_wow_root_candidates() -> Vec<PathBuf> {
+    let mut cands = vec![];
+    // Common installs
+    let defaults = [
+        r"C:\Program Files (x86)\World of Warcraft",
+        r"C:\Program Files\World of Warcraft",
+        r"D:\World of Warcraft",
+        r"E:\World of Warcraft",
+    ];
+    for d in defaults {
+        let p = PathBuf::from(d);
+        if p.exists() {
+            cands.push(p);
+        }
+    }
+    // Look for folders containing Interface\AddOns under any *_retail_ or *_classic_* branch
+    let drives = ['C', 'D', 'E', 'F'];
+    for drive in drives {
+        let pattern = format!(r"{drive}:\**\World of Warcraft");
+        for p in glob(&pattern).into_iter().flatten().flatten() {
+            if p.exists() && p.is_dir() {
+                cands.push(p);
+            }
+        }
+    }
+    cands.sort();
+    cands.dedup();
+    cands
+}
+
+fn choose_branch(root: &Path) -> Result<String> {
+    let branches = ["_retail_", "_classic_", "_classic_era_", "_classic_ptr_"];
+    let mut present: Vec<String> = branches
+        .iter()
+        .filter(|b| root.join(b).exists())
+        .map(|s| s.to_string())
+        .collect();
+
+    if present.is_empty() {
+        // Still allow manual selection
+        present = branches.iter().map(|s| s.to_string()).collect();
+    }
+
+    let idx = Select::new()
+        .with_prompt("Select WoW branch to monitor")
+        .items(&present)
+        .default(0)
+        .interact()
+        .unwrap_or(0);
+
+    Ok(present[idx].clone())
+}
+
+async fn first_run_wizard() -> Result<Config> {
+    println!("Welcome to DeathLogger Agent!");
+
+    let detect = Confirm::new()
+        .with_prompt("Try to detect your World of Warcraft folder automatically?")
+        .default(true)
+        .interact()
+        .unwrap_or(true);
+
+    let wow_root = if detect {
+        let cands = try_detect_wow_root_candidates();
+        if !cands.is_empty() {
+            let items: Vec<String> = cands.iter().map(|p| p.display().to_string()).collect();
+            let idx = Select::new()
+                .with_prompt("Select your WoW root folder")
+                .items(&items)
+                .default(0)
+                .interact()
+                .unwrap_or(0);
+            cands[idx].clone()
+        } else {
+            println!("No installs detected.");
+            PathBuf::from(
+                Input::<String>::new()
+                    .with_prompt("Enter your WoW root folder (contains _retail_/_classic_)")
+                    .interact_text()?,
+            )
+        }
+    } else {
+        PathBuf::from(
+            Input::<String>::new()
+                .with_prompt("Enter your WoW root folder (contains _retail_/_classic_)")
+                .interact_text()?,
+        )
+    };
+
+    if !wow_root.exists() {
+        return Err(anyhow!(
+            "Path does not exist: {}",
+            wow_root.display()
+        ));
+    }
+
+    let branch = choose_branch(&wow_root)?;
+
+    let api_url: String = Input::new()
+        .with_prompt("Enter your server upload URL")
+        .default("https://your-server.example/upload".into())
+        .interact_text()?;
+
+    let auth = Select::new()
+        .with_prompt("How does your server authenticate the agent?")
+        .items(&["API token (bearer)", "OAuth2 client credentials", "Client certificate (mutual TLS)", "No authentication"])
+        .default(0)
+        .interact()
+        .unwrap_or(0);
+    let (mut api_token, mut tls_client_cert, mut tls_client_key, mut tls_client_key_password) =
+        (String::new(), String::new(), String::new(), String::new());
+    let mut oauth = None;
+    match auth {
+        0 => {
+            api_token = Input::new().with_prompt("Enter API token").allow_empty(true).interact_text()?;
+        }
+        1 => {
+            let token_url: String = Input::new().with_prompt("Token URL").interact_text()?;
+            let client_id: String = Input::new().with_prompt("Client id").interact_text()?;
+            let client_secret = Password::new().with_prompt("Client secret").interact()?;
+            let scope: String = Input::new().with_prompt("Scope (blank for the default)").allow_empty(true).interact_text()?;
+            oauth = Some(OAuthConfig {
+                token_url: token_url.trim().to_string(),
+                client_id: client_id.trim().to_string(),
+                client_secret,
+                scope: scope.trim().to_string(),
+            });
+        }
+        2 => {
+            tls_client_cert =
+                Input::new().with_prompt("Path to the client certificate (PEM, .p12 or .pfx)").interact_text()?;
+            let ext = Path::new(tls_client_cert.trim()).extension().and_then(|e| e.to_str()).unwrap_or("");
+            if ext.eq_ignore_ascii_case("p12") || ext.eq_ignore_ascii_case("pfx") {
+                tls_client_key_password =
+                    Password::new().with_prompt("Certificate password").allow_empty_password(true).interact()?;
+            } else {
+                tls_client_key = Input::new()
+                    .with_prompt("Path to its private key (PEM), blank if it is in the certificate file")
+                    .allow_empty(true)
+                    .interact_text()?;
+            }
+        }
+        _ => {}
+    }
+
+    let start_with_windows = Confirm::new()
+        .with_prompt("Start this agent with Windows?")
+        .default(false)
+        .interact()
+        .unwrap_or(false);
+
+    let mut cfg = Config {
+        wow_root: wow_root.to_string_lossy().to_string(),
+        wow_branch: branch,
+        api_url,
+        api_token,
+        tls_client_cert: tls_client_cert.trim().to_string(),
+        tls_client_key: tls_client_key.trim().to_string(),
+        tls_client_key_password,
+        oauth,
+        start_with_windows,
+        pair_window_before_secs: Some(DEFAULT_PAIR_WINDOW_BEFORE_SECS),
+        pair_window_after_secs: Some(DEFAULT_PAIR_WINDOW_AFTER_SECS),
+        update_addon_on_start: true,
+        ..Config::default()
+    };
+
+    // If the server can't be reached at all, a private CA or a proxy is the usual culprit.
+    let check = wizard_check(&cfg).await?;
+    println!("{}: {}", cfg.api_url, check.summary());
+    match check.reachability {
+        Reachability::Certificate => {
+            let path: String = Input::new()
+                .with_prompt("Path to your server's CA certificate (PEM), blank to skip")
+                .allow_empty(true)
+                .interact_text()?;
+            if !path.trim().is_empty() {
+                cfg.tls_ca_file = path.trim().to_string();
+                match wizard_check(&cfg).await?.reachability {
+                    Reachability::Certificate => {
+                        println!("Still can't verify the server; you can change tls_ca_file in the config later.")
+                    }
+                    _ => println!("Server certificate verified with that CA."),
+                }
+            }
+        }
+        Reachability::Refused | Reachability::Timeout | Reachability::Dns => {
+            let use_proxy = Confirm::new()
+                .with_prompt("Are you behind a proxy?")
+                .default(false)
+                .interact()
+                .unwrap_or(false);
+            if use_proxy {
+                cfg.proxy_url = Input::new()
+                    .with_prompt("Proxy URL (http://[user:pass@]host:port or socks5://host:port)")
+                    .interact_text()?;
+                let check = wizard_check(&cfg).await?;
+                println!("Through the proxy: {}", check.summary());
+                if check.reachability != Reachability::Ok {
+                    println!("You can change proxy_url in the config later.");
+                }
+            }
+        }
+        _ => {}
+    }
+
+    cfg.telemetry = Confirm::new()
+        .with_prompt(
+            "Send an anonymous daily usage ping to the DeathLogger maintainers? It contains only the agent/addon/OS \
+             versions, monitored branch, which features are on, and that day's upload/parse-error counts, plus a \
+             random install id. No names, realms or URLs. (`deathlogger-agent telemetry preview` shows it)",
+        )
+        .default(false)
+        .interact()
+        .unwrap_or(false);
+
+    println!(
+        "Money, bags, equipped items and map coordinates can be kept out of uploads with a [privacy] \
+         section in {} (redact_money, redact_bags, redact_equipped, redact_coordinates).",
+        config_path()?.display()
+    );
+
+    // New installs decide explicitly whether anything leaves the machine.
+    if WowPaths::from_config(&cfg).is_pre_first_launch() {
+        println!("{}", PRE_FIRST_LAUNCH_HINT);
+    } else {
+        print_preview(&cfg, false)?;
+    }
+    cfg.uploads_enabled = Confirm::new()
+        .with_prompt("Enable uploads now?")
+        .default(true)
+        .interact()
+        .unwrap_or(false);
+
+    fs::create_dir_all(config_dir()?)?;
+    fs::write(config_path()?, toml::to_string_pretty(&cfg)?)?;
+
+    if cfg.start_with_windows {
+        set_startup(true, cfg.event_log)?;
+    }
+
+    Ok(cfg)
+}
+
+// ---------- Offline mode ----------
+//
+// When an upload URL can't be reached (no network, DNS failure, connection
+// refused or timed out), uploads to it stop: deaths go to the retry queue,
+// which is saved with the state, as holds that cost no attempt and log no
+// error. The main loop probes the URL every OFFLINE_PROBE_SECS. Once it
+// answers, everything queued for it is released, oldest death first.
+
+const OFFLINE_PROBE_SECS: u64 = 45;
+
+struct OfflineSince {
+    since: std::time::Instant,
+    next_probe: std::time::Instant,
+}
+
+impl Http {
+    /// The hold for uploads to `url`, while it is offline. It runs a full probe
+    /// interval past the next probe, which releases the queue early if the URL
+    /// answers; the fallback only matters if the agent restarts meanwhile.
+    fn offline_hold(&self, url: &str) -> Option<RateLimited> {
+        let offline = self.offline.lock().unwrap();
+        let wait = offline.get(url)?.next_probe.saturating_duration_since(std::time::Instant::now())
+            + Duration::from_secs(OFFLINE_PROBE_SECS);
+        Some(RateLimited { wait, cause: HoldCause::Offline })
+    }
+
+    fn check_offline(&self, url: &str) -> Result<()> {
+        match self.offline_hold(url) {
+            Some(hold) => Err(hold.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Turn a network failure of an upload to `url` into a hold, and take the URL offline.
+    fn note_offline<T>(&self, url: &str, result: Result<T>) -> Result<T> {
+        match result {
+            Err(e) if connection_problem(&e).is_offline() => {
+                self.go_offline(url, &format!("{e:#}"));
+                Err(self.offline_hold(url).map(anyhow::Error::from).unwrap_or(e))
+            }
+            other => other,
+        }
+    }
+
+    fn go_offline(&self, url: &str, reason: &str) {
+        let now = std::time::Instant::now();
+        let mut offline = self.offline.lock().unwrap();
+        if offline.contains_key(url) {
+            return;
+        }
+        offline.insert(url.to_string(), OfflineSince { since: now, next_probe: now + Duration::from_secs(OFFLINE_PROBE_SECS) });
+        let msg = format!("{} is unreachable ({}); uploads to it are queued until it answers again", url, reason);
+        println!("[offline] {}", msg);
+        eventlog::report(Level::Warning, EventClass::Upload, &msg);
+    }
+
+    /// Offline URLs due for another probe.
+    fn offline_probes_due(&self) -> Vec<String> {
+        let now = std::time::Instant::now();
+        self.offline.lock().unwrap().iter().filter(|(_, o)| o.next_probe <= now).map(|(url, _)| url.clone()).collect()
+    }
+}
+
+/// Probe the offline upload URLs that are due; release the queue of any that answer.
+async fn probe_offline(cfg: &Config, http: &Http, state: &mut State) {
+    for url in http.offline_probes_due() {
+        let endpoint = api_endpoints(cfg).into_iter().find(|e| e.url == url);
+        let check = match &endpoint {
+            Some(ep) => check_connectivity(&cfg.for_endpoint(ep), http).await,
+            None => ConnectivityCheck { reachability: Reachability::Ok, detail: "no longer configured".into() },
+        };
+        let now = std::time::Instant::now();
+        let mut offline = http.offline.lock().unwrap();
+        if check.reachability.is_offline() {
+            if let Some(o) = offline.get_mut(&url) {
+                o.next_probe = now + Duration::from_secs(OFFLINE_PROBE_SECS);
+            }
+            continue;
+        }
+        let Some(o) = offline.remove(&url) else { continue };
+        drop(offline);
+        let name = endpoint.map(|e| e.name).unwrap_or_default();
+        let epoch = Utc::now().timestamp();
+        let mut queued = 0;
+        for r in &mut state.retry_queue {
+            if r.targets.is_empty() || r.targets.contains(&name) {
+                r.next_at = r.next_at.min(epoch);
+                queued += 1;
+            }
+        }
+        let offline_secs = o.since.elapsed().as_secs();
+        let msg = format!(
+            "{} is reachable again after {} offline; sending {} queued death(s), oldest first",
+            url,
+            if offline_secs < 120 { format!("{}s", offline_secs) } else { format!("{}m", offline_secs / 60) },
+            queued
+        );
+        println!("[online] {}", msg);
+        eventlog::report(Level::Info, EventClass::Upload, &msg);
+    }
+}
+
+async fn wizard_check(cfg: &Config) -> Result<ConnectivityCheck> {
+    let http = Http::new(cfg)?;
+    Ok(check_connectivity(cfg, &http).await)
+}
+
+/// True when a request failed because the server's certificate wasn't trusted.
+fn is_certificate_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|e| {
+        let text = e.to_string().to_ascii_lowercase();
+        text.contains("certificate") || text.contains("unknownissuer") || text.contains("self signed")
+    })
+}
+
+// ---------- Connectivity check ----------
+//
+// A HEAD request to each upload URL at startup and in the wizard, with the
+// same credentials and headers an upload carries, so a wrong host, path or
+// token shows up right away instead of with the first death. Informational only:
+// the agent runs either way and real uploads keep their own retry handling.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reachability {
+    /// Any answer that means the URL exists and we may post to it (2xx, 405, ...)
+    Ok,
+    Dns,
+    Certificate,
+    Tls,
+    /// This machine has no route to the server
+    NoNetwork,
+    Refused,
+    Timeout,
+    Blocked,
+    /// The `[oauth]` token could not be fetched
+    Token,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    ServerError,
+    Other,
+}
+
+struct ConnectivityCheck {
+    reachability: Reachability,
+    /// Status line or error text
+    detail: String,
+}
+
+impl ConnectivityCheck {
+    /// What to do about it, in words a first-time user can act on.
+    fn advice(&self) -> &'static str {
+        match self.reachability {
+            Reachability::Ok => "reachable",
+            Reachability::Dns => "the host name does not resolve; check the spelling of the URL and your DNS/VPN",
+            Reachability::Certificate => "the server's TLS certificate is not trusted; for a private CA set tls_ca_file",
+            Reachability::Tls => {
+                "the TLS handshake failed; if the server wants a client certificate set tls_client_cert, \
+                 otherwise check that the URL should be https://"
+            }
+            Reachability::NoNetwork => "no route to the server; check this computer's network connection",
+            Reachability::Refused => "nothing answers on that host and port; check the port and that the server is running",
+            Reachability::Timeout => "no answer in time; a firewall or a required proxy (proxy_url) is the usual cause",
+            Reachability::Blocked => "the host is not in network_allowlist",
+            Reachability::Token => "no OAuth token could be fetched; check token_url, client_id and client_secret in [oauth]",
+            Reachability::Unauthorized => "the server rejected the credentials; check api_token (or [oauth], tls_client_cert)",
+            Reachability::Forbidden => {
+                "the credentials were accepted but not allowed to upload, or an access proxy in front of the \
+                 server refused the request ([headers])"
+            }
+            Reachability::NotFound => "the host answered but the path does not exist; check the path part of the URL",
+            Reachability::ServerError => "the server is up but failing; uploads will be retried",
+            Reachability::Other => "unexpected answer",
+        }
+    }
+
+    /// One line for the `[run]` banner and the wizard.
+    fn summary(&self) -> String {
+        match self.reachability {
+            Reachability::Ok => format!("reachable ({})", self.detail),
+            _ => format!("PROBLEM: {} ({})", self.advice(), self.detail),
+        }
+    }
+}
+
+/// HEAD `cfg.api_url` and classify the outcome. Never fails: problems are the result.
+async fn check_connectivity(cfg: &Config, http: &Http) -> ConnectivityCheck {
+    let result = async {
+        let req = http.authorize(cfg, http.client.head(&cfg.api_url)).await?;
+        http.send(NetFeature::Upload, req).await
+    }
+    .await;
+    let (reachability, detail) = match result {
+        Ok(resp) => {
+            let status = resp.status();
+            let reachability = match status.as_u16() {
+                401 => Reachability::Unauthorized,
+                403 => Reachability::Forbidden,
+                404 | 410 => Reachability::NotFound,
+                500..=599 => Reachability::ServerError,
+                // A HEAD on an upload URL often gets 405 or a validation error: the path exists.
+                200..=299 | 400 | 405 | 415 | 422 | 429 => Reachability::Ok,
+                _ => Reachability::Other,
+            };
+            (reachability, format!("HTTP {}", status))
+        }
+        Err(e) => (connection_problem(&e), format!("{e:#}")),
+    };
+    ConnectivityCheck { reachability, detail }
+}
+
+impl Reachability {
+    /// The server (or the network) is down rather than misconfigured.
+    fn is_offline(self) -> bool {
+        matches!(self, Reachability::Dns | Reachability::NoNetwork | Reachability::Refused | Reachability::Timeout)
+    }
+}
+
+/// Network failures are checked first, so an OAuth token that couldn't be
+/// fetched because the network is down still reads as offline.
+fn connection_problem(err: &anyhow::Error) -> Reachability {
+    let text = format!("{err:#}").to_ascii_lowercase();
+    let reqwest_err = err.chain().find_map(|e| e.downcast_ref::<reqwest::Error>());
+    if text.contains("blocked by network_allowlist") {
+        Reachability::Blocked
+    } else if text.contains("dns error") || text.contains("failed to lookup address") || text.contains("no such host") {
+        Reachability::Dns
+    } else if text.contains("network is unreachable") || text.contains("no route to host") || text.contains("host is unreachable") {
+        Reachability::NoNetwork
+    } else if reqwest_err.map(|e| e.is_timeout()).unwrap_or(false) || text.contains("timed out") {
+        Reachability::Timeout
+    } else if text.contains("connection refused") || text.contains("actively refused") {
+        Reachability::Refused
+    } else if err.downcast_ref::<OAuthError>().is_some() {
+        Reachability::Token
+    } else if is_certificate_error(err) {
+        Reachability::Certificate
+    } else if text.contains("tls") || text.contains("ssl") || text.contains("handshake") {
+        Reachability::Tls
+    } else {
+        Reachability::Other
+    }
+}
+
+/// Check every upload server and print one line each, as part of the `[run]` banner.
+async fn print_connectivity(cfg: &Config, http: &Http) {
+    for ep in api_endpoints(cfg) {
+        let ep_cfg = cfg.for_endpoint(&ep);
+        let check = check_connectivity(&ep_cfg, http).await;
+        println!("      Upload URL: {} ({}): {}", ep.url, ep.name, check.summary());
+        if check.reachability.is_offline() {
+            http.go_offline(&ep.url, &check.detail);
+        }
+        if check.reachability != Reachability::Ok {
+            let msg = format!("connectivity check for {} failed: {}", ep.url, check.summary());
+            eventlog::report(Level::Warning, EventClass::Config, &msg);
+        }
+    }
+}
+
+// ---------- Payload encryption ----------
+
+/// Version of the cleartext envelope sent next to `death_encrypted`.
+const ENVELOPE_VERSION: u32 = 1;
+
+/// The configured recipient key, if encryption is enabled.
+fn encryption_key(cfg: &Config) -> Result<Option<crypto_box::PublicKey>> {
+    let text = cfg.encrypt_to_public_key.trim();
+    if text.is_empty() {
+        return Ok(None);
+    }
+    let bytes = BASE64.decode(text).context("not valid base64")?;
+    let raw: [u8; 32] = bytes
+        .try_into()
+        .map_err(|b: Vec<u8>| anyhow!("expected a 32-byte X25519 key, got {} bytes", b.len()))?;
+    Ok(Some(crypto_box::PublicKey::from(raw)))
+}
+
+fn seal(key: &crypto_box::PublicKey, plaintext: &[u8]) -> Result<Vec<u8>> {
+    key.seal(&mut crypto_box::aead::OsRng, plaintext)
+        .map_err(|_| anyhow!("encrypting payload failed"))
+}
+
+/// What a relay may see about a sealed death: enough to route and dedupe, nothing more.
+fn encrypted_envelope(death: &DeathPayload, screenshot_sealed: bool) -> serde_json::Value {
+    let character = to_key(&death.player, &death.realm);
+    json!({
+        "schema_version": ENVELOPE_VERSION,
+        "character_hash": format!("{:x}", Sha256::digest(character.as_bytes())),
+        "payload_sha256": format!("{:x}", Sha256::digest(canonical_json(death))),
+        "at": death.at,
+        "screenshot_encrypted": screenshot_sealed,
+    })
+}
+
+/// `deathlogger-agent keygen`: print a keypair for a server operator.
+fn keygen_command() -> Result<()> {
+    let secret = crypto_box::SecretKey::generate(&mut crypto_box::aead::OsRng);
+    println!("public key (encrypt_to_public_key): {}", BASE64.encode(secret.public_key().as_bytes()));
+    println!("secret key (keep on the receiving server): {}", BASE64.encode(secret.to_bytes()));
+    Ok(())
+}
+
+/// Every DeathLogger SavedVariables file, account-level and per-character.
+fn account_sv_paths(wow: &WowPaths) -> Vec<PathBuf> {
+    let mut v = vec![];
+    for pattern in wow.wtf_savedvariables_globs() {
+        for p in glob(&pattern).into_iter().flatten().flatten() {
+            v.push(p);
+        }
+    }
+    v.sort();
+    v.dedup();
+    v
+}
+
+fn format_epoch(ts: i64) -> String {
+    let dt: DateTime<Utc> = DateTime::from_timestamp(ts, 0).unwrap_or_else(|| DateTime::from(SystemTime::now()));
+    dt.to_rfc3339()
+}
+
+// ---------- Screenshot redaction ----------
+
+/// Rectangle in percent of the image size, from the top-left corner.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct BlurRegion {
+    x: f64,
+    y: f64,
+    w: f64,
+    h: f64,
+}
+
+/// Where the default chat frame sits: the lower left of the screen, above the action bars.
+fn default_blur_regions() -> Vec<BlurRegion> {
+    vec![BlurRegion { x: 0.0, y: 55.0, w: 38.0, h: 38.0 }]
+}
+
+impl BlurRegion {
+    /// Pixel rectangle (x, y, w, h) on a `width` x `height` image, clamped to it.
+    fn to_pixels(&self, width: u32, height: u32) -> (u32, u32, u32, u32) {
+        let px = |pct: f64, size: u32| ((pct.clamp(0.0, 100.0) / 100.0) * size as f64).round() as u32;
+        let (x0, y0) = (px(self.x, width), px(self.y, height));
+        let (x1, y1) = (px(self.x + self.w, width), px(self.y + self.h, height));
+        (x0, y0, x1.saturating_sub(x0), y1.saturating_sub(y0))
+    }
+}
+
+/// Replace every block in the rectangle with its average colour. Blocks scale
+/// with the image so text stays unreadable at any resolution.
+fn pixelate(img: &mut image::RgbImage, (x, y, w, h): (u32, u32, u32, u32)) {
+    let block = (img.width().max(img.height()) / 80).max(8);
+    for by in (y..y + h).step_by(block as usize) {
+        for bx in (x..x + w).step_by(block as usize) {
+            let (ex, ey) = ((bx + block).min(x + w), (by + block).min(y + h));
+            let mut sum = [0u64; 3];
+            for py in by..ey {
+                for px in bx..ex {
+                    let p = img.get_pixel(px, py).0;
+                    for c in 0..3 {
+                        sum[c] += p[c] as u64;
+                    }
+                }
+            }
+            let n = ((ex - bx) * (ey - by)).max(1) as u64;
+            let avg = image::Rgb([(sum[0] / n) as u8, (sum[1] / n) as u8, (sum[2] / n) as u8]);
+            for py in by..ey {
+                for px in bx..ex {
+                    img.put_pixel(px, py, avg);
+                }
+            }
+        }
+    }
+}
+
+/// A screenshot as it goes out: name and type follow the bytes, so a PNG
+/// recompressed to JPEG is sent as one.
+struct ScreenshotFile {
+    file_name: String,
+    bytes: Vec<u8>,
+    content_type: &'static str,
+}
+
+/// The screenshot to send: the file as-is, or shrunk to `screenshot_max_dimension`,
+/// with `blur_regions` pixelated and/or recompressed to JPEG
+/// (`screenshot_recompress`). The file on disk is never changed, and nothing is
+/// re-encoded unless one of those applies. If the image can't be decoded the
+/// original goes out, except when blurring: that is an error, since the
+/// original would show what was meant to be hidden.
+fn read_screenshot(cfg: &Config, sc: &Path) -> Result<ScreenshotFile> {
+    let bytes = fs::read(sc).with_context(|| format!("reading {}", sc.display()))?;
+    let file_name = sc.file_name().and_then(|s| s.to_str()).unwrap_or("screenshot.jpg").to_string();
+    let original = ScreenshotFile { file_name, bytes, content_type: screenshot_mime(sc) };
+    let blur = cfg.blur_chat_region && !cfg.blur_regions.is_empty();
+    // Only the header is read here, so screenshots within the limit stay byte-identical.
+    let oversized = cfg.screenshot_max_dimension > 0
+        && image::ImageReader::new(std::io::Cursor::new(&original.bytes))
+            .with_guessed_format()
+            .ok()
+            .and_then(|r| r.into_dimensions().ok())
+            .map(|(w, h)| w.max(h) > cfg.screenshot_max_dimension)
+            .unwrap_or(false);
+    let tga = is_tga(sc);
+    if !blur && !cfg.screenshot_recompress && !oversized && !tga {
+        return Ok(original);
+    }
+    let cache = if cfg.screenshot_cache_max_mb > 0 {
+        shotcache::shared().map_err(|e| eprintln!("[warn] screenshot cache unavailable: {e:#}")).ok()
+    } else {
+        None
+    };
+    let cache_params = screenshot_cache_params(cfg, blur);
+    let cache_key = shotcache::key(&format!("{:x}", Sha256::digest(&original.bytes)), &cache_params);
+    if let Some((bytes, ext)) = cache.and_then(|c| c.get(&cache_key)) {
+        return Ok(cached_screenshot(&original.file_name, bytes, &ext));
+    }
+    match reencode_screenshot(cfg, &original, blur) {
+        Ok(file) => {
+            if let Some(c) = cache {
+                let ext = if file.content_type == "image/png" { "png" } else { "jpg" };
+                if let Err(e) = c.insert(&cache_key, &cache_params, ext, &file.bytes, cfg.screenshot_cache_max_mb * 1024 * 1024) {
+                    eprintln!("[warn] could not cache the processed {} ({e:#})", sc.display());
+                }
+            }
+            Ok(file)
+        }
+        Err(e) if tga => Err(e.context(format!("converting {}", sc.display()))),
+        Err(e) if !blur => {
+            eprintln!("[warn] could not re-encode {} ({e:#}); sending the original", sc.display());
+            Ok(original)
+        }
+        Err(e) => Err(e.context(format!("blurring {}", sc.display()))),
+    }
+}
+
+/// What the re-encoded bytes depend on besides the source file, so a cached
+/// copy made with other settings is never used.
+fn screenshot_cache_params(cfg: &Config, blur: bool) -> String {
+    let regions = blur.then(|| serde_json::to_string(&cfg.blur_regions).unwrap_or_default());
+    shotcache::params(cfg.screenshot_max_dimension, cfg.screenshot_quality.clamp(1, 100), cfg.screenshot_recompress, regions.as_deref())
+}
+
+/// A cached artifact named the way `reencode_screenshot` would have named it.
+fn cached_screenshot(original_name: &str, bytes: Vec<u8>, ext: &str) -> ScreenshotFile {
+    let stem = Path::new(original_name).file_stem().and_then(|s| s.to_str()).unwrap_or("screenshot");
+    if ext == "png" {
+        return ScreenshotFile { file_name: format!("{}.png", stem), bytes, content_type: "image/png" };
+    }
+    ScreenshotFile { file_name: format!("{}.jpg", stem), bytes, content_type: "image/jpeg" }
+}
+
+fn reencode_screenshot(cfg: &Config, original: &ScreenshotFile, blur: bool) -> Result<ScreenshotFile> {
+    // TGA has no signature to sniff; the extension is all there is.
+    let format = match image::ImageFormat::from_path(&original.file_name) {
+        Ok(image::ImageFormat::Tga) => image::ImageFormat::Tga,
+        _ => image::guess_format(&original.bytes)?,
+    };
+    let mut img = image::load_from_memory_with_format(&original.bytes, format)?.to_rgb8();
+    let (w, h) = img.dimensions();
+    let max = cfg.screenshot_max_dimension;
+    if max > 0 && w.max(h) > max {
+        let scale = max as f64 / w.max(h) as f64;
+        let (nw, nh) = (((w as f64 * scale).round() as u32).max(1), ((h as f64 * scale).round() as u32).max(1));
+        img = image::imageops::resize(&img, nw, nh, image::imageops::FilterType::CatmullRom);
+    }
+    if blur {
+        for region in &cfg.blur_regions {
+            let rect = region.to_pixels(img.width(), img.height());
+            pixelate(&mut img, rect);
+        }
+    }
+    let mut out = std::io::Cursor::new(Vec::new());
+    let stem = Path::new(&original.file_name).file_stem().and_then(|s| s.to_str()).unwrap_or("screenshot");
+    // Lossless sources stay lossless unless recompression is asked for.
+    if matches!(format, image::ImageFormat::Png | image::ImageFormat::Tga) && !cfg.screenshot_recompress {
+        img.write_to(&mut out, image::ImageFormat::Png)?;
+        return Ok(ScreenshotFile { file_name: format!("{}.png", stem), bytes: out.into_inner(), content_type: "image/png" });
+    }
+    let quality = cfg.screenshot_quality.clamp(1, 100);
+    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut out, quality).encode_image(&img)?;
+    Ok(ScreenshotFile { file_name: format!("{}.jpg", stem), bytes: out.into_inner(), content_type: "image/jpeg" })
+}
+
+/// A JPEG of the screenshot as it is sent, its longer side at most
+/// `thumbnail_max_dimension`.
+fn make_thumbnail(cfg: &Config, shot: &ScreenshotFile) -> Result<ScreenshotFile> {
+    let img = image::load_from_memory(&shot.bytes)?;
+    let max = cfg.thumbnail_max_dimension.max(1);
+    let thumb = if img.width().max(img.height()) > max { img.thumbnail(max, max) } else { img }.to_rgb8();
+    let mut out = std::io::Cursor::new(Vec::new());
+    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut out, cfg.screenshot_quality.clamp(1, 100)).encode_image(&thumb)?;
+    let stem = Path::new(&shot.file_name).file_stem().and_then(|s| s.to_str()).unwrap_or("screenshot");
+    Ok(ScreenshotFile { file_name: format!("{}_thumb.jpg", stem), bytes: out.into_inner(), content_type: "image/jpeg" })
+}
+
+// ---------- Screenshot hash index ----------
+
+/// Content hashes of files in the Screenshots folder. Files already there at
+/// startup are hashed a few at a time so a large folder never gets hashed in one
+/// go; new ones are added as the watcher reports them. Persisted so the build
+/// resumes where it left off after a restart.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct ScreenshotIndex {
+    /// Keyed by full path
+    entries: BTreeMap<String, IndexedShot>,
+    /// Files from the startup listing not looked at yet
+    #[serde(skip)]
+    todo: VecDeque<PathBuf>,
+    /// Changed since the last save
+    #[serde(skip)]
+    dirty: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct IndexedShot {
+    size: u64,
+    mtime: i64,
+    sha256: String,
+}
+
+fn file_size_mtime(path: &Path) -> Option<(u64, i64)> {
+    let meta = path.metadata().ok()?;
+    let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64;
+    Some((meta.len(), mtime))
+}
+
+fn file_sha256(path: &Path) -> Result<String> {
+    let mut f = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut f, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn list_screenshots(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .map(|rd| rd.filter_map(|e| e.ok()).map(|e| e.path()).filter(|p| is_screenshot_file(p)).collect())
+        .unwrap_or_default();
+    files.sort();
+    files
+}
+
+impl ScreenshotIndex {
+    fn load() -> Self {
+        screenshot_index_path()
+            .and_then(|p| Ok(serde_json::from_str(&fs::read_to_string(p)?)?))
+            .unwrap_or_default()
+    }
+
+    fn save(&self) -> Result<()> {
+        fs::create_dir_all(config_dir()?)?;
+        write_atomic(&screenshot_index_path()?, serde_json::to_string(self)?.as_bytes())
+    }
+
+    /// True when the entry for `path` matches the file's current size and mtime.
+    fn is_fresh(&self, path: &Path, size: u64, mtime: i64) -> bool {
+        self.entries
+            .get(path.to_string_lossy().as_ref())
+            .map(|e| e.size == size && e.mtime == mtime)
+            .unwrap_or(false)
+    }
+
+    /// List `dir` once, forget entries whose files are gone and queue the rest
+    /// for `build_step`. After that the watcher keeps the index current.
+    fn queue_build(&mut self, dir: &Path) {
+        let files = list_screenshots(dir);
+        let present: BTreeSet<String> = files.iter().map(|p| p.to_string_lossy().to_string()).collect();
+        let before = self.entries.len();
+        self.entries.retain(|k, _| !Path::new(k).starts_with(dir) || present.contains(k));
+        self.dirty |= before != self.entries.len();
+        self.todo = files.into();
+    }
+
+    /// Hash at most `budget` new or changed files from the startup listing.
+    /// Returns how many files were hashed.
+    fn build_step(&mut self, budget: usize) -> usize {
+        let mut hashed = 0;
+        while hashed < budget {
+            let Some(path) = self.todo.pop_front() else { break };
+            let Some((size, mtime)) = file_size_mtime(&path) else { continue };
+            if self.is_fresh(&path, size, mtime) {
+                continue;
+            }
+            self.insert(&path, size, mtime);
+            hashed += 1;
+        }
+        self.flush();
+        hashed
+    }
+
+    fn insert(&mut self, path: &Path, size: u64, mtime: i64) -> Option<String> {
+        match file_sha256(path) {
+            Ok(sha256) => {
+                self.entries.insert(path.to_string_lossy().to_string(), IndexedShot { size, mtime, sha256: sha256.clone() });
+                self.dirty = true;
+                Some(sha256)
+            }
+            Err(e) => {
+                eprintln!("[index] could not hash {}: {e:#}", path.display());
+                None
+            }
+        }
+    }
+
+    /// Index a file the watcher reported as created or changed.
+    fn note_file(&mut self, path: &Path) {
+        if let Some((size, mtime)) = file_size_mtime(path) {
+            if !self.is_fresh(path, size, mtime) {
+                self.insert(path, size, mtime);
+            }
+        }
+    }
+
+    /// Drop a file the watcher reported as removed.
+    fn forget(&mut self, path: &Path) {
+        self.dirty |= self.entries.remove(path.to_string_lossy().as_ref()).is_some();
+    }
+
+    /// The file's content hash: from the index when its entry is current,
+    /// otherwise hashed now (the index may still be building).
+    fn hash_of(&self, path: &Path) -> Option<String> {
+        let (size, mtime) = file_size_mtime(path)?;
+        match self.entries.get(path.to_string_lossy().as_ref()) {
+            Some(e) if e.size == size && e.mtime == mtime => Some(e.sha256.clone()),
+            _ => file_sha256(path).ok(),
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.dirty && self.save().is_ok() {
+            self.dirty = false;
+        }
+    }
+
+    /// (indexed and up to date, total screenshots on disk)
+    fn progress(&self, dir: &Path) -> (usize, usize) {
+        let files = list_screenshots(dir);
+        let fresh = files
+            .iter()
+            .filter(|p| file_size_mtime(p).map(|(s, m)| self.is_fresh(p, s, m)).unwrap_or(false))
+            .count();
+        (fresh, files.len())
+    }
+}
+
+/// Runs the agent with the process arguments; the binary's whole `main`.
+#[doc(hidden)]
+pub async fn run() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => {}
+        Some("--force-downgrade-state") if args.len() == 1 => {}
+        Some("doctor") => return doctor(),
+        Some("status") => return status(),
+        Some("stats") => return stats(&args[1..]),
+        Some("debug") => return debug_command(&args[1..]),
+        Some("keygen") => return keygen_command(),
+        Some("apidoc") => return apidoc_command(&args[1..]),
+        Some("config") => return config_command(&args[1..]),
+        Some("preview") => return preview_command(args.iter().any(|a| a == "--full")),
+        Some("telemetry") => return telemetry_command(&args[1..]),
+        Some("annotate") => return annotate_command(&args[1..]).await,
+        Some("replay") => return replay_command(&args[1..]),
+        Some("parse") => return parse_command(&args[1..]),
+        Some(other) => return Err(anyhow!("Unknown command: {}", other)),
+    }
+
+    // One resident agent at a time; a second instance would race the first on state.json.
+    let locks = ProcessLock::acquire_for("run", Duration::ZERO)
+        .context("the agent is already running; stop it first or use the read-only commands (status, doctor)")?;
+
+    // Load or create config
+    let cfg_path = config_path()?;
+    if !cfg_path.exists() {
+        first_run_wizard().await?;
+    }
+    let mut agent = AgentBuilder::new()
+        .config_file(&cfg_path)
+        .force_downgrade_state(args.iter().any(|a| a == "--force-downgrade-state"))
+        .interactive(locks)
+        .build()?;
+
+    // Offer to toggle startup
+    let cfg = &mut agent.cfg;
+    let want_toggle = Confirm::new()
+        .with_prompt(format!(
+            "Start with Windows is currently {}. Change it?",
+            if cfg.start_with_windows { "ENABLED" } else { "DISABLED" }
+        ))
+        .default(false)
+        .interact()
+        .unwrap_or(false);
+
+    if want_toggle {
+        let enable = Confirm::new()
+            .with_prompt("Enable start with Windows?")
+            .default(cfg.start_with_windows)
+            .interact()
+            .unwrap_or(cfg.start_with_windows);
+        set_startup(enable, cfg.event_log)?;
+        cfg.start_with_windows = enable;
+        fs::write(cfg_path, toml::to_string_pretty(&cfg)?)?;
+    }
+
+    install_shutdown_handler(agent.cfg.shutdown_grace_secs);
+    agent.start()?;
+    while agent.is_running() {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    agent.shutdown()
+}
+
+/// The resident agent: watch the game folders and upload until a shutdown is
+/// requested. Runs on the thread `AgentHandle::start` spawns.
+async fn watch(cfg: Config, force_downgrade_state: bool, interactive: bool) -> Result<()> {
+    let wow = WowPaths::from_config(&cfg);
+    let http = Http::new(&cfg)?;
+    if cfg.tls_accept_invalid_certs {
+        let msg = "TLS CERTIFICATE CHECKS ARE OFF for upload servers (tls_accept_invalid_certs = true)";
+        eprintln!("[warn] !!! {} !!!", msg);
+        eventlog::report(Level::Warning, EventClass::Config, msg);
+    }
+
+    // Install/update addon
+    if cfg.update_addon_on_start {
+        if let Err(e) = install_or_update_addon(&http, &wow).await {
+            eprintln!("[warn] addon update failed: {e:#}");
+            eventlog::report(Level::Warning, EventClass::Lifecycle, &format!("addon update failed: {e:#}"));
+        }
+    } else {
+        // still ensure folder exists
+        fs::create_dir_all(wow.addons_dir().join("DeathLogger")).ok();
+    }
+    if interactive {
+        offer_legacy_migration(&wow);
+    }
+
+    // WTF and Screenshots belong to the game: never create them, watch them once they appear.
+    let mut pre_first_launch = wow.is_pre_first_launch();
+    if pre_first_launch {
+        println!("[info] Waiting for first game launch. {}", PRE_FIRST_LAUNCH_HINT);
+    } else {
+        print_monitoring_summary(&wow);
+    }
+
+    // Start file watchers
+    let (tx, rx) = std::sync::mpsc::channel::<notify::Result<Event>>();
+    let mut watcher = new_watcher(tx.clone())?;
+    let mut watches = GameWatches::default();
+    watches.attach(&mut watcher, &wow);
+
+    // Load persisted state
+    let mut state = load_state_for_write(force_downgrade_state)?;
+    // The gap since the last write seen includes the time the agent was stopped.
+    state.metrics.last_sv_activity = None;
+    let moved = migrate_account_keys(&mut state, &wow);
+    if moved > 0 {
+        println!("[state] Moved {} per-character entries to account-qualified keys", moved);
+        save_state(&state)?;
+    }
+    state.shot_index = ScreenshotIndex::load();
+    state.shot_index.queue_build(&wow.screenshots_dir());
+    if expire_pending_screens(&cfg, &mut state, last_sv_write(&wow), Utc::now().timestamp()) > 0 {
+        save_state(&state)?;
+    }
+    let confirm = |n: usize| {
+        if cfg.refilter_on_change {
+            return true;
+        }
+        if !interactive {
+            println!("[filter] Set refilter_on_change = true to upload them");
+            return false;
+        }
+        Confirm::new().with_prompt(format!("Upload the {} death(s) now?", n)).default(false).interact().unwrap_or(false)
+    };
+    if let Err(e) = refilter_journal(&cfg, &http, &wow, &mut state, confirm).await {
+        eprintln!("[warn] checking filtered deaths against the new filters failed: {e:#}");
+    }
+
+    println!("[run] Agent is running. Press Ctrl+C to exit.");
+    eventlog::report(Level::Info, EventClass::Lifecycle, &format!("agent {} started", env!("CARGO_PKG_VERSION")));
+    println!("      WoW: {}", wow.branch_root().display());
+    println!("      Architecture: {}", arch_summary());
+    if cfg.upload_mode == UploadMode::Discord {
+        for ep in api_endpoints(&cfg) {
+            println!("      Upload URL: {} ({})", ep.url, ep.name);
+        }
+    } else {
+        print_connectivity(&cfg, &http).await;
+    }
+    if !cfg.uploads_enabled {
+        println!("      Uploads are DISABLED (uploads_enabled = false); run `deathlogger-agent preview` to review and enable.");
+    }
+
+    let mut trace = TraceRecorder::new(cfg.event_trace_max);
+    let mut heartbeat = Heartbeat::new();
+    let mut deferred = DeferredParses::default();
+
+    // Main loop: also do a periodic poll to catch writes some drivers miss
+    let grace = Duration::from_secs(cfg.shutdown_grace_secs);
+    let mut abandoned = false;
+    let mut last_poll = SystemTime::now();
+    let handoff = handoff_path().ok();
+    if let Some(h) = handoff.as_deref().and_then(|p| take_handoff(p, cfg.handoff_max_age_secs)) {
+        h.resume(&mut deferred, &mut last_poll, &mut heartbeat, &http);
+    }
+    while !shutdown_requested() {
+        // Non-blocking check for events (with small timeout)
+        let ev = rx.recv_timeout(Duration::from_millis(500));
+        let step = async {
+            for shot in settle_screenshot_writes(&mut state, std::time::Instant::now()) {
+                attach_late_screenshot(&cfg, &http, &mut state, Path::new(&shot)).await;
+            }
+            match ev {
+                Ok(Err(e)) => {
+                    // The OS watch is broken (overflowed queue, folder gone with its drive).
+                    // Start over with a fresh watcher; the next poll catches what was missed.
+                    eprintln!("[warn] file watcher failed: {e}; restarting it");
+                    eventlog::report(Level::Warning, EventClass::Watch, &format!("file watcher failed: {e}"));
+                    match new_watcher(tx.clone()) {
+                        Ok(w) => {
+                            watcher = w;
+                            watches = GameWatches::default();
+                            watches.attach(&mut watcher, &wow);
+                            state.metrics.today().watcher_restarts += 1;
+                        }
+                        Err(e) => eprintln!("[warn] could not restart the file watcher: {e}"),
+                    }
+                }
+                Ok(Ok(event)) => {
+                    match event.kind {
+                        EventKind::Create(_) | EventKind::Modify(_) => {
+                            for p in event.paths {
+                                if wow.is_sv_file(&p) {
+                                    state.metrics.record_sv_activity(Utc::now().timestamp());
+                                    if let Some(trace) = &mut trace {
+                                        trace.sv_change(&wow, &p);
+                                    }
+                                    if let Err(e) = handle_sv_change_or_defer(&cfg, &http, &wow, &mut state, &mut deferred, &p).await {
+                                        eprintln!("[error] SV handle: {e:#}");
+                                        eventlog::report(Level::Error, EventClass::Parse, &format!("{}: {e:#}", p.display()));
+                                    }
+                                } else if is_screenshot_file(&p) {
+                                    if let Some(trace) = &mut trace {
+                                        let path = redact_trace_path(&wow, &p);
+                                        trace.record(TraceEvent::Screenshot { at: Utc::now().timestamp(), path, ts: screenshot_ts(&p) });
+                                    }
+                                    if let Err(e) = handle_screenshot_created(&wow, &mut state, &p) {
+                                        eprintln!("[error] shot handle: {e:#}");
+                                    }
+                                }
+                            }
+                        }
+                        EventKind::Remove(_) => {
+                            for p in event.paths.iter().filter(|p| p.starts_with(wow.screenshots_dir())) {
+                                state.shot_index.forget(p);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                Err(_timeout) => {
+                    record_blocked(&http, &mut state);
+                    probe_offline(&cfg, &http, &mut state).await;
+                    heartbeat.tick(&cfg, &http, &wow, &state).await;
+                    deferred.retry_due(&cfg, &http, &wow, &mut state).await;
+                    let now = Utc::now().timestamp();
+                    if state.retry_queue.iter().any(|r| r.next_at <= now) {
+                        retry_due(&cfg, &http, &mut state).await;
+                    }
+                    if !state.surges.is_empty() {
+                        flush_surges(&cfg, &http, &mut state).await;
+                    }
+                    // periodic poll every 10s to match lingering screenshots with new SV writes
+                    if last_poll.elapsed().unwrap_or(Duration::ZERO) > POLL_INTERVAL {
+                        last_poll = SystemTime::now();
+                        watches.attach(&mut watcher, &wow);
+                        if pre_first_launch && !wow.is_pre_first_launch() {
+                            pre_first_launch = false;
+                            println!("[info] Game folders appeared.");
+                            print_monitoring_summary(&wow);
+                        }
+                        maybe_send_telemetry(&cfg, &http, &wow, &mut state).await;
+                        if let Some(trace) = &mut trace {
+                            trace.record(TraceEvent::Poll { at: Utc::now().timestamp() });
+                        }
+                        if let Err(e) = periodic_poll(&cfg, &http, &wow, &mut state, &mut deferred).await {
+                            eprintln!("[warn] poll failed: {e:#}");
+                            eventlog::report(Level::Warning, EventClass::Watch, &format!("poll failed: {e:#}"));
+                        }
+                        // Only reached when the event queue is idle and no upload is running,
+                        // so index building never competes with real work.
+                        if cfg.screenshot_index_batch > 0 {
+                            state.shot_index.build_step(cfg.screenshot_index_batch);
+                        }
+                        state.shot_index.flush();
+                    }
+                }
+            }
+        };
+        let finished = within_grace(grace, step).await.is_some();
+        flush_events();
+        if !finished {
+            abandoned = true;
+            break;
+        }
+    }
+    record_blocked(&http, &mut state);
+    if let Some(path) = handoff.filter(|_| cfg.handoff_max_age_secs > 0) {
+        if let Err(e) = write_handoff(&path, &Handoff::capture(&deferred, last_poll, &heartbeat, &http)) {
+            eprintln!("[warn] could not write {}: {e:#}", path.display());
+        }
+    }
+    finish_shutdown(&cfg, &state, abandoned);
+    Ok(())
+}
+
+fn new_watcher(tx: std::sync::mpsc::Sender<notify::Result<Event>>) -> Result<RecommendedWatcher> {
+    Ok(RecommendedWatcher::new(
+        move |res| {
+            let _ = tx.send(res);
+        },
+        NotifyConfig::default(),
+    )?)
+}
+
+fn print_monitoring_summary(wow: &WowPaths) {
+    let sv_files = account_sv_paths(wow);
+    if sv_files.is_empty() {
+        println!("[info] No SavedVariables found yet. The file appears after running the game once with the addon loaded.");
+    } else {
+        let addons: Vec<&str> = wow.adapters.iter().map(|a| a.name()).collect();
+        println!("[watch] Monitoring {} SavedVariables file(s) ({})", sv_files.len(), addons.join(", "));
+    }
+    for sv in &sv_files {
+        let Ok(snapshot) = wow.parse_sv(sv, None) else { continue };
+        if let (Some(owner), Some(settings)) = (settings_owner(sv, &snapshot), &snapshot.settings) {
+            if settings.auto_screenshot_off() {
+                println!("[info] auto-screenshot: OFF for {} — pairing will rely on manual screenshots", owner);
+            }
+        }
+    }
+}
+
+async fn handle_sv_change(cfg: &Config, http: &Http, wow: &WowPaths, state: &mut State, sv_file: &Path) -> Result<()> {
+    if !sv_file.exists() {
+        return Ok(());
+    }
+    // Taken before parsing: a write during the parse changes it again.
+    let meta = sv_file.metadata().ok().and_then(|m| Some((m.len(), m.modified().ok()?)));
+    if !cfg.reparse_unchanged_sv && meta.is_some() && state.sv_seen.get(sv_file) == meta.as_ref() {
+        return Ok(());
+    }
+    let account = account_from_sv_path(sv_file);
+    let path = sv_file.to_string_lossy().to_string();
+    let marks = SvMarks { uploaded: account_marks(state, account.as_deref()), cursor: state.sv_cursors.get(&path).cloned() };
+    let started = std::time::Instant::now();
+    let mut snapshot = wow.parse_sv(sv_file, Some(&marks))
+        .map_err(|e| SvParseError { path: sv_file.display().to_string(), reason: format!("{e:#}") })?;
+    println!(
+        "[parse] {}: {} of {} entries read in {} ms",
+        sv_file.display(),
+        snapshot.scanned,
+        snapshot.count,
+        started.elapsed().as_millis()
+    );
+    let cursor = snapshot.cursor.take();
+    let main_error = snapshot.main_error.take();
+    let mut ready = ready_deaths(state, sv_file, &mut snapshot);
+    // A failed milestone is only tried again on the next parse.
+    let settled = check_milestones(cfg, http, wow, state, account.as_deref(), &snapshot.levels).await;
+    match meta {
+        Some(meta) if settled && main_error.is_none() => state.sv_seen.insert(sv_file.to_path_buf(), meta),
+        _ => state.sv_seen.remove(sv_file),
+    };
+    if backfill_applies(cfg) && ready.len() > 1 {
+        let newest = ready.split_off(ready.len() - 1);
+        let earlier = backfill_candidates(cfg, state, std::mem::replace(&mut ready, newest))?;
+        backfill(cfg, http, state, earlier).await;
+    }
+
+    if cfg.batch_uploads
+        && ready.len() > 1
+        && cfg.encrypt_to_public_key.is_empty()
+        && cfg.upload_format == UploadFormat::Multipart
+        && cfg.upload_mode != UploadMode::Discord
+    {
+        process_deaths_batched(cfg, http, state, ready).await?;
+    } else {
+        let times: Vec<i64> = ready.iter().map(|d| d.at).collect();
+        for (i, death) in ready.into_iter().enumerate() {
+            process_death(cfg, http, state, death, &times[i + 1..]).await?;
+        }
+    }
+    // The deaths in the backup are handled; the file itself still has to parse
+    // once WoW writes it again, so it stays deferred.
+    if let Some(reason) = main_error {
+        return Err(SvParseError { path: sv_file.display().to_string(), reason }.into());
+    }
+    // Every entry so far was sent, queued, held or skipped for good; with uploads
+    // off they are still owed, so the next parse has to read them again.
+    let cursor = cursor.filter(|_| cfg.uploads_enabled);
+    if state.sv_cursors.get(&path) != cursor.as_ref() {
+        match cursor {
+            Some(cursor) => state.sv_cursors.insert(path, cursor),
+            None => state.sv_cursors.remove(&path),
+        };
+        save_state(state).ok();
+    }
+    Ok(())
+}
+
+/// Deaths from a parsed SV file that are ready to stage, oldest first: every
+/// entry not uploaded yet plus any quarantined ones the new context resolves.
+/// Network-free, so `replay` runs it too.
+fn ready_deaths(state: &mut State, sv_file: &Path, snapshot: &mut SvSnapshot) -> Vec<DeathPayload> {
+    note_table_reset(state, sv_file, snapshot);
+    note_undated_entries(state, sv_file, snapshot);
+
+    // Quarantined deaths from this file may be resolvable with the new context.
+    let path = sv_file.to_string_lossy().to_string();
+    let account = account_from_sv_path(sv_file);
+    let mut ready = vec![];
+    let held = state.quarantine.len();
+    state.quarantine.retain(|q| {
+        let mut d = q.death.clone();
+        d.account = account.clone();
+        if q.sv_path == path && resolve_identity(&mut d, sv_file, snapshot.recent_identity.as_ref()) {
+            println!("[quarantine] Resolved identity {} for death at {}", to_key(&d.player, &d.realm), format_epoch(d.at));
+            ready.push(d);
+            false
+        } else {
+            true
+        }
+    });
+    if state.quarantine.len() != held {
+        save_state(state).ok();
+    }
+
+    note_addon_settings(state, sv_file, snapshot);
+    note_addon_version(state, sv_file, snapshot);
+
+    let history = std::mem::take(&mut snapshot.history);
+    for mut death in history.into_iter().chain(snapshot.latest.take()) {
+        death.addon = snapshot.addon_info();
+        death.account = account.clone();
+        if resolve_identity(&mut death, sv_file, snapshot.recent_identity.as_ref()) {
+            // A quarantined death still in the file was just resolved above; keep one copy.
+            ready.retain(|r| r.at != death.at || r.key() != death.key());
+            ready.push(death);
+        } else {
+            quarantine_death(state, sv_file, death);
+        }
+    }
+
+    let mut skipped = BTreeSet::new();
+    ready.retain(|death| match death_violation(death) {
+        Some(violation) => {
+            skipped.insert(format!("entry {} ({} at {}): {}", death.entry.as_deref().unwrap_or("?"), death.key(), death.at, violation));
+            false
+        }
+        None => true,
+    });
+    note_skipped_entries(state, sv_file, skipped);
+
+    ready.sort_by_key(|d| d.at);
+    ready
+}
+
+/// Why `death` is too malformed to send, once its identity is resolved. None
+/// when it passes.
+fn death_violation(death: &DeathPayload) -> Option<String> {
+    if death.at <= 0 {
+        return Some(format!("at = {} is not a time", death.at));
+    }
+    if death.player.trim().is_empty() || death.realm.trim().is_empty() {
+        return Some("player or realm is empty".into());
+    }
+    if let Some(level) = death.level.filter(|l| !(1..=100).contains(l)) {
+        return Some(format!("level {level} is outside 1-100"));
+    }
+    let money = [
+        ("moneyCopper", death.money_copper),
+        ("moneyGold", death.money_gold),
+        ("moneySilver", death.money_silver),
+        ("moneyCopperOnly", death.money_copper_only),
+    ];
+    if let Some((field, Some(n))) = money.into_iter().find(|(_, n)| n.is_some_and(|n| n < 0)) {
+        return Some(format!("{field} = {n} is negative"));
+    }
+    if let Killer::Raw(v) = &death.killer {
+        if !v.is_null() && !v.is_object() {
+            return Some(format!("killer is {v}, not a table"));
+        }
+    }
+    None
+}
+
+/// Report entries `ready_deaths` skipped as malformed, each once while it stays
+/// in the file, and count them for `status` and the reliability summary.
+fn note_skipped_entries(state: &mut State, sv_file: &Path, skipped: BTreeSet<String>) {
+    let known = state.skipped_entries.get(sv_file);
+    let new: Vec<&String> = skipped.iter().filter(|s| !known.is_some_and(|k| k.contains(*s))).collect();
+    for s in &new {
+        let msg = format!("{}: {}", sv_file.display(), s);
+        println!("[skip] {msg}");
+        eventlog::report(Level::Warning, EventClass::Parse, &msg);
+    }
+    if !new.is_empty() {
+        state.metrics.today().entries_skipped += new.len() as u64;
+    }
+    if known.map_or(skipped.is_empty(), |k| *k == skipped) {
+        return;
+    }
+    if skipped.is_empty() {
+        state.skipped_entries.remove(sv_file);
+    } else {
+        state.skipped_entries.insert(sv_file.to_path_buf(), skipped);
+    }
+    save_state(state).ok();
+}
+
+/// A death that passed every check and is ready to send.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StagedDeath {
+    key: String,
+    idempotency_key: String,
+    death: DeathPayload,
+    /// Entry hash of the death as parsed, remembered once it settles
+    #[serde(default)]
+    hash: String,
+    /// Nearest screenshot in the pairing window
+    screenshot: Option<String>,
+    /// The others, nearest first (`attach_all_screenshots_in_window`)
+    #[serde(default)]
+    extra_screenshots: Vec<String>,
+    bag_base: Option<BagBase>,
+}
+
+impl StagedDeath {
+    fn screenshots(&self) -> Vec<String> {
+        self.screenshot.iter().chain(&self.extra_screenshots).cloned().collect()
+    }
+
+    fn screenshot_paths(&self) -> Vec<&Path> {
+        self.screenshot.iter().chain(&self.extra_screenshots).map(Path::new).collect()
+    }
+}
+
+/// A death later than the watermark is new. One at or before it is only new when
+/// its hash hasn't been seen and it is newer than what the hashes cover: a second
+/// death in the same second, or one recorded while the clock was behind.
+fn is_new_death(state: &State, key: &str, at: i64, hash: &str) -> bool {
+    if at > state.last_uploaded.get(key).copied().unwrap_or(0) {
+        return true;
+    }
+    state.seen_deaths.get(key).is_some_and(|seen| at > seen.since && !seen.recent.iter().any(|(_, h)| h == hash))
+}
+
+/// Note a death as handled: it was sent, queued or held.
+fn remember_death(state: &mut State, key: &str, at: i64, hash: String) {
+    let mark = state.last_uploaded.get(key).copied().unwrap_or(0);
+    let seen = state.seen_deaths.entry(key.to_string()).or_insert_with(|| SeenDeaths { since: mark, ..SeenDeaths::default() });
+    if seen.recent.iter().any(|(a, h)| *a == at && *h == hash) {
+        return;
+    }
+    seen.recent.push_back((at, hash));
+    while seen.recent.len() > MAX_SEEN_DEATHS {
+        if let Some((evicted, _)) = seen.recent.pop_front() {
+            seen.since = seen.since.max(evicted);
+        }
+    }
+}
+
+/// Apply the payload pipeline and every gate to a parsed death. None when there
+/// is nothing to send (already uploaded, uploads disabled, or owned by the retry queue).
+/// `later` are the times of deaths staged after it from the same parse.
+/// The death and its screenshots are only marked as handled once it settles.
+fn stage_death(cfg: &Config, state: &mut State, mut latest: DeathPayload, later: &[i64]) -> Result<Option<StagedDeath>> {
+    let hash = entry_hash(&latest);
+    prepare_payload(cfg, &mut latest)?;
+
+    let key = latest.key();
+    if !is_new_death(state, &key, latest.at, &hash) {
+        // nothing new
+        return Ok(None);
+    }
+    state.metrics.record_detected(&key, latest.at);
+    emit(AgentEvent::DeathDetected { character: key.clone(), death: Box::new(latest.clone()) });
+    stage_unsent(cfg, state, latest, hash, later)
+}
+
+/// `stage_death` for a death known to be unsent, prepared already: also for
+/// deaths the watermark passed while a filter kept them back.
+fn stage_unsent(cfg: &Config, state: &mut State, mut latest: DeathPayload, hash: String, later: &[i64]) -> Result<Option<StagedDeath>> {
+    let key = latest.key();
+    // Master switch: every sink is gated here. The death stays pending so it
+    // goes out once uploads are enabled.
+    if !cfg.uploads_enabled {
+        return Ok(None);
+    }
+    // Already failed once; the retry queue owns it now.
+    if state.retry_queue.iter().any(|r| r.key() == key && r.death.at == latest.at) {
+        return Ok(None);
+    }
+    // Held in a surge group; it goes out with the group.
+    if state.surges.get(&key).is_some_and(|s| s.held.iter().any(|h| h.death.at == latest.at)) {
+        return Ok(None);
+    }
+    if let Some(rule) = cfg.filters.excludes(&latest) {
+        skip_filtered(cfg, state, &latest, hash, rule);
+        return Ok(None);
+    }
+    if !latest.killer_issues.is_empty() {
+        let msg = format!("killer of {} at {}: {}", key, latest.at, latest.killer_issues.join("; "));
+        println!("[warn] {msg}");
+        eventlog::report(Level::Warning, EventClass::Parse, &msg);
+    }
+    if let Some(mismatch) = money_mismatch(&latest) {
+        let msg = format!("money of {} at {}: {}", key, latest.at, mismatch);
+        println!("[warn] {msg}");
+        eventlog::report(Level::Warning, EventClass::Parse, &msg);
+    }
+
+    let bag_base = apply_bags_mode(cfg, state, &key, &mut latest);
+
+    // Find the nearest screenshot(s) within the window. They stay pending until
+    // the death settles, claimed so no other death pairs with them meanwhile.
+    let near = find_screenshots(cfg, state, latest.at, later);
+    state.claimed_screens.extend(near.iter().map(|n| n.path.clone()));
+    for n in &near {
+        emit(AgentEvent::ScreenshotPaired { character: key.clone(), at: latest.at, screenshot: PathBuf::from(&n.path) });
+    }
+    if !near.is_empty() && cfg.blur_chat_region && !cfg.blur_regions.is_empty() {
+        latest.screenshot_redacted = Some(true);
+        latest.screenshot_redacted_regions = Some(cfg.blur_regions.clone());
+    }
+
+    println!(
+        "[upload] {} new death for {} at {} (screenshot: {})",
+        latest.class.clone().unwrap_or_default(),
+        key,
+        format_epoch(latest.at),
+        match near.len() {
+            0 => "no".to_string(),
+            1 => "yes".to_string(),
+            n => format!("{} attached", n),
+        }
+    );
+    if let Some(note) = take_pending_note(&key, latest.at) {
+        latest.note = Some(note);
+    }
+    let idempotency_key = idempotency_key_for(state, &key, &latest);
+    let mut paths = near.into_iter().map(|n| n.path);
+    let screenshot = paths.next();
+    let extra_screenshots = paths.collect();
+    Ok(Some(StagedDeath { key, idempotency_key, death: latest, hash, screenshot, extra_screenshots, bag_base }))
+}
+
+/// Mark a staged death as handled once it was sent, queued or held, and its
+/// screenshots as spoken for: they went out, or stay with the retry entry or group.
+fn settle_staged(state: &mut State, staged: &StagedDeath) {
+    remember_death(state, &staged.key, staged.death.at, staged.hash.clone());
+    let shots = staged.screenshots();
+    state.pending_screens.retain(|p| !shots.contains(&p.path));
+    for shot in &shots {
+        state.claimed_screens.remove(shot);
+    }
+}
+
+/// Record how one staged death's upload went.
+fn finish_staged(state: &mut State, staged: StagedDeath, results: Vec<(String, Result<Option<UploadReceipt>>)>, started: std::time::Instant) {
+    settle_staged(state, &staged);
+    let screenshots = staged.screenshots();
+    if screenshots.is_empty() {
+        let targets: Vec<String> =
+            results.iter().filter(|(t, r)| r.is_ok() && t != DISCORD_TARGET).map(|(t, _)| t.clone()).collect();
+        await_late_screenshot(state, &staged, targets);
+    }
+    if results.iter().any(|(_, r)| r.is_ok()) {
+        // Without the base on the server a diff is useless: the next death goes out in full.
+        if results.iter().any(|(_, r)| matches!(r, Ok(Some(receipt)) if receipt.bags_base_missing)) {
+            state.bag_bases.remove(&staged.key);
+        } else if let Some(base) = staged.bag_base {
+            state.bag_bases.insert(staged.key.clone(), base);
+        }
+    }
+    record_delivery(state, staged.death, screenshots, results, false, 0, started);
+}
+
+/// Count a death once any target accepted it, and queue the targets that
+/// failed (or record them as rejected). Targets are tracked separately so a
+/// flaky mirror neither holds up nor duplicates uploads to the others.
+fn record_delivery(
+    state: &mut State,
+    death: DeathPayload,
+    screenshots: Vec<String>,
+    results: Vec<(String, Result<Option<UploadReceipt>>)>,
+    previously_delivered: bool,
+    attempts: u32,
+    started: std::time::Instant,
+) {
+    let key = death.key();
+    let accepted: Vec<String> = results.iter().filter(|(_, r)| r.is_ok()).map(|(t, _)| t.clone()).collect();
+    for (target, result) in &results {
+        if let Ok(Some(receipt)) = result {
+            record_receipt(state, &key, death.at, target, receipt);
+        }
+        let event = match result {
+            Ok(_) => AgentEvent::Uploaded { character: key.clone(), at: death.at, target: target.clone() },
+            Err(e) => AgentEvent::UploadFailed {
+                character: key.clone(),
+                at: death.at,
+                target: target.clone(),
+                error: format!("{e:#}"),
+                will_retry: !is_permanent_failure(e),
+            },
+        };
+        emit(event);
+    }
+    let failures: Vec<(String, anyhow::Error)> = results.into_iter().filter_map(|(t, r)| r.err().map(|e| (t, e))).collect();
+    for (target, e) in failures.iter().filter(|(_, e)| e.downcast_ref::<RateLimited>().is_none()) {
+        if let Some(oauth) = e.downcast_ref::<OAuthError>() {
+            eprintln!("[oauth] {}; the death for {} stays queued for {}", oauth, key, target);
+            eventlog::report(Level::Error, EventClass::Config, &oauth.to_string());
+            continue;
+        }
+        eprintln!("[error] upload to {} failed: {e:#}", target);
+        eventlog::report(Level::Error, EventClass::Upload, &format!("upload for {} to {} failed: {e:#}", key, target));
+    }
+    if !accepted.is_empty() && !previously_delivered {
+        record_uploaded(state, &key, death.at, !screenshots.is_empty(), started);
+    }
+    // Those targets are reachable again: stop waiting out their earlier backoffs.
+    let now = Utc::now().timestamp();
+    for r in &mut state.retry_queue {
+        if r.targets.is_empty() || r.targets.iter().any(|t| accepted.contains(t)) {
+            r.next_at = r.next_at.min(now);
+        }
+    }
+    if !failures.is_empty() {
+        let delivered = previously_delivered || !accepted.is_empty();
+        schedule_retry(state, death, screenshots, failures, delivered, attempts);
+    }
+    prune_idempotency_keys(state, &key);
+}
+
+/// Keep what the server returned for a death and show its page, if any.
+fn record_receipt(state: &mut State, key: &str, at: i64, target: &str, receipt: &UploadReceipt) {
+    match (&receipt.url, &receipt.id) {
+        _ if receipt.duplicate => println!("[skip] Server already has this death: {} at {} on {}", key, format_epoch(at), target),
+        (Some(url), _) => println!("[upload] Death for {} at {} on {}: {}", key, format_epoch(at), target, url),
+        (None, Some(id)) => println!("[upload] Death for {} at {} on {} has id {}", key, format_epoch(at), target, id),
+        (None, None) => {}
+    }
+    let records = state.upload_history.entry(death_ref(key, at)).or_default();
+    records.retain(|r| r.target != target);
+    records.push(UploadRecord {
+        target: target.to_string(),
+        id: receipt.id.clone(),
+        url: receipt.url.clone(),
+        uploaded_at: Utc::now().timestamp(),
+    });
+    while state.upload_history.len() > MAX_UPLOAD_HISTORY {
+        let Some(oldest) = state
+            .upload_history
+            .iter()
+            .min_by_key(|(_, r)| r.iter().map(|r| r.uploaded_at).max().unwrap_or(0))
+            .map(|(k, _)| k.clone())
+        else {
+            break;
+        };
+        state.upload_history.remove(&oldest);
+    }
+}
+
+/// Upload one parsed death unless it is already covered by the watermark.
+async fn process_death(cfg: &Config, http: &Http, state: &mut State, latest: DeathPayload, later: &[i64]) -> Result<()> {
+    let Some(staged) = stage_death(cfg, state, latest, later)? else {
+        return Ok(());
+    };
+    let Some(mut staged) = hold_for_surge(cfg, state, staged) else {
+        flush_surges(cfg, http, state).await;
+        save_state(state).ok();
+        return Ok(());
+    };
+    if staged.death.note.is_none() && cfg.annotation_prompt_secs > 0 {
+        staged.death.note = prompt_note(cfg.annotation_prompt_secs);
+    }
+    let started = std::time::Instant::now();
+    let targets = upload_targets(cfg);
+    let results = deliver(cfg, http, &mut state.media_ids, &staged.death, &staged.idempotency_key, &staged.screenshot_paths(), &targets).await;
+    finish_staged(state, staged, results, started);
+    save_state(state).ok();
+    Ok(())
+}
+
+/// Send several deaths in `max_batch_size` requests instead of one request each.
+async fn process_deaths_batched(cfg: &Config, http: &Http, state: &mut State, deaths: Vec<DeathPayload>) -> Result<()> {
+    let mut staged = vec![];
+    let times: Vec<i64> = deaths.iter().map(|d| d.at).collect();
+    for (i, death) in deaths.into_iter().enumerate() {
+        staged.extend(stage_death(cfg, state, death, &times[i + 1..])?.and_then(|s| hold_for_surge(cfg, state, s)));
+    }
+    send_grouped(cfg, http, state, staged, false).await;
+    flush_surges(cfg, http, state).await;
+    save_state(state).ok();
+    Ok(())
+}
+
+/// Send staged deaths in `max_batch_size` groups: one batch request per endpoint
+/// when the server takes batches (one upload each otherwise, and when encrypted,
+/// since batches can't be sealed), and to Discord one message per death or, with
+/// `summarize`, one per group.
+async fn send_grouped(cfg: &Config, http: &Http, state: &mut State, staged: Vec<StagedDeath>, summarize: bool) {
+    let mut rest = staged.into_iter().peekable();
+    while rest.peek().is_some() {
+        let chunk: Vec<StagedDeath> = rest.by_ref().take(cfg.max_batch_size.max(1)).collect();
+        let started = std::time::Instant::now();
+        let mut per_death: Vec<Vec<(String, Result<Option<UploadReceipt>>)>> = chunk.iter().map(|_| vec![]).collect();
+        for target in upload_targets(cfg) {
+            if target == DISCORD_TARGET && summarize {
+                let deaths: Vec<&DeathPayload> = chunk.iter().map(|s| &s.death).collect();
+                let result = post_discord_summary(cfg, http, &deaths).await;
+                for results in per_death.iter_mut() {
+                    results.push((target.clone(), result.as_ref().map(|_| None).map_err(copy_error)));
+                }
+                continue;
+            }
+            if target == DISCORD_TARGET {
+                for (i, staged) in chunk.iter().enumerate() {
+                    let result = post_discord(cfg, http, &staged.death, staged.screenshot.as_deref().map(Path::new)).await;
+                    per_death[i].push((target.clone(), result.map(|_| None)));
+                }
+                continue;
+            }
+            let Some(ep) = api_endpoints(cfg).into_iter().find(|e| e.name == target) else { continue };
+            let ep_cfg = cfg.for_endpoint(&ep);
+            if cfg.batch_uploads
+                && cfg.encrypt_to_public_key.is_empty()
+                && cfg.upload_format == UploadFormat::Multipart
+                && cfg.upload_flow == UploadFlow::Inline
+            {
+                for (i, result) in upload_batch(&ep_cfg, http, &chunk).await.into_iter().enumerate() {
+                    per_death[i].push((target.clone(), result));
+                }
+                continue;
+            }
+            for (i, staged) in chunk.iter().enumerate() {
+                let shots = staged.screenshot_paths();
+                let result = upload(&ep_cfg, http, &mut state.media_ids, &staged.death, &staged.idempotency_key, &shots).await;
+                per_death[i].push((target.clone(), result));
+            }
+        }
+        for (staged, results) in chunk.into_iter().zip(per_death) {
+            finish_staged(state, staged, results, started);
+        }
+    }
+}
+
+// ---------- Death filters ----------
+//
+// `[filters]` keeps some deaths off the servers. A filtered death counts as
+// handled (the watermark moves past it), and its fingerprint, the rule that
+// excluded it and a digest of its payload go to filtered.jsonl. When the agent
+// starts with other filters than the last run, the journal is checked against
+// the new ones: deaths they now let through are read again from their
+// SavedVariables file and uploaded, with `refilter_on_change` or once the user
+// agrees. Tighter filters only apply from then on; nothing already uploaded is
+// withdrawn. Entries leave the journal after `filter_journal_days`.
+
+/// `[filters]`: deaths matching any of these are not uploaded.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct DeathFilters {
+    /// Deaths below this level; 0 lets every level through
+    min_level: i64,
+    /// "Player@Realm", any case
+    skip_characters: Vec<String>,
+    /// Zone names, any case
+    skip_zones: Vec<String>,
+}
+
+impl DeathFilters {
+    /// The rule that excludes `death`, if any.
+    fn excludes(&self, death: &DeathPayload) -> Option<String> {
+        if let Some(level) = death.level.filter(|l| *l < self.min_level) {
+            return Some(format!("min_level = {} (level {})", self.min_level, level));
+        }
+        let character = to_key(&death.player, &death.realm);
+        if let Some(c) = self.skip_characters.iter().find(|c| c.eq_ignore_ascii_case(&character)) {
+            return Some(format!("skip_characters {}", c));
+        }
+        let zone = death.location.as_ref().and_then(|l| l.zone.as_deref()).unwrap_or_default();
+        if let Some(z) = self.skip_zones.iter().find(|z| z.eq_ignore_ascii_case(zone)) {
+            return Some(format!("skip_zones {}", z));
+        }
+        None
+    }
+}
+
+/// Most deaths kept in filtered.jsonl; the oldest go first.
+const MAX_FILTER_JOURNAL: usize = 1000;
+
+/// filtered.jsonl is read and rewritten whole, one writer at a time.
+static FILTER_JOURNAL_LOCK: Mutex<()> = Mutex::new(());
+
+/// One line of filtered.jsonl.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct FilteredDeath {
+    /// Character key, account-qualified
+    character: String,
+    account: Option<String>,
+    at: i64,
+    /// Entry hash of the death as parsed
+    fingerprint: String,
+    rule: String,
+    /// SHA-256 of the payload that would have been sent
+    digest: String,
+    /// Epoch secs
+    filtered_at: i64,
+}
+
+impl FilteredDeath {
+    fn is(&self, character: &str, at: i64, fingerprint: &str) -> bool {
+        self.character == character && self.at == at && self.fingerprint == fingerprint
+    }
+}
+
+/// Entries newer than `days`, oldest first; lines that don't parse are dropped.
+fn read_filter_journal(path: &Path, days: i64) -> Vec<FilteredDeath> {
+    let cutoff = Utc::now().timestamp() - days * 86_400;
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|l| serde_json::from_str::<FilteredDeath>(l).ok())
+        .filter(|e| e.filtered_at > cutoff)
+        .collect()
+}
+
+/// Write the newest `MAX_FILTER_JOURNAL` entries.
+fn write_filter_journal(path: &Path, entries: &[FilteredDeath]) -> Result<()> {
+    let mut text = String::new();
+    for e in &entries[entries.len().saturating_sub(MAX_FILTER_JOURNAL)..] {
+        text.push_str(&serde_json::to_string(e)?);
+        text.push('\n');
+    }
+    write_atomic(path, text.as_bytes())
+}
+
+/// Change the journal under its lock, dropping expired entries on the way.
+fn update_filter_journal(cfg: &Config, change: impl FnOnce(&mut Vec<FilteredDeath>)) -> Result<()> {
+    let _lock = FILTER_JOURNAL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let path = filter_journal_path()?;
+    let mut entries = read_filter_journal(&path, cfg.filter_journal_days);
+    change(&mut entries);
+    write_filter_journal(&path, &entries)
+}
+
+/// Mark a death a filter excluded as handled, and journal it.
+fn skip_filtered(cfg: &Config, state: &mut State, death: &DeathPayload, hash: String, rule: String) {
+    let key = death.key();
+    println!("[filter] Not uploading the death of {} at {}: {}", key, format_epoch(death.at), rule);
+    remember_death(state, &key, death.at, hash.clone());
+    let mark = state.last_uploaded.entry(key.clone()).or_insert(death.at);
+    *mark = (*mark).max(death.at);
+    let entry = FilteredDeath {
+        character: key,
+        account: death.account.clone(),
+        at: death.at,
+        fingerprint: hash,
+        rule,
+        digest: format!("{:x}", Sha256::digest(serde_json::to_vec(death).unwrap_or_default())),
+        filtered_at: Utc::now().timestamp(),
+    };
+    let journaled = update_filter_journal(cfg, |entries| {
+        entries.retain(|e| !e.is(&entry.character, entry.at, &entry.fingerprint));
+        entries.push(entry);
+    });
+    if let Err(e) = journaled {
+        eprintln!("[warn] could not journal the filtered death: {e:#}");
+    }
+    save_state(state).ok();
+}
+
+/// Every death in the account's SavedVariables files, as `ready_deaths` sees them.
+fn account_deaths(wow: &WowPaths, account: Option<&str>) -> Vec<DeathPayload> {
+    let mut deaths = vec![];
+    for sv in account_sv_paths(wow).into_iter().filter(|sv| account_from_sv_path(sv).as_deref() == account) {
+        let Ok(snapshot) = wow.parse_sv(&sv, None) else { continue };
+        for mut death in snapshot.history.iter().cloned().chain(snapshot.latest.clone()) {
+            death.addon = snapshot.addon_info();
+            death.account = account.map(str::to_string);
+            if resolve_identity(&mut death, &sv, snapshot.recent_identity.as_ref()) {
+                deaths.push(death);
+            }
+        }
+    }
+    deaths
+}
+
+/// When the filters differ from the last run's, check the journal against them
+/// and upload the deaths they now let through if `confirm` (given how many)
+/// agrees. Entries whose death isn't in this install's files are left alone.
+async fn refilter_journal(cfg: &Config, http: &Http, wow: &WowPaths, state: &mut State, confirm: impl FnOnce(usize) -> bool) -> Result<()> {
+    let changed = state.applied_filters.as_ref().is_some_and(|f| *f != cfg.filters);
+    if state.applied_filters.as_ref() != Some(&cfg.filters) {
+        state.applied_filters = Some(cfg.filters.clone());
+        save_state(state)?;
+    }
+    let journal = {
+        let _lock = FILTER_JOURNAL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        read_filter_journal(&filter_journal_path()?, cfg.filter_journal_days)
+    };
+    if !changed {
+        return update_filter_journal(cfg, |_| {});
+    }
+    println!("[filter] The filters changed; deaths already uploaded stay on the server");
+
+    let mut by_account: BTreeMap<Option<String>, Vec<DeathPayload>> = BTreeMap::new();
+    let mut passing = vec![];
+    let mut still: Vec<(FilteredDeath, String)> = vec![];
+    for entry in &journal {
+        let deaths = by_account.entry(entry.account.clone()).or_insert_with(|| account_deaths(wow, entry.account.as_deref()));
+        let Some(death) = deaths.iter().find(|d| entry.is(&d.key(), d.at, &entry_hash(d))) else { continue };
+        let mut death = death.clone();
+        prepare_payload(cfg, &mut death)?;
+        match cfg.filters.excludes(&death) {
+            Some(rule) => still.push((entry.clone(), rule)),
+            None => passing.push((entry.clone(), death)),
+        }
+    }
+    for (entry, rule) in still.iter().filter(|(e, rule)| e.rule != *rule) {
+        println!("[filter] {} at {} is still filtered, now by {} (was {})", entry.character, format_epoch(entry.at), rule, entry.rule);
+    }
+    let mut sent: Vec<FilteredDeath> = vec![];
+    if !passing.is_empty() {
+        println!("[filter] {} filtered death(s) pass the new filters", passing.len());
+        if !cfg.uploads_enabled {
+            println!("[filter] Not uploading them: uploads_enabled = false");
+        } else if confirm(passing.len()) {
+            let mut staged = vec![];
+            for (entry, death) in passing {
+                if let Some(s) = stage_unsent(cfg, state, death, entry.fingerprint.clone(), &[])? {
+                    staged.push(s);
+                    sent.push(entry);
+                }
+            }
+            send_grouped(cfg, http, state, staged, false).await;
+            save_state(state).ok();
+        }
+    }
+    update_filter_journal(cfg, |entries| {
+        entries.retain(|e| !sent.iter().any(|s| e.is(&s.character, s.at, &s.fingerprint)));
+        for e in entries.iter_mut() {
+            if let Some((_, rule)) = still.iter().find(|(s, _)| e.is(&s.character, s.at, &s.fingerprint)) {
+                e.rule = rule.clone();
+            }
+        }
+    })
+}
+
+// ---------- Death surges ----------
+//
+// A character dying more than `surge_deaths` times in `surge_window_secs` (death
+// nights, duel tournaments) has its deaths held and sent in groups: one batch
+// request per endpoint (with batch_uploads), one Discord summary, and a
+// screenshot only for the first death of each group. Held deaths are kept in state.json until sent and go out
+// in order before any newer death of that character, so nothing is dropped or
+// overtaken; each keeps its own idempotency key.
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct Surge {
+    /// Death times (the `at` field) inside the current window
+    recent: VecDeque<i64>,
+    /// Grouping stays on until then (epoch secs); 0 when off
+    until: i64,
+    held: Vec<StagedDeath>,
+    /// When the oldest held death was staged (epoch secs)
+    held_since: i64,
+}
+
+/// Track the character's death rate and hold the death while it is surging.
+/// Hands the death back when it should go out on its own.
+fn hold_for_surge(cfg: &Config, state: &mut State, mut staged: StagedDeath) -> Option<StagedDeath> {
+    if cfg.surge_deaths == 0 {
+        return Some(staged);
+    }
+    let now = Utc::now().timestamp();
+    let surge = state.surges.entry(staged.key.clone()).or_default();
+    surge.recent.push_back(staged.death.at);
+    let floor = staged.death.at - cfg.surge_window_secs;
+    surge.recent.retain(|at| *at > floor);
+    if surge.recent.len() >= cfg.surge_deaths {
+        if surge.until <= now {
+            let msg = format!(
+                "{} died {} times in {}s; grouping its uploads until it calms down",
+                staged.key,
+                surge.recent.len(),
+                cfg.surge_window_secs
+            );
+            println!("[surge] {}", msg);
+            eventlog::report(Level::Info, EventClass::Upload, &msg);
+        }
+        surge.until = now + cfg.surge_cooldown_secs;
+    }
+    // Once something is held, newer deaths queue behind it even after the surge ends.
+    if surge.until <= now && surge.held.is_empty() {
+        return Some(staged);
+    }
+    let mut dropped = vec![];
+    if surge.held.is_empty() {
+        surge.held_since = now;
+    } else if staged.screenshot.is_some() {
+        dropped = staged.screenshots();
+        staged.screenshot = None;
+        staged.extra_screenshots.clear();
+        println!("[surge] Not attaching {} to the death at {}; only the first death of a group gets one", dropped.join(", "), format_epoch(staged.death.at));
+    }
+    let held = staged.clone();
+    surge.held.push(staged);
+    settle_staged(state, &held);
+    state.pending_screens.retain(|p| !dropped.contains(&p.path));
+    for shot in &dropped {
+        state.claimed_screens.remove(shot);
+    }
+    None
+}
+
+/// Send the held groups that are full, have waited `surge_flush_secs`, or whose
+/// character calmed down.
+async fn flush_surges(cfg: &Config, http: &Http, state: &mut State) {
+    let now = Utc::now().timestamp();
+    let due: Vec<String> = state
+        .surges
+        .iter()
+        .filter(|(_, s)| {
+            !s.held.is_empty()
+                && (s.until <= now || s.held.len() >= cfg.max_batch_size.max(1) || now - s.held_since >= cfg.surge_flush_secs)
+        })
+        .map(|(k, _)| k.clone())
+        .collect();
+    for key in &due {
+        let held = state.surges.get_mut(key).map(|s| std::mem::take(&mut s.held)).unwrap_or_default();
+        println!("[surge] Sending {} grouped death(s) for {}", held.len(), key);
+        send_grouped(cfg, http, state, held, true).await;
+    }
+    let mut calmed = false;
+    for (key, surge) in state.surges.iter_mut() {
+        if surge.until != 0 && surge.until <= now && surge.held.is_empty() {
+            println!("[surge] {} is back to normal uploads", key);
+            surge.until = 0;
+            calmed = true;
+        }
+    }
+    // Windows go by recorded death times, which lag the clock when a backlog is read.
+    let newest = state.surges.values().filter_map(|s| s.recent.back().copied()).max().unwrap_or(0);
+    state.surges.retain(|_, s| !s.held.is_empty() || s.until != 0 || s.recent.back().is_some_and(|at| newest - at < cfg.surge_window_secs));
+    if !due.is_empty() || calmed {
+        save_state(state).ok();
+    }
+}
+
+/// Count a successful upload and advance the character's watermark.
+fn record_uploaded(state: &mut State, key: &str, at: i64, paired: bool, started: std::time::Instant) {
+    let day = state.metrics.today();
+    day.deaths_uploaded += 1;
+    day.upload_latency_ms_total += started.elapsed().as_millis() as u64;
+    if paired {
+        day.screenshots_paired += 1;
+    }
+    let mark = state.last_uploaded.entry(key.to_string()).or_insert(at);
+    *mark = (*mark).max(at);
+}
+
+// ---------- Milestones ----------
+
+/// One row of `DeathLoggerDB.levels`.
+#[derive(Debug, Clone, Default)]
+struct LevelRecord {
+    player: String,
+    realm: String,
+    level: i64,
+    /// When `level` was reached (epoch secs)
+    at: i64,
+    time_played_secs: Option<i64>,
+}
+
+/// `DeathLoggerDB.levels` by character name, since Lua's table order isn't
+/// the data parser's.
+fn read_levels<T: svdata::Table>(db: &T) -> Vec<LevelRecord> {
+    let Some(levels) = db.field("levels").into_table() else {
+        return vec![];
+    };
+    let mut out = vec![];
+    for (_, v) in levels.entries() {
+        let SvValue::Table(t) = v else { continue };
+        let player = t.field("player").to_text().unwrap_or_default();
+        let realm = t.field("realm").to_text().unwrap_or_default();
+        let Some(level) = t.field("level").as_int() else { continue };
+        if player.is_empty() || realm.is_empty() {
+            continue;
+        }
+        out.push(LevelRecord {
+            player,
+            realm,
+            level,
+            at: t.field("at").as_int().unwrap_or(0),
+            time_played_secs: t.field("timePlayed").as_int(),
+        });
+    }
+    out.sort_by(|a, b| (&a.player, &a.realm).cmp(&(&b.player, &b.realm)));
+    out
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct MilestonePayload {
+    player: String,
+    realm: String,
+    level: i64,
+    at: i64,
+    time_played_secs: Option<i64>,
+    branch: String,
+    agent: AgentInfo,
+}
+
+/// The client version for this branch from `<root>/.build.info`, e.g. "1.15.7.61582".
+fn client_version(wow: &WowPaths) -> Option<String> {
+    let product = match wow.branch.as_str() {
+        "_retail_" => "wow",
+        "_classic_" => "wow_classic",
+        "_classic_era_" => "wow_classic_era",
+        "_ptr_" => "wowt",
+        "_classic_ptr_" => "wow_classic_ptr",
+        _ => return None,
+    };
+    read_build_info(&wow.root)?
+        .into_iter()
+        .find(|row| row.get("Product").map(String::as_str) == Some(product))
+        .and_then(|mut row| row.remove("Version"))
+}
+
+/// Rows of the install's `.build.info`, keyed by column name ("Product!STRING:0" -> "Product").
+fn read_build_info(root: &Path) -> Option<Vec<BTreeMap<String, String>>> {
+    let info = fs::read_to_string(root.join(".build.info")).ok()?;
+    let mut lines = info.lines();
+    let header: Vec<String> = lines.next()?.split('|').map(|h| h.split('!').next().unwrap_or("").to_string()).collect();
+    Some(
+        lines
+            .filter(|l| !l.trim().is_empty())
+            .map(|l| header.iter().cloned().zip(l.split('|').map(str::to_string)).collect())
+            .collect(),
+    )
+}
+
+/// Level cap for a client major version.
+fn max_level_for_version(version: &str) -> Option<i64> {
+    let major: u32 = version.split('.').next()?.parse().ok()?;
+    Some(match major {
+        1 => 60,
+        2 => 70,
+        3 => 80,
+        4 => 85,
+        5 => 90,
+        6 => 100,
+        7 => 110,
+        8 => 120,
+        9 => 60,
+        10 => 70,
+        11 => 80,
+        _ => return None,
+    })
+}
+
+/// Configured milestone levels, or the branch's level cap.
+fn milestone_levels(cfg: &Config, wow: &WowPaths) -> Vec<i64> {
+    if !cfg.milestone_levels.is_empty() {
+        return cfg.milestone_levels.clone();
+    }
+    client_version(wow).as_deref().and_then(max_level_for_version).into_iter().collect()
+}
+
+fn milestones_url(cfg: &Config) -> String {
+    if cfg.milestones_url.is_empty() {
+        format!("{}/milestones", primary_endpoint(cfg).url.trim_end_matches('/'))
+    } else {
+        cfg.milestones_url.clone()
+    }
+}
+
+async fn upload_milestone(cfg: &Config, http: &Http, milestone: &MilestonePayload) -> Result<()> {
+    let ep_cfg = cfg.for_endpoint(&primary_endpoint(cfg));
+    let req = http.authorize(&ep_cfg, http.post(&milestones_url(cfg)).json(milestone)).await?;
+    let resp = http.send(NetFeature::Milestones, req).await?;
+    let status = resp.status();
+    let retry_after = retry_after_of(&resp);
+    if classify_status(status.as_u16()) != UploadOutcome::Accepted {
+        let body = resp.text().await.unwrap_or_default();
+        return Err(UploadError { status, body, retry_after }.into());
+    }
+    Ok(())
+}
+
+/// Announce each milestone once per character. The first time a character is
+/// seen, milestones it already passed count as history and are only announced
+/// with `announce_historical_milestones`. Failed announcements stay unmarked
+/// and are tried again on the next parse.
+/// False when an announcement failed and should be tried again.
+async fn check_milestones(
+    cfg: &Config,
+    http: &Http,
+    wow: &WowPaths,
+    state: &mut State,
+    account: Option<&str>,
+    levels: &[LevelRecord],
+) -> bool {
+    if levels.is_empty() {
+        return true;
+    }
+    let milestones = milestone_levels(cfg, wow);
+    let mut changed = false;
+    let mut settled = true;
+    for rec in levels {
+        let key = character_key(account, &rec.player, &rec.realm);
+        let reached = milestones.iter().copied().filter(|m| rec.level >= *m).max().unwrap_or(0);
+        let mark = match state.milestone_marks.get(&key) {
+            Some(m) => *m,
+            None if cfg.announce_historical_milestones => 0,
+            None => {
+                state.milestone_marks.insert(key, reached);
+                changed = true;
+                continue;
+            }
+        };
+        if reached <= mark || !cfg.uploads_enabled {
+            continue;
+        }
+        let milestone = MilestonePayload {
+            player: rec.player.clone(),
+            realm: rec.realm.clone(),
+            level: rec.level,
+            at: rec.at,
+            time_played_secs: rec.time_played_secs,
+            branch: wow.branch.clone(),
+            agent: agent_info(cfg),
+        };
+        match upload_milestone(cfg, http, &milestone).await {
+            Ok(()) => {
+                println!("[milestone] {} reached level {}", key, rec.level);
+                state.milestone_marks.insert(key, reached);
+                changed = true;
+            }
+            Err(e) => {
+                eprintln!("[milestone] announcing level {} for {} failed: {e:#}", rec.level, key);
+                settled = false;
+            }
+        }
+    }
+    if changed {
+        save_state(state).ok();
+    }
+    settled
+}
+
+// ---------- Death notes ----------
+//
+// Notes for deaths that haven't gone out yet live in notes.json, not state.json,
+// so `annotate` can add one while the agent is running without the agent's next
+// state save overwriting it. Uploaded deaths a server can't PATCH a note onto
+// wait in note_resends.json the same way until the agent queues them again.
+
+const MAX_NOTE_CHARS: usize = 140;
+
+/// One line, trimmed and capped; None when nothing is left.
+fn clean_note(text: &str) -> Option<String> {
+    let line = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    (!line.is_empty()).then(|| truncate_chars(&line, MAX_NOTE_CHARS))
+}
+
+/// "Player@Realm#<at>", how `annotate` and notes.json name a death.
+fn death_ref(key: &str, at: i64) -> String {
+    format!("{}#{}", key, at)
+}
+
+fn load_pending_notes() -> BTreeMap<String, String> {
+    notes_path()
+        .and_then(|p| Ok(serde_json::from_str(&fs::read_to_string(p)?)?))
+        .unwrap_or_default()
+}
+
+/// Read-modify-write notes.json under the state lock.
+fn update_pending_notes<T>(f: impl FnOnce(&mut BTreeMap<String, String>) -> T) -> Result<T> {
+    fs::create_dir_all(config_dir()?)?;
+    let _lock = ProcessLock::acquire(LockName::State, STATE_LOCK_TIMEOUT)?;
+    let mut notes = load_pending_notes();
+    let out = f(&mut notes);
+    write_atomic(&notes_path()?, serde_json::to_string_pretty(&notes)?.as_bytes())?;
+    Ok(out)
+}
+
+/// The queued note for a death, removed from notes.json. Notes queued as plain
+/// "Player@Realm#<at>" match the death on any account.
+fn take_pending_note(key: &str, at: i64) -> Option<String> {
+    let notes = load_pending_notes();
+    let character = key.split_once(':').map_or(key, |(_, c)| c);
+    let death_ref = [death_ref(key, at), death_ref(character, at)].into_iter().find(|r| notes.contains_key(r))?;
+    update_pending_notes(|notes| notes.remove(&death_ref)).ok().flatten()
+}
+
+/// An uploaded death to be sent again because its endpoints can't PATCH the note
+/// onto it. The note itself waits in notes.json like any other.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct NoteResend {
+    death: DeathPayload,
+    /// Endpoints without PATCH support
+    targets: Vec<String>,
+    /// Not the first upload's, or a server deduplicating on it would answer with that
+    idempotency_key: String,
+}
+
+fn load_note_resends() -> Vec<NoteResend> {
+    note_resends_path()
+        .and_then(|p| Ok(serde_json::from_str(&fs::read_to_string(p)?)?))
+        .unwrap_or_default()
+}
+
+/// Read-modify-write note_resends.json under the state lock.
+fn update_note_resends<T>(f: impl FnOnce(&mut Vec<NoteResend>) -> T) -> Result<T> {
+    fs::create_dir_all(config_dir()?)?;
+    let _lock = ProcessLock::acquire(LockName::State, STATE_LOCK_TIMEOUT)?;
+    let mut resends = load_note_resends();
+    let out = f(&mut resends);
+    write_atomic(&note_resends_path()?, serde_json::to_string_pretty(&resends)?.as_bytes())?;
+    Ok(out)
+}
+
+/// Move the deaths `annotate` asked to send again into the retry queue, due now.
+/// `retry_due` attaches their notes like for any queued death.
+fn queue_note_resends(state: &mut State) -> usize {
+    if load_note_resends().is_empty() {
+        return 0;
+    }
+    let Ok(resends) = update_note_resends(std::mem::take) else { return 0 };
+    let now = Utc::now().timestamp();
+    let queued = resends.len();
+    for resend in resends {
+        let key = resend.death.key();
+        println!("[note] Sending the death for {} at {} again with its note", key, format_epoch(resend.death.at));
+        state.retry_queue.retain(|r| !(r.key() == key && r.death.at == resend.death.at));
+        state.retry_queue.push(RetryEntry {
+            death: resend.death,
+            screenshot: None,
+            extra_screenshots: vec![],
+            attempts: 0,
+            next_at: now,
+            last_error: "sent again to carry a note".into(),
+            targets: resend.targets,
+            delivered: true,
+            idempotency_key: Some(resend.idempotency_key),
+        });
+    }
+    queued
+}
+
+/// Console lines, read on one long-lived thread so a prompt that timed out
+/// doesn't leave a reader behind that swallows the next answer.
+static STDIN_LINES: once_cell::sync::Lazy<Mutex<std::sync::mpsc::Receiver<String>>> = once_cell::sync::Lazy::new(|| {
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else { break };
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    Mutex::new(rx)
+});
+
+/// Offer a note for a just-detected death for at most `secs` seconds. Only in
+/// an interactive console; the upload goes ahead as soon as the window closes.
+fn prompt_note(secs: u64) -> Option<String> {
+    if !std::io::IsTerminal::is_terminal(&std::io::stdin()) {
+        return None;
+    }
+    let rx = STDIN_LINES.lock().ok()?;
+    while rx.try_recv().is_ok() {}
+    println!("[note] Add a note for this death and press Enter ({}s, Enter alone skips):", secs);
+    rx.recv_timeout(Duration::from_secs(secs)).ok().and_then(|l| clean_note(&l))
+}
+
+/// `deathlogger-agent annotate <[Account:]Player@Realm[#at]> "text"`
+///
+/// A death still waiting to be sent gets the note with it. For one already
+/// uploaded the note is sent as a PATCH to each endpoint; endpoints without
+/// PATCH support get the death again (read back from the SavedVariables, without
+/// its screenshot) with the note, sent by the running agent.
+async fn annotate_command(args: &[String]) -> Result<()> {
+    let usage = || anyhow!("Usage: deathlogger-agent annotate <[Account:]Player@Realm[#at]> \"note\"");
+    let [target, text] = args else { return Err(usage()) };
+    let cfg = load_config(&config_path()?)?;
+    let state = load_state().unwrap_or_default();
+    annotate(&cfg, &state, target, text).await
+}
+
+async fn annotate(cfg: &Config, state: &State, target: &str, text: &str) -> Result<()> {
+    let usage = || anyhow!("Usage: deathlogger-agent annotate <[Account:]Player@Realm[#at]> \"note\"");
+    let note = clean_note(text).ok_or_else(usage)?;
+    let (key, at) = match target.rsplit_once('#') {
+        Some((key, at)) => (key.to_string(), Some(at.parse::<i64>().map_err(|_| usage())?)),
+        None => (target.to_string(), None),
+    };
+    let key = annotate_key(state, &key)?;
+    let mark = state.last_uploaded.get(&key).copied();
+
+    // Not sent yet (retrying, quarantined or uploads disabled): it rides along.
+    let waiting = state
+        .retry_queue
+        .iter()
+        .map(|r| (r.key(), r.death.at))
+        .chain(state.quarantine.iter().map(|q| (q.death.key(), q.death.at)))
+        .filter(|(k, a)| *k == key && at.map(|at| at == *a).unwrap_or(true))
+        .map(|(_, a)| a)
+        .max();
+    let at = match (at, waiting, mark) {
+        (_, Some(w), _) => w,
+        (Some(at), None, mark) if mark.map(|m| at > m).unwrap_or(true) => at,
+        (Some(at), None, _) => return annotate_uploaded(cfg, &key, at, note).await,
+        (None, None, Some(m)) => return annotate_uploaded(cfg, &key, m, note).await,
+        (None, None, None) => return Err(anyhow!("no death known for {}; pass <Player@Realm>#<at> for one not detected yet", key)),
+    };
+    let death_ref = death_ref(&key, at);
+    update_pending_notes(|notes| notes.insert(death_ref.clone(), note))?;
+    println!("[note] Queued; the note goes out with the death {} ({})", death_ref, format_epoch(at));
+    Ok(())
+}
+
+/// State key `annotate` means. A plain "Player@Realm" is looked up on every
+/// account and must name one character; until the death has been seen it stays
+/// plain and the note matches it on whichever account it turns up.
+fn annotate_key(state: &State, target: &str) -> Result<String> {
+    if target.contains(':') {
+        return Ok(target.to_string());
+    }
+    let mut known: Vec<String> = state
+        .last_uploaded
+        .keys()
+        .cloned()
+        .chain(state.retry_queue.iter().map(|r| r.key()))
+        .chain(state.quarantine.iter().map(|q| q.death.key()))
+        .filter(|k| k.split_once(':').map_or(k.as_str(), |(_, c)| c) == target)
+        .collect();
+    known.sort();
+    known.dedup();
+    match known.len() {
+        0 | 1 => Ok(known.pop().unwrap_or_else(|| target.to_string())),
+        _ => Err(anyhow!("{} is on more than one account ({}); pass <Account:Player@Realm>", target, known.join(", "))),
+    }
+}
+
+/// PATCH the note onto an uploaded death, and queue the death to be sent again
+/// with it for the endpoints that can't take a PATCH.
+async fn annotate_uploaded(cfg: &Config, key: &str, at: i64, note: String) -> Result<()> {
+    let unsupported = patch_note(cfg, key, at, &note).await?;
+    if unsupported.is_empty() {
+        return Ok(());
+    }
+    let Some(death) = find_sv_death(cfg, key, at)? else {
+        return Err(anyhow!(
+            "{} can't take notes on uploaded deaths (no PATCH support), and the death {} is no longer in the SavedVariables to send again",
+            unsupported.join(", "),
+            death_ref(key, at)
+        ));
+    };
+    let death_ref = death_ref(&death.key(), at);
+    let idempotency_key = format!("{:x}", Sha256::digest(format!("{}\n{}", idempotency_key(&death), note)));
+    update_pending_notes(|notes| notes.insert(death_ref.clone(), note))?;
+    update_note_resends(|resends| {
+        resends.retain(|r| !(r.death.key() == death.key() && r.death.at == at));
+        resends.push(NoteResend { death, targets: unsupported.clone(), idempotency_key });
+    })?;
+    println!(
+        "[note] {} can't take notes on uploaded deaths; the agent sends the death {} there again with the note",
+        unsupported.join(", "),
+        death_ref
+    );
+    Ok(())
+}
+
+/// An uploaded death read back from the SavedVariables, prepared as it was sent.
+/// A plain "Player@Realm" key matches the character on any account.
+fn find_sv_death(cfg: &Config, key: &str, at: i64) -> Result<Option<DeathPayload>> {
+    let wow = WowPaths::from_config(cfg);
+    let every = SvMarks { uploaded: BTreeMap::new(), cursor: None };
+    for sv in account_sv_paths(&wow) {
+        let Ok(snapshot) = wow.parse_sv(&sv, Some(&every)) else { continue };
+        let account = account_from_sv_path(&sv);
+        for mut death in snapshot.history.into_iter().chain(snapshot.latest) {
+            death.account = death.account.or_else(|| account.clone());
+            if death.at == at && (death.key() == key || to_key(&death.player, &death.realm) == key) {
+                prepare_payload(cfg, &mut death)?;
+                return Ok(Some(death));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Send a note for an uploaded death to every endpoint that accepts PATCH.
+/// Returns the endpoints that don't.
+async fn patch_note(cfg: &Config, key: &str, at: i64, note: &str) -> Result<Vec<String>> {
+    let http = Http::new(cfg)?;
+    let (account, character) = key.split_once(':').map_or((None, key), |(a, c)| (Some(a), c));
+    let (player, realm) = character.split_once('@').unwrap_or((character, ""));
+    let mut body = json!({ "player": player, "realm": realm, "at": at, "note": note });
+    if let Some(account) = account {
+        body["account"] = json!(account);
+    }
+    let mut unsupported = vec![];
+    for ep in api_endpoints(cfg) {
+        let req = http.authorize(&cfg.for_endpoint(&ep), http.client.request(Method::PATCH, &ep.url).json(&body)).await?;
+        let resp = http.send(NetFeature::Notes, req).await?;
+        match resp.status().as_u16() {
+            200..=299 => println!("[note] {} updated the death {}", ep.name, death_ref(key, at)),
+            404 | 405 | 501 => unsupported.push(ep.name),
+            _ => {
+                let status = resp.status();
+                let retry_after = retry_after_of(&resp);
+                let body = resp.text().await.unwrap_or_default();
+                return Err(UploadError { status, body, retry_after }.into());
+            }
+        }
+    }
+    Ok(unsupported)
+}
+
+/// Character the settings in an SV file belong to: the path for per-character
+/// files, otherwise whoever died most recently in it.
+fn settings_owner(sv_file: &Path, snapshot: &SvSnapshot) -> Option<String> {
+    identity_from_sv_path(sv_file)
+        .map(|(realm, player)| (player, realm))
+        .or_else(|| snapshot.recent_identity.clone())
+        .map(|(player, realm)| character_key(account_from_sv_path(sv_file).as_deref(), &player, &realm))
+}
+
+/// Cache the addon's settings per character and say so when auto-screenshot gets turned off.
+fn note_addon_settings(state: &mut State, sv_file: &Path, snapshot: &SvSnapshot) {
+    let (Some(owner), Some(settings)) = (settings_owner(sv_file, snapshot), snapshot.settings.clone()) else {
+        return;
+    };
+    if state.addon_settings.get(&owner) == Some(&settings) {
+        return;
+    }
+    if settings.auto_screenshot_off() {
+        println!("[settings] auto-screenshot: OFF for {} — pairing will rely on manual screenshots", owner);
+    }
+    state.addon_settings.insert(owner, settings);
+    save_state(state).ok();
+}
+
+/// Warn about deaths table entries skipped for lack of a usable `at`, once per file
+/// and set of entries. Uploading them as `at = 0` would have them deduped forever.
+fn note_undated_entries(state: &mut State, sv_file: &Path, snapshot: &SvSnapshot) {
+    if snapshot.undated.is_empty() || state.undated_seen.get(sv_file) == Some(&snapshot.undated) {
+        return;
+    }
+    let msg = format!(
+        "{}: deaths table entries [{}] have no usable `at` timestamp and were skipped",
+        sv_file.display(),
+        snapshot.undated.join(", ")
+    );
+    println!("[warn] {msg}");
+    eventlog::report(Level::Warning, EventClass::Parse, &msg);
+    state.undated_seen.insert(sv_file.to_path_buf(), snapshot.undated.clone());
+}
+
+/// Tell the player to update the addon when an SV file was written by one older
+/// than MIN_ADDON_VERSION, once per file and version.
+fn note_addon_version(state: &mut State, sv_file: &Path, snapshot: &SvSnapshot) {
+    let Some(version) = &snapshot.addon_version else { return };
+    if !addon_outdated(version) || state.outdated_addon_seen.get(sv_file) == Some(version) {
+        return;
+    }
+    let msg = format!(
+        "{} was written by addon version {version}, older than {MIN_ADDON_VERSION}; some death details may be missing. \
+         Update the addon and /reload in game.",
+        sv_file.display()
+    );
+    println!("[warn] {msg}");
+    eventlog::report(Level::Warning, EventClass::Parse, &msg);
+    state.outdated_addon_seen.insert(sv_file.to_path_buf(), version.clone());
+}
+
+/// Detect the addon clearing its deaths table (fewer entries or a lower max index
+/// than last time). Watermarks are per character and based on `at`, so older
+/// entries that reappear at new indices are still skipped and the next real
+/// death uploads normally; this only records and logs the reset.
+fn note_table_reset(state: &mut State, sv_file: &Path, snapshot: &SvSnapshot) {
+    let path = sv_file.to_string_lossy().to_string();
+    let now = SvTableInfo { max_index: snapshot.max_index, count: snapshot.count };
+    if let Some(prev) = state.sv_tables.get(&path) {
+        if now == *prev {
+            return;
+        }
+        if now.count < prev.count || now.max_index < prev.max_index {
+            println!(
+                "[reset] Deaths table in {} was cleared ({} -> {} entries); continuing from per-character watermarks",
+                sv_file.display(),
+                prev.count,
+                now.count
+            );
+        }
+    }
+    state.sv_tables.insert(path, now);
+    save_state(state).ok();
+}
+
+async fn periodic_poll(cfg: &Config, http: &Http, wow: &WowPaths, state: &mut State, deferred: &mut DeferredParses) -> Result<()> {
+    // Re-scan SV files (new accounts may have appeared)
+    for sv in account_sv_paths(wow) {
+        if let Err(e) = handle_sv_change_or_defer(cfg, http, wow, state, deferred, &sv).await {
+            eprintln!("[poll] SV check error: {e}");
+        }
+    }
+    maybe_log_reliability_summary(cfg, state);
+    if queue_note_resends(state) > 0 {
+        save_state(state).ok();
+    }
+    if expire_pending_screens(cfg, state, last_sv_write(wow), Utc::now().timestamp()) > 0 {
+        save_state(state).ok();
+    }
+    sweep_screenshot_cache(cfg);
+    Ok(())
+}
+
+/// Drop cached screenshots made with settings no longer in use, files the
+/// cache index has lost track of, and the oldest entries over
+/// `screenshot_cache_max_mb`. At most every ten minutes.
+fn sweep_screenshot_cache(cfg: &Config) {
+    if cfg.screenshot_cache_max_mb == 0 {
+        return;
+    }
+    let Ok(cache) = shotcache::shared() else { return };
+    if !cache.sweep_due(Duration::from_secs(600)) {
+        return;
+    }
+    let blur = cfg.blur_chat_region && !cfg.blur_regions.is_empty();
+    match cache.sweep(&screenshot_cache_params(cfg, blur), cfg.screenshot_cache_max_mb * 1024 * 1024) {
+        Ok(r) if r != shotcache::SweepReport::default() => eprintln!(
+            "[poll] screenshot cache: removed {} stale, {} missing, {} orphaned, {} over the size limit",
+            r.stale, r.missing, r.orphans, r.evicted
+        ),
+        Ok(_) => {}
+        Err(e) => eprintln!("[poll] screenshot cache sweep failed: {e:#}"),
+    }
+}
+
+/// Write the reliability summary to the log once per configured period.
+fn maybe_log_reliability_summary(cfg: &Config, state: &mut State) {
+    if cfg.reliability_summary_days <= 0 {
+        return;
+    }
+    let now = Utc::now().timestamp();
+    let last = match state.metrics.last_summary_at {
+        Some(t) => t,
+        None => {
+            // First run: start the clock, summarize after a full period.
+            state.metrics.last_summary_at = Some(now);
+            save_state(state).ok();
+            return;
+        }
+    };
+    if now - last < cfg.reliability_summary_days * 86_400 {
+        return;
+    }
+    let summary = state
+        .metrics
+        .reliability_summary(Utc::now().date_naive() + chrono::Duration::days(1), cfg.reliability_summary_days);
+    for line in summary.lines() {
+        println!("[stats] {}", line);
+    }
+    if cfg.reliability_summary_toast {
+        if let Err(e) = show_toast("DeathLogger reliability", &summary.headline()) {
+            eprintln!("[warn] reliability toast: {e:#}");
+        }
+    }
+    state.metrics.last_summary_at = Some(now);
+    save_state(state).ok();
+}
+
+// ---------- Deferred parses ----------
+//
+// WoW rewrites SavedVariables files in place, so a change event often finds one
+// half written. A file that doesn't parse is tried again after 1s, 3s and 10s;
+// only when the last attempt fails too is it reported. A parse that succeeds
+// meanwhile (from a later event or poll) cancels the remaining attempts.
+
+const SV_RETRY_DELAYS_SECS: [u64; 3] = [1, 3, 10];
+
+/// How often the main loop re-scans the game folders when idle.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, thiserror::Error)]
+#[error("{path} did not parse: {reason}")]
+struct SvParseError {
+    path: String,
+    reason: String,
+}
+
+struct DeferredParse {
+    /// Retries made so far
+    attempts: usize,
+    next_at: std::time::Instant,
+}
+
+#[derive(Default)]
+struct DeferredParses {
+    files: BTreeMap<PathBuf, DeferredParse>,
+}
+
+impl DeferredParses {
+    /// Schedule the first retry, unless the file is already waiting for one.
+    fn defer(&mut self, path: &Path) {
+        self.files.entry(path.to_path_buf()).or_insert_with(|| DeferredParse {
+            attempts: 0,
+            next_at: std::time::Instant::now() + Duration::from_secs(SV_RETRY_DELAYS_SECS[0]),
+        });
+    }
+
+    async fn retry_due(&mut self, cfg: &Config, http: &Http, wow: &WowPaths, state: &mut State) {
+        let now = std::time::Instant::now();
+        let due: Vec<PathBuf> = self.files.iter().filter(|(_, d)| d.next_at <= now).map(|(p, _)| p.clone()).collect();
+        for path in due {
+            let Err(e) = handle_sv_change(cfg, http, wow, state, &path).await else {
+                self.files.remove(&path);
+                continue;
+            };
+            if e.downcast_ref::<SvParseError>().is_none() {
+                self.files.remove(&path);
+                eprintln!("[error] SV handle: {e:#}");
+                eventlog::report(Level::Error, EventClass::Parse, &format!("{}: {e:#}", path.display()));
+                continue;
+            }
+            let Some(entry) = self.files.get_mut(&path) else { continue };
+            entry.attempts += 1;
+            match SV_RETRY_DELAYS_SECS.get(entry.attempts) {
+                Some(delay) => entry.next_at = std::time::Instant::now() + Duration::from_secs(*delay),
+                None => {
+                    self.files.remove(&path);
+                    state.metrics.today().parse_errors += 1;
+                    eprintln!("[error] {e} (gave up after {} retries)", SV_RETRY_DELAYS_SECS.len());
+                    eventlog::report(Level::Error, EventClass::Parse, &e.to_string());
+                }
+            }
+        }
+    }
+}
+
+/// `handle_sv_change`, except that a file which doesn't parse is deferred
+/// instead of failing.
+async fn handle_sv_change_or_defer(
+    cfg: &Config,
+    http: &Http,
+    wow: &WowPaths,
+    state: &mut State,
+    deferred: &mut DeferredParses,
+    sv_file: &Path,
+) -> Result<()> {
+    match handle_sv_change(cfg, http, wow, state, sv_file).await {
+        Err(e) if e.downcast_ref::<SvParseError>().is_some() => {
+            deferred.defer(sv_file);
+            Ok(())
+        }
+        result => {
+            if result.is_ok() {
+                deferred.files.remove(sv_file);
+            }
+            result
+        }
+    }
+}
+
+// ---------- Shutdown ----------
+//
+// The first Ctrl+C stops the main loop from taking new events. Whatever it is
+// doing (an upload, a retry round) gets `shutdown_grace_secs` to finish and is
+// dropped after that; a dropped death was never marked uploaded, so the next
+// start finds it again. State is saved and the agent exits normally. A second
+// Ctrl+C exits at once.
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::Relaxed)
+}
+
+fn install_shutdown_handler(grace_secs: u64) {
+    tokio::spawn(async move {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            if SHUTDOWN.swap(true, Ordering::Relaxed) {
+                eprintln!("[shutdown] Second Ctrl+C; exiting now");
+                std::process::exit(130);
+            }
+            println!("[shutdown] Stopping; giving running work up to {}s (Ctrl+C again to quit now)", grace_secs);
+        }
+    });
+}
+
+/// Run one main-loop step to completion, unless a shutdown is requested and it
+/// is still running `grace` later. None when it was dropped.
+async fn within_grace<F: std::future::Future>(grace: Duration, step: F) -> Option<F::Output> {
+    let deadline = async {
+        while !shutdown_requested() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        tokio::time::sleep(grace).await;
+    };
+    tokio::select! {
+        out = step => Some(out),
+        _ = deadline => None,
+    }
+}
+
+/// Save state and list what is still waiting to go out.
+fn finish_shutdown(cfg: &Config, state: &State, abandoned: bool) {
+    if abandoned {
+        println!(
+            "[shutdown] Work still running after {}s was stopped; its death is picked up again on the next start",
+            cfg.shutdown_grace_secs
+        );
+    }
+    if let Err(e) = save_state(state) {
+        eprintln!("[error] saving state on shutdown failed: {e:#}");
+    }
+    let held: usize = state.surges.values().map(|s| s.held.len()).sum();
+    if state.retry_queue.is_empty() && held == 0 && state.quarantine.is_empty() {
+        println!("[shutdown] Nothing left to send");
+    } else {
+        println!(
+            "[shutdown] Saved for the next start: {} death(s) to retry, {} held in surge groups, {} waiting for an identity",
+            state.retry_queue.len(),
+            held,
+            state.quarantine.len()
+        );
+        for r in &state.retry_queue {
+            let targets = if r.targets.is_empty() { "all targets".to_string() } else { r.targets.join(", ") };
+            println!("      {} at {} for {}", r.key(), format_epoch(r.death.at), targets);
+        }
+    }
+    eventlog::report(Level::Info, EventClass::Lifecycle, &format!("agent {} stopped", env!("CARGO_PKG_VERSION")));
+}
+
+// ---------- Heartbeat ----------
+//
+// With `heartbeat_url` set the agent posts a small status body every
+// `heartbeat_interval_secs` so the server can show which agents are alive. It
+// runs from the idle loop with its own short timeout and never touches the
+// upload state; a failure is reported once, then again only after a success.
+
+const HEARTBEAT_MIN_SECS: u64 = 30;
+const HEARTBEAT_TIMEOUT_SECS: u64 = 5;
+
+#[derive(Debug, Serialize)]
+struct HeartbeatPayload {
+    agent: AgentInfo,
+    branch: String,
+    /// SavedVariables files currently watched
+    sv_files: usize,
+    /// Newest uploaded death (the `at` field) per character
+    last_uploaded: BTreeMap<String, i64>,
+    /// Deaths waiting in the retry queue
+    retry_queue: usize,
+}
+
+#[derive(Debug)]
+struct Heartbeat {
+    next_at: std::time::Instant,
+    failing: bool,
+}
+
+impl Heartbeat {
+    fn new() -> Self {
+        Self { next_at: std::time::Instant::now(), failing: false }
+    }
+
+    /// Send the heartbeat when it is due.
+    async fn tick(&mut self, cfg: &Config, http: &Http, wow: &WowPaths, state: &State) {
+        if cfg.heartbeat_url.is_empty() || std::time::Instant::now() < self.next_at {
+            return;
+        }
+        self.next_at = std::time::Instant::now() + Duration::from_secs(cfg.heartbeat_interval_secs.max(HEARTBEAT_MIN_SECS));
+        let payload = HeartbeatPayload {
+            agent: agent_info(cfg),
+            branch: wow.branch.clone(),
+            sv_files: account_sv_paths(wow).len(),
+            last_uploaded: state.last_uploaded.clone(),
+            retry_queue: state.retry_queue.len(),
+        };
+        let sent = tokio::time::timeout(Duration::from_secs(HEARTBEAT_TIMEOUT_SECS), send_heartbeat(cfg, http, &payload)).await;
+        match sent.unwrap_or_else(|_| Err(anyhow!("no answer within {}s", HEARTBEAT_TIMEOUT_SECS))) {
+            Ok(()) if self.failing => {
+                self.failing = false;
+                println!("[heartbeat] {} is answering again", cfg.heartbeat_url);
+            }
+            Ok(()) => {}
+            Err(e) if !self.failing => {
+                self.failing = true;
+                eprintln!("[heartbeat] {} failed: {e:#}; retrying every interval without further messages", cfg.heartbeat_url);
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+async fn send_heartbeat(cfg: &Config, http: &Http, payload: &HeartbeatPayload) -> Result<()> {
+    let ep_cfg = cfg.for_endpoint(&primary_endpoint(cfg));
+    let req = http.authorize(&ep_cfg, http.post(&cfg.heartbeat_url).json(payload)).await?;
+    http.send(NetFeature::Heartbeat, req).await?.error_for_status()?;
+    Ok(())
+}
+
+// ---------- Restart handoff ----------
+//
+// Timers the main loop only keeps in memory (SavedVariables files waiting for a
+// parse retry, the next poll and heartbeat, the upload rate limit's tokens) are
+// written to handoff.json on a clean stop. A start within
+// `handoff_max_age_secs` carries on with them, deadlines moved on by the time
+// the agent was down, and deletes the file. An older file, or one written by
+// another format version, is dropped with a log line and the agent starts cold.
+// Everything else that outlives a restart is in state.json already.
+
+const HANDOFF_VERSION: u32 = 1;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Handoff {
+    version: u32,
+    /// Epoch millis
+    written_at: i64,
+    deferred: Vec<HandoffParse>,
+    /// Millis from `written_at` until the next poll
+    poll_in: u64,
+    /// Millis from `written_at` until the next heartbeat
+    heartbeat_in: u64,
+    heartbeat_failing: bool,
+    /// Free upload tokens when `max_uploads_per_minute` is set
+    upload_tokens: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct HandoffParse {
+    path: PathBuf,
+    attempts: usize,
+    /// Millis from `written_at` until the retry
+    due_in: u64,
+}
+
+impl Handoff {
+    fn capture(deferred: &DeferredParses, last_poll: SystemTime, heartbeat: &Heartbeat, http: &Http) -> Self {
+        let now = std::time::Instant::now();
+        let until = |at: std::time::Instant| at.saturating_duration_since(now).as_millis() as u64;
+        Self {
+            version: HANDOFF_VERSION,
+            written_at: Utc::now().timestamp_millis(),
+            deferred: deferred
+                .files
+                .iter()
+                .map(|(path, d)| HandoffParse { path: path.clone(), attempts: d.attempts, due_in: until(d.next_at) })
+                .collect(),
+            poll_in: POLL_INTERVAL.saturating_sub(last_poll.elapsed().unwrap_or(Duration::ZERO)).as_millis() as u64,
+            heartbeat_in: until(heartbeat.next_at),
+            heartbeat_failing: heartbeat.failing,
+            upload_tokens: http.upload_limiter.as_ref().map(RateLimiter::tokens),
+        }
+    }
+
+    /// Put the timers back, each due as much sooner as the agent was down.
+    fn resume(self, deferred: &mut DeferredParses, last_poll: &mut SystemTime, heartbeat: &mut Heartbeat, http: &Http) {
+        let down = Duration::from_millis((Utc::now().timestamp_millis() - self.written_at).max(0) as u64);
+        let now = std::time::Instant::now();
+        let at = |millis: u64| now + Duration::from_millis(millis).saturating_sub(down);
+        let parses = self.deferred.len();
+        for p in self.deferred {
+            deferred.files.insert(p.path, DeferredParse { attempts: p.attempts, next_at: at(p.due_in) });
+        }
+        let poll_in = Duration::from_millis(self.poll_in).saturating_sub(down);
+        *last_poll = SystemTime::now() - POLL_INTERVAL.saturating_sub(poll_in);
+        heartbeat.next_at = at(self.heartbeat_in);
+        heartbeat.failing = self.heartbeat_failing;
+        if let (Some(limiter), Some(tokens)) = (&http.upload_limiter, self.upload_tokens) {
+            limiter.resume(tokens, down);
+        }
+        println!("[handoff] Resumed from a stop {}s ago: {} SavedVariables file(s) waiting to be parsed again", down.as_secs(), parses);
+    }
+}
+
+fn write_handoff(path: &Path, handoff: &Handoff) -> Result<()> {
+    write_atomic(path, serde_json::to_string_pretty(handoff)?.as_bytes())
+}
+
+/// The handoff at `path` if it is recent enough and of this version. The file
+/// is deleted either way: it is only ever good for the next start.
+fn take_handoff(path: &Path, max_age_secs: u64) -> Option<Handoff> {
+    let text = fs::read_to_string(path).ok()?;
+    fs::remove_file(path).ok();
+    if max_age_secs == 0 {
+        return None;
+    }
+    let handoff = match serde_json::from_str::<Handoff>(&text) {
+        Ok(h) if h.version == HANDOFF_VERSION => h,
+        Ok(h) => {
+            println!("[handoff] Ignoring {}: version {}, this agent reads {}", path.display(), h.version, HANDOFF_VERSION);
+            return None;
+        }
+        Err(e) => {
+            println!("[handoff] Ignoring {}: {e}", path.display());
+            return None;
+        }
+    };
+    let age = Utc::now().timestamp_millis() - handoff.written_at;
+    if !(0..=max_age_secs as i64 * 1000).contains(&age) {
+        println!(
+            "[handoff] Ignoring {}: written at {}, not within the last {}s",
+            path.display(),
+            format_epoch(handoff.written_at / 1000),
+            max_age_secs
+        );
+        return None;
+    }
+    Some(handoff)
+}
+
+// ---------- Usage telemetry ----------
+//
+// Strictly opt-in (`telemetry = true`). The ping carries versions, feature
+// switches and counts only; nothing that names a player, realm or server. The
+// install id is random and can be replaced with `telemetry reset-id`.
+
+const TELEMETRY_INTERVAL_SECS: i64 = 24 * 3600;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct TelemetryState {
+    /// Random UUID; generated on first use
+    install_id: String,
+    /// When the last ping was accepted (epoch secs)
+    last_sent_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct TelemetryPayload {
+    install_id: String,
+    agent_version: &'static str,
+    os: String,
+    branches: Vec<String>,
+    addon_version: Option<String>,
+    features: BTreeMap<&'static str, bool>,
+    /// UTC day the counts cover
+    day: chrono::NaiveDate,
+    deaths_uploaded: u64,
+    parse_errors: u64,
+}
+
+fn random_uuid() -> String {
+    use crypto_box::aead::rand_core::RngCore;
+    let mut b = [0u8; 16];
+    crypto_box::aead::OsRng.fill_bytes(&mut b);
+    b[6] = (b[6] & 0x0f) | 0x40;
+    b[8] = (b[8] & 0x3f) | 0x80;
+    let hex: String = b.iter().map(|x| format!("{:02x}", x)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+#[cfg(windows)]
+fn os_version() -> String {
+    let key = RegKey::predef(winreg::enums::HKEY_LOCAL_MACHINE)
+        .open_subkey("SOFTWARE\\Microsoft\\Windows NT\\CurrentVersion");
+    match key.and_then(|k| k.get_value::<String, _>("CurrentBuild")) {
+        Ok(build) => format!("windows build {}", build),
+        Err(_) => "windows".into(),
+    }
+}
+
+#[cfg(not(windows))]
+fn os_version() -> String {
+    std::env::consts::OS.into()
+}
+
+/// "## Version:" from the installed addon's TOC.
+fn installed_addon_version(wow: &WowPaths) -> Option<String> {
+    let toc = fs::read_to_string(wow.addons_dir().join("DeathLogger").join("DeathLogger.toc")).ok()?;
+    toc.lines()
+        .find_map(|l| l.trim().strip_prefix("## Version:"))
+        .map(|v| v.trim().to_string())
+}
+
+fn telemetry_features(cfg: &Config) -> BTreeMap<&'static str, bool> {
+    BTreeMap::from([
+        ("uploads_enabled", cfg.uploads_enabled),
+        ("update_addon_on_start", cfg.update_addon_on_start),
+        ("start_with_windows", cfg.start_with_windows),
+        ("bags_diff", cfg.bags_mode == BagsMode::Diff),
+        ("inventory_summary", cfg.inventory_detail != InventoryDetail::Full),
+        ("privacy_redaction", cfg.privacy.any()),
+        ("screenshot_thumbnail", cfg.screenshot_thumbnail),
+        ("late_screenshots", late_screenshots_apply(cfg)),
+        ("drop_unknown_fields", cfg.drop_unknown_fields),
+        ("previous_payload_schema", cfg.payload_schema_version < SCHEMA_VERSION),
+        ("batch_uploads", cfg.batch_uploads),
+        ("compress_uploads", cfg.compress_uploads),
+        ("resumable_uploads", cfg.resumable_uploads),
+        ("presigned_uploads", cfg.upload_flow == UploadFlow::Presigned),
+        ("bulk_backfill", backfill_applies(cfg)),
+        ("encryption", !cfg.encrypt_to_public_key.is_empty()),
+        ("hmac_signing", !cfg.hmac_secret.is_empty()),
+        ("screenshot_challenge", !cfg.screenshot_challenge_url.is_empty()),
+        ("screenshot_cache", cfg.screenshot_cache_max_mb > 0),
+        ("network_allowlist", !cfg.network_allowlist.is_empty()),
+        ("proxy", !cfg.proxy_url.is_empty()),
+        ("client_certificate", !cfg.tls_client_cert.is_empty()),
+        ("oauth", cfg.oauth.is_some()),
+        ("event_log", cfg.event_log),
+        ("death_filters", cfg.filters != DeathFilters::default()),
+        ("restart_handoff", cfg.handoff_max_age_secs > 0),
+        ("reliability_summary_toast", cfg.reliability_summary_toast),
+        ("killer_remap", !cfg.killer_remap.is_empty()),
+        ("multiple_endpoints", api_endpoints(cfg).len() > 1),
+        ("custom_savedvariables", cfg.sv_file_name != SV_FILE_NAME || cfg.sv_global_names != [SV_GLOBAL_NAME]),
+        ("timestamp_mode", cfg.timestamp_mode != TimestampMode::AsIs),
+        ("sv_adapters", cfg.sv_adapters != ["deathlogger"]),
+    ])
+}
+
+fn telemetry_payload(cfg: &Config, wow: &WowPaths, state: &mut State) -> TelemetryPayload {
+    if state.telemetry.install_id.is_empty() {
+        state.telemetry.install_id = random_uuid();
+    }
+    // The previous UTC day is complete by the time the ping goes out.
+    let day = Utc::now().date_naive() - chrono::Duration::days(1);
+    let counts = state.metrics.days.get(&day).cloned().unwrap_or_default();
+    TelemetryPayload {
+        install_id: state.telemetry.install_id.clone(),
+        agent_version: env!("CARGO_PKG_VERSION"),
+        os: os_version(),
+        branches: vec![cfg.wow_branch.clone()],
+        addon_version: installed_addon_version(wow),
+        features: telemetry_features(cfg),
+        day,
+        deaths_uploaded: counts.deaths_uploaded,
+        parse_errors: counts.parse_errors,
+    }
+}
+
+/// The exact body sent, after the same secret redaction as logs and captures.
+fn telemetry_body(cfg: &Config, wow: &WowPaths, state: &mut State) -> Result<String> {
+    let body = serde_json::to_string(&telemetry_payload(cfg, wow, state))?;
+    Ok(redact_secrets(&body, &config_secrets(cfg)))
+}
+
+/// Send the ping when telemetry is on and a day has passed since the last
+/// accepted one. Failures are logged and tried again on a later tick.
+async fn maybe_send_telemetry(cfg: &Config, http: &Http, wow: &WowPaths, state: &mut State) {
+    if !cfg.telemetry {
+        return;
+    }
+    let now = Utc::now().timestamp();
+    if state.telemetry.last_sent_at.map(|t| now - t < TELEMETRY_INTERVAL_SECS).unwrap_or(false) {
+        return;
+    }
+    if url_host(&cfg.telemetry_url).is_none() {
+        return;
+    }
+    let result = async {
+        let body = telemetry_body(cfg, wow, state)?;
+        let req = http.post(&cfg.telemetry_url).header(CONTENT_TYPE, "application/json").body(body);
+        http.send(NetFeature::Telemetry, req).await?.error_for_status()?;
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    match result {
+        Ok(()) => {
+            state.telemetry.last_sent_at = Some(now);
+            save_state(state).ok();
+        }
+        Err(e) => eprintln!("[telemetry] usage ping failed: {e:#}"),
+    }
+}
+
+/// `deathlogger-agent telemetry preview|reset-id`
+fn telemetry_command(args: &[String]) -> Result<()> {
+    let cfg_path = config_path()?;
+    let cfg = if cfg_path.exists() { load_config(&cfg_path)? } else { Config::default() };
+    let wow = WowPaths::from_config(&cfg);
+    match args.first().map(String::as_str) {
+        Some("preview") => {
+            let mut state = load_state().unwrap_or_default();
+            let body = telemetry_body(&cfg, &wow, &mut state)?;
+            let value: serde_json::Value = serde_json::from_str(&body)?;
+            println!("[telemetry] Telemetry is {}.", if cfg.telemetry { "ON" } else { "OFF (nothing is sent)" });
+            println!("[telemetry] Destination: {}", if cfg.telemetry_url.is_empty() { "(not set)" } else { &cfg.telemetry_url });
+            println!("{}", serde_json::to_string_pretty(&value)?);
+            Ok(())
+        }
+        Some("reset-id") => {
+            let _locks = ProcessLock::acquire_for("telemetry reset-id", Duration::ZERO)
+                .context("stop the agent before resetting the install id")?;
+            let mut state = load_state_for_write(false)?;
+            state.telemetry.install_id = random_uuid();
+            save_state(&state)?;
+            println!("[telemetry] New install id: {}", state.telemetry.install_id);
+            Ok(())
+        }
+        _ => Err(anyhow!("Usage: deathlogger-agent telemetry preview|reset-id")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    /// Point config_dir at a scratch folder so save_state and friends stay out
+    /// of the real one.
+    fn scratch_dir() -> PathBuf {
+        CONFIG_DIR_OVERRIDE
+            .get_or_init(|| {
+                let dir = std::env::temp_dir().join(format!("deathlogger-test-{}", std::process::id()));
+                fs::create_dir_all(&dir).unwrap();
+                dir
+            })
+            .clone()
+    }
+
+    /// A request the mock server saw: method, path and body.
+    type Seen = Arc<Mutex<Vec<(String, String, Vec<u8>)>>>;
+
+    /// Serve HTTP/1.1 on a free port, answering each request with `respond`
+    /// (status and body, or status and Location for a 3xx). Returns the base URL
+    /// and the requests seen so far.
+    fn mock_server(respond: impl Fn(&str, &[u8]) -> (u16, String) + Send + 'static) -> (String, Seen) {
+        mock_server_with_headers(move |path, body| {
+            let (status, reply) = respond(path, body);
+            (status, vec![], reply)
+        })
+    }
+
+    /// `mock_server` whose answers also carry the given response headers.
+    fn mock_server_with_headers(respond: impl Fn(&str, &[u8]) -> (u16, Vec<(&'static str, String)>, String) + Send + 'static) -> (String, Seen) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let seen: Seen = Arc::default();
+        let log = seen.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { continue };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut head = String::new();
+                reader.read_line(&mut head).unwrap();
+                let mut parts = head.split_whitespace();
+                let (method, path) = (parts.next().unwrap_or("").to_string(), parts.next().unwrap_or("").to_string());
+                let (mut length, mut chunked) = (0usize, false);
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    let lower = line.to_ascii_lowercase();
+                    if let Some(v) = lower.strip_prefix("content-length:") {
+                        length = v.trim().parse().unwrap();
+                    }
+                    chunked |= lower.starts_with("transfer-encoding:") && lower.contains("chunked");
+                }
+                let mut body = vec![];
+                if chunked {
+                    loop {
+                        let mut size = String::new();
+                        reader.read_line(&mut size).unwrap();
+                        let size = usize::from_str_radix(size.trim(), 16).unwrap();
+                        let mut chunk = vec![0; size + 2];
+                        reader.read_exact(&mut chunk).unwrap();
+                        if size == 0 {
+                            break;
+                        }
+                        body.extend_from_slice(&chunk[..size]);
+                    }
+                } else {
+                    body.resize(length, 0);
+                    reader.read_exact(&mut body).unwrap();
+                }
+                let (status, headers, reply) = respond(&path, &body);
+                log.lock().unwrap().push((method, path, body));
+                let extra: String = headers.iter().map(|(name, value)| format!("{name}: {value}\r\n")).collect();
+                // For a redirect the reply is the Location.
+                let resp = if (300..400).contains(&status) {
+                    format!("HTTP/1.1 {} X\r\nLocation: {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n", status, reply, extra)
+                } else {
+                    format!(
+                        "HTTP/1.1 {} X\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        extra,
+                        reply.len(),
+                        reply
+                    )
+                };
+                stream.write_all(resp.as_bytes()).ok();
+            }
+        });
+        (url, seen)
+    }
+
+    fn death(player: &str, at: i64) -> DeathPayload {
+        DeathPayload { at, player: player.into(), realm: "Realm".into(), level: Some(10), ..DeathPayload::default() }
+    }
+
+    fn paths(seen: &Seen) -> Vec<String> {
+        seen.lock().unwrap().iter().map(|(_, p, _)| p.clone()).collect()
+    }
+
+    // ---------- Network allowlist ----------
+
+    #[test]
+    fn allowlist_patterns_match_exact_and_wildcard_hosts() {
+        assert!(host_matches("deaths.example.com", "deaths.example.com"));
+        assert!(host_matches("Deaths.Example.com.", "deaths.example.COM"));
+        assert!(!host_matches("deaths.example.com", "api.deaths.example.com"));
+        assert!(host_matches("*.example.com", "api.example.com"));
+        assert!(host_matches("*.example.com", "a.b.example.com"));
+        assert!(!host_matches("*.example.com", "example.com"), "a wildcard does not cover the bare domain");
+        assert!(!host_matches("*.example.com", "badexample.com"));
+        assert!(!host_matches("*.example.com", "example.com.evil.net"));
+    }
+
+    #[tokio::test]
+    async fn blocked_requests_are_counted_per_feature() {
+        scratch_dir();
+        let (url, seen) = mock_server(|_, _| (200, "{}".into()));
+        let http = Http::new(&Config { network_allowlist: vec!["*.example.com".into()], ..Config::default() }).unwrap();
+        let features = [NetFeature::Upload, NetFeature::Heartbeat, NetFeature::Heartbeat, NetFeature::Media, NetFeature::Telemetry];
+        for feature in features {
+            let err = http.send(feature, http.get(&url)).await.unwrap_err();
+            assert_eq!(err.to_string(), format!("{} request to 127.0.0.1 blocked by network_allowlist", feature.name()));
+        }
+        assert!(seen.lock().unwrap().is_empty(), "no connection is made");
+
+        let mut state = State::default();
+        record_blocked(&http, &mut state);
+        let day = state.metrics.today();
+        let counts: Vec<(&str, u64)> = day.requests_blocked.iter().map(|(f, n)| (f.as_str(), *n)).collect();
+        assert_eq!(counts, [("heartbeat", 2), ("media", 1), ("telemetry", 1), ("upload", 1)]);
+        assert!(http.take_blocked().is_empty(), "counts move to the metrics once");
+    }
+
+    #[test]
+    fn contactable_hosts_are_labelled_with_the_feature_that_calls_them() {
+        let cfg = Config {
+            api_url: "https://deaths.example/upload".into(),
+            milestones_url: "https://levels.example/m".into(),
+            screenshot_challenge_url: "https://nonce.example/c".into(),
+            media_url: "https://media.example/m".into(),
+            heartbeat_url: "https://alive.example/h".into(),
+            bulk_api_url: "https://bulk.example/b".into(),
+            update_addon_on_start: false,
+            ..Config::default()
+        };
+        let hosts: Vec<(&str, String)> = contactable_hosts(&cfg).into_iter().map(|(f, h)| (f.name(), h)).collect();
+        let expected = [
+            ("upload", "deaths.example"),
+            ("media", "media.example"),
+            ("challenge", "nonce.example"),
+            ("bulk", "bulk.example"),
+            ("milestones", "levels.example"),
+            ("heartbeat", "alive.example"),
+        ];
+        assert_eq!(hosts, expected.map(|(f, h)| (f, h.to_string())));
+    }
+
+    #[tokio::test]
+    async fn redirect_to_an_unlisted_host_is_blocked() {
+        scratch_dir();
+        let (target, target_seen) = mock_server(|_, _| (200, "{}".into()));
+        let elsewhere = target.replace("127.0.0.1", "localhost");
+        let (url, _) = mock_server(move |_, _| (302, format!("{elsewhere}/landing")));
+        let http = Http::new(&Config { network_allowlist: vec!["127.0.0.1".into()], ..Config::default() }).unwrap();
+        let err = http.send(NetFeature::Discord, http.get(&url)).await.unwrap_err();
+        assert_eq!(err.to_string(), "discord request to localhost blocked by network_allowlist");
+        assert!(target_seen.lock().unwrap().is_empty());
+        assert_eq!(http.take_blocked().get(&NetFeature::Discord), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn redirect_within_the_allowlist_is_followed() {
+        scratch_dir();
+        let (target, target_seen) = mock_server(|_, _| (200, "{}".into()));
+        let elsewhere = target.replace("127.0.0.1", "localhost");
+        let (url, _) = mock_server(move |_, _| (302, format!("{elsewhere}/landing")));
+        let allowlist = vec!["127.0.0.1".into(), "localhost".into()];
+        let http = Http::new(&Config { network_allowlist: allowlist, ..Config::default() }).unwrap();
+        let resp = http.send(NetFeature::Upload, http.get(&url)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(paths(&target_seen), ["/landing"]);
+        assert!(http.take_blocked().is_empty());
+    }
+
+    #[tokio::test]
+    async fn upload_redirect_to_another_host_keeps_its_body_without_an_allowlist() {
+        scratch_dir();
+        let (target, target_seen) = mock_server(|_, _| (201, "{}".into()));
+        let elsewhere = target.replace("127.0.0.1", "localhost");
+        let (url, _) = mock_server(move |_, _| (307, format!("{elsewhere}/moved")));
+        let http = Http::new(&Config::default()).unwrap();
+        let resp = http.send(NetFeature::Upload, http.post(&url).body("{\"at\":1}")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let seen = target_seen.lock().unwrap();
+        assert_eq!((seen[0].0.as_str(), seen[0].1.as_str(), seen[0].2.as_slice()), ("POST", "/moved", b"{\"at\":1}".as_slice()));
+    }
+
+    // ---------- Request capture ----------
+
+    /// An `Http` capturing `limit` requests, starting from an empty ring.
+    fn capturing_http(limit: usize, token: &str, allowlist: &[&str]) -> Http {
+        scratch_dir();
+        let cfg = Config {
+            capture_last_requests: limit,
+            api_token: token.into(),
+            network_allowlist: allowlist.iter().map(|h| h.to_string()).collect(),
+            ..Config::default()
+        };
+        let http = Http::new(&cfg).unwrap();
+        // Other tests share the scratch dir's last_requests.json.
+        http.captured.as_ref().unwrap().lock().unwrap().clear();
+        http
+    }
+
+    fn captured(http: &Http) -> Vec<CapturedRequest> {
+        http.captured.as_ref().unwrap().lock().unwrap().iter().cloned().collect()
+    }
+
+    #[tokio::test]
+    async fn captured_requests_never_contain_the_token() {
+        const TOKEN: &str = "tok-5f3a9c1e77";
+        let http = capturing_http(5, TOKEN, &[]);
+        let (url, seen) = mock_server(|_, _| (422, "{}".into()));
+        let parts = vec![
+            FormPart::text("death", format!(r#"{{"note":"pasted {TOKEN} by mistake"}}"#)),
+            FormPart::file("screenshot", format!("shot-{TOKEN}.png"), b"IMAGE-BYTES-SENTINEL".to_vec(), "image/png"),
+        ];
+        let req = http
+            .post(&format!("{url}/deaths?key={TOKEN}"))
+            .bearer_auth(TOKEN)
+            .header("x-debug", format!("before-{TOKEN}-after"));
+        let resp = http.send_multipart(NetFeature::Upload, req, parts).await.unwrap();
+        assert_eq!(resp.status().as_u16(), 422);
+        assert!(String::from_utf8_lossy(&seen.lock().unwrap()[0].2).contains(TOKEN), "the server still gets it");
+
+        // A failed request records its error, redacted the same way.
+        let blocked = capturing_http(5, TOKEN, &["example.com"]);
+        blocked.send(NetFeature::Upload, blocked.get(&format!("{url}/{TOKEN}"))).await.unwrap_err();
+
+        for http in [&http, &blocked] {
+            let records = captured(http);
+            assert_eq!(records.len(), 1);
+            let json = serde_json::to_string(&records).unwrap();
+            assert!(!json.contains(TOKEN), "{json}");
+            // No substring long enough to be useful either.
+            for start in 0..=TOKEN.len() - 6 {
+                assert!(!json.contains(&TOKEN[start..start + 6]), "{} leaked in {json}", &TOKEN[start..start + 6]);
+            }
+        }
+
+        let record = &captured(&http)[0];
+        assert_eq!(record.method, "POST");
+        assert_eq!(record.status, Some(422));
+        assert_eq!(record.headers["authorization"], "<redacted>");
+        assert_eq!(record.headers["x-debug"], "before-<redacted>-after");
+        assert!(record.url.ends_with("/deaths?key=<redacted>"));
+        let shot = record.parts.iter().find(|p| p.name == "screenshot").unwrap();
+        assert_eq!(shot.size, Some(20));
+        assert_eq!(shot.content_type.as_deref(), Some("image/png"));
+        assert_eq!(shot.file_name.as_deref(), Some("shot-<redacted>.png"));
+        assert!(shot.text.is_none() && shot.sha256.is_some());
+        assert!(!serde_json::to_string(record).unwrap().contains("IMAGE-BYTES-SENTINEL"));
+        assert!(captured(&blocked)[0].error.as_deref().unwrap().contains("blocked by network_allowlist"));
+    }
+
+    #[tokio::test]
+    async fn capture_ring_keeps_only_the_last_requests() {
+        let http = capturing_http(3, "tok", &[]);
+        let (url, _) = mock_server(|_, _| (200, "{}".into()));
+        for i in 0..5 {
+            http.send(NetFeature::Upload, http.get(&format!("{url}/r{i}"))).await.unwrap();
+            assert_eq!(captured(&http).len(), (i + 1).min(3));
+        }
+        let urls: Vec<String> = captured(&http).into_iter().map(|r| r.url).collect();
+        assert_eq!(urls, ["/r2", "/r3", "/r4"].map(|p| format!("{url}{p}")), "oldest first");
+
+        scratch_dir();
+        let off = Http::new(&Config { capture_last_requests: 0, ..Config::default() }).unwrap();
+        assert!(off.captured.is_none(), "nothing is kept when capture is disabled");
+        off.send(NetFeature::Upload, off.get(&url)).await.unwrap();
+        assert!(!off.capture_flush_pending.load(Ordering::SeqCst));
+    }
+
+    // ---------- Upload preview ----------
+
+    /// A WoW folder under the scratch dir with one account-level SavedVariables file.
+    fn wow_with_sv(name: &str, lua: &str) -> (Config, PathBuf) {
+        let root = scratch_dir().join(name);
+        fs::remove_dir_all(&root).ok();
+        let sv_dir = root.join("_retail_/WTF/Account/ACC/SavedVariables");
+        fs::create_dir_all(&sv_dir).unwrap();
+        fs::create_dir_all(root.join("_retail_/Screenshots")).unwrap();
+        let sv = sv_dir.join(SV_FILE_NAME);
+        fs::write(&sv, lua).unwrap();
+        (Config { wow_root: root.to_string_lossy().to_string(), ..Config::default() }, sv)
+    }
+
+    fn preview_lua(player: &str, at: i64) -> String {
+        let link = format!("|cffffffff|Hitem:117::::::::24:::::::::|h[Tough Jerky]|h|r{}", "x".repeat(80));
+        let slots: Vec<String> = (1..=5).map(|i| format!("{{ [\"slot\"] = {i}, [\"hyperlink\"] = \"{link}\" }}")).collect();
+        format!(
+            "DeathLoggerDB = {{ [\"deaths\"] = {{ {{ [\"player\"] = \"{player}\", [\"realm\"] = \"Realm\", [\"at\"] = {at}, \
+             [\"level\"] = 20, [\"bags\"] = {{ {{ [\"bagID\"] = 0, [\"slots\"] = {{ {} }} }} }} }} }} }}\n",
+            slots.join(", ")
+        )
+    }
+
+    #[test]
+    fn preview_shows_the_payload_per_target_without_touching_state() {
+        let (mut cfg, sv) = wow_with_sv("wow-preview", &preview_lua("Previewed", 1_700_000_000));
+        cfg.api_url = "https://deaths.example/upload".into();
+        let text = preview_text(&cfg, false).unwrap().unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], format!("[preview] Most recent death: Previewed@Realm at {}", format_epoch(1_700_000_000)));
+        assert_eq!(lines[1], "[preview] Target: https://deaths.example/upload (default)");
+        assert_eq!(*lines.last().unwrap(), "[preview] Screenshot: none would be attached");
+        let body: serde_json::Value = serde_json::from_str(&lines[2..lines.len() - 1].join("\n")).unwrap();
+        assert_eq!(body["player"], "Previewed");
+        let slots = body["bags"][0]["slots"].as_array().unwrap();
+        assert_eq!(slots.len(), 4, "three items and a note");
+        assert_eq!(slots[3], "… 2 more item(s), use --full to show");
+        let link = slots[0]["hyperlink"].as_str().unwrap();
+        assert_eq!(link.chars().count(), PREVIEW_MAX_STRING + "… (138 chars)".chars().count(), "{link}");
+        assert!(link.ends_with("… (138 chars)"), "{link}");
+
+        let full = preview_text(&cfg, true).unwrap().unwrap();
+        let lines: Vec<&str> = full.lines().collect();
+        let body: serde_json::Value = serde_json::from_str(&lines[2..lines.len() - 1].join("\n")).unwrap();
+        assert_eq!(body["bags"][0]["slots"].as_array().unwrap().len(), 5);
+
+        let state = load_state().unwrap_or_default();
+        assert!(!state.sv_seen.contains_key(&sv));
+        assert!(!state.last_uploaded.keys().any(|k| k.contains("Previewed")));
+    }
+
+    #[test]
+    fn preview_without_deaths_has_nothing_to_show() {
+        let (cfg, _) = wow_with_sv("wow-preview-empty", "DeathLoggerDB = { [\"deaths\"] = {} }\n");
+        assert!(preview_text(&cfg, false).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn uploads_switch_blocks_every_sink() {
+        let (url, seen) = mock_server(|_, _| (200, "{}".into()));
+        let (mut cfg, sv) = wow_with_sv("wow-switch-off", &preview_lua("Switched", 1_700_000_100));
+        cfg.api_url = format!("{url}/upload");
+        cfg.discord_webhook_url = format!("{url}/discord");
+        cfg.bulk_api_url = format!("{url}/bulk");
+        cfg.uploads_enabled = false;
+        let wow = WowPaths::from_config(&cfg);
+        let http = Http::new(&cfg).unwrap();
+        let mut state = State::default();
+        state.retry_queue.push(queued("Queued", 1_700_000_050));
+
+        handle_sv_change(&cfg, &http, &wow, &mut state, &sv).await.unwrap();
+        retry_due(&cfg, &http, &mut state).await;
+        flush_surges(&cfg, &http, &mut state).await;
+        assert!(seen.lock().unwrap().is_empty(), "requests: {:?}", paths(&seen));
+        assert_eq!(state.retry_queue.len(), 1);
+        assert!(state.last_uploaded.is_empty());
+        assert!(state.sv_cursors.is_empty(), "the deaths are still owed");
+
+        // Switched on, the same file goes out.
+        cfg.uploads_enabled = true;
+        state.sv_seen.clear();
+        handle_sv_change(&cfg, &http, &wow, &mut state, &sv).await.unwrap();
+        assert!(paths(&seen).contains(&"/upload".to_string()));
+        assert_eq!(state.last_uploaded.get("ACC:Switched@Realm"), Some(&1_700_000_100));
+    }
+
+    // ---------- Death surges ----------
+
+    /// The `at` of every death each request carried, by path.
+    fn delivered(seen: &Seen) -> Vec<(String, Vec<i64>)> {
+        let at = |v: &serde_json::Value| v["at"].as_i64().unwrap();
+        seen.lock()
+            .unwrap()
+            .iter()
+            .map(|(_, path, body)| {
+                let parts = form_parts(body);
+                let ats = match (parts.get(PART_DEATH), parts.get(PART_DEATHS)) {
+                    (Some(one), _) => vec![at(&serde_json::from_slice(one).unwrap())],
+                    (_, Some(many)) => serde_json::from_slice::<Vec<serde_json::Value>>(many).unwrap().iter().map(at).collect(),
+                    _ => vec![],
+                };
+                (path.clone(), ats)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn surging_characters_are_grouped_and_go_back_to_single_uploads() {
+        scratch_dir();
+        let (url, seen) = mock_server(|_, _| (200, "{}".into()));
+        let cfg = Config {
+            api_url: format!("{url}/upload"),
+            discord_webhook_url: format!("{url}/discord"),
+            batch_uploads: true,
+            surge_deaths: 3,
+            surge_window_secs: 300,
+            ..Config::default()
+        };
+        let http = Http::new(&cfg).unwrap();
+        let mut state = State::default();
+        let t0 = 1_700_001_000;
+        let die = |player: &'static str, at: i64| death(player, t0 + at);
+
+        // Rapid and Duelist each die three times within the window; Calm once.
+        for (player, at) in [("Rapid", 0), ("Duelist", 5), ("Rapid", 20), ("Calm", 25), ("Duelist", 30), ("Rapid", 40), ("Duelist", 50), ("Rapid", 60)] {
+            process_death(&cfg, &http, &mut state, die(player, at), &[]).await.unwrap();
+        }
+        let uploads = |seen: &Seen| delivered(seen).into_iter().filter(|(p, _)| p == "/upload").map(|(_, ats)| ats).collect::<Vec<_>>();
+        assert_eq!(uploads(&seen), [[t0], [t0 + 5], [t0 + 20], [t0 + 25], [t0 + 30]], "the third death in the window starts grouping");
+        assert_eq!(state.surges["Rapid@Realm"].held.len(), 2);
+        assert_eq!(state.surges["Duelist@Realm"].held.len(), 1);
+        assert!(state.surges["Rapid@Realm"].until > Utc::now().timestamp());
+        assert_eq!(state.last_uploaded["Rapid@Realm"], t0 + 20, "held deaths are not marked uploaded yet");
+
+        // Once surge_flush_secs has passed, each character's held deaths go out as one batch
+        // with one Discord summary.
+        for surge in state.surges.values_mut() {
+            surge.held_since -= cfg.surge_flush_secs;
+        }
+        seen.lock().unwrap().clear();
+        flush_surges(&cfg, &http, &mut state).await;
+        let mut grouped = delivered(&seen);
+        grouped.sort();
+        assert_eq!(
+            grouped,
+            [
+                ("/discord".to_string(), vec![]),
+                ("/discord".to_string(), vec![]),
+                ("/upload".to_string(), vec![t0 + 40, t0 + 60]),
+                ("/upload".to_string(), vec![t0 + 50]),
+            ]
+        );
+        assert_eq!(state.last_uploaded["Rapid@Realm"], t0 + 60);
+        assert_eq!(state.last_uploaded["Duelist@Realm"], t0 + 50);
+        assert!(state.retry_queue.is_empty() && state.failed_uploads.is_empty());
+
+        // Still surging, the next death is held again rather than sent.
+        process_death(&cfg, &http, &mut state, die("Rapid", 80), &[]).await.unwrap();
+        assert_eq!(state.surges["Rapid@Realm"].held.len(), 1);
+
+        // After the cool-down the held death goes out, and later ones are single uploads again.
+        for surge in state.surges.values_mut() {
+            surge.until = Utc::now().timestamp() - 1;
+        }
+        seen.lock().unwrap().clear();
+        flush_surges(&cfg, &http, &mut state).await;
+        assert_eq!(uploads(&seen), [[t0 + 80]]);
+        flush_surges(&cfg, &http, &mut state).await;
+        assert!(state.surges.values().all(|s| s.until == 0 && s.held.is_empty()));
+        seen.lock().unwrap().clear();
+        process_death(&cfg, &http, &mut state, die("Rapid", 900), &[]).await.unwrap();
+        assert_eq!(uploads(&seen), [[t0 + 900]]);
+        let single = &seen.lock().unwrap().iter().find(|(_, p, _)| p == "/upload").unwrap().2.clone();
+        assert!(form_parts(single).contains_key(PART_DEATH));
+        assert_eq!(state.last_uploaded["Rapid@Realm"], t0 + 900);
+    }
+
+    // ---------- Deaths table resets ----------
+
+    /// Write fixture `reset/<step>.lua` over `sv` and handle it; returns the `at`
+    /// of every death uploaded by that step.
+    async fn reset_step(cfg: &Config, http: &Http, state: &mut State, sv: &Path, seen: &Seen, step: &str) -> Vec<i64> {
+        fs::copy(fixture(&format!("reset/{step}.lua")), sv).unwrap();
+        let before = seen.lock().unwrap().len();
+        handle_sv_change(cfg, http, &WowPaths::from_config(cfg), state, sv).await.unwrap();
+        let uploads: Vec<Vec<u8>> = seen.lock().unwrap()[before..].iter().map(|(_, _, body)| body.clone()).collect();
+        uploads
+            .iter()
+            .flat_map(|body| [1_700_000_100, 1_700_000_200, 1_700_000_300, 1_700_000_400].into_iter().filter(|at| contains(body, &at.to_string())))
+            .collect()
+    }
+
+    fn contains(body: &[u8], needle: &str) -> bool {
+        body.windows(needle.len()).any(|w| w == needle.as_bytes())
+    }
+
+    fn reset_setup(name: &str) -> (Config, Http, PathBuf, Seen) {
+        let (url, seen) = mock_server(|_, _| (200, "{}".into()));
+        let (mut cfg, sv) = wow_with_sv(name, "");
+        cfg.api_url = format!("{url}/upload");
+        let http = Http::new(&cfg).unwrap();
+        (cfg, http, sv, seen)
+    }
+
+    #[tokio::test]
+    async fn cleared_table_then_nothing_uploads_nothing() {
+        let (cfg, http, sv, seen) = reset_setup("wow-reset-nothing");
+        let mut state = State::default();
+        assert_eq!(reset_step(&cfg, &http, &mut state, &sv, &seen, "before").await.last(), Some(&1_700_000_300));
+        let table = sv.to_string_lossy().to_string();
+        assert_eq!(state.sv_tables[&table], SvTableInfo { max_index: 3, count: 3 });
+
+        assert!(reset_step(&cfg, &http, &mut state, &sv, &seen, "cleared").await.is_empty());
+        assert_eq!(state.sv_tables[&table], SvTableInfo { max_index: 0, count: 0 });
+        assert_eq!(state.last_uploaded.get("ACC:Resetti@Realm"), Some(&1_700_000_300), "the watermark survives the reset");
+
+        // An older death reappearing at index 1 is still older than the watermark.
+        assert!(reset_step(&cfg, &http, &mut state, &sv, &seen, "restored_older").await.is_empty());
+        assert_eq!(state.last_uploaded.get("ACC:Resetti@Realm"), Some(&1_700_000_300));
+    }
+
+    #[tokio::test]
+    async fn cleared_table_then_a_death_uploads_it_once() {
+        let (cfg, http, sv, seen) = reset_setup("wow-reset-died");
+        let mut state = State::default();
+        reset_step(&cfg, &http, &mut state, &sv, &seen, "before").await;
+        let table = sv.to_string_lossy().to_string();
+        assert_eq!(state.sv_cursors[&table].index, 3);
+
+        assert_eq!(reset_step(&cfg, &http, &mut state, &sv, &seen, "cleared_then_died").await, [1_700_000_400]);
+        assert_eq!(state.sv_tables[&table], SvTableInfo { max_index: 1, count: 1 });
+        let cursor = &state.sv_cursors[&table];
+        assert_eq!((cursor.index, cursor.at, cursor.count), (1, 1_700_000_400, 1), "the cursor is re-anchored at the new index");
+        assert_eq!(state.last_uploaded.get("ACC:Resetti@Realm"), Some(&1_700_000_400));
+
+        // The same file read again, from the cursor or in full, sends nothing more.
+        assert!(reset_step(&cfg, &http, &mut state, &sv, &seen, "cleared_then_died").await.is_empty());
+        state.sv_cursors.clear();
+        state.sv_seen.clear();
+        assert!(reset_step(&cfg, &http, &mut state, &sv, &seen, "cleared_then_died").await.is_empty());
+    }
+
+    // ---------- Player identity ----------
+
+    /// Copy fixture `identity/<name>.lua` to `rel` under a scratch WoW folder and
+    /// parse its full history.
+    fn identity_sv(dir: &str, rel: &str, name: &str) -> (PathBuf, SvSnapshot) {
+        let path = scratch_dir().join(dir).join(rel);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::copy(fixture(&format!("identity/{name}.lua")), &path).unwrap();
+        let cfg = Config::default();
+        let adapter = DeathLoggerAdapter { file_name: cfg.sv_file_name.clone(), fmt: SvFormat::from_config(&cfg) };
+        let snapshot = adapter.parse(&path, Some(&SvMarks { uploaded: BTreeMap::new(), cursor: None })).unwrap();
+        (path, snapshot)
+    }
+
+    const ACCOUNT_SV: &str = "WTF/Account/ACC/SavedVariables/DeathLogger.lua";
+    const CHARACTER_SV: &str = "WTF/Account/ACC/Firemaw/Quillon/SavedVariables/DeathLogger.lua";
+
+    #[test]
+    fn identity_resolution_precedence() {
+        let account = Path::new("/wow/_retail_").join(ACCOUNT_SV);
+        let character = Path::new("/wow/_retail_").join(CHARACTER_SV);
+        let recent = ("Recent".to_string(), "RecentRealm".to_string());
+        // (player, realm, path, recent) -> resolved identity
+        let cases = [
+            ("Bob", "Realm", &character, Some(&recent), Some(("Bob", "Realm"))),
+            ("Bob-Gehennas", "", &account, None, Some(("Bob", "Gehennas"))),
+            ("Bob-Gehennas", "Realm", &account, None, Some(("Bob", "Realm"))),
+            (" Bob ", "  ", &character, Some(&recent), Some(("Bob", "Firemaw"))),
+            ("", "", &character, Some(&recent), Some(("Quillon", "Firemaw"))),
+            ("", "", &account, Some(&recent), Some(("Recent", "RecentRealm"))),
+            ("Bob", "", &account, Some(&recent), Some(("Bob", "RecentRealm"))),
+            ("", "", &account, None, None),
+        ];
+        for (player, realm, path, recent, expected) in cases {
+            let mut death = DeathPayload { player: player.into(), realm: realm.into(), ..DeathPayload::default() };
+            let resolved = resolve_identity(&mut death, path, recent);
+            let got = resolved.then_some((death.player.as_str(), death.realm.as_str()));
+            assert_eq!(got, expected, "{player:?} / {realm:?} in {}", path.display());
+        }
+    }
+
+    #[test]
+    fn embedded_realm_is_split_out_of_the_key() {
+        let (path, mut snapshot) = identity_sv("identity-embedded", ACCOUNT_SV, "embedded_realm");
+        let mut state = State::default();
+        let ready = ready_deaths(&mut state, &path, &mut snapshot);
+        assert_eq!(ready.iter().map(|d| d.key()).collect::<Vec<_>>(), ["ACC:Quillon@Firemaw"]);
+        assert!(state.quarantine.is_empty());
+    }
+
+    #[test]
+    fn character_file_supplies_a_missing_identity() {
+        let (path, mut snapshot) = identity_sv("identity-character", CHARACTER_SV, "loading_screen");
+        let mut state = State::default();
+        let ready = ready_deaths(&mut state, &path, &mut snapshot);
+        assert_eq!(ready.iter().map(|d| d.key()).collect::<Vec<_>>(), ["ACC:Quillon@Firemaw"]);
+        assert!(state.quarantine.is_empty());
+    }
+
+    #[test]
+    fn unresolvable_death_is_quarantined_until_a_later_entry_resolves_it() {
+        scratch_dir();
+        let (path, mut snapshot) = identity_sv("identity-quarantine", ACCOUNT_SV, "loading_screen");
+        let mut state = State::default();
+        assert!(ready_deaths(&mut state, &path, &mut snapshot).is_empty(), "an empty identity is never sent");
+        assert_eq!(state.quarantine.len(), 1);
+        assert_eq!(state.quarantine[0].death.at, 1_700_000_100);
+
+        // Parsed again unchanged: still held, and only once.
+        let (_, mut again) = identity_sv("identity-quarantine", ACCOUNT_SV, "loading_screen");
+        assert!(ready_deaths(&mut state, &path, &mut again).is_empty());
+        assert_eq!(state.quarantine.len(), 1);
+
+        // The next death in the file names the character.
+        let (_, mut later) = identity_sv("identity-quarantine", ACCOUNT_SV, "loading_screen_then_valid");
+        let ready = ready_deaths(&mut state, &path, &mut later);
+        let got: Vec<(String, i64)> = ready.iter().map(|d| (d.key(), d.at)).collect();
+        assert_eq!(got, [("ACC:Quillon@Firemaw".to_string(), 1_700_000_100), ("ACC:Quillon@Firemaw".to_string(), 1_700_000_200)]);
+        assert!(state.quarantine.is_empty());
+    }
+
+    // ---------- Bag diffs ----------
+
+    fn bags_lua(deaths: &[(i64, i64)]) -> String {
+        let entries: Vec<String> = deaths
+            .iter()
+            .map(|(at, count)| {
+                format!(
+                    "{{ [\"player\"] = \"Baggins\", [\"realm\"] = \"Realm\", [\"at\"] = {at}, [\"level\"] = 10, \
+                     [\"bags\"] = {{ {{ [\"bagID\"] = 0, [\"slots\"] = {{ {{ [\"slot\"] = 1, [\"itemID\"] = 6948, [\"stackCount\"] = 1 }}, \
+                     {{ [\"slot\"] = 2, [\"itemID\"] = 2589, [\"stackCount\"] = {count} }} }} }} }} }}"
+                )
+            })
+            .collect();
+        format!("DeathLoggerDB = {{ [\"deaths\"] = {{ {} }} }}\n", entries.join(", "))
+    }
+
+    #[tokio::test]
+    async fn missing_bags_base_on_the_server_sends_the_next_death_in_full() {
+        let answers = Arc::new(Mutex::new(vec!["{}", r#"{"bags_base_missing": true}"#, "{}", "{}"].into_iter()));
+        let (url, seen) = mock_server(move |_, _| (200, answers.lock().unwrap().next().unwrap_or("{}").into()));
+        let (mut cfg, sv) = wow_with_sv("wow-bags-base", "");
+        cfg.api_url = format!("{url}/upload");
+        cfg.bags_mode = BagsMode::Diff;
+        let wow = WowPaths::from_config(&cfg);
+        let http = Http::new(&cfg).unwrap();
+        let mut state = State::default();
+        let mut deaths = vec![];
+        let mut sent = vec![];
+        for (i, at) in (1_700_000_100..).step_by(100).take(4).enumerate() {
+            deaths.push((at, 20 - i as i64));
+            fs::write(&sv, bags_lua(&deaths)).unwrap();
+            handle_sv_change(&cfg, &http, &wow, &mut state, &sv).await.unwrap();
+            let body = seen.lock().unwrap().last().unwrap().2.clone();
+            sent.push(if contains(&body, "bags_diff") { "diff" } else { "full" });
+        }
+        assert_eq!(sent, ["full", "diff", "full", "diff"]);
+    }
+
+    // ---------- Payload encryption ----------
+
+    /// Named parts of a multipart body, by part name.
+    fn form_parts(body: &[u8]) -> BTreeMap<String, Vec<u8>> {
+        let text = String::from_utf8_lossy(body);
+        let boundary = text.lines().next().unwrap().trim_end().to_string();
+        let mut parts = BTreeMap::new();
+        let mut rest = body;
+        while let Some(start) = find(rest, boundary.as_bytes()) {
+            rest = &rest[start + boundary.len()..];
+            let Some(head_end) = find(rest, b"\r\n\r\n") else { break };
+            let head = String::from_utf8_lossy(&rest[..head_end]).to_string();
+            let data = &rest[head_end + 4..];
+            let end = find(data, format!("\r\n{boundary}").as_bytes()).unwrap_or(data.len());
+            if let Some(name) = head.split("name=\"").nth(1).and_then(|s| s.split('"').next()) {
+                parts.insert(name.to_string(), data[..end].to_vec());
+            }
+            rest = &data[end..];
+        }
+        parts
+    }
+
+    fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+        haystack.windows(needle.len()).position(|w| w == needle)
+    }
+
+    #[tokio::test]
+    async fn sealed_upload_decrypts_to_the_original_payload() {
+        scratch_dir();
+        let secret = crypto_box::SecretKey::generate(&mut crypto_box::aead::OsRng);
+        let (url, seen) = mock_server(|_, _| (200, "{}".into()));
+        let cfg = Config {
+            api_url: format!("{url}/upload"),
+            encrypt_to_public_key: BASE64.encode(secret.public_key().as_bytes()),
+            encrypt_screenshot: true,
+            capture_last_requests: 5,
+            ..Config::default()
+        };
+        let http = Http::new(&cfg).unwrap();
+        http.captured.as_ref().unwrap().lock().unwrap().clear();
+        let shot = shots_dir("sealed-shot", &[("s.jpg", b"JPEG-BYTES-OF-A-DEATH")]).join("s.jpg");
+        let mut death = death("Sealed", 1_700_000_100);
+        death.extra.insert("guild".into(), json!("Secret Society"));
+        let idem = idempotency_key(&death);
+
+        upload_once(&cfg, &http, &death, &idem, &[shot.as_path()]).await.unwrap();
+        let body = seen.lock().unwrap()[0].2.clone();
+        for plain in ["Sealed", "Secret Society", "JPEG-BYTES"] {
+            assert!(!contains(&body, plain), "{plain} leaked to the relay");
+        }
+
+        let parts = form_parts(&body);
+        assert_eq!(parts.keys().collect::<Vec<_>>(), [PART_DEATH_ENCRYPTED, PART_ENVELOPE, PART_SCREENSHOT_ENCRYPTED]);
+        let opened = secret.unseal(&parts[PART_DEATH_ENCRYPTED]).unwrap();
+        let roundtrip: DeathPayload = serde_json::from_slice(&opened).unwrap();
+        assert_eq!(serde_json::to_value(&roundtrip).unwrap(), serde_json::to_value(&death).unwrap());
+        assert_eq!(secret.unseal(&parts[PART_SCREENSHOT_ENCRYPTED]).unwrap(), b"JPEG-BYTES-OF-A-DEATH");
+
+        let envelope: serde_json::Value = serde_json::from_slice(&parts[PART_ENVELOPE]).unwrap();
+        assert_eq!(envelope["schema_version"], json!(ENVELOPE_VERSION));
+        assert_eq!(envelope["at"], json!(1_700_000_100));
+        assert_eq!(envelope["screenshot_encrypted"], json!(true));
+        assert_eq!(envelope["character_hash"], json!(format!("{:x}", Sha256::digest(b"Sealed@Realm"))));
+        assert_eq!(envelope["payload_sha256"], json!(format!("{:x}", Sha256::digest(canonical_json(&roundtrip)))));
+
+        // Dedupe keys come from the plaintext, so they match an unencrypted upload.
+        let captured = http.captured.as_ref().unwrap().lock().unwrap()[0].clone();
+        assert_eq!(captured.headers["idempotency-key"], idem);
+    }
+
+    #[tokio::test]
+    async fn screenshot_stays_clear_unless_encrypt_screenshot() {
+        scratch_dir();
+        let secret = crypto_box::SecretKey::generate(&mut crypto_box::aead::OsRng);
+        let (url, seen) = mock_server(|_, _| (200, "{}".into()));
+        let cfg = Config {
+            api_url: format!("{url}/upload"),
+            encrypt_to_public_key: BASE64.encode(secret.public_key().as_bytes()),
+            encrypt_screenshot: false,
+            ..Config::default()
+        };
+        let http = Http::new(&cfg).unwrap();
+        let shot = shots_dir("clear-shot", &[("s.jpg", b"CLEAR-SHOT")]).join("s.jpg");
+        upload_once(&cfg, &http, &death("Clear", 100), "k", &[shot.as_path()]).await.unwrap();
+        let parts = form_parts(&seen.lock().unwrap()[0].2);
+        assert_eq!(parts[PART_SCREENSHOT], b"CLEAR-SHOT");
+        let envelope: serde_json::Value = serde_json::from_slice(&parts[PART_ENVELOPE]).unwrap();
+        assert_eq!(envelope["screenshot_encrypted"], json!(false));
+    }
+
+    #[test]
+    fn bad_public_keys_fail_config_load() {
+        let dir = scratch_dir().join("bad-keys");
+        fs::create_dir_all(&dir).unwrap();
+        let cases = [
+            ("not base64!", "not valid base64"),
+            (&BASE64.encode([7u8; 16]), "expected a 32-byte X25519 key, got 16 bytes"),
+        ];
+        for (key, reason) in cases {
+            let path = dir.join("config.toml");
+            fs::write(&path, format!("encrypt_to_public_key = {key:?}\n")).unwrap();
+            let err = load_config(&path).unwrap_err();
+            assert_eq!(format!("{err:#}").split(": ").take(2).collect::<Vec<_>>(), ["encrypt_to_public_key", reason]);
+        }
+        fs::write(dir.join("config.toml"), format!("encrypt_to_public_key = {:?}\n", BASE64.encode([7u8; 32]))).unwrap();
+        assert!(load_config(&dir.join("config.toml")).is_ok());
+    }
+
+    // ---------- API documentation ----------
+
+    /// Every URL option pointing at "https://srv.test/<option>".
+    fn config_with_every_url() -> (Config, Vec<String>) {
+        let mut v = serde_json::to_value(Config::default()).unwrap();
+        let keys: Vec<String> = v.as_object().unwrap().keys().filter(|k| k.ends_with("_url")).cloned().collect();
+        for k in &keys {
+            v[k] = json!(format!("https://srv.test/{k}"));
+        }
+        (serde_json::from_value(v).unwrap(), keys)
+    }
+
+    #[test]
+    fn apidoc_covers_every_server_endpoint() {
+        let (cfg, keys) = config_with_every_url();
+        let doc = apidoc_markdown(&cfg);
+        let headings: Vec<&str> = doc.lines().filter(|l| l.starts_with("## ")).collect();
+        // Not the operator's server: Discord, the project's telemetry, and the proxy.
+        let elsewhere = ["discord_webhook_url", "telemetry_url", "proxy_url"];
+        for key in keys.iter().filter(|k| !elsewhere.contains(&k.as_str())) {
+            assert!(
+                headings.iter().any(|h| h.contains(&format!("<{key}>")) || h.ends_with(&format!("srv.test/{key}"))),
+                "{key} has no section: {headings:#?}"
+            );
+        }
+        // URLs the server hands out rather than the config.
+        for derived in ["<upload_url>", "<presigned_url>"] {
+            assert!(headings.iter().any(|h| h.contains(derived)), "{derived}");
+        }
+        for part in UPLOAD_PARTS {
+            assert!(doc.contains(&format!("| `{}` |", part.name)), "part {}", part.name);
+        }
+    }
+
+    #[test]
+    fn apidoc_documents_every_status_as_classified() {
+        let (cfg, _) = config_with_every_url();
+        let doc = apidoc_markdown(&cfg);
+        let openapi = apidoc_openapi(&cfg);
+        let responses = openapi["paths"]["/api_url"]["post"]["responses"].as_object().unwrap();
+        for code in 100..=599u16 {
+            let rule = STATUS_RULES.iter().find(|r| r.codes.contains(&code)).unwrap_or_else(|| panic!("{code} has no rule"));
+            assert_eq!(rule.outcome, classify_status(code));
+            assert!(doc.contains(&format!("| {}-{} | {} |", rule.codes.start(), rule.codes.end(), rule.outcome.describe())));
+            let entry = responses.get(&code.to_string()).or_else(|| responses.get(&format!("{}XX", code / 100)));
+            let description = entry.unwrap_or_else(|| panic!("{code} missing from openapi"))["description"].as_str().unwrap();
+            // A class entry may describe the first rule in it; single codes are exact.
+            if responses.contains_key(&code.to_string()) {
+                assert!(description.starts_with(classify_status(code).describe()), "{code}: {description}");
+            }
+        }
+    }
+
+    #[test]
+    fn apidoc_openapi_is_structurally_valid() {
+        let (cfg, _) = config_with_every_url();
+        let doc = apidoc_openapi(&cfg);
+        assert_eq!(doc["openapi"], "3.0.3");
+        assert!(doc["info"]["title"].is_string() && doc["info"]["version"].is_string());
+        assert_eq!(doc["servers"][0]["url"], "https://srv.test");
+        let response_key = regex::Regex::new(r"^[1-5]([0-9]{2}|XX)$").unwrap();
+        for (path, item) in doc["paths"].as_object().unwrap() {
+            assert!(path.starts_with('/'), "{path}");
+            for (method, op) in item.as_object().unwrap() {
+                assert!(["get", "put", "post", "delete", "head", "patch"].contains(&method.as_str()));
+                for (code, response) in op["responses"].as_object().unwrap() {
+                    assert!(response_key.is_match(code), "{code}");
+                    assert!(response["description"].is_string(), "{code} needs a description");
+                }
+                let form = &op["requestBody"]["content"]["multipart/form-data"];
+                let properties = form["schema"]["properties"].as_object().unwrap();
+                assert_eq!(form["encoding"].as_object().unwrap().keys().collect::<Vec<_>>(), properties.keys().collect::<Vec<_>>());
+            }
+        }
+        // Every $ref points at a component that exists.
+        let text = doc.to_string();
+        for r in text.split("\"$ref\":\"#/").skip(1).map(|s| s.split('"').next().unwrap()) {
+            assert!(doc.pointer(&format!("/{r}")).is_some(), "dangling $ref {r}");
+        }
+        assert_eq!(doc["components"]["schemas"]["Death"]["type"], "object");
+    }
+
+    // ---------- Cross-process locks ----------
+
+    /// Set by `lock_contention_across_processes` for the copy of this binary it starts.
+    const LOCK_CHILD_DIR: &str = "DEATHLOGGER_TEST_LOCK_CHILD_DIR";
+
+    /// Not a test on its own: run by `lock_contention_across_processes` in a second
+    /// process, it holds the agent lock like a resident agent until stdin closes.
+    #[test]
+    #[ignore]
+    fn lock_holder_child() {
+        let Ok(dir) = std::env::var(LOCK_CHILD_DIR) else { return };
+        CONFIG_DIR_OVERRIDE.set(PathBuf::from(dir)).unwrap();
+        let _lock = ProcessLock::acquire_for("run", Duration::ZERO).unwrap();
+        println!("locked");
+        std::io::stdout().flush().unwrap();
+        std::io::stdin().read_to_end(&mut vec![]).ok();
+    }
+
+    #[test]
+    fn lock_contention_across_processes() {
+        let dir = scratch_dir();
+        let mut child = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["tests::lock_holder_child", "--exact", "--ignored", "--nocapture", "--test-threads=1"])
+            .env(LOCK_CHILD_DIR, &dir)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut out = BufReader::new(child.stdout.take().unwrap());
+        let mut line = String::new();
+        // libtest prints the test name on the same line first.
+        while !line.trim_end().ends_with("locked") {
+            line.clear();
+            assert!(out.read_line(&mut line).unwrap() > 0, "the child exited before taking the lock");
+        }
+
+        for command in ["run", "telemetry reset-id"] {
+            let err = ProcessLock::acquire_for(command, Duration::from_millis(300)).err().unwrap();
+            assert_eq!(err.to_string(), format!("agent.lock is held by another DeathLogger agent process (pid {})", child.id()));
+        }
+        // Only the agent lock is taken: state writes still go through.
+        assert!(ProcessLock::acquire(LockName::State, Duration::ZERO).is_ok());
+
+        // The OS drops the lock with the process.
+        drop(child.stdin.take());
+        assert!(child.wait().unwrap().success());
+        assert!(ProcessLock::acquire_for("run", Duration::ZERO).is_ok());
+    }
+
+    #[test]
+    fn state_lock_waits_for_its_holder() {
+        scratch_dir();
+        let held = ProcessLock::acquire(LockName::State, Duration::ZERO).unwrap();
+        let err = ProcessLock::acquire(LockName::State, Duration::ZERO).err().unwrap();
+        assert!(err.to_string().starts_with("state.lock is held by another DeathLogger agent process"), "{err}");
+
+        let release = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(300));
+            drop(held);
+        });
+        let started = std::time::Instant::now();
+        assert!(ProcessLock::acquire(LockName::State, Duration::from_secs(5)).is_ok());
+        assert!(started.elapsed() >= Duration::from_millis(200), "acquired before the holder let go");
+        release.join().unwrap();
+    }
+
+    #[test]
+    fn commands_declare_their_locks() {
+        assert_eq!(locks_for("run"), [LockName::Agent]);
+        assert_eq!(locks_for("telemetry reset-id"), [LockName::Agent]);
+        assert!(locks_for("status").is_empty(), "read-only commands take none");
+        for (_, locks) in COMMAND_LOCKS {
+            let mut sorted = locks.to_vec();
+            sorted.sort();
+            assert_eq!(sorted, *locks, "declared out of hierarchy order");
+        }
+    }
+
+    #[test]
+    fn concurrent_note_writers_lose_nothing() {
+        scratch_dir();
+        // Each thread stands for a separate `annotate` queueing notes for the resident agent.
+        let writers: Vec<_> = (0..8)
+            .map(|w| {
+                std::thread::spawn(move || {
+                    for n in 0..20 {
+                        update_pending_notes(|notes| notes.insert(format!("ACC:Lock{w}@Realm#{n}"), format!("note {w}/{n}"))).unwrap();
+                    }
+                })
+            })
+            .collect();
+        // Meanwhile the agent keeps rewriting state.json under the same lock.
+        let saver = std::thread::spawn(|| {
+            for i in 0..20 {
+                save_state(&State { last_uploaded: BTreeMap::from([("ACC:Saver@Realm".into(), i)]), ..State::default() }).unwrap();
+            }
+        });
+        writers.into_iter().for_each(|w| w.join().unwrap());
+        saver.join().unwrap();
+
+        let notes = load_pending_notes();
+        for w in 0..8 {
+            for n in 0..20 {
+                assert_eq!(notes.get(&format!("ACC:Lock{w}@Realm#{n}")), Some(&format!("note {w}/{n}")));
+            }
+        }
+        let state: serde_json::Value = serde_json::from_str(&fs::read_to_string(state_path().unwrap()).unwrap()).unwrap();
+        assert!(state["last_uploaded"].is_object(), "state.json is whole");
+
+        // The agent picks each note up once.
+        assert_eq!(take_pending_note("ACC:Lock3@Realm", 7).as_deref(), Some("note 3/7"));
+        assert_eq!(take_pending_note("ACC:Lock3@Realm", 7), None);
+    }
+
+    #[tokio::test]
+    async fn annotate_patches_an_uploaded_death_on_servers_that_support_it() {
+        scratch_dir();
+        let (url, seen) = mock_server(|_, _| (200, "{}".into()));
+        let cfg = Config { api_url: format!("{url}/upload"), ..Config::default() };
+        let state = State { last_uploaded: BTreeMap::from([("ACC:Patched@Realm".into(), 1_700_006_000)]), ..State::default() };
+        annotate(&cfg, &state, "Patched@Realm", "  lag   spike ").await.unwrap();
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 1);
+        assert_eq!((seen[0].0.as_str(), seen[0].1.as_str()), ("PATCH", "/upload"));
+        let body: serde_json::Value = serde_json::from_slice(&seen[0].2).unwrap();
+        assert_eq!(body, json!({ "player": "Patched", "realm": "Realm", "at": 1_700_006_000, "note": "lag spike", "account": "ACC" }));
+        assert!(!load_pending_notes().keys().any(|r| r.contains("Patched@Realm")), "nothing left to send");
+        assert!(!load_note_resends().iter().any(|r| r.death.player == "Patched"));
+    }
+
+    #[tokio::test]
+    async fn annotate_sends_the_death_again_where_patch_is_unsupported() {
+        // PATCH bodies are plain JSON, uploads multipart.
+        let (url, seen) = mock_server(|_, body| if body.starts_with(b"{") { (405, String::new()) } else { (200, "{}".into()) });
+        let (mut cfg, _) = wow_with_sv(
+            "wow-annotate",
+            "DeathLoggerDB = { [\"deaths\"] = { { [\"player\"] = \"Resent\", [\"realm\"] = \"Realm\", [\"at\"] = 1700007000, [\"level\"] = 30 } } }\n",
+        );
+        cfg.api_url = format!("{url}/upload");
+        cfg.capture_last_requests = 5;
+        let http = Http::new(&cfg).unwrap();
+        let mut state = State { last_uploaded: BTreeMap::from([("ACC:Resent@Realm".into(), 1_700_007_000)]), ..State::default() };
+        let first = idempotency_key(&find_sv_death(&cfg, "ACC:Resent@Realm", 1_700_007_000).unwrap().unwrap());
+        annotate(&cfg, &state, "Resent@Realm#1700007000", "disconnect death").await.unwrap();
+        assert_eq!(load_pending_notes().get("ACC:Resent@Realm#1700007000").map(String::as_str), Some("disconnect death"));
+
+        // The running agent picks the re-send up and the retry pass attaches the note.
+        assert_eq!(queue_note_resends(&mut state), 1);
+        assert_eq!(state.retry_queue.len(), 1);
+        assert!(state.retry_queue[0].delivered, "already counted as uploaded");
+        retry_due(&cfg, &http, &mut state).await;
+        assert!(state.retry_queue.is_empty() && state.failed_uploads.is_empty());
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.iter().map(|(m, _, _)| m.as_str()).collect::<Vec<_>>(), ["PATCH", "POST"]);
+        let parts = form_parts(&seen[1].2);
+        let sent: DeathPayload = serde_json::from_slice(&parts[PART_DEATH]).unwrap();
+        assert_eq!((sent.player.as_str(), sent.at, sent.note.as_deref()), ("Resent", 1_700_007_000, Some("disconnect death")));
+        let sent_key = captured(&http).last().unwrap().headers[&IDEMPOTENCY_HEADER.to_ascii_lowercase()].clone();
+        assert_ne!(sent_key, first, "not deduplicated against the first upload");
+        assert!(!load_pending_notes().contains_key("ACC:Resent@Realm#1700007000"));
+        assert_eq!(queue_note_resends(&mut state), 0);
+    }
+
+    // ---------- Legacy addon folders ----------
+
+    const OUR_TOC: &str = "## Interface: 11503\n## Title: Death Logger\n## SavedVariables: DeathLoggerDB\nDeathLogger.lua\n";
+
+    /// A WoW folder with the given AddOns folders, each a list of (file, content).
+    fn addon_tree(name: &str, folders: &[(&str, &[(&str, &str)])]) -> WowPaths {
+        let root = scratch_dir().join(name);
+        fs::remove_dir_all(&root).ok();
+        let cfg = Config { wow_root: root.to_string_lossy().to_string(), ..Config::default() };
+        let wow = WowPaths::from_config(&cfg);
+        for (folder, files) in folders {
+            for (file, content) in *files {
+                let path = wow.addons_dir().join(folder).join(file);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, content).unwrap();
+            }
+        }
+        wow
+    }
+
+    fn legacy_names(wow: &WowPaths) -> Vec<String> {
+        find_legacy_addon_dirs(&wow.addons_dir()).iter().map(|p| p.file_name().unwrap().to_string_lossy().to_string()).collect()
+    }
+
+    #[test]
+    fn legacy_addon_folders_are_found_by_content() {
+        let clean: &[(&str, &[(&str, &str)])] = &[("DeathLogger", &[("DeathLogger.toc", OUR_TOC)])];
+        assert!(legacy_names(&addon_tree("addons-clean", clean)).is_empty());
+
+        let legacy: &[(&str, &[(&str, &str)])] = &[("DeathLogger-main", &[("DeathLogger.toc", OUR_TOC)])];
+        assert_eq!(legacy_names(&addon_tree("addons-legacy", legacy)), ["DeathLogger-main"]);
+
+        let both: &[(&str, &[(&str, &str)])] = &[
+            ("DeathLogger", &[("DeathLogger.toc", OUR_TOC)]),
+            ("DeathLogger-main", &[("DeathLogger.toc", "## title: death logger\n")]),
+            // A whole repository archive keeps the addon one level down.
+            ("DeathLogger-1.4", &[("Addon/DeathLogger.lua", "DeathLoggerDB = DeathLoggerDB or {}\n")]),
+            ("DeathLogger-old.migrated", &[("DeathLogger.toc", OUR_TOC)]),
+            ("Details", &[("Details.toc", "## Title: Details!\n## SavedVariables: _detalhes_global\n")]),
+            ("Deep", &[("a/b/DeathLogger.lua", "DeathLoggerDB = {}\n")]),
+        ];
+        assert_eq!(legacy_names(&addon_tree("addons-both", both)), ["DeathLogger-1.4", "DeathLogger-main"]);
+    }
+
+    #[test]
+    fn addons_txt_entries_follow_the_rename() {
+        let cases: [(&str, Option<&str>); 5] = [
+            // Only the legacy entry: it becomes ours, with its state.
+            ("Details: enabled\nDeathLogger-main: enabled\n", Some("Details: enabled\nDeathLogger: enabled\n")),
+            ("DeathLogger-main: disabled", Some("DeathLogger: disabled")),
+            // Both: ours keeps its own state.
+            ("DeathLogger: disabled\ndeathlogger-MAIN: enabled\n", Some("DeathLogger: disabled\n")),
+            // Nothing to do.
+            ("DeathLogger: enabled\nDetails: enabled\n", None),
+            ("", None),
+        ];
+        for (content, expected) in cases {
+            assert_eq!(rewrite_addons_txt(content, "DeathLogger-main", "DeathLogger").as_deref(), expected, "{content:?}");
+        }
+    }
+
+    #[test]
+    fn migration_renames_the_copy_and_repoints_every_character() {
+        let folders: &[(&str, &[(&str, &str)])] =
+            &[("DeathLogger", &[("DeathLogger.toc", OUR_TOC)]), ("DeathLogger-main", &[("DeathLogger.toc", OUR_TOC)])];
+        let wow = addon_tree("addons-migrate", folders);
+        let characters = ["ACC/Firemaw/Quillon", "ACC/Firemaw/Alt", "OTHER/Gehennas/Main"];
+        for (i, c) in characters.iter().enumerate() {
+            let dir = wow.wtf_account_dir().join(c);
+            fs::create_dir_all(&dir).unwrap();
+            let content = if i == 2 { "Details: enabled\n" } else { "DeathLogger-main: enabled\n" };
+            fs::write(dir.join("AddOns.txt"), content).unwrap();
+        }
+
+        let legacy = wow.addons_dir().join("DeathLogger-main");
+        migrate_legacy_addon_dir(&wow, &legacy).unwrap();
+        assert!(!legacy.exists());
+        assert!(wow.addons_dir().join("DeathLogger-main.migrated/DeathLogger.toc").exists(), "nothing is deleted");
+        assert!(legacy_names(&wow).is_empty());
+        for c in &characters[..2] {
+            let dir = wow.wtf_account_dir().join(c);
+            assert_eq!(fs::read_to_string(dir.join("AddOns.txt")).unwrap(), "DeathLogger: enabled\n");
+            assert_eq!(fs::read_to_string(dir.join("AddOns.txt.bak")).unwrap(), "DeathLogger-main: enabled\n", "undo copy");
+        }
+        let untouched = wow.wtf_account_dir().join(characters[2]);
+        assert_eq!(fs::read_to_string(untouched.join("AddOns.txt")).unwrap(), "Details: enabled\n");
+        assert!(!untouched.join("AddOns.txt.bak").exists());
+
+        // A second copy under the same name can't overwrite the first one's backup.
+        fs::create_dir_all(&legacy).unwrap();
+        fs::write(legacy.join("DeathLogger.toc"), OUR_TOC).unwrap();
+        let err = migrate_legacy_addon_dir(&wow, &legacy).unwrap_err();
+        assert!(err.to_string().ends_with("already exists; move it away and retry"), "{err}");
+        assert!(legacy.exists());
+    }
+
+    // ---------- Milestones ----------
+
+    struct MilestoneRig {
+        cfg: Config,
+        http: Http,
+        sv: PathBuf,
+        seen: Seen,
+        state: State,
+    }
+
+    impl MilestoneRig {
+        fn new(name: &str, status: u16, configure: impl FnOnce(&mut Config)) -> Self {
+            let (url, seen) = mock_server(move |_, _| (status, "{}".into()));
+            let (mut cfg, sv) = wow_with_sv(name, "");
+            cfg.api_url = format!("{url}/upload");
+            cfg.milestone_levels = vec![60];
+            configure(&mut cfg);
+            let http = Http::new(&cfg).unwrap();
+            Self { cfg, http, sv, seen, state: State::default() }
+        }
+
+        /// Write fixture `milestones/<step>.lua` and handle it; returns the
+        /// milestones announced by that step.
+        async fn step(&mut self, step: &str) -> Vec<serde_json::Value> {
+            fs::copy(fixture(&format!("milestones/{step}.lua")), &self.sv).unwrap();
+            let before = self.seen.lock().unwrap().len();
+            let wow = WowPaths::from_config(&self.cfg);
+            handle_sv_change(&self.cfg, &self.http, &wow, &mut self.state, &self.sv).await.unwrap();
+            self.seen.lock().unwrap()[before..]
+                .iter()
+                .filter(|(_, path, _)| path == "/upload/milestones")
+                .map(|(_, _, body)| serde_json::from_slice(body).unwrap())
+                .collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn milestone_crossed_in_one_update_fires_once() {
+        let mut rig = MilestoneRig::new("wow-milestone-cross", 200, |_| {});
+        assert!(rig.step("below").await.is_empty());
+        let sent = rig.step("crossed").await;
+        assert_eq!(sent.len(), 1);
+        let m = &sent[0];
+        assert_eq!((m["player"].as_str(), m["realm"].as_str(), m["level"].as_i64()), (Some("Quillon"), Some("Firemaw"), Some(60)));
+        assert_eq!((m["at"].as_i64(), m["time_played_secs"].as_i64()), (Some(1_700_000_200), Some(1_600_000)));
+        assert_eq!(m["branch"], "_retail_");
+        assert_eq!(rig.state.milestone_marks.get("ACC:Quillon@Firemaw"), Some(&60));
+        assert!(rig.step("still_at_cap").await.is_empty(), "once per character per milestone");
+    }
+
+    #[tokio::test]
+    async fn milestone_already_past_at_first_parse_is_history() {
+        let mut rig = MilestoneRig::new("wow-milestone-past", 200, |_| {});
+        assert!(rig.step("crossed").await.is_empty());
+        assert_eq!(rig.state.milestone_marks.get("ACC:Quillon@Firemaw"), Some(&60));
+        assert_eq!(rig.state.milestone_marks.get("ACC:Alt@Firemaw"), Some(&0));
+        assert!(rig.step("still_at_cap").await.is_empty());
+
+        let mut rig = MilestoneRig::new("wow-milestone-historical", 200, |c| c.announce_historical_milestones = true);
+        assert_eq!(rig.step("crossed").await.len(), 1, "announce_historical_milestones sends it");
+        assert!(rig.step("still_at_cap").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_milestone_is_tried_again() {
+        let mut rig = MilestoneRig::new("wow-milestone-retry", 503, |_| {});
+        rig.step("below").await;
+        assert_eq!(rig.step("crossed").await.len(), 1);
+        assert_eq!(rig.state.milestone_marks.get("ACC:Quillon@Firemaw"), Some(&0), "not marked");
+        assert!(!rig.state.sv_seen.contains_key(&rig.sv), "the file is parsed again");
+        assert_eq!(rig.step("crossed").await.len(), 1);
+
+        let mut off = MilestoneRig::new("wow-milestone-off", 200, |c| c.uploads_enabled = false);
+        off.step("below").await;
+        assert!(off.step("crossed").await.is_empty(), "the uploads switch covers milestones");
+    }
+
+    #[test]
+    fn milestone_levels_default_to_the_branch_cap() {
+        let (mut cfg, _) = wow_with_sv("wow-milestone-cap", "");
+        let build_info = "Branch!STRING:0|Active!DEC:1|Version!STRING:0|Product!STRING:0\n\
+                          us|1|1.15.7.61582|wow_classic_era\nus|1|11.1.5.60392|wow\n";
+        fs::write(Path::new(&cfg.wow_root).join(".build.info"), build_info).unwrap();
+        for (branch, levels) in [("_retail_", vec![80]), ("_classic_era_", vec![60]), ("_classic_", vec![])] {
+            cfg.wow_branch = branch.into();
+            assert_eq!(milestone_levels(&cfg, &WowPaths::from_config(&cfg)), levels, "{branch}");
+        }
+        cfg.milestone_levels = vec![10, 20];
+        assert_eq!(milestone_levels(&cfg, &WowPaths::from_config(&cfg)), [10, 20]);
+        cfg.api_url = "https://deaths.example.com/api/".into();
+        assert_eq!(milestones_url(&cfg), "https://deaths.example.com/api/milestones");
+        cfg.milestones_url = "https://hooks.example.com/survivors".into();
+        assert_eq!(milestones_url(&cfg), "https://hooks.example.com/survivors");
+    }
+
+    // ---------- Upload signing ----------
+
+    // Expected values computed independently (Python's hmac/hashlib) over the
+    // documented string, so a change to the format shows up as a mismatch here.
+    const SIGNED_JSON: &[u8] = br#"{"at":1700000000,"player":"Quillon"}"#;
+
+    fn shot_hashes() -> Vec<String> {
+        [&b"first"[..], b"second"].iter().map(|b| format!("{:x}", Sha256::digest(b))).collect()
+    }
+
+    #[test]
+    fn signed_string_matches_the_documented_layout() {
+        assert_eq!(signed_string(1_700_000_000, SIGNED_JSON, &[]), [b"v1\n1700000000\n", SIGNED_JSON, b"\n"].concat());
+        assert_eq!(
+            String::from_utf8(signed_string(-5, b"{}", &shot_hashes())).unwrap(),
+            "v1\n-5\n{}\n\
+             a7937b64b8caa58f03721bb6bacf5c78cb235febe0e70b1b84cd99541461a08e,\
+             16367aacb67a4a017c8da8ab95682ccb390863780f7114dda0a0e0c55644c7c4"
+        );
+    }
+
+    #[test]
+    fn signatures_match_known_answers() {
+        let cases = [
+            ("s3cret", 1_700_000_000, SIGNED_JSON, vec![], "9321a2a0e086c24d518215b6fedb667cc94f7e2734bae3b4322a4f9550814da7"),
+            ("s3cret", 1_700_000_000, SIGNED_JSON, shot_hashes(), "acc286fbaa3d7479a99f60d752e3ac6711b324d75490c5ead986304721219969"),
+            ("✓ unicode key", 0, &b""[..], vec![], "6662697c6f8278d557ce3f53f04cd1d6877839df85772b8ac59bf014be5dfe67"),
+        ];
+        for (secret, ts, json, shots, hex) in cases {
+            assert_eq!(sign_upload(secret, ts, json, &shots), format!("v1={hex}"), "{secret} {ts}");
+        }
+        // Any change to the inputs changes the signature.
+        let base = sign_upload("s3cret", 1_700_000_000, SIGNED_JSON, &[]);
+        assert_ne!(sign_upload("s3cret!", 1_700_000_000, SIGNED_JSON, &[]), base);
+        assert_ne!(sign_upload("s3cret", 1_700_000_001, SIGNED_JSON, &[]), base);
+        assert_ne!(sign_upload("s3cret", 1_700_000_000, b"{}", &[]), base);
+        let mut swapped = shot_hashes();
+        swapped.reverse();
+        assert_ne!(sign_upload("s3cret", 0, b"{}", &swapped), sign_upload("s3cret", 0, b"{}", &shot_hashes()), "part order counts");
+    }
+
+    #[test]
+    fn ndjson_signature_covers_the_whole_body() {
+        let deaths = [death("First", 100), death("Second", 200), death("Third", 300)];
+        let body: Vec<u8> = deaths.iter().flat_map(|d| [canonical_text(&serde_json::to_vec(d).unwrap()), b"\n".to_vec()].concat()).collect();
+        assert_eq!(sign_ndjson("s3cret", 42, &deaths).unwrap(), sign_upload("s3cret", 42, &body, &[]));
+        assert_eq!(sign_ndjson("s3cret", 42, &[]).unwrap(), sign_upload("s3cret", 42, b"", &[]));
+    }
+
+    #[tokio::test]
+    async fn uploads_carry_a_signature_the_server_can_verify() {
+        scratch_dir();
+        let (url, seen) = mock_server(|_, _| (200, "{}".into()));
+        let unsigned = Config { api_url: format!("{url}/upload"), capture_last_requests: 5, ..Config::default() };
+        assert!(signature_headers(&unsigned, b"{}", &[]).is_empty(), "no secret, no headers");
+
+        let cfg = Config { hmac_secret: "s3cret".into(), ..unsigned };
+        let http = Http::new(&cfg).unwrap();
+        http.captured.as_ref().unwrap().lock().unwrap().clear();
+        let dir = shots_dir("signed-shots", &[("a.jpg", b"first"), ("b.jpg", b"second")]);
+        let shots = [dir.join("a.jpg"), dir.join("b.jpg")];
+        let shots: Vec<&Path> = shots.iter().map(|p| p.as_path()).collect();
+        let before = Utc::now().timestamp();
+        upload_once(&cfg, &http, &death("Signed", 1_700_000_000), "k", &shots).await.unwrap();
+
+        let headers = captured(&http)[0].headers.clone();
+        let ts: i64 = headers[&TIMESTAMP_HEADER.to_ascii_lowercase()].parse().unwrap();
+        assert!((before..=Utc::now().timestamp()).contains(&ts));
+        let parts = form_parts(&seen.lock().unwrap()[0].2);
+        let expected = sign_upload("s3cret", ts, &canonical_text(&parts[PART_DEATH]), &shot_hashes());
+        assert_eq!(headers[&SIGNATURE_HEADER.to_ascii_lowercase()], expected);
+    }
+
+    // ---------- Usage telemetry ----------
+
+    #[tokio::test]
+    async fn telemetry_sends_nothing_unless_opted_in() {
+        scratch_dir();
+        let (url, seen) = mock_server(|_, _| (200, String::new()));
+        let (mut cfg, _) = wow_with_sv("telemetry-off", "DeathLoggerDB = {}\n");
+        cfg.telemetry_url = format!("{url}/ping");
+        let wow = WowPaths::from_config(&cfg);
+        let http = Http::new(&cfg).unwrap();
+        let mut state = State::default();
+        maybe_send_telemetry(&cfg, &http, &wow, &mut state).await;
+        assert!(seen.lock().unwrap().is_empty());
+        assert!(state.telemetry.install_id.is_empty(), "no id is even made up");
+        assert_eq!(state.telemetry.last_sent_at, None);
+    }
+
+    #[tokio::test]
+    async fn telemetry_pings_once_a_day_with_counts_only() {
+        scratch_dir();
+        let fail = Arc::new(AtomicBool::new(true));
+        let failing = fail.clone();
+        let (url, seen) = mock_server(move |_, _| (if failing.load(Ordering::SeqCst) { 503 } else { 200 }, String::new()));
+        let (mut cfg, _) = wow_with_sv("telemetry-on", "DeathLoggerDB = {}\n");
+        cfg.telemetry = true;
+        cfg.telemetry_url = format!("{url}/ping");
+        cfg.api_url = "https://deaths.example/upload".into();
+        cfg.hmac_secret = "s3cret".into();
+        let wow = WowPaths::from_config(&cfg);
+        let http = Http::new(&cfg).unwrap();
+        let mut state = State::default();
+        let yesterday = Utc::now().date_naive() - chrono::Duration::days(1);
+        state.metrics.days.insert(yesterday, metrics::DayMetrics { deaths_uploaded: 3, parse_errors: 1, ..Default::default() });
+        state.last_uploaded.insert("ACC:Secretname@Realm".into(), 1_700_000_000);
+
+        // A failed ping is tried again on the next tick.
+        maybe_send_telemetry(&cfg, &http, &wow, &mut state).await;
+        assert_eq!(state.telemetry.last_sent_at, None);
+        fail.store(false, Ordering::SeqCst);
+        let before = Utc::now().timestamp();
+        maybe_send_telemetry(&cfg, &http, &wow, &mut state).await;
+        assert!(state.telemetry.last_sent_at.unwrap() >= before);
+        assert_eq!(seen.lock().unwrap().len(), 2);
+
+        let (method, path, body) = seen.lock().unwrap()[1].clone();
+        assert_eq!((method.as_str(), path.as_str()), ("POST", "/ping"));
+        let ping: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let fields: Vec<&str> = ping.as_object().unwrap().keys().map(String::as_str).collect();
+        assert_eq!(
+            fields,
+            ["addon_version", "agent_version", "branches", "day", "deaths_uploaded", "features", "install_id", "os", "parse_errors"]
+        );
+        let id = ping["install_id"].as_str().unwrap();
+        assert_eq!((id.len(), &id[14..15]), (36, "4"), "a random v4 UUID: {id}");
+        assert_eq!(id, state.telemetry.install_id);
+        assert_eq!(ping["day"], json!(yesterday.to_string()));
+        assert_eq!((ping["deaths_uploaded"].as_u64(), ping["parse_errors"].as_u64()), (Some(3), Some(1)));
+        assert_eq!(ping["agent_version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(ping["features"]["hmac_signing"], true);
+        assert!(ping["features"].as_object().unwrap().values().all(|v| v.is_boolean()));
+        let text = String::from_utf8(body).unwrap();
+        for private in ["Secretname", "Realm\"", "deaths.example", "s3cret", cfg.wow_root.as_str()] {
+            assert!(!text.contains(private), "{private} leaked: {text}");
+        }
+
+        // Not again within the day, then again once it has passed, under the same id.
+        maybe_send_telemetry(&cfg, &http, &wow, &mut state).await;
+        assert_eq!(seen.lock().unwrap().len(), 2);
+        state.telemetry.last_sent_at = Some(Utc::now().timestamp() - TELEMETRY_INTERVAL_SECS);
+        maybe_send_telemetry(&cfg, &http, &wow, &mut state).await;
+        assert_eq!(seen.lock().unwrap().len(), 3);
+        let again: serde_json::Value = serde_json::from_slice(&seen.lock().unwrap()[2].2).unwrap();
+        assert_eq!(again["install_id"], json!(id));
+    }
+
+    // ---------- Media pre-upload ----------
+
+    /// (path, media id the death referenced, whether the screenshot came inline) per request.
+    fn media_requests(seen: &Seen) -> Vec<(String, Option<String>, bool)> {
+        seen.lock()
+            .unwrap()
+            .iter()
+            .map(|(_, path, body)| {
+                if path != "/upload" {
+                    return (path.clone(), None, false);
+                }
+                let parts = form_parts(body);
+                let death: serde_json::Value = serde_json::from_slice(&parts[PART_DEATH]).unwrap();
+                (path.clone(), death["screenshot_media_id"].as_str().map(String::from), parts.contains_key(PART_SCREENSHOT))
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn media_is_uploaded_once_and_reused_on_retry() {
+        scratch_dir();
+        let uploads = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let count = uploads.clone();
+        let (url, seen) = mock_server(move |path, _| match path {
+            "/media" => (201, r#"{"media_id":"m-1"}"#.into()),
+            _ if count.fetch_add(1, Ordering::SeqCst) == 0 => (503, "busy".into()),
+            _ => (200, "{}".into()),
+        });
+        let cfg = Config { api_url: format!("{url}/upload"), media_url: format!("{url}/media"), ..Config::default() };
+        let http = Http::new(&cfg).unwrap();
+        let dir = shots_dir("media-retry", &[("death.jpg", b"\xFF\xD8 the picture \xFF\xD9")]);
+        let mut state = State::default();
+        state.retry_queue.push(RetryEntry { screenshot: Some(dir.join("death.jpg").to_string_lossy().to_string()), attempts: 0, ..queued("Media", 1_700_000_800) });
+
+        retry_due(&cfg, &http, &mut state).await;
+        assert_eq!(state.retry_queue.len(), 1, "the 503 queues it again");
+        state.retry_queue[0].next_at = 0;
+        retry_due(&cfg, &http, &mut state).await;
+        assert!(state.retry_queue.is_empty());
+
+        let m1 = Some("m-1".to_string());
+        assert_eq!(
+            media_requests(&seen),
+            [("/media".to_string(), None, false), ("/upload".to_string(), m1.clone(), false), ("/upload".to_string(), m1, false)],
+            "the retry reuses the id instead of sending the picture again"
+        );
+        assert_eq!(seen.lock().unwrap()[0].2, b"\xFF\xD8 the picture \xFF\xD9");
+        assert_eq!(state.media_ids.values().map(|m| m.media_id.as_str()).collect::<Vec<_>>(), ["m-1"]);
+    }
+
+    #[tokio::test]
+    async fn forgotten_media_is_uploaded_again_then_sent_inline() {
+        scratch_dir();
+        // "forget": every media id is unknown; "media down": the media endpoint fails.
+        let mode = Arc::new(Mutex::new("remember"));
+        let current = mode.clone();
+        let minted = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let (url, seen) = mock_server(move |path, body| {
+            let mode = *current.lock().unwrap();
+            match path {
+                "/media" if mode == "media down" => (500, String::new()),
+                "/media" => (200, format!(r#"{{"media_id":"m-{}"}}"#, minted.fetch_add(1, Ordering::SeqCst) + 1)),
+                _ if contains(body, "stale") || (mode == "forget" && contains(body, "screenshot_media_id")) => (404, "unknown media".into()),
+                _ => (200, "{}".into()),
+            }
+        });
+        let cfg = Config { api_url: format!("{url}/upload"), media_url: format!("{url}/media"), ..Config::default() };
+        let http = Http::new(&cfg).unwrap();
+        let dir = shots_dir("media-unknown", &[("death.jpg", b"\xFF\xD8 another picture \xFF\xD9")]);
+        let shot = dir.join("death.jpg");
+        let hash = format!("{:x}", Sha256::digest(fs::read(&shot).unwrap()));
+        let mut media_ids = BTreeMap::from([(hash.clone(), MediaRef { media_id: "stale".into(), uploaded_at: 0 })]);
+        let forgotten = death("Forgotten", 1_700_000_900);
+
+        // The server lost a cached id: the picture goes up again under a new one.
+        upload(&cfg, &http, &mut media_ids, &forgotten, "k", &[shot.as_path()]).await.unwrap();
+        assert_eq!(
+            media_requests(&seen),
+            [
+                ("/upload".to_string(), Some("stale".to_string()), false),
+                ("/media".to_string(), None, false),
+                ("/upload".to_string(), Some("m-1".to_string()), false),
+            ]
+        );
+        assert_eq!(media_ids[&hash].media_id, "m-1");
+
+        // Lost twice in a row: sent inline.
+        *mode.lock().unwrap() = "forget";
+        seen.lock().unwrap().clear();
+        upload(&cfg, &http, &mut media_ids, &forgotten, "k", &[shot.as_path()]).await.unwrap();
+        let steps: Vec<(String, bool)> = media_requests(&seen).into_iter().map(|(p, _, inline)| (p, inline)).collect();
+        assert_eq!(steps, [("/upload".into(), false), ("/media".into(), false), ("/upload".into(), false), ("/upload".into(), true)]);
+        assert!(!media_ids.contains_key(&hash));
+
+        // The media endpoint failing its attempts also means inline.
+        *mode.lock().unwrap() = "media down";
+        seen.lock().unwrap().clear();
+        upload(&cfg, &http, &mut media_ids, &forgotten, "k", &[shot.as_path()]).await.unwrap();
+        let steps: Vec<(String, bool)> = media_requests(&seen).into_iter().map(|(p, _, inline)| (p, inline)).collect();
+        assert_eq!(steps, [("/media".into(), false), ("/media".into(), false), ("/upload".into(), true)]);
+    }
+
+    // ---------- Screenshot freshness challenge ----------
+
+    /// An upload with a screenshot to a server whose challenge endpoint answers
+    /// with `challenge`, one answer after another; returns the upload result.
+    async fn challenged_upload(name: &str, challenge: Vec<(u16, &'static str)>, age_secs: u64) -> (Result<Option<UploadReceipt>>, Seen, Vec<CapturedRequest>, PathBuf) {
+        let answers = Arc::new(Mutex::new(VecDeque::from(challenge)));
+        let (url, seen) = mock_server(move |path, body| match path {
+            "/challenge" => {
+                let (status, reply) = answers.lock().unwrap().pop_front().unwrap_or((404, ""));
+                (status, reply.to_string())
+            }
+            _ if contains(body, "n-expired") => (403, r#"{"error":"challenge_expired"}"#.into()),
+            _ => (200, "{}".into()),
+        });
+        let cfg = Config {
+            api_url: format!("{url}/upload"),
+            screenshot_challenge_url: format!("{url}/challenge"),
+            capture_last_requests: 10,
+            ..Config::default()
+        };
+        let http = Http::new(&cfg).unwrap();
+        http.captured.as_ref().unwrap().lock().unwrap().clear();
+        let shot = shots_dir(name, &[("death.jpg", b"\xFF\xD8 challenged \xFF\xD9")]).join("death.jpg");
+        let taken = SystemTime::now() - Duration::from_secs(age_secs);
+        File::options().write(true).open(&shot).unwrap().set_modified(taken).unwrap();
+        let result = upload(&cfg, &http, &mut BTreeMap::new(), &death("Challenged", Utc::now().timestamp() - 30), "k", &[shot.as_path()]).await;
+        let sent = captured(&http);
+        (result, seen, sent, shot)
+    }
+
+    #[tokio::test]
+    async fn a_challenge_nonce_travels_with_the_screenshot() {
+        scratch_dir();
+        let (result, seen, sent, shot) = challenged_upload("challenge-ok", vec![(200, r#"{"nonce":"n-1","max_age_secs":3600}"#)], 60).await;
+        result.unwrap();
+        assert_eq!(paths(&seen), ["/challenge", "/upload"]);
+        assert_eq!(sent.last().unwrap().headers[&CHALLENGE_HEADER.to_ascii_lowercase()], "n-1");
+        let parts = form_parts(&seen.lock().unwrap()[1].2);
+        assert_eq!(parts[PART_SCREENSHOT_CHALLENGE], b"n-1");
+        assert!(parts.contains_key(PART_SCREENSHOT));
+        let meta: serde_json::Value = serde_json::from_slice(&parts[PART_SCREENSHOT_META]).unwrap();
+        assert_eq!(meta["sha256"], json!(format!("{:x}", Sha256::digest(fs::read(&shot).unwrap()))));
+        assert!((Utc::now().timestamp() - 61..=Utc::now().timestamp() - 59).contains(&meta["modified"].as_i64().unwrap()));
+        assert!((30..=32).contains(&meta["death_to_upload_secs"].as_i64().unwrap()), "{meta}");
+
+        // Older than the server's max_age_secs: the death goes without it, still with the nonce.
+        let (result, seen, _, _) = challenged_upload("challenge-old", vec![(200, r#"{"nonce":"n-2","max_age_secs":3600}"#)], 7200).await;
+        result.unwrap();
+        let parts = form_parts(&seen.lock().unwrap()[1].2);
+        assert_eq!(parts[PART_SCREENSHOT_CHALLENGE], b"n-2");
+        assert!(!parts.contains_key(PART_SCREENSHOT) && !parts.contains_key(PART_SCREENSHOT_META));
+    }
+
+    #[tokio::test]
+    async fn an_expired_nonce_is_replaced_once() {
+        scratch_dir();
+        let fresh = vec![(200, r#"{"nonce":"n-expired"}"#), (200, r#"{"nonce":"n-fresh"}"#)];
+        let (result, seen, _, _) = challenged_upload("challenge-expired", fresh, 10).await;
+        result.unwrap();
+        assert_eq!(paths(&seen), ["/challenge", "/upload", "/challenge", "/upload"]);
+        assert_eq!(form_parts(&seen.lock().unwrap()[3].2)[PART_SCREENSHOT_CHALLENGE], b"n-fresh");
+
+        // Expired again: that answer stands rather than looping.
+        let stale = vec![(200, r#"{"nonce":"n-expired"}"#), (200, r#"{"nonce":"n-expired"}"#)];
+        let (result, seen, _, _) = challenged_upload("challenge-expired-twice", stale, 10).await;
+        assert!(is_permanent_failure(&result.unwrap_err()));
+        assert_eq!(paths(&seen).len(), 4);
+    }
+
+    #[tokio::test]
+    async fn servers_without_challenges_get_plain_uploads() {
+        scratch_dir();
+        let (result, seen, sent, _) = challenged_upload("challenge-missing", vec![], 10).await;
+        result.unwrap();
+        assert_eq!(paths(&seen), ["/challenge", "/challenge", "/upload"], "tried twice, then without");
+        let parts = form_parts(&seen.lock().unwrap()[2].2);
+        assert!(parts.contains_key(PART_SCREENSHOT));
+        assert!(!parts.contains_key(PART_SCREENSHOT_CHALLENGE) && !parts.contains_key(PART_SCREENSHOT_META));
+        assert!(!sent.last().unwrap().headers.contains_key(&CHALLENGE_HEADER.to_ascii_lowercase()));
+    }
+
+    // ---------- Compressed uploads ----------
+
+    /// The death part as the server would read it, unzipped when it came gzipped.
+    fn death_json(body: &[u8]) -> serde_json::Value {
+        let mut part = form_parts(body).remove(PART_DEATH).unwrap();
+        if contains(body, "content-encoding: gzip") {
+            let mut plain = vec![];
+            flate2::read::GzDecoder::new(&part[..]).read_to_end(&mut plain).unwrap();
+            part = plain;
+        }
+        let mut v: serde_json::Value = serde_json::from_slice(&part).unwrap();
+        v["agent"]["uploaded_at"] = json!(0);
+        v
+    }
+
+    #[tokio::test]
+    async fn a_gzipped_death_unzips_to_the_plain_json() {
+        scratch_dir();
+        let (url, seen) = mock_server(|_, _| (200, "{}".into()));
+        let mut cfg = Config { api_url: format!("{url}/upload"), compress_uploads: true, ..Config::default() };
+        let d = DeathPayload { class: Some("Warrior".into()), ..death("Zipped", 1_700_000_000) };
+        upload(&cfg, &Http::new(&cfg).unwrap(), &mut BTreeMap::new(), &d, "k", &[]).await.unwrap();
+        cfg.compress_uploads = false;
+        upload(&cfg, &Http::new(&cfg).unwrap(), &mut BTreeMap::new(), &d, "k", &[]).await.unwrap();
+
+        let seen = seen.lock().unwrap();
+        let (zipped, plain) = (&seen[0].2, &seen[1].2);
+        assert!(contains(zipped, "content-encoding: gzip") && !contains(zipped, "Warrior"));
+        assert!(!contains(plain, "content-encoding: gzip"));
+        assert_eq!(death_json(zipped), death_json(plain));
+        assert_eq!(death_json(zipped)["class"], "Warrior");
+    }
+
+    #[tokio::test]
+    async fn a_server_refusing_gzip_gets_plain_json_from_then_on() {
+        scratch_dir();
+        let (url, seen) = mock_server(|_, body| if contains(body, "content-encoding: gzip") { (415, String::new()) } else { (200, "{}".into()) });
+        let cfg = Config { api_url: format!("{url}/upload"), compress_uploads: true, ..Config::default() };
+        let http = Http::new(&cfg).unwrap();
+        upload(&cfg, &http, &mut BTreeMap::new(), &death("Refused", 1_700_000_000), "k1", &[]).await.unwrap();
+        upload(&cfg, &http, &mut BTreeMap::new(), &death("Refused", 1_700_000_100), "k2", &[]).await.unwrap();
+
+        let gzipped: Vec<bool> = seen.lock().unwrap().iter().map(|(_, _, b)| contains(b, "content-encoding: gzip")).collect();
+        assert_eq!(gzipped, [true, false, false], "resent plain, then plain for the session");
+    }
+
+    // ---------- Proxy ----------
+
+    #[tokio::test]
+    async fn uploads_go_through_the_configured_proxy() {
+        scratch_dir();
+        // A plain HTTP proxy is asked for the absolute URL; the mock answers for the origin.
+        let (proxy, seen) = mock_server(|_, _| (200, "{}".into()));
+        let cfg = Config {
+            api_url: "http://deaths.invalid/api/deaths".into(),
+            proxy_url: proxy,
+            ..Config::default()
+        };
+        let http = Http::new(&cfg).unwrap();
+        let shot = shots_dir("proxied", &[("death.jpg", b"\xFF\xD8 proxied \xFF\xD9")]).join("death.jpg");
+        upload(&cfg, &http, &mut BTreeMap::new(), &death("Proxied", 1_700_000_000), "k", &[shot.as_path()]).await.unwrap();
+
+        let seen = seen.lock().unwrap();
+        let [(method, path, body)] = &seen[..] else { panic!("{} requests through the proxy, wanted one", seen.len()) };
+        assert_eq!((method.as_str(), path.as_str()), ("POST", "http://deaths.invalid/api/deaths"));
+        let parts = form_parts(body);
+        assert!(contains(&parts[PART_DEATH], "Proxied"));
+        assert_eq!(parts[PART_SCREENSHOT], b"\xFF\xD8 proxied \xFF\xD9");
+    }
+
+    // ---------- Idempotency keys ----------
+
+    #[test]
+    fn idempotency_key_covers_identity_fields_only() {
+        let base = death("Keyed", 1_700_000_500);
+        let key = idempotency_key(&base);
+        assert_eq!(key.len(), 64);
+        let mut noted = base.clone();
+        noted.note = Some("should have rolled need".into());
+        noted.level = Some(70);
+        assert_eq!(idempotency_key(&noted), key, "notes and stats don't change the key");
+        for change in [
+            |d: &mut DeathPayload| d.player = "Other".into(),
+            |d: &mut DeathPayload| d.realm = "Elsewhere".into(),
+            |d: &mut DeathPayload| d.at += 1,
+            |d: &mut DeathPayload| d.killer = Killer::Raw(json!("Hogger")),
+        ] {
+            let mut changed = base.clone();
+            change(&mut changed);
+            assert_ne!(idempotency_key(&changed), key, "{changed:?}");
+        }
+    }
+
+    fn idempotency_headers(http: &Http) -> Vec<String> {
+        captured(http).into_iter().map(|r| r.headers["idempotency-key"].clone()).collect()
+    }
+
+    #[tokio::test]
+    async fn retries_across_a_restart_reuse_the_key_and_409_counts_as_stored() {
+        scratch_dir();
+        let status = Arc::new(std::sync::atomic::AtomicU16::new(503));
+        let answer = status.clone();
+        let (url, seen) = mock_server(move |_, _| (answer.load(std::sync::atomic::Ordering::SeqCst), "{}".into()));
+        let cfg = Config { api_url: format!("{url}/upload"), capture_last_requests: 5, ..Config::default() };
+        let fresh_http = || {
+            let http = Http::new(&cfg).unwrap();
+            http.captured.as_ref().unwrap().lock().unwrap().clear();
+            http
+        };
+
+        let dying = death("Keyed", 1_700_000_500);
+        let (char_key, expected) = (dying.key(), idempotency_key(&dying));
+        let mut state = State::default();
+        let http = fresh_http();
+        process_death(&cfg, &http, &mut state, dying, &[]).await.unwrap();
+        assert_eq!(state.retry_queue.len(), 1, "a 503 is retried");
+        let stored = &state.idempotency_keys[&char_key];
+        assert_eq!((stored.at, stored.key.as_str()), (1_700_000_500, expected.as_str()));
+        assert_eq!(idempotency_headers(&http), std::slice::from_ref(&expected));
+
+        // Restart: state comes back from disk, the client is new, and a remap
+        // rule added meanwhile changed the queued killer.
+        let mut state: State = serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+        state.retry_queue[0].next_at = 0;
+        state.retry_queue[0].death.killer = Killer::Raw(json!("Renamed by a new rule"));
+        status.store(409, std::sync::atomic::Ordering::SeqCst);
+        let http = fresh_http();
+        retry_due(&cfg, &http, &mut state).await;
+        assert_eq!(idempotency_headers(&http), [expected], "the server sees the same key");
+        assert!(state.retry_queue.is_empty() && state.failed_uploads.is_empty(), "409 is success");
+        assert_eq!(state.last_uploaded.get(&char_key), Some(&1_700_000_500));
+        assert_eq!(seen.lock().unwrap().len(), 2);
+
+        prune_idempotency_keys(&mut state, &char_key);
+        assert!(!state.idempotency_keys.contains_key(&char_key), "acknowledged keys are forgotten");
+    }
+
+    // ---------- Chat blurring ----------
+
+    /// A 1px black/white checkerboard PNG: any averaging leaves grey behind.
+    fn checkerboard(name: &str, width: u32, height: u32) -> PathBuf {
+        let img = image::RgbImage::from_fn(width, height, |x, y| image::Rgb([((x + y) % 2 * 255) as u8; 3]));
+        let dir = shots_dir(name, &[]);
+        let path = dir.join("board.png");
+        img.save(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn blur_changes_only_its_regions_at_any_aspect_ratio() {
+        let regions = vec![BlurRegion { x: 0.0, y: 55.0, w: 38.0, h: 38.0 }, BlurRegion { x: 90.0, y: 0.0, w: 25.0, h: 5.0 }];
+        for (width, height) in [(1600, 900), (1280, 1024)] {
+            let path = checkerboard(&format!("blur-{width}x{height}"), width, height);
+            let on_disk = fs::read(&path).unwrap();
+            let cfg = Config { blur_chat_region: true, blur_regions: regions.clone(), ..Config::default() };
+            let shot = read_screenshot(&cfg, &path).unwrap();
+            assert_eq!((shot.file_name.as_str(), shot.content_type), ("board.png", "image/png"));
+            assert_eq!(fs::read(&path).unwrap(), on_disk, "the original is untouched");
+
+            let out = image::load_from_memory(&shot.bytes).unwrap().to_rgb8();
+            assert_eq!(out.dimensions(), (width, height));
+            let rects: Vec<_> = regions.iter().map(|r| r.to_pixels(width, height)).collect();
+            // The second region runs off the right edge and is clamped to it.
+            assert_eq!(rects[1].0 + rects[1].2, width);
+            let inside = |x: u32, y: u32| rects.iter().any(|&(rx, ry, rw, rh)| (rx..rx + rw).contains(&x) && (ry..ry + rh).contains(&y));
+            let (mut blurred, mut kept) = (0, 0);
+            for (x, y, pixel) in out.enumerate_pixels() {
+                let original = ((x + y) % 2 * 255) as u8;
+                if inside(x, y) {
+                    assert!(pixel.0[0] != 0 && pixel.0[0] != 255, "{width}x{height}: ({x}, {y}) still readable");
+                    blurred += 1;
+                } else {
+                    assert_eq!(pixel.0, [original; 3], "{width}x{height}: ({x}, {y}) changed outside the regions");
+                    kept += 1;
+                }
+            }
+            let area: u32 = rects.iter().map(|r| r.2 * r.3).sum();
+            assert_eq!((blurred, blurred + kept), (area, width * height));
+        }
+    }
+
+    #[test]
+    fn blur_regions_scale_with_the_image() {
+        let chat = &default_blur_regions()[0];
+        assert_eq!(chat.to_pixels(1920, 1080), (0, 594, 730, 410));
+        assert_eq!(chat.to_pixels(3440, 1440), (0, 792, 1307, 547));
+        assert_eq!(BlurRegion { x: -10.0, y: 95.0, w: 50.0, h: 50.0 }.to_pixels(100, 100), (0, 95, 40, 5));
+    }
+
+    #[test]
+    fn blurring_fails_rather_than_sending_the_original() {
+        let dir = shots_dir("blur-undecodable", &[("broken.png", b"not really a png")]);
+        let blurring = Config { blur_chat_region: true, ..Config::default() };
+        let err = read_screenshot(&blurring, &dir.join("broken.png")).err().unwrap();
+        assert!(format!("{err:#}").contains("blurring"), "{err:#}");
+        // Without blurring the file goes out as it is.
+        let shot = read_screenshot(&Config { screenshot_recompress: true, ..Config::default() }, &dir.join("broken.png")).unwrap();
+        assert_eq!(shot.bytes, b"not really a png");
+        // An empty region list means nothing to hide.
+        let shot = read_screenshot(&Config { blur_regions: vec![], ..blurring }, &dir.join("broken.png")).unwrap();
+        assert_eq!(shot.bytes, b"not really a png");
+    }
+
+    #[test]
+    fn reencoded_screenshots_are_cached_per_settings() {
+        let path = checkerboard("cache-hit", 800, 600);
+        let source = format!("{:x}", Sha256::digest(fs::read(&path).unwrap()));
+        let cfg = Config { screenshot_recompress: true, screenshot_quality: 70, ..Config::default() };
+        let first = read_screenshot(&cfg, &path).unwrap();
+        let key = shotcache::key(&source, &screenshot_cache_params(&cfg, false));
+        let (cached, ext) = shotcache::shared().unwrap().get(&key).unwrap();
+        assert_eq!((cached, ext.as_str()), (first.bytes.clone(), "jpg"));
+        let again = read_screenshot(&cfg, &path).unwrap();
+        assert_eq!((again.file_name, again.bytes, again.content_type), ("board.jpg".to_string(), first.bytes, "image/jpeg"));
+
+        // Other settings are another entry; 0 turns the cache off.
+        let other = Config { screenshot_quality: 71, ..cfg.clone() };
+        assert!(shotcache::shared().unwrap().get(&shotcache::key(&source, &screenshot_cache_params(&other, false))).is_none());
+        let off = Config { screenshot_cache_max_mb: 0, ..other.clone() };
+        read_screenshot(&off, &path).unwrap();
+        assert!(shotcache::shared().unwrap().get(&shotcache::key(&source, &screenshot_cache_params(&other, false))).is_none());
+        let blurred = Config { blur_chat_region: true, ..cfg.clone() };
+        assert_ne!(screenshot_cache_params(&blurred, true), screenshot_cache_params(&cfg, false));
+    }
+
+    // ---------- Upload rate limit ----------
+
+    #[test]
+    fn rate_limiter_allows_a_burst_then_spaces_uploads() {
+        let limiter = RateLimiter::new(3);
+        for _ in 0..3 {
+            assert_eq!(limiter.check(true), Duration::ZERO);
+        }
+        let wait = limiter.check(false);
+        assert!(wait > Duration::from_secs(19) && wait <= Duration::from_secs(20), "{wait:?}");
+        assert!(limiter.check(true) > Duration::from_secs(19), "an empty bucket hands out nothing");
+
+        // 6000 a minute is one token every 10ms.
+        let fast = RateLimiter::new(6000);
+        fast.bucket.lock().unwrap().0 = 0.0;
+        assert!(!fast.check(false).is_zero());
+        assert!(!fast.check(false).is_zero(), "checking doesn't use up time");
+        std::thread::sleep(Duration::from_millis(25));
+        assert_eq!(fast.check(true), Duration::ZERO);
+        // Refills never go past a minute's worth.
+        fast.bucket.lock().unwrap().1 -= Duration::from_secs(3600);
+        fast.check(false);
+        assert_eq!(fast.bucket.lock().unwrap().0, 6000.0);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_deaths_wait_in_the_queue_without_costing_attempts() {
+        scratch_dir();
+        let (url, seen) = mock_server(|_, _| (200, "{}".into()));
+        let cfg = Config { api_url: format!("{url}/upload"), max_uploads_per_minute: 2, ..Config::default() };
+        let http = Http::new(&cfg).unwrap();
+        let mut state = State::default();
+        let before = Utc::now().timestamp();
+        for player in ["Quick", "Quicker", "Held"] {
+            process_death(&cfg, &http, &mut state, death(player, 1_700_000_600), &[]).await.unwrap();
+        }
+        assert_eq!(seen.lock().unwrap().len(), 2, "the burst goes out, the third waits");
+        assert!(state.failed_uploads.is_empty());
+        let held = &state.retry_queue[..];
+        assert_eq!(held.len(), 1);
+        assert_eq!((held[0].death.player.as_str(), held[0].attempts), ("Held", 0));
+        assert!((before + 29..=Utc::now().timestamp() + 30).contains(&held[0].next_at), "{}", held[0].next_at);
+        assert!(held[0].last_error.contains("upload rate limit"), "{}", held[0].last_error);
+
+        // Due early, it is held again rather than sent or counted as a failure.
+        state.retry_queue[0].next_at = 0;
+        retry_due(&cfg, &http, &mut state).await;
+        assert_eq!(seen.lock().unwrap().len(), 2);
+        assert_eq!(state.retry_queue[0].attempts, 0);
+        assert!(state.retry_queue[0].next_at >= before + 29);
+        assert!(!state.last_uploaded.contains_key(&death("Held", 0).key()));
+    }
+
+    #[test]
+    fn retry_after_accepts_seconds_and_http_dates() {
+        let now = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        assert_eq!(parse_retry_after("120", now), Some(120));
+        assert_eq!(parse_retry_after(" 0 ", now), Some(0));
+        assert_eq!(parse_retry_after("Fri, 01 Mar 2024 12:05:00 GMT", now), Some(300), "a date ahead");
+        assert_eq!(parse_retry_after("Fri, 01 Mar 2024 11:00:00 GMT", now), Some(0), "a date already past means now");
+        assert_eq!(parse_retry_after(&MAX_RETRY_AFTER_SECS.to_string(), now), Some(MAX_RETRY_AFTER_SECS));
+        assert_eq!(parse_retry_after(&(MAX_RETRY_AFTER_SECS + 1).to_string(), now), None, "too long");
+        assert_eq!(parse_retry_after("Sat, 02 Mar 2024 12:00:00 GMT", now), None, "a day ahead is too long");
+        for garbage in ["", "soon", "-5", "1.5", "120s", "Fri, 31 Feb 2024 12:00:00 GMT"] {
+            assert_eq!(parse_retry_after(garbage, now), None, "{garbage:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn a_server_retry_after_replaces_the_backoff() {
+        scratch_dir();
+        let answers = Arc::new(Mutex::new(VecDeque::from([
+            (429, "120".to_string()),
+            (503, httpdate(Utc::now() + chrono::Duration::seconds(600))),
+            (429, "whenever".to_string()),
+            (429, (MAX_RETRY_AFTER_SECS * 2).to_string()),
+        ])));
+        let next = answers.clone();
+        let (url, seen) = mock_server_with_headers(move |_, _| {
+            let (status, wait) = next.lock().unwrap().pop_front().unwrap_or((200, String::new()));
+            (status, vec![("Retry-After", wait)], "busy".into())
+        });
+        let cfg = Config { api_url: format!("{url}/upload"), ..Config::default() };
+        let mut waits = vec![];
+        for player in ["Seconds", "Date", "Garbage", "TooLong"] {
+            // A fresh client each time, so the first answer's pause doesn't hold the rest back.
+            let http = Http::new(&cfg).unwrap();
+            let mut state = State::default();
+            let before = Utc::now().timestamp();
+            process_death(&cfg, &http, &mut state, death(player, 1_700_000_700), &[]).await.unwrap();
+            let queued = &state.retry_queue[0];
+            assert_eq!(queued.attempts, 1, "{player}");
+            waits.push(queued.next_at - before);
+        }
+        assert_eq!(seen.lock().unwrap().len(), 4);
+        // Waits count from the answer, and a second may tick over after `before`.
+        assert!((119..=121).contains(&waits[0]), "{waits:?}");
+        assert!((598..=601).contains(&waits[1]), "{waits:?}");
+        let backoff = retry_delay_secs(1);
+        assert!((backoff - 1..=backoff + 1).contains(&waits[2]), "unparseable falls back to the backoff: {waits:?}");
+        assert!((backoff - 1..=backoff + 1).contains(&waits[3]), "too long falls back to the backoff: {waits:?}");
+    }
+
+    fn httpdate(at: DateTime<Utc>) -> String {
+        at.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+    }
+
+    // ---------- Upload receipts ----------
+
+    #[test]
+    fn receipts_come_from_json_bodies_only() {
+        let receipt = |id: Option<&str>, url: Option<&str>, duplicate| {
+            Some(UploadReceipt { id: id.map(Into::into), url: url.map(Into::into), duplicate, bags_base_missing: false })
+        };
+        let ok = StatusCode::OK;
+        let cases = [
+            (ok, r#"{"id":"d-1","url":"https://deaths.example.com/d-1"}"#, receipt(Some("d-1"), Some("https://deaths.example.com/d-1"), false)),
+            (ok, r#"{"id":42,"extra":[1,2]}"#, receipt(Some("42"), None, false)),
+            (ok, r#"{"id":"","url":null}"#, None),
+            (ok, r#"{"url":"https://deaths.example.com/x","duplicate":true}"#, receipt(None, Some("https://deaths.example.com/x"), true)),
+            (ok, r#"{"duplicate":"yes"}"#, None),
+            (ok, "", None),
+            (ok, "OK", None),
+            (ok, "<html>saved</html>", None),
+            (ok, "[1,2,3]", None),
+            (StatusCode::CREATED, r#"{"id":"d-2"}"#, receipt(Some("d-2"), None, false)),
+            (StatusCode::CONFLICT, "", receipt(None, None, true)),
+            (StatusCode::CONFLICT, r#"{"id":"d-1"}"#, receipt(Some("d-1"), None, true)),
+        ];
+        for (status, body, expected) in cases {
+            assert_eq!(parse_receipt(status, body), expected, "{status} {body}");
+        }
+    }
+
+    #[tokio::test]
+    async fn returned_ids_are_kept_per_death_and_plain_answers_still_work() {
+        scratch_dir();
+        let (url, _) = mock_server(|_, body| {
+            if contains(body, "Plain") {
+                (200, "thanks".into())
+            } else {
+                (200, r#"{"id":"d-77","url":"https://deaths.example.com/d/77"}"#.into())
+            }
+        });
+        let cfg = Config { api_url: format!("{url}/upload"), ..Config::default() };
+        let http = Http::new(&cfg).unwrap();
+        let mut state = State::default();
+        let (page, plain) = (death("Paged", 1_700_000_700), death("Plain", 1_700_000_700));
+        process_death(&cfg, &http, &mut state, page.clone(), &[]).await.unwrap();
+        process_death(&cfg, &http, &mut state, plain.clone(), &[]).await.unwrap();
+
+        assert_eq!(state.last_uploaded.get(&plain.key()), Some(&1_700_000_700), "a non-JSON answer is still a success");
+        assert_eq!(state.upload_history.keys().collect::<Vec<_>>(), [&death_ref(&page.key(), 1_700_000_700)]);
+        let state: State = serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+        let records = &state.upload_history[&death_ref(&page.key(), 1_700_000_700)];
+        assert_eq!(records.len(), 1);
+        assert_eq!((records[0].id.as_deref(), records[0].url.as_deref()), (Some("d-77"), Some("https://deaths.example.com/d/77")));
+        assert_eq!(records[0].target, upload_targets(&cfg)[0]);
+    }
+
+    #[test]
+    fn upload_history_keeps_one_record_per_target_and_drops_the_oldest_deaths() {
+        let mut state = State::default();
+        let receipt = |id: &str| UploadReceipt { id: Some(id.into()), ..UploadReceipt::default() };
+        record_receipt(&mut state, "A@Realm", 1, "api", &receipt("first"));
+        record_receipt(&mut state, "A@Realm", 1, "api", &receipt("again"));
+        record_receipt(&mut state, "A@Realm", 1, "mirror", &receipt("m"));
+        let ids: Vec<_> = state.upload_history["A@Realm#1"].iter().map(|r| r.id.clone().unwrap()).collect();
+        assert_eq!(ids, ["again", "m"]);
+
+        for r in state.upload_history.values_mut().flatten() {
+            r.uploaded_at = 0;
+        }
+        for at in 2..=MAX_UPLOAD_HISTORY as i64 + 1 {
+            record_receipt(&mut state, "B@Realm", at, "api", &receipt("b"));
+        }
+        assert_eq!(state.upload_history.len(), MAX_UPLOAD_HISTORY);
+        assert!(!state.upload_history.contains_key("A@Realm#1"), "the least recently uploaded death goes first");
+    }
+
+    // ---------- Upload formats ----------
+
+    async fn upload_as(format: UploadFormat, embed: bool, name: &str) -> (String, Vec<u8>, DeathPayload) {
+        scratch_dir();
+        let (url, seen) = mock_server(|_, _| (200, "{}".into()));
+        let cfg = Config {
+            api_url: format!("{url}/upload"),
+            upload_format: format,
+            embed_screenshot_base64: embed,
+            capture_last_requests: 5,
+            ..Config::default()
+        };
+        let http = Http::new(&cfg).unwrap();
+        http.captured.as_ref().unwrap().lock().unwrap().clear();
+        let shot = shots_dir(name, &[("death.jpg", b"JPEG-OF-THE-FALL")]).join("death.jpg");
+        let mut dead = death("Formatted", 1_700_000_800);
+        dead.extra.insert("guild".into(), json!("Format Guild"));
+        upload_once(&cfg, &http, &dead, "k", &[shot.as_path()]).await.unwrap();
+        let content_type = captured(&http)[0].headers["content-type"].clone();
+        let (method, path, body) = seen.lock().unwrap()[0].clone();
+        assert_eq!((method.as_str(), path.as_str()), ("POST", "/upload"));
+        (content_type, body, dead)
+    }
+
+    #[tokio::test]
+    async fn json_format_posts_the_death_as_the_body() {
+        let (content_type, body, dead) = upload_as(UploadFormat::Json, false, "format-json").await;
+        assert_eq!(content_type, "application/json");
+        let sent: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(sent, serde_json::to_value(&dead).unwrap(), "exactly the death, no screenshot fields");
+    }
+
+    #[tokio::test]
+    async fn json_format_can_embed_the_screenshot() {
+        let (content_type, body, dead) = upload_as(UploadFormat::Json, true, "format-json-embed").await;
+        assert_eq!(content_type, "application/json");
+        let mut sent: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let fields = sent.as_object_mut().unwrap();
+        assert_eq!(BASE64.decode(fields.remove("screenshot").unwrap().as_str().unwrap()).unwrap(), b"JPEG-OF-THE-FALL");
+        assert_eq!(fields.remove("screenshot_filename"), Some(json!("death.jpg")));
+        assert_eq!(fields.remove("screenshot_content_type"), Some(json!("image/jpeg")));
+        assert_eq!(sent, serde_json::to_value(&dead).unwrap(), "the rest is the death unchanged");
+    }
+
+    #[tokio::test]
+    async fn multipart_format_sends_death_and_screenshot_parts() {
+        let (content_type, body, dead) = upload_as(UploadFormat::Multipart, true, "format-multipart").await;
+        assert!(content_type.starts_with("multipart/form-data; boundary="), "{content_type}");
+        let parts = form_parts(&body);
+        assert_eq!(parts.keys().collect::<Vec<_>>(), [PART_DEATH, PART_SCREENSHOT], "embed_screenshot_base64 is ignored here");
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&parts[PART_DEATH]).unwrap(), serde_json::to_value(&dead).unwrap());
+        assert_eq!(parts[PART_SCREENSHOT], b"JPEG-OF-THE-FALL");
+    }
+
+    // ---------- SavedVariables layouts ----------
+
+    const ACCOUNT_LEVEL: &str = "WTF/Account/ACC/SavedVariables/DeathLogger.lua";
+    const CHARACTER_LEVEL: &str = "WTF/Account/ACC/Firemaw/Brakka/SavedVariables/DeathLogger.lua";
+    /// Files the agent must not pick up: wrong depth, wrong name, a backup.
+    const NOT_SV: &[&str] = &[
+        "WTF/Account/ACC/Firemaw/SavedVariables/DeathLogger.lua",
+        "WTF/Account/ACC/Firemaw/Brakka/Pets/SavedVariables/DeathLogger.lua",
+        "WTF/Account/ACC/SavedVariables/DeathLogger.lua.bak",
+        "WTF/Account/ACC/SavedVariables/Other.lua",
+        "WTF/SavedVariables/DeathLogger.lua",
+    ];
+
+    /// A WoW install with `files` copied from `layouts/<fixture>.lua` plus the
+    /// `NOT_SV` decoys; returns the config and the root of `_retail_`.
+    fn sv_layout_install(name: &str, files: &[(&str, &str)]) -> (Config, PathBuf) {
+        let root = scratch_dir().join(name);
+        fs::remove_dir_all(&root).ok();
+        let branch = root.join("_retail_");
+        let decoys = NOT_SV.iter().map(|rel| (*rel, "account"));
+        for (rel, fixture_name) in files.iter().copied().chain(decoys) {
+            let path = branch.join(rel);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::copy(fixture(&format!("layouts/{fixture_name}.lua")), path).unwrap();
+        }
+        (Config { wow_root: root.to_string_lossy().to_string(), ..Config::default() }, branch)
+    }
+
+    #[test]
+    fn sv_files_are_found_at_account_and_character_level() {
+        let installs: [(&str, &[(&str, &str)]); 3] = [
+            ("layout-account", &[(ACCOUNT_LEVEL, "account")]),
+            ("layout-character", &[(CHARACTER_LEVEL, "character")]),
+            ("layout-mixed", &[(ACCOUNT_LEVEL, "account"), (CHARACTER_LEVEL, "character")]),
+        ];
+        for (name, files) in installs {
+            let (cfg, branch) = sv_layout_install(name, files);
+            let wow = WowPaths::from_config(&cfg);
+            let mut expected: Vec<PathBuf> = files.iter().map(|(rel, _)| branch.join(rel)).collect();
+            expected.sort();
+            assert_eq!(account_sv_paths(&wow), expected, "{name}");
+            for (rel, _) in files {
+                assert!(wow.is_sv_file(&branch.join(rel)), "{name}: {rel}");
+            }
+            for rel in NOT_SV {
+                assert!(!wow.is_sv_file(&branch.join(rel)), "{name}: {rel}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn deaths_upload_from_both_layouts_in_one_install() {
+        let (url, seen) = mock_server(|_, _| (200, "{}".into()));
+        let (mut cfg, _) = sv_layout_install("layout-upload", &[(ACCOUNT_LEVEL, "account"), (CHARACTER_LEVEL, "character")]);
+        cfg.api_url = format!("{url}/upload");
+        let http = Http::new(&cfg).unwrap();
+        let wow = WowPaths::from_config(&cfg);
+        let mut state = State::default();
+        for sv in account_sv_paths(&wow) {
+            handle_sv_change(&cfg, &http, &wow, &mut state, &sv).await.unwrap();
+        }
+        assert_eq!(seen.lock().unwrap().len(), 2);
+        let marks: Vec<(&str, i64)> = state.last_uploaded.iter().map(|(k, at)| (k.as_str(), *at)).collect();
+        assert_eq!(marks, [("ACC:Brakka@Firemaw", 1_700_000_200), ("ACC:Quillon@Firemaw", 1_700_000_100)]);
+    }
+
+    // ---------- Data parser fallback ----------
+
+    /// `path` read by the Lua interpreter and by the data parser, with history.
+    fn both_parsers(path: &Path) -> (SvSnapshot, SvSnapshot) {
+        let cfg = Config::default();
+        let adapter = DeathLoggerAdapter { file_name: cfg.sv_file_name.clone(), fmt: SvFormat::from_config(&cfg) };
+        let marks = SvMarks { uploaded: BTreeMap::new(), cursor: None };
+        let content = String::from_utf8_lossy(&fs::read(path).unwrap()).to_string();
+        let lua = Lua::new();
+        let by_lua = evaluate_sv(&lua, &content, path, &adapter.fmt, &adapter, Some(&marks)).unwrap();
+        let mut globals = svdata::parse_globals(&content).unwrap();
+        let db = adapter.fmt.globals.iter().find_map(|name| globals.remove(name)?.into_table()).unwrap();
+        (by_lua, adapter.extract(&db, path, Some(&marks)))
+    }
+
+    #[test]
+    fn data_parser_reads_sv_samples_like_lua() {
+        let samples = [
+            "sv/retail_melee.lua",
+            "sv/classic_history.lua",
+            "sv/WTF/Account/ANON1234/Firemaw/Quillon/SavedVariables/DeathLogger.lua",
+            "unknown_fields.lua",
+            "reset/before.lua",
+            "milestones/crossed.lua",
+            "identity/embedded_realm.lua",
+            "keys/string.lua",
+            "keys/mixed.lua",
+            "nested/characters.lua",
+            "numbers/bags.lua",
+        ];
+        for sample in samples {
+            let (by_lua, by_data) = both_parsers(&fixture(sample));
+            assert!(by_lua.count > 0 || !by_lua.levels.is_empty(), "{sample} has something to compare");
+            assert_eq!(format!("{by_data:#?}"), format!("{by_lua:#?}"), "{sample}");
+            assert_eq!(
+                serde_json::to_value(&by_data.history).unwrap(),
+                serde_json::to_value(&by_lua.history).unwrap(),
+                "{sample}: payload JSON"
+            );
+        }
+    }
+
+    #[test]
+    fn data_parser_handles_the_literal_forms_lua_does() {
+        let content = r#"
+            -- a comment, and a --[[ long ]] one
+            DeathLoggerDB = {
+                ["deaths"] = {
+                    {
+                        player = "Esc\"aped\\\n\t\65\u{263A}", ['realm'] = [==[Long]]Realm]==],
+                        at = 0x6553F100, level = 6e1, ["flag"] = true, ["off"] = false, ["gone"] = nil,
+                        ["neg"] = -1.5, ["nested"] = { { 1, 2, { "three" } }, [10] = "sparse" },
+                        [1.0] = "float key", ["ratio"] = .25;
+                    },
+                },
+            }
+        "#;
+        let path = scratch_dir().join("literal-forms.lua");
+        fs::write(&path, content).unwrap();
+        let (by_lua, by_data) = both_parsers(&path);
+        assert_eq!(format!("{by_data:#?}"), format!("{by_lua:#?}"));
+        let death = by_data.latest.unwrap();
+        assert_eq!(death.player, "Esc\"aped\\\n\tA\u{263A}");
+        assert_eq!((death.realm.as_str(), death.at, death.level), ("Long]]Realm", 0x6553F100, Some(60)));
+    }
+
+    #[test]
+    fn files_lua_refuses_fall_back_to_the_data_parser() {
+        let path = fixture("bom.lua");
+        let content = fs::read_to_string(&path).unwrap();
+        let cfg = Config::default();
+        let adapter = DeathLoggerAdapter { file_name: cfg.sv_file_name.clone(), fmt: SvFormat::from_config(&cfg) };
+        let lua = Lua::new();
+        assert!(evaluate_sv(&lua, &content, &path, &adapter.fmt, &adapter, None).is_err(), "Lua rejects the byte order mark");
+        let fallback = parse_sv_text(&content, &path, &adapter.fmt, &adapter, None).unwrap();
+        let stripped = content.trim_start_matches('\u{feff}');
+        let by_lua = evaluate_sv(&Lua::new(), stripped, &path, &adapter.fmt, &adapter, None).unwrap();
+        assert_eq!(format!("{fallback:#?}"), format!("{by_lua:#?}"));
+
+        let err = parse_sv_text("DeathLoggerDB = { ] }", &path, &adapter.fmt, &adapter, None).unwrap_err();
+        assert!(format!("{err:#}").contains("data parser"), "{err:#}");
+    }
+
+    #[test]
+    fn the_data_parser_first_leaves_lua_only_files_to_lua() {
+        assert_eq!(SvParser::default(), if cfg!(target_arch = "aarch64") { SvParser::Data } else { SvParser::Lua });
+        let adapter = |parser| {
+            let cfg = Config { sv_parser: parser, ..Config::default() };
+            DeathLoggerAdapter { file_name: cfg.sv_file_name.clone(), fmt: SvFormat::from_config(&cfg) }
+        };
+        let path = fixture("sv/classic_history.lua");
+        let content = fs::read_to_string(&path).unwrap();
+        let [by_lua, by_data] = [SvParser::Lua, SvParser::Data].map(|parser| {
+            let adapter = adapter(parser);
+            format!("{:#?}", parse_sv_text(&content, &path, &adapter.fmt, &adapter, Some(&SvMarks::default())).unwrap())
+        });
+        assert_eq!(by_data, by_lua);
+
+        let computed = "local base = 1700000000\nDeathLoggerDB = { deaths = { { player = \"Comp\" .. \"uted\", realm = \"Realm\", at = base + 5 } } }";
+        let data_first = adapter(SvParser::Data);
+        let death = parse_sv_text(computed, &path, &data_first.fmt, &data_first, None).unwrap().latest.unwrap();
+        assert_eq!((death.player.as_str(), death.at), ("Computed", 1_700_000_005));
+        let err = parse_sv_text("DeathLoggerDB = { ] }", &path, &data_first.fmt, &data_first, None).unwrap_err();
+        assert!(format!("{err:#}").starts_with("data parser: "), "{err:#}");
+    }
+
+    // ---------- Lua state scope ----------
+
+    /// Compiles only for data that owns everything it holds. mlua values borrow
+    /// their state and none of them is Send, so no Lua value fits.
+    fn owned<T: Send + 'static>() {}
+
+    #[test]
+    fn parses_hand_back_no_lua_values() {
+        // Pinning the signatures: whatever leaves a parse is owned Rust data.
+        let parse: fn(&str, &Path, &SvFormat, &DeathLoggerAdapter, Option<&SvMarks>) -> Result<SvSnapshot> = parse_sv_text;
+        let _: fn(&str) -> Result<Vec<DeathPayload>> = sv::parse_deaths;
+        owned::<SvSnapshot>();
+        owned::<DeathPayload>();
+        // Nor is any kept between parses.
+        owned::<State>();
+        owned::<DeferredParses>();
+
+        let cfg = Config::default();
+        let adapter = DeathLoggerAdapter { file_name: cfg.sv_file_name.clone(), fmt: SvFormat::from_config(&cfg) };
+        let path = fixture("sv/classic_history.lua");
+        let snapshot = parse(&fs::read_to_string(&path).unwrap(), &path, &adapter.fmt, &adapter, Some(&SvMarks::default())).unwrap();
+        assert!(snapshot.latest.is_some() && !snapshot.history.is_empty());
+    }
+
+    // ---------- Timestamp forms ----------
+
+    /// One death per `at` literal, player `P<n>` for the n-th.
+    fn sv_with_at_literals(name: &str, ats: &[&str]) -> PathBuf {
+        let mut text = String::from("DeathLoggerDB = {\n\t[\"deaths\"] = {\n");
+        for (n, at) in ats.iter().enumerate() {
+            let at = if at.is_empty() { String::new() } else { format!("[\"at\"] = {at}, ") };
+            text.push_str(&format!("\t\t{{ [\"player\"] = \"P{}\", [\"realm\"] = \"Realm\", {at}[\"level\"] = 10 }},\n", n + 1));
+        }
+        text.push_str("\t},\n}\n");
+        let path = scratch_dir().join(name);
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn at_is_read_from_floats_and_strings() {
+        let literals = [
+            "1700000000",
+            "1700000100.0",
+            "1700000199.6",
+            r#""1700000300""#,
+            r#"" 1700000400.4 ""#,
+            r#""2023-11-14T23:45:00+01:00""#,
+            // Unusable: skipped rather than read as 0.
+            r#""yesterday""#,
+            "0",
+            "-5",
+            r#""0""#,
+            "true",
+            "",
+        ];
+        let path = sv_with_at_literals("at-forms.lua", &literals);
+        for timestamp_mode in [TimestampMode::AsIs, TimestampMode::OffsetSecs(3600)] {
+            let cfg = Config { timestamp_mode, ..Config::default() };
+            let adapter = DeathLoggerAdapter { file_name: cfg.sv_file_name.clone(), fmt: SvFormat::from_config(&cfg) };
+            let snapshot = adapter.parse(&path, Some(&SvMarks { uploaded: BTreeMap::new(), cursor: None })).unwrap();
+            let ats: Vec<i64> = snapshot.history.iter().chain(&snapshot.latest).map(|d| d.at).collect();
+            let shift = if timestamp_mode == TimestampMode::AsIs { 0 } else { 3600 };
+            let expected = [1_700_000_000, 1_700_000_100, 1_700_000_200, 1_700_000_300, 1_700_000_400].map(|at| at + shift);
+            assert_eq!(ats[..5], expected, "{timestamp_mode:?}");
+            assert_eq!(ats[5], 1_700_001_900, "RFC 3339 carries its own offset");
+            assert_eq!(snapshot.latest.as_ref().unwrap().player, "P6");
+            assert_eq!(snapshot.undated, ["7", "8", "9", "10", "11", "12"]);
+            assert_eq!(snapshot.count, 12);
+        }
+    }
+
+    #[tokio::test]
+    async fn undated_entries_never_reach_the_watermark() {
+        let (url, seen) = mock_server(|_, _| (200, "{}".into()));
+        let (mut cfg, sv) = wow_with_sv("wow-undated", "");
+        cfg.api_url = format!("{url}/upload");
+        let http = Http::new(&cfg).unwrap();
+        let mut state = State::default();
+        let written = sv_with_at_literals("undated-only.lua", &[r#""soon""#, "0"]);
+        fs::copy(written, &sv).unwrap();
+        handle_sv_change(&cfg, &http, &WowPaths::from_config(&cfg), &mut state, &sv).await.unwrap();
+        assert!(seen.lock().unwrap().is_empty());
+        assert!(state.last_uploaded.is_empty(), "{:?}", state.last_uploaded);
+        assert_eq!(state.undated_seen[&sv], ["1", "2"]);
+
+        // A dated death added later still goes out.
+        let written = sv_with_at_literals("undated-then-dated.lua", &[r#""soon""#, "0", r#""1700000500""#]);
+        fs::copy(written, &sv).unwrap();
+        handle_sv_change(&cfg, &http, &WowPaths::from_config(&cfg), &mut state, &sv).await.unwrap();
+        assert_eq!(seen.lock().unwrap().len(), 1);
+        assert_eq!(state.last_uploaded.get("ACC:P3@Realm"), Some(&1_700_000_500));
+    }
+
+    // ---------- Deaths table keys ----------
+
+    #[test]
+    fn deaths_come_out_in_time_order_whatever_the_keys() {
+        let cfg = Config::default();
+        let adapter = DeathLoggerAdapter { file_name: cfg.sv_file_name.clone(), fmt: SvFormat::from_config(&cfg) };
+        let read = |name: &str| {
+            let snapshot = adapter.parse(&fixture(&format!("keys/{name}.lua")), Some(&SvMarks { uploaded: BTreeMap::new(), cursor: None })).unwrap();
+            let deaths: Vec<serde_json::Value> =
+                snapshot.history.iter().chain(&snapshot.latest).map(|d| serde_json::to_value(d).unwrap()).collect();
+            (snapshot, deaths)
+        };
+        let (integer, expected) = read("integer");
+        let ats: Vec<_> = expected.iter().map(|d| d["at"].as_i64().unwrap()).collect();
+        assert_eq!(ats, [1_700_000_100, 1_700_000_200, 1_700_000_300, 1_700_000_400]);
+        assert_eq!(expected[2]["player"], json!("Brakka"));
+        assert_eq!(expected[3]["killer"]["name"], json!("Falling"));
+        assert_eq!((integer.count, integer.max_index), (4, 4));
+
+        for (name, max_index) in [("string", 0), ("mixed", 2)] {
+            let (snapshot, deaths) = read(name);
+            assert_eq!(deaths, expected, "{name}");
+            assert_eq!((snapshot.count, snapshot.max_index), (4, max_index), "{name}");
+            assert!(snapshot.undated.is_empty(), "{name}");
+            assert!(snapshot.cursor.is_none(), "{name}: only plain arrays resume from a cursor");
+        }
+    }
+
+    // ---------- Per-character deaths tables ----------
+
+    #[test]
+    fn per_character_tables_fill_in_the_owner() {
+        let cfg = Config::default();
+        let adapter = DeathLoggerAdapter { file_name: cfg.sv_file_name.clone(), fmt: SvFormat::from_config(&cfg) };
+        let marks = SvMarks { uploaded: BTreeMap::new(), cursor: None };
+        for name in ["characters", "chars"] {
+            let snapshot = adapter.parse(&fixture(&format!("nested/{name}.lua")), Some(&marks)).unwrap();
+            let deaths: Vec<(&str, &str, i64)> =
+                snapshot.history.iter().chain(&snapshot.latest).map(|d| (d.player.as_str(), d.realm.as_str(), d.at)).collect();
+            assert_eq!(
+                deaths,
+                [("Quillon", "Firemaw", 1_700_000_100), ("Brakka", "Firemaw", 1_700_000_200), ("Quillon", "Firemaw", 1_700_000_300)],
+                "{name}"
+            );
+            assert_eq!(snapshot.undated, ["Quillon-Firemaw[3]"], "{name}");
+            assert_eq!(snapshot.count, 4, "{name}");
+            assert_eq!(snapshot.addon_version.as_deref(), Some("2.0.0"));
+        }
+
+        // A flat table, when there is one, is what the file is read from.
+        let both = adapter.parse(&fixture("nested/both.lua"), Some(&marks)).unwrap();
+        assert!(both.history.is_empty());
+        assert_eq!(both.latest.map(|d| d.at), Some(1_700_000_050));
+    }
+
+    #[tokio::test]
+    async fn per_character_deaths_upload_under_their_own_keys() {
+        let (url, seen) = mock_server(|_, _| (200, "{}".into()));
+        let (mut cfg, sv) = wow_with_sv("wow-nested", "");
+        cfg.api_url = format!("{url}/upload");
+        fs::copy(fixture("nested/characters.lua"), &sv).unwrap();
+        let http = Http::new(&cfg).unwrap();
+        let mut state = State::default();
+        handle_sv_change(&cfg, &http, &WowPaths::from_config(&cfg), &mut state, &sv).await.unwrap();
+        let marks: Vec<(&str, i64)> = state.last_uploaded.iter().map(|(k, at)| (k.as_str(), *at)).collect();
+        assert_eq!(marks, [("ACC:Brakka@Firemaw", 1_700_000_200), ("ACC:Quillon@Firemaw", 1_700_000_300)]);
+        let bodies: Vec<Vec<u8>> = seen.lock().unwrap().iter().map(|(_, _, body)| body.clone()).collect();
+        assert!(bodies.iter().any(|b| contains(b, r#""player":"Quillon""#) && contains(b, r#""realm":"Firemaw""#)));
+    }
+
+    // ---------- Cyclic tables ----------
+
+    #[test]
+    fn cyclic_and_deep_tables_become_markers() {
+        for max_depth in [64, 8] {
+            let cfg = Config { sv_max_table_depth: max_depth, ..Config::default() };
+            let death = parse_fixture(&cfg, "cycles/self_reference.lua").latest.unwrap();
+            let json = serde_json::to_value(&death).unwrap();
+            assert_eq!(json["player"], json!("Cyclic"));
+            assert_eq!(json["me"], json!(RECURSION_MARKER), "the entry inside itself");
+            assert_eq!(
+                json["loop"],
+                json!({ "name": "loop", "self": RECURSION_MARKER, "list": [RECURSION_MARKER, { "inner": RECURSION_MARKER }] })
+            );
+            assert_eq!(json["pair"], json!({ "a": { "id": 7 }, "b": { "id": 7 } }), "a table seen twice side by side is no cycle");
+
+            // The entry itself counts as the first level.
+            let mut node = &json["deep"];
+            let mut levels = 2;
+            while node.is_object() {
+                node = &node["next"];
+                levels += 1;
+            }
+            assert_eq!((node, levels), (&json!(RECURSION_MARKER), max_depth + 1), "sv_max_table_depth = {max_depth}");
+            assert!(serde_json::to_string(&death).unwrap().len() < 10_000);
+        }
+    }
+
+    // ---------- Whole numbers ----------
+
+    #[test]
+    fn whole_numbers_serialize_as_integers() {
+        let death = parse_fixture(&Config::default(), "numbers/bags.lua").latest.unwrap();
+        let text = serde_json::to_string(&death).unwrap();
+        for literal in [
+            r#""at":1700001000"#,
+            r#""level":60"#,
+            r#""id":19019,"#,
+            r#""count":1,"#,
+            r#""bonusIDs":[6652,7756,-1]"#,
+            r#""guid":9007199254740993"#,
+            r#""weight":0.5"#,
+            r#""slot":16"#,
+        ] {
+            assert!(text.contains(literal), "{literal} missing from {text}");
+        }
+        assert!(!text.contains(".0,") && !text.contains(".0}") && !text.contains(".0]"), "{text}");
+        assert_eq!((death.money_copper, death.money_gold, death.money_silver, death.money_copper_only), (Some(1_234_567), Some(123), Some(45), Some(67)));
+        assert_eq!(death.level, Some(60));
+        // The receiving side sees integers too.
+        let back: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert!(back["bags"][0]["guid"].is_u64() && back["bags"][0]["id"].is_u64());
+        assert_eq!(back["bags"][0]["guid"].as_i64(), Some(9_007_199_254_740_993));
+    }
+
+    // ---------- Locations ----------
+
+    #[test]
+    fn location_encodings_normalize_to_percent() {
+        let goldshire = LocationPayload {
+            zone: Some("Elwynn Forest".into()),
+            subzone: Some("Goldshire".into()),
+            map_id: Some(37),
+            x: Some(42.13),
+            y: Some(65.79),
+            facing: Some(1.5),
+            extra: serde_json::Map::new(),
+        };
+        for name in ["fractions", "percent_posxy", "nested_table", "nested_array"] {
+            let death = parse_fixture(&Config::default(), &format!("location/{name}.lua")).latest.unwrap();
+            let mut location = death.location.clone().unwrap();
+            let extra = std::mem::take(&mut location.extra);
+            assert_eq!(location, goldshire, "{name}");
+            if name == "nested_array" {
+                assert_eq!(serde_json::Value::Object(extra), json!({ "instance": "none", "continent": 13 }));
+            } else {
+                assert!(extra.is_empty(), "{name}: {extra:?}");
+            }
+            let sent = serde_json::to_value(&death).unwrap();
+            assert_eq!(sent["location"]["mapID"], json!(37), "{name}");
+            assert_eq!((sent["location"]["x"].as_f64(), sent["location"]["y"].as_f64()), (Some(42.13), Some(65.79)), "{name}");
+        }
+    }
+
+    // ---------- State versions ----------
+
+    /// A copy of fixture `state_v<version>.json` as the state.json of a private directory.
+    fn state_fixture(version: u32) -> PathBuf {
+        let dir = scratch_dir().join(format!("state-v{version}"));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let p = dir.join("state.json");
+        fs::copy(fixture(&format!("state_v{version}.json")), &p).unwrap();
+        p
+    }
+
+    #[test]
+    fn every_released_state_version_loads_as_the_current_one() {
+        let (cfg, _) = wow_with_sv("state-versions", "DeathLoggerDB = {}\n");
+        let wow = WowPaths::from_config(&cfg);
+        let current = serde_json::to_value(load_state_from(&state_fixture(STATE_VERSION)).unwrap()).unwrap();
+        assert_eq!(current["last_uploaded"]["ACC:Hero@Realm"], 1_700_000_100);
+
+        for version in 0..STATE_VERSION {
+            let mut state = load_state_from(&state_fixture(version)).unwrap();
+            migrate_account_keys(&mut state, &wow);
+            let migrated = serde_json::to_value(&state).unwrap();
+            if version == 0 {
+                // Only what a version-0 agent kept, the rest at its defaults.
+                let only = |v: &serde_json::Value| json!({ "last_uploaded": v["last_uploaded"], "pending_screens": v["pending_screens"] });
+                assert_eq!(only(&migrated), only(&current));
+                let defaults = serde_json::to_value(State::default()).unwrap();
+                let rest = |v: &serde_json::Value| {
+                    let mut v = v.clone();
+                    v.as_object_mut().unwrap().retain(|k, _| k != "last_uploaded" && k != "pending_screens");
+                    v
+                };
+                assert_eq!(rest(&migrated), rest(&defaults));
+            } else {
+                assert_eq!(migrated, current, "state version {version}");
+            }
+        }
+    }
+
+    #[test]
+    fn a_state_from_a_newer_agent_is_refused_and_left_alone() {
+        let p = state_fixture(3);
+        let before = fs::read(&p).unwrap();
+        for err in [load_state_from(&p).unwrap_err(), load_state_for_write_from(&p, false).unwrap_err()] {
+            assert!(err.is::<StateTooNew>(), "{err:#}");
+            assert!(err.to_string().contains("--force-downgrade-state"), "{err}");
+        }
+        assert_eq!(fs::read(&p).unwrap(), before, "untouched");
+
+        // Forced, the newer file is archived as it was and its known parts kept.
+        let state = load_state_for_write_from(&p, true).unwrap();
+        assert!(!p.exists());
+        assert_eq!(state.last_uploaded["ACC:Hero@Realm"], 1_700_000_500);
+        let archived: Vec<PathBuf> = fs::read_dir(p.parent().unwrap()).unwrap().flatten().map(|e| e.path()).collect();
+        assert_eq!(archived.len(), 1);
+        assert!(archived[0].file_name().unwrap().to_string_lossy().starts_with("state.v3."), "{:?}", archived);
+        assert_eq!(fs::read(&archived[0]).unwrap(), before);
+
+        // A newer file that says we can still write it loads normally.
+        let mut readable: serde_json::Value = serde_json::from_slice(&before).unwrap();
+        readable["min_compatible_version"] = json!(STATE_VERSION);
+        fs::write(&p, readable.to_string()).unwrap();
+        assert_eq!(load_state_from(&p).unwrap().last_uploaded["ACC:Hero@Realm"], 1_700_000_500);
+    }
+
+    #[test]
+    fn saved_state_carries_its_version_markers() {
+        scratch_dir();
+        save_state(&State::default()).unwrap();
+        let saved: serde_json::Value = serde_json::from_str(&fs::read_to_string(state_path().unwrap()).unwrap()).unwrap();
+        assert_eq!(state_versions(&saved), (STATE_VERSION, MIN_COMPATIBLE_STATE_VERSION));
+    }
+
+    // ---------- Moved drives ----------
+
+    /// Volumes as a list instead of Windows drive letters: every root a path can
+    /// be on, and which of them are mounted with what serial.
+    struct FakeVolumes {
+        roots: Vec<PathBuf>,
+        mounted: Vec<volumes::Volume>,
+    }
+
+    impl VolumeSource for FakeVolumes {
+        fn volumes(&self) -> Vec<volumes::Volume> {
+            self.mounted.clone()
+        }
+
+        fn split(&self, path: &Path) -> Option<(PathBuf, PathBuf)> {
+            let root = self.roots.iter().find(|r| path.starts_with(r))?;
+            Some((root.clone(), path.strip_prefix(root).ok()?.to_path_buf()))
+        }
+    }
+
+    /// A WoW install at `<volume>/Games/World of Warcraft` with the given products.
+    fn install_on(volume: &Path, products: &[&str]) -> PathBuf {
+        let root = volume.join("Games/World of Warcraft");
+        fs::create_dir_all(root.join("_retail_")).unwrap();
+        let rows: String = products.iter().map(|p| format!("us|1|11.0.2.56313|{p}\n")).collect();
+        fs::write(root.join(".build.info"), format!("Branch!STRING:0|Active!DEC:1|Version!STRING:0|Product!STRING:0\n{rows}")).unwrap();
+        root
+    }
+
+    #[test]
+    fn wow_root_follows_its_drive_to_a_new_letter() {
+        let drives = scratch_dir().join("drives");
+        fs::remove_dir_all(&drives).ok();
+        let [e, f, g, h] = ["E", "F", "G", "H"].map(|d| drives.join(d));
+        let mounted = |list: &[(&PathBuf, u32)]| FakeVolumes {
+            roots: vec![e.clone(), f.clone(), g.clone(), h.clone()],
+            mounted: list.iter().map(|(root, serial)| volumes::Volume { root: (*root).clone(), serial: Some(*serial) }).collect(),
+        };
+        let cfg_path = drives.join("config.toml");
+        let mut cfg = Config { wow_root: install_on(&e, &["wow"]).to_string_lossy().to_string(), ..Config::default() };
+
+        // Seen in place: the serial and fingerprint are recorded.
+        rebind_wow_root(&mut cfg, Some(&cfg_path), &mounted(&[(&e, 0x1A2B_3C4D)]), false).unwrap();
+        assert_eq!((cfg.wow_volume_serial.as_str(), cfg.wow_fingerprint.as_str()), ("1A2B-3C4D", "_retail_|wow"));
+
+        // The drive comes back as F:, next to G: holding an unrelated install at the same path.
+        fs::rename(&e, &f).unwrap();
+        install_on(&g, &["wow_classic"]);
+        let now = mounted(&[(&g, 0x0BAD_F00D), (&f, 0x1A2B_3C4D)]);
+        rebind_wow_root(&mut cfg, Some(&cfg_path), &now, false).unwrap();
+        let on_f = f.join("Games/World of Warcraft");
+        assert_eq!(PathBuf::from(&cfg.wow_root), on_f);
+        let saved: Config = toml::from_str(&fs::read_to_string(&cfg_path).unwrap()).unwrap();
+        assert_eq!((saved.wow_root, saved.wow_volume_serial), (cfg.wow_root.clone(), "1A2B-3C4D".to_string()));
+
+        // Copied to a drive with another serial: found by its fingerprint, and the new serial recorded.
+        fs::rename(&f, &h).unwrap();
+        rebind_wow_root(&mut cfg, None, &mounted(&[(&g, 0x0BAD_F00D), (&h, 0x5555_AAAA)]), false).unwrap();
+        assert_eq!(PathBuf::from(&cfg.wow_root), h.join("Games/World of Warcraft"));
+        assert_eq!(cfg.wow_volume_serial, "5555-AAAA");
+
+        // Nowhere to be found: left as it is.
+        fs::remove_dir_all(&h).unwrap();
+        let before = cfg.wow_root.clone();
+        assert_eq!(find_moved_wow_root(&cfg, &mounted(&[(&g, 0x0BAD_F00D)])), None);
+        rebind_wow_root(&mut cfg, None, &mounted(&[(&g, 0x0BAD_F00D)]), false).unwrap();
+        assert_eq!(cfg.wow_root, before);
+    }
+
+    // ---------- Restart handoff ----------
+
+    #[tokio::test]
+    async fn handoff_resumes_a_deferred_parse_that_pairs_its_late_screenshot() {
+        let (url, seen) = mock_server(|_, _| (200, "{}".into()));
+        // Caught mid-write: the file doesn't parse yet and waits for a retry.
+        let (mut cfg, sv) = wow_with_sv("wow-handoff", "DeathLoggerDB = { ] }");
+        cfg.api_url = format!("{url}/upload");
+        cfg.max_uploads_per_minute = 6;
+        let wow = WowPaths::from_config(&cfg);
+        let http = Http::new(&cfg).unwrap();
+        let mut state = State::default();
+        let mut deferred = DeferredParses::default();
+        handle_sv_change_or_defer(&cfg, &http, &wow, &mut state, &mut deferred, &sv).await.unwrap();
+        assert!(deferred.files.contains_key(&sv));
+        let shot = wow.screenshots_dir().join("WoWScrnShot_late.jpg");
+        fs::write(&shot, b"LATE-SCREENSHOT").unwrap();
+        queue_screenshot(&mut state, shot.to_string_lossy().to_string(), 1_700_000_102);
+        assert!(http.upload_limiter.as_ref().unwrap().check(true).is_zero());
+
+        // Stop, then start again straight away.
+        let path = scratch_dir().join("handoff-resume.json");
+        let heartbeat = Heartbeat { next_at: std::time::Instant::now() + Duration::from_secs(40), failing: true };
+        write_handoff(&path, &Handoff::capture(&deferred, SystemTime::now(), &heartbeat, &http)).unwrap();
+        let mut state: State = serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+        let http = Http::new(&cfg).unwrap();
+        let (mut deferred, mut last_poll, mut heartbeat) = (DeferredParses::default(), SystemTime::UNIX_EPOCH, Heartbeat::new());
+        let started = std::time::Instant::now();
+        take_handoff(&path, 300).unwrap().resume(&mut deferred, &mut last_poll, &mut heartbeat, &http);
+        assert!(!path.exists(), "a handoff is used once");
+
+        let retry = &deferred.files[&sv];
+        assert_eq!(retry.attempts, 0);
+        assert!(retry.next_at <= started + Duration::from_secs(SV_RETRY_DELAYS_SECS[0]));
+        let since_poll = last_poll.elapsed().unwrap();
+        assert!(since_poll < Duration::from_secs(1), "the poll isn't due again yet: {since_poll:?}");
+        assert!(heartbeat.failing);
+        assert!(heartbeat.next_at > started + Duration::from_secs(38));
+        let tokens = http.upload_limiter.as_ref().unwrap().tokens();
+        assert!((5.0..5.1).contains(&tokens), "the used token stays used: {tokens}");
+
+        // The addon finishes writing; the resumed retry pairs the screenshot.
+        fs::write(&sv, preview_lua("Handoff", 1_700_000_100)).unwrap();
+        deferred.files.get_mut(&sv).unwrap().next_at = std::time::Instant::now();
+        deferred.retry_due(&cfg, &http, &wow, &mut state).await;
+        assert!(deferred.files.is_empty());
+        assert_eq!(state.last_uploaded.get("ACC:Handoff@Realm"), Some(&1_700_000_100));
+        let (_, _, body) = seen.lock().unwrap().last().unwrap().clone();
+        assert!(contains(&body, "LATE-SCREENSHOT"), "{}", String::from_utf8_lossy(&body));
+        assert!(contains(&body, "WoWScrnShot_late.jpg"));
+    }
+
+    #[test]
+    fn stale_or_foreign_handoffs_are_ignored() {
+        let path = scratch_dir().join("handoff-stale.json");
+        let now = Utc::now().timestamp_millis();
+        let written = |version: u32, written_at: i64| {
+            let handoff = Handoff { version, written_at, ..Handoff::default() };
+            write_handoff(&path, &handoff).unwrap();
+        };
+
+        written(HANDOFF_VERSION, now - 301_000);
+        assert!(take_handoff(&path, 300).is_none());
+        assert!(!path.exists(), "a stale handoff is removed");
+        written(HANDOFF_VERSION, now + 60_000);
+        assert!(take_handoff(&path, 300).is_none(), "written in the future");
+        written(HANDOFF_VERSION + 1, now);
+        assert!(take_handoff(&path, 300).is_none());
+        assert!(!path.exists());
+        fs::write(&path, "{ not json").unwrap();
+        assert!(take_handoff(&path, 300).is_none());
+        assert!(!path.exists());
+        written(HANDOFF_VERSION, now);
+        assert!(take_handoff(&path, 0).is_none(), "0 turns handoffs off");
+        assert!(!path.exists());
+        assert!(take_handoff(&path, 300).is_none(), "no file");
+
+        written(HANDOFF_VERSION, now - 10_000);
+        let h = take_handoff(&path, 300).unwrap();
+        assert_eq!(h.written_at, now - 10_000);
+    }
+
+    // ---------- Death filters ----------
+
+    #[test]
+    fn filters_name_the_rule_that_excludes_a_death() {
+        let filters = DeathFilters {
+            min_level: 10,
+            skip_characters: vec!["bankalt@realm".into()],
+            skip_zones: vec!["Elwynn Forest".into()],
+        };
+        let mut d = death("Hero", 100);
+        assert_eq!(filters.excludes(&d), None);
+        d.level = Some(9);
+        assert_eq!(filters.excludes(&d).as_deref(), Some("min_level = 10 (level 9)"));
+        d.level = None;
+        assert_eq!(filters.excludes(&d), None, "an unknown level isn't below anything");
+        d.player = "Bankalt".into();
+        assert_eq!(filters.excludes(&d).as_deref(), Some("skip_characters bankalt@realm"));
+        d.player = "Hero".into();
+        d.location = Some(LocationPayload { zone: Some("elwynn forest".into()), ..LocationPayload::default() });
+        assert_eq!(filters.excludes(&d).as_deref(), Some("skip_zones Elwynn Forest"));
+        assert_eq!(DeathFilters::default().excludes(&d), None);
+    }
+
+    fn journaled(character: &str) -> Vec<FilteredDeath> {
+        let journal = read_filter_journal(&filter_journal_path().unwrap(), 30);
+        journal.into_iter().filter(|e| e.character == character).collect()
+    }
+
+    #[tokio::test]
+    async fn relaxed_filters_upload_journaled_deaths() {
+        let (url, seen) = mock_server(|_, _| (200, "{}".into()));
+        let (mut cfg, sv) = wow_with_sv("wow-filter-relax", &preview_lua("Lowbie", 1_700_000_300));
+        cfg.api_url = format!("{url}/upload");
+        cfg.filters.min_level = 30;
+        let wow = WowPaths::from_config(&cfg);
+        let http = Http::new(&cfg).unwrap();
+        let mut state = State::default();
+        refilter_journal(&cfg, &http, &wow, &mut state, |_| panic!("nothing changed")).await.unwrap();
+        assert_eq!(state.applied_filters.as_ref(), Some(&cfg.filters));
+
+        handle_sv_change(&cfg, &http, &wow, &mut state, &sv).await.unwrap();
+        assert!(seen.lock().unwrap().is_empty());
+        assert_eq!(state.last_uploaded.get("ACC:Lowbie@Realm"), Some(&1_700_000_300), "handled under the old filters");
+        let entry = &journaled("ACC:Lowbie@Realm")[0];
+        assert_eq!((entry.at, entry.rule.as_str(), entry.account.as_deref()), (1_700_000_300, "min_level = 30 (level 20)", Some("ACC")));
+        assert_eq!(entry.digest.len(), 64);
+
+        // Relaxed but declined: nothing goes out and the entry stays.
+        cfg.filters.min_level = 20;
+        let mut offered = 0;
+        refilter_journal(&cfg, &http, &wow, &mut state, |n| {
+            offered = n;
+            false
+        })
+        .await
+        .unwrap();
+        assert_eq!(offered, 1);
+        assert!(seen.lock().unwrap().is_empty());
+        assert_eq!(journaled("ACC:Lowbie@Realm").len(), 1);
+
+        // Changed again and accepted: the death is read back from the file and sent.
+        cfg.filters.skip_zones = vec!["Nowhere".into()];
+        refilter_journal(&cfg, &http, &wow, &mut state, |_| true).await.unwrap();
+        let requests = seen.lock().unwrap().clone();
+        assert_eq!(requests.len(), 1, "{:?}", paths(&seen));
+        assert!(contains(&requests[0].2, "Lowbie") && contains(&requests[0].2, "1700000300"));
+        assert!(journaled("ACC:Lowbie@Realm").is_empty());
+        assert_eq!(state.last_uploaded.get("ACC:Lowbie@Realm"), Some(&1_700_000_300));
+
+        // Once sent it isn't offered again.
+        cfg.filters = DeathFilters::default();
+        refilter_journal(&cfg, &http, &wow, &mut state, |_| panic!("nothing left to offer")).await.unwrap();
+        assert_eq!(seen.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn tightened_filters_leave_uploaded_deaths_alone() {
+        let (url, seen) = mock_server(|_, _| (200, "{}".into()));
+        let (mut cfg, sv) = wow_with_sv("wow-filter-tighten", &preview_lua("Tight", 1_700_000_400));
+        cfg.api_url = format!("{url}/upload");
+        let wow = WowPaths::from_config(&cfg);
+        let http = Http::new(&cfg).unwrap();
+        let mut state = State::default();
+        refilter_journal(&cfg, &http, &wow, &mut state, |_| panic!("first start")).await.unwrap();
+        handle_sv_change(&cfg, &http, &wow, &mut state, &sv).await.unwrap();
+        assert_eq!(seen.lock().unwrap().len(), 1);
+
+        cfg.filters.skip_characters = vec!["tight@realm".into()];
+        refilter_journal(&cfg, &http, &wow, &mut state, |_| panic!("nothing passes")).await.unwrap();
+        assert_eq!(seen.lock().unwrap().len(), 1, "no request to withdraw the uploaded death");
+        assert!(journaled("ACC:Tight@Realm").is_empty());
+        assert_eq!(state.last_uploaded.get("ACC:Tight@Realm"), Some(&1_700_000_400));
+
+        // The next death is filtered and journaled.
+        fs::write(&sv, preview_lua("Tight", 1_700_000_500)).unwrap();
+        state.sv_seen.clear();
+        handle_sv_change(&cfg, &http, &wow, &mut state, &sv).await.unwrap();
+        assert_eq!(seen.lock().unwrap().len(), 1);
+        let journal = journaled("ACC:Tight@Realm");
+        assert_eq!(journal.iter().map(|e| (e.at, e.rule.as_str())).collect::<Vec<_>>(), [(1_700_000_500, "skip_characters tight@realm")]);
+        assert_eq!(state.last_uploaded.get("ACC:Tight@Realm"), Some(&1_700_000_500));
+    }
+
+    #[test]
+    fn filter_journal_expires_and_stays_bounded() {
+        let path = scratch_dir().join("filtered-expiry.jsonl");
+        let now = Utc::now().timestamp();
+        let entry = |at: i64, age_days: i64| FilteredDeath {
+            character: "ACC:Old@Realm".into(),
+            account: Some("ACC".into()),
+            at,
+            fingerprint: format!("{at:064x}"),
+            rule: "min_level = 10 (level 5)".into(),
+            digest: String::new(),
+            filtered_at: now - age_days * 86_400,
+        };
+        write_filter_journal(&path, &[entry(1, 31), entry(2, 29), entry(3, 0)]).unwrap();
+        fs::write(&path, fs::read_to_string(&path).unwrap() + "not json\n").unwrap();
+        assert_eq!(read_filter_journal(&path, 30).iter().map(|e| e.at).collect::<Vec<_>>(), [2, 3]);
+        assert_eq!(read_filter_journal(&path, 7).iter().map(|e| e.at).collect::<Vec<_>>(), [3]);
+
+        let many: Vec<FilteredDeath> = (0..MAX_FILTER_JOURNAL as i64 + 5).map(|at| entry(at, 0)).collect();
+        write_filter_journal(&path, &many).unwrap();
+        let kept = read_filter_journal(&path, 30);
+        assert_eq!(kept.len(), MAX_FILTER_JOURNAL);
+        assert_eq!(kept[0].at, 5, "the oldest go first");
+    }
+
+    // ---------- Event trace and replay ----------
+
+    fn replay_session_lua(deaths: &[(&str, i64)]) -> String {
+        let entries: Vec<String> =
+            deaths.iter().map(|(player, at)| format!("{{ [\"player\"] = \"{player}\", [\"realm\"] = \"Realm\", [\"at\"] = {at}, [\"level\"] = 30 }}")).collect();
+        format!("DeathLoggerDB = {{ [\"deaths\"] = {{ {} }} }}\n", entries.join(", "))
+    }
+
+    #[tokio::test]
+    async fn replaying_a_recorded_session_gives_the_same_decisions() {
+        scratch_dir();
+        let (url, seen) = mock_server(|_, _| (200, "{}".into()));
+        let (mut cfg, sv) = wow_with_sv("replay-session", &replay_session_lua(&[("Traced", 1_700_000_100)]));
+        cfg.api_url = format!("{url}/upload");
+        cfg.event_trace_max = 100;
+        let wow = WowPaths::from_config(&cfg);
+        let http = Http::new(&cfg).unwrap();
+        let mut state = State::default();
+        fs::remove_file(trace_path().unwrap()).ok();
+        fs::remove_dir_all(trace_files_dir().unwrap()).ok();
+        let mut trace = TraceRecorder::new(cfg.event_trace_max).unwrap();
+
+        // The same steps the run loop takes: a screenshot, two SV writes, a poll.
+        let shot = wow.screenshots_dir().join("death.jpg");
+        fs::write(&shot, b"\xFF\xD8 picture \xFF\xD9").unwrap();
+        File::options().write(true).open(&shot).unwrap().set_modified(UNIX_EPOCH + Duration::from_secs(1_700_000_103)).unwrap();
+        trace.record(TraceEvent::Screenshot { at: Utc::now().timestamp(), path: redact_trace_path(&wow, &shot), ts: screenshot_ts(&shot) });
+        handle_screenshot_created(&wow, &mut state, &shot).unwrap();
+        settle_screenshot_writes(&mut state, std::time::Instant::now());
+        trace.sv_change(&wow, &sv);
+        handle_sv_change(&cfg, &http, &wow, &mut state, &sv).await.unwrap();
+        fs::write(&sv, replay_session_lua(&[("Traced", 1_700_000_100), ("Traced", 1_700_000_900)])).unwrap();
+        trace.sv_change(&wow, &sv);
+        handle_sv_change(&cfg, &http, &wow, &mut state, &sv).await.unwrap();
+        trace.record(TraceEvent::Poll { at: Utc::now().timestamp() });
+        periodic_poll(&cfg, &http, &wow, &mut state, &mut DeferredParses::default()).await.unwrap();
+
+        let live: Vec<(i64, bool)> = seen
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, _, body)| form_parts(body))
+            .map(|parts| (serde_json::from_slice::<serde_json::Value>(&parts[PART_DEATH]).unwrap()["at"].as_i64().unwrap(), parts.contains_key(PART_SCREENSHOT)))
+            .collect();
+        assert_eq!(live, [(1_700_000_100, true), (1_700_000_900, false)]);
+
+        // Offline, the recorded trace gives the decisions the fixture trace does,
+        // and they match what the live session sent.
+        let recorded = replay_events(&cfg, &read_trace(&trace_path().unwrap()).unwrap(), &trace_files_dir().unwrap()).unwrap();
+        let dir = fixture("replay");
+        let replayed = replay_events(&cfg, &read_trace(&dir.join("trace.jsonl")).unwrap(), &dir.join("trace")).unwrap();
+        assert_eq!(recorded, replayed);
+        let expected: Vec<String> = fs::read_to_string(dir.join("decisions.txt")).unwrap().lines().map(String::from).collect();
+        assert_eq!(replayed, expected);
+        let (key, mark) = state.last_uploaded.iter().next().unwrap();
+        assert_eq!(expected.iter().rfind(|l| l.starts_with("watermark ")).unwrap(), &format!("watermark {} = {}", key, mark));
+        let submits = expected.iter().filter(|l| l.starts_with("submit ")).collect::<Vec<_>>();
+        assert_eq!(submits.len(), live.len());
+        assert!(submits[0].ends_with("screenshot <wow>/_retail_/Screenshots/death.jpg"), "{}", submits[0]);
+    }
+
+    // ---------- Parse command ----------
+
+    #[test]
+    fn parse_reads_any_file_newest_or_all() {
+        let path = sv_with_deaths("parse-command.lua", [1_000_001, 1_000_002, 1_000_003]);
+        let cfg = Config::default();
+        let (count, newest) = parse_file(&cfg, &path, false).unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(newest.iter().map(|d| (d.key(), d.at)).collect::<Vec<_>>(), [("Cursor@Realm".to_string(), 1_000_003)]);
+        let (_, all) = parse_file(&cfg, &path, true).unwrap();
+        assert_eq!(all.iter().map(|d| d.at).collect::<Vec<_>>(), [1_000_001, 1_000_002, 1_000_003]);
+        // As sent: the same JSON the upload carries.
+        let sent = serde_json::to_value(&all[2]).unwrap();
+        assert_eq!(sent["player"], "Cursor");
+        assert_eq!(sent["bags"][1]["count"], 20);
+
+        // A file in a WoW install gets its account, like a live parse.
+        let (_, sv) = wow_with_sv("parse-command-wow", &preview_lua("Parsed", 1_700_000_600));
+        let (_, deaths) = parse_file(&cfg, &sv, false).unwrap();
+        assert_eq!(deaths[0].key(), "ACC:Parsed@Realm");
+    }
+
+    #[test]
+    fn parse_fails_with_the_parser_error() {
+        let path = scratch_dir().join("parse-broken.lua");
+        fs::write(&path, "DeathLoggerDB = { ] }").unwrap();
+        let err = parse_file(&Config::default(), &path, false).unwrap_err();
+        let msg = format!("{err:#}");
+        assert!(msg.starts_with(&format!("parsing {}", path.display())), "{msg}");
+        assert!(msg.contains("unexpected symbol"), "{msg}");
+        assert!(parse_file(&Config::default(), &scratch_dir().join("parse-missing.lua"), true).is_err());
+        assert!(parse_command(&[]).unwrap_err().to_string().starts_with("Usage: deathlogger-agent parse"));
+        let err = parse_command(&[path.to_string_lossy().to_string(), "--pretty".into()]).unwrap_err();
+        assert!(err.to_string().starts_with("Unknown option --pretty"), "{err}");
+    }
+
+    // ---------- Payload schema ----------
+
+    #[tokio::test]
+    async fn deaths_carry_the_configured_schema_version() {
+        scratch_dir();
+        let (url, seen) = mock_server(|_, _| (200, "{}".into()));
+        for version in [SCHEMA_VERSION, 1] {
+            let cfg = Config { api_url: format!("{url}/upload"), payload_schema_version: version, ..Config::default() };
+            let http = Http::new(&cfg).unwrap();
+            let mut state = State::default();
+            process_death(&cfg, &http, &mut state, death("Versioned", 1_700_000_700 + i64::from(version)), &[]).await.unwrap();
+            let header = with_schema_header(&cfg, http.client.post(&cfg.api_url)).build().unwrap();
+            let sent = header.headers().get(SCHEMA_HEADER).map(|v| v.to_str().unwrap().to_string());
+            let last = seen.lock().unwrap().last().unwrap().2.clone();
+            if version == 1 {
+                assert_eq!(sent, None);
+                assert!(!contains(&last, "schema_version"), "version 1 has no schema_version");
+            } else {
+                assert_eq!(sent.as_deref(), Some("2"));
+                assert!(contains(&last, "\"schema_version\":2"));
+            }
+        }
+        let mut recorded = death("Versioned", 1);
+        let hash = entry_hash(&recorded);
+        recorded.schema_version = SCHEMA_VERSION;
+        assert_eq!(entry_hash(&recorded), hash, "the schema version is not part of the entry");
+    }
+
+    #[tokio::test]
+    async fn a_406_with_supported_versions_says_what_to_change() {
+        scratch_dir();
+        let newer = format!("{{\"supported_versions\": [{}]}}", SCHEMA_VERSION + 1);
+        let (url, _) = mock_server(move |_, _| (406, newer.clone()));
+        let cfg = Config { api_url: format!("{url}/upload"), ..Config::default() };
+        let http = Http::new(&cfg).unwrap();
+        let mut state = State::default();
+        process_death(&cfg, &http, &mut state, death("Outdated", 1_700_000_800), &[]).await.unwrap();
+        let error = &state.failed_uploads.last().expect("406 is not retried").error;
+        assert!(
+            error.contains(&format!("server requires payload schema {}, agent produces {SCHEMA_VERSION}; please update", SCHEMA_VERSION + 1)),
+            "{error}"
+        );
+
+        let older = schema_mismatch(StatusCode::NOT_ACCEPTABLE, r#"{"supported_versions": [1]}"#).unwrap();
+        assert!(older.contains("set payload_schema_version = 1"), "{older}");
+        assert_eq!(schema_mismatch(StatusCode::NOT_ACCEPTABLE, "not json"), None);
+        assert_eq!(schema_mismatch(StatusCode::BAD_REQUEST, r#"{"supported_versions": [1]}"#), None);
+        let cfg = Config { payload_schema_version: SCHEMA_VERSION + 1, ..Config::default() };
+        assert!(validate_config(&cfg).iter().any(|f| f.message.contains("payload_schema_version")));
+    }
+
+    // ---------- Privacy ----------
+
+    #[tokio::test]
+    async fn privacy_redacts_before_upload_and_keeps_the_schema() {
+        scratch_dir();
+        let (url, seen) = mock_server(|_, _| (201, String::new()));
+        let privacy = Privacy { redact_money: true, redact_bags: true, redact_equipped: true, redact_coordinates: true };
+        let cfg = Config { api_url: url, upload_format: UploadFormat::Json, privacy, ..Config::default() };
+        let death = parse_fixture(&cfg, "sv/retail_melee.lua").latest.unwrap();
+        let http = Http::new(&cfg).unwrap();
+        let mut state = State::default();
+        process_death(&cfg, &http, &mut state, death, &[]).await.unwrap();
+
+        let body = seen.lock().unwrap()[0].2.clone();
+        assert!(contains(&body, "Hogger"));
+        for secret in ["34217", "Tough Jerky", "Red Linen Shirt", "26.53", "78.41"] {
+            assert!(!contains(&body, secret), "{secret} was uploaded");
+        }
+        let sent: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(sent["location"]["zone"], "Elwynn Forest");
+        assert!(sent["moneyCopper"].is_null() && sent["money_total_copper"].is_null());
+        let schema = death_schema();
+        for key in schema["required"].as_array().unwrap() {
+            assert!(sent.get(key.as_str().unwrap()).is_some(), "required {key} missing");
+        }
+        for (key, rule) in schema["properties"].as_object().unwrap() {
+            let Some(value) = sent.get(key) else { continue };
+            let ok = match rule["type"].as_str() {
+                Some("integer") => value.is_i64(),
+                Some("string") => value.is_string(),
+                Some("object") => value.is_object(),
+                _ => true,
+            };
+            assert!(ok, "{key} = {value} is not {rule}");
+        }
+    }
+
+    // ---------- Screenshot name timestamps ----------
+
+    #[test]
+    fn screenshot_names_give_the_capture_time() {
+        let tz = chrono::FixedOffset::east_opt(3600).unwrap();
+        let at = |y, mo, d, h, mi, se| tz.with_ymd_and_hms(y, mo, d, h, mi, se).unwrap().timestamp();
+        // Two-digit years pivot like %y: 69-99 are 19xx, 00-68 20xx.
+        assert_eq!(screenshot_name_ts("WoWScrnShot_123199_235959", &tz), Some(at(1999, 12, 31, 23, 59, 59)));
+        assert_eq!(screenshot_name_ts("WoWScrnShot_010100_000000", &tz), Some(at(2000, 1, 1, 0, 0, 0)));
+        assert_eq!(screenshot_name_ts("WoWScrnShot_070468_120000", &tz), Some(at(2068, 7, 4, 12, 0, 0)));
+        assert_eq!(screenshot_name_ts("WoWScrnShot_070469_120000", &tz), Some(at(1969, 7, 4, 12, 0, 0)));
+        // Across midnight (and the year) the name still orders and spaces shots correctly.
+        let before = screenshot_name_ts("WoWScrnShot_123123_235958", &tz).unwrap();
+        let after = screenshot_name_ts("WoWScrnShot_010124_000001", &tz).unwrap();
+        assert_eq!(after - before, 3);
+        for odd in ["WoWScrnShot_023024_120000", "WoWScrnShot_010124_250000", "WoWScrnShot_0101_000000", "Screenshot 2024"] {
+            assert_eq!(screenshot_name_ts(odd, &tz), None, "{odd}");
+        }
+
+        // The queued time comes from the name; other names fall back to the mtime.
+        let dir = shots_dir("name-ts", &[("WoWScrnShot_010124_000001.jpg", b"jpg"), ("custom.jpg", b"another")]);
+        let wow = WowPaths::from_config(&Config::default());
+        let mut state = State::default();
+        handle_screenshot_created(&wow, &mut state, &dir.join("WoWScrnShot_010124_000001.jpg")).unwrap();
+        handle_screenshot_created(&wow, &mut state, &dir.join("custom.jpg")).unwrap();
+        settle_screenshot_writes(&mut state, std::time::Instant::now() + SCREENSHOT_SETTLE);
+        let named = Local.with_ymd_and_hms(2024, 1, 1, 0, 0, 1).earliest().unwrap().timestamp();
+        assert_eq!(state.pending_screens[0].ts_epoch, named);
+        assert!((state.pending_screens[1].ts_epoch - Utc::now().timestamp()).abs() < 60);
+        let cfg = Config { pair_window_secs: Some(5), ..Config::default() };
+        let near = find_screenshots(&cfg, &state, named - 3, &[]);
+        assert_eq!(near.len(), 1, "the death just before midnight pairs with the shot after it");
+    }
+
+    // ---------- TGA screenshots ----------
+
+    #[tokio::test]
+    async fn tga_screenshots_are_sent_as_png_and_broken_ones_left_out() {
+        let (url, seen) = mock_server(|_, _| (200, "{}".into()));
+        let cfg = Config { api_url: format!("{url}/upload"), ..Config::default() };
+        let dir = shots_dir("tga", &[("WoWScrnShot_broken.tga", b"not a targa")]);
+        let tga = dir.join("WoWScrnShot_good.tga");
+        let img = image::RgbImage::from_fn(8, 4, |x, _| image::Rgb([(x * 30) as u8, 0, 0]));
+        img.save_with_format(&tga, image::ImageFormat::Tga).unwrap();
+        let on_disk = fs::read(&tga).unwrap();
+        assert!(is_screenshot_file(&tga));
+
+        let shot = read_screenshot(&cfg, &tga).unwrap();
+        assert_eq!((shot.file_name.as_str(), shot.content_type), ("WoWScrnShot_good.png", "image/png"));
+        assert_eq!(image::load_from_memory(&shot.bytes).unwrap().to_rgb8(), img);
+        let jpeg = read_screenshot(&Config { screenshot_recompress: true, ..cfg.clone() }, &tga).unwrap();
+        assert_eq!(jpeg.content_type, "image/jpeg");
+        assert_eq!(fs::read(&tga).unwrap(), on_disk, "the file itself is never changed");
+
+        let http = Http::new(&cfg).unwrap();
+        for (name, at) in [("WoWScrnShot_good.tga", 1_700_000_900), ("WoWScrnShot_broken.tga", 1_700_001_900)] {
+            let mut state = State::default();
+            queue_screenshot(&mut state, dir.join(name).to_string_lossy().to_string(), at);
+            process_death(&cfg, &http, &mut state, death("Targa", at), &[]).await.unwrap();
+            assert!(state.retry_queue.is_empty() && state.failed_uploads.is_empty(), "{name}");
+        }
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 2);
+        assert!(contains(&seen[0].2, "WoWScrnShot_good.png") && contains(&seen[0].2, "PNG"));
+        assert!(contains(&seen[1].2, "Targa") && !contains(&seen[1].2, "WoWScrnShot_broken"), "sent without the screenshot");
+    }
+
+    // ---------- Pending screenshot expiry ----------
+
+    #[test]
+    fn old_screenshots_leave_the_queue_once_no_death_can_pair_with_them() {
+        scratch_dir();
+        let cfg = Config::default();
+        let now = 1_700_100_000;
+        let queued = || {
+            let mut state = State::default();
+            for (name, age) in [("week.jpg", 7 * 86400), ("stale.jpg", 550), ("claimed.jpg", 550), ("fresh.jpg", 100)] {
+                queue_screenshot(&mut state, name.into(), now - age);
+            }
+            state.claimed_screens.insert("claimed.jpg".into());
+            state
+        };
+        let names = |state: &State| state.pending_screens.iter().map(|p| p.path.clone()).collect::<Vec<_>>();
+
+        // Past the default TTL (4 x 120s) and the SavedVariables written since.
+        let mut state = queued();
+        assert_eq!(expire_pending_screens(&cfg, &mut state, Some(now - 10), now), 2);
+        assert_eq!(names(&state), ["claimed.jpg", "fresh.jpg"]);
+
+        // Not written since the week-old one: deaths still in the game may be near the others.
+        let mut state = queued();
+        assert_eq!(expire_pending_screens(&cfg, &mut state, Some(now - 2000), now), 1);
+        assert_eq!(names(&state), ["stale.jpg", "claimed.jpg", "fresh.jpg"]);
+
+        // A file last written days ago holds them back no longer than the TTL plus the pairing window.
+        let mut state = queued();
+        queue_screenshot(&mut state, "older.jpg".into(), now - 700);
+        assert_eq!(expire_pending_screens(&cfg, &mut state, Some(now - 3 * 86400), now), 2);
+        assert_eq!(names(&state), ["stale.jpg", "claimed.jpg", "fresh.jpg"]);
+
+        let longer = Config { screenshot_ttl_secs: 3600, ..Config::default() };
+        let mut state = queued();
+        assert_eq!(expire_pending_screens(&longer, &mut state, None, now), 1);
+
+        // The count cap still applies on its own.
+        let mut state = State::default();
+        for i in 0..60 {
+            queue_screenshot(&mut state, format!("s{i}.jpg"), now);
+        }
+        assert_eq!(state.pending_screens.len(), 50);
+        assert_eq!(expire_pending_screens(&cfg, &mut state, Some(now), now), 0);
+    }
+
+    // ---------- Screenshot queue dedup ----------
+
+    #[test]
+    fn create_then_modify_queues_a_screenshot_once() {
+        let dir = shots_dir("queue-dedup", &[("WoWScrnShot_010124_120000.jpg", b"")]);
+        let wow = WowPaths::from_config(&Config::default());
+        let mut state = State::default();
+        let shot = dir.join("WoWScrnShot_010124_120000.jpg");
+        // Create (still empty), then two Modify events as the game writes it.
+        handle_screenshot_created(&wow, &mut state, &shot).unwrap();
+        fs::write(&shot, b"half").unwrap();
+        handle_screenshot_created(&wow, &mut state, &shot).unwrap();
+        fs::write(&shot, b"half and the rest").unwrap();
+        handle_screenshot_created(&wow, &mut state, &shot).unwrap();
+        assert_eq!(state.pending_screens.len(), 1);
+        assert_eq!(state.pending_screens[0].ts_epoch, screenshot_ts(&shot));
+
+        // Refreshing an entry moves its time, not its place.
+        queue_screenshot(&mut state, "other.jpg".into(), 5);
+        assert!(!queue_screenshot(&mut state, "other.jpg".into(), 7));
+        assert_eq!(state.pending_screens.iter().map(|p| p.ts_epoch).collect::<Vec<_>>(), [screenshot_ts(&shot), 7]);
+
+        // The same image saved again under a new name is not another shot; a different one is.
+        fs::write(dir.join("copy.jpg"), b"half and the rest").unwrap();
+        handle_screenshot_created(&wow, &mut state, &dir.join("copy.jpg")).unwrap();
+        fs::write(dir.join("new.jpg"), b"something else").unwrap();
+        handle_screenshot_created(&wow, &mut state, &dir.join("new.jpg")).unwrap();
+        let queued: Vec<&str> = state.pending_screens.iter().map(|p| p.path.as_str()).collect();
+        assert_eq!(queued.len(), 3, "{queued:?}");
+        assert!(queued[2].ends_with("new.jpg"));
+    }
+
+    // ---------- Thumbnails ----------
+
+    #[tokio::test]
+    async fn thumbnails_ride_along_when_enabled_and_never_block_the_upload() {
+        let (url, seen) = mock_server(|_, _| (200, "{}".into()));
+        let board = checkerboard("thumbnail", 1280, 720);
+        fs::write(board.with_file_name("broken.png"), b"not an image").unwrap();
+        let cfg = Config { api_url: format!("{url}/upload"), screenshot_thumbnail: true, ..Config::default() };
+        let http = Http::new(&cfg).unwrap();
+        let off = Config { screenshot_thumbnail: false, ..cfg.clone() };
+        let broken = board.with_file_name("broken.png");
+        for (cfg, shot, at) in [(&cfg, &board, 1_700_002_000), (&off, &board, 1_700_003_000), (&cfg, &broken, 1_700_004_000)] {
+            let mut state = State::default();
+            queue_screenshot(&mut state, shot.to_string_lossy().to_string(), at);
+            process_death(cfg, &http, &mut state, death("Thumbs", at), &[]).await.unwrap();
+            assert!(state.retry_queue.is_empty() && state.failed_uploads.is_empty());
+        }
+        let seen = seen.lock().unwrap();
+        let parts: Vec<BTreeMap<String, Vec<u8>>> = seen.iter().map(|(_, _, body)| form_parts(body)).collect();
+        let thumb = image::load_from_memory(&parts[0][PART_THUMBNAIL]).unwrap();
+        assert_eq!((thumb.width(), thumb.height()), (320, 180));
+        assert_eq!(image::guess_format(&parts[0][PART_THUMBNAIL]).unwrap(), image::ImageFormat::Jpeg);
+        assert!(contains(&seen[0].2, "board_thumb.jpg"));
+        assert_eq!(parts[0][PART_SCREENSHOT], fs::read(&board).unwrap(), "the screenshot itself is unchanged");
+        assert!(!parts[1].contains_key(PART_THUMBNAIL), "off by default");
+        assert!(parts[2].contains_key(PART_SCREENSHOT) && !parts[2].contains_key(PART_THUMBNAIL));
+    }
+
+    // ---------- Late screenshots ----------
+
+    #[tokio::test]
+    async fn a_screenshot_saved_after_the_upload_follows_the_death() {
+        let (url, seen) = mock_server(|_, _| (200, r#"{"id": "d-1"}"#.into()));
+        let cfg = Config { api_url: format!("{url}/upload"), ..Config::default() };
+        let http = Http::new(&cfg).unwrap();
+        let wow = WowPaths::from_config(&cfg);
+        let mut state = State::default();
+        let at = Utc::now().timestamp() - 5;
+        process_death(&cfg, &http, &mut state, death("Late", at), &[]).await.unwrap();
+        assert_eq!(state.awaiting_screenshot.len(), 1);
+        let idempotency_key = state.awaiting_screenshot[0].idempotency_key.clone();
+
+        // Disabled, or a screenshot outside the window: nothing is sent and it stays queued.
+        let dir = shots_dir("late", &[("late.jpg", b"late shot")]);
+        let shot = dir.join("late.jpg");
+        handle_screenshot_created(&wow, &mut state, &shot).unwrap();
+        attach_late_screenshot(&cfg, &http, &mut state, &shot).await;
+        assert_eq!(seen.lock().unwrap().len(), 1, "not while it may still be written");
+        assert_eq!(settle_screenshot_writes(&mut state, std::time::Instant::now() + SCREENSHOT_SETTLE), [shot.to_string_lossy()]);
+        let off = Config { late_screenshots: false, ..cfg.clone() };
+        attach_late_screenshot(&off, &http, &mut state, &shot).await;
+        state.pending_screens[0].ts_epoch = at + 500;
+        attach_late_screenshot(&cfg, &http, &mut state, &shot).await;
+        assert_eq!((seen.lock().unwrap().len(), state.pending_screens.len()), (1, 1));
+
+        state.pending_screens[0].ts_epoch = at + 5;
+        attach_late_screenshot(&cfg, &http, &mut state, &shot).await;
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 2);
+        let parts = form_parts(&seen[1].2);
+        assert!(!parts.contains_key(PART_DEATH));
+        assert_eq!(parts[PART_SCREENSHOT], b"late shot");
+        let late: serde_json::Value = serde_json::from_slice(&parts[PART_LATE_SCREENSHOT]).unwrap();
+        assert_eq!(late, json!({ "idempotency_key": idempotency_key, "id": "d-1", "at": at }));
+        assert!(state.pending_screens.is_empty() && state.awaiting_screenshot.is_empty());
+    }
+
+    // ---------- Addon settings ----------
+
+    /// Note the settings in an SV fixture as a parse would; returns whose they are.
+    fn settings_from(state: &mut State, name: &str) -> String {
+        let snapshot = parse_fixture(&Config::default(), name);
+        note_addon_settings(state, &fixture(name), &snapshot);
+        settings_owner(&fixture(name), &snapshot).unwrap()
+    }
+
+    #[tokio::test]
+    async fn auto_screenshot_off_ends_the_late_screenshot_wait_with_the_window() {
+        scratch_dir();
+        let (url, seen) = mock_server(|_, _| (200, "{}".into()));
+        let cfg = Config { api_url: format!("{url}/upload"), ..Config::default() };
+        let http = Http::new(&cfg).unwrap();
+        let wow = WowPaths::from_config(&cfg);
+        let mut state = State::default();
+        // Uploaded without a screenshot; the pairing window closed 30s ago.
+        let at = Utc::now().timestamp() - cfg.pair_window_after() - 30;
+        for (name, sent) in [("settings/off.lua", 0), ("settings/on.lua", 1)] {
+            let owner = settings_from(&mut state, name);
+            state.awaiting_screenshot = vec![AwaitingShot {
+                key: owner,
+                at,
+                idempotency_key: "k".into(),
+                targets: api_endpoints(&cfg).into_iter().map(|e| e.name).collect(),
+            }];
+            state.pending_screens.clear();
+            let shot = shots_dir(&format!("grace-{sent}"), &[("manual.jpg", b"manual shot")]).join("manual.jpg");
+            handle_screenshot_created(&wow, &mut state, &shot).unwrap();
+            settle_screenshot_writes(&mut state, std::time::Instant::now() + SCREENSHOT_SETTLE);
+            state.pending_screens[0].ts_epoch = at + 5;
+            attach_late_screenshot(&cfg, &http, &mut state, &shot).await;
+            assert_eq!(seen.lock().unwrap().len(), sent, "{name}");
+            assert!(state.awaiting_screenshot.is_empty(), "{name}: given up, or attached");
+        }
+    }
+
+    #[test]
+    fn doctor_reports_the_addon_settings_per_character() {
+        scratch_dir();
+        let cfg = Config::default();
+        let mut state = State::default();
+        let owner = settings_from(&mut state, "settings/off.lua");
+        assert_eq!(owner, "Thornwick@Silvermoon");
+        assert_eq!(
+            addon_settings_report(&cfg, &state),
+            [
+                "[doctor] Addon settings for Thornwick@Silvermoon: auto-screenshot OFF (pairing relies on manual screenshots)",
+                "      warning: the addon waits 200s before its screenshot but pair_window_after_secs is 120",
+            ]
+        );
+
+        // Turned back on in game: the next parse replaces what doctor shows.
+        assert_eq!(settings_from(&mut state, "settings/on.lua"), owner);
+        assert_eq!(
+            addon_settings_report(&cfg, &state),
+            ["[doctor] Addon settings for Thornwick@Silvermoon: auto-screenshot ON, keeps 50 deaths"]
+        );
+        assert!(addon_settings_report(&cfg, &State::default()).is_empty());
+    }
+
+    // ---------- Screenshot writes ----------
+
+    #[test]
+    fn screenshots_pair_only_once_written() {
+        let dir = shots_dir("shot-writes", &[("a.jpg", &[0xFF, 0xD8, 0xFF, 0xE0]), ("b.png", b"\x89PNG"), ("c.jpg", b"")]);
+        let wow = WowPaths::from_config(&Config::default());
+        let mut state = State::default();
+        let [a, b, c] = ["a.jpg", "b.png", "c.jpg"].map(|n| dir.join(n));
+        for shot in [&a, &b, &c] {
+            handle_screenshot_created(&wow, &mut state, shot).unwrap();
+        }
+        let t0 = std::time::Instant::now();
+        let pairable = |state: &State| {
+            let at = state.pending_screens[0].ts_epoch;
+            find_screenshots(&Config { attach_all_screenshots_in_window: true, ..Config::default() }, state, at, &[]).len()
+        };
+        assert!(settle_screenshot_writes(&mut state, t0).is_empty());
+        assert_eq!(pairable(&state), 0);
+
+        // A JPEG with its end marker is done at once.
+        fs::write(&a, [0xFF, 0xD8, 0xFF, 0xE0, 0, 0, 0, 0, 0, 0, 0xFF, 0xD9]).unwrap();
+        assert_eq!(settle_screenshot_writes(&mut state, t0), [a.to_string_lossy()]);
+        assert_eq!(pairable(&state), 1);
+
+        // Otherwise the size has to hold still for a moment; growing starts that over.
+        fs::write(&b, b"\x89PNG more").unwrap();
+        assert!(settle_screenshot_writes(&mut state, t0 + SCREENSHOT_SETTLE).is_empty());
+        assert_eq!(settle_screenshot_writes(&mut state, t0 + SCREENSHOT_SETTLE * 2), [b.to_string_lossy()]);
+
+        // One that stays empty is dropped.
+        assert!(settle_screenshot_writes(&mut state, t0 + SCREENSHOT_WRITE_TIMEOUT).is_empty());
+        let queued: Vec<&str> = state.pending_screens.iter().map(|p| p.path.as_str()).collect();
+        assert_eq!(queued.len(), 2, "{queued:?}");
+        assert!(state.unsettled_screens.is_empty());
+    }
+
+    // ---------- Pairing window ----------
+
+    #[test]
+    fn pairing_window_is_short_before_the_death_and_long_after() {
+        let at = 1_700_005_000;
+        let pairs = |cfg: &Config, offsets: &[i64]| {
+            let mut state = State::default();
+            for off in offsets {
+                queue_screenshot(&mut state, format!("{off}.jpg"), at + off);
+            }
+            find_screenshots(cfg, &state, at, &[]).into_iter().map(|p| p.ts_epoch - at).collect::<Vec<_>>()
+        };
+        let cfg = Config::default();
+        assert_eq!((cfg.pair_window_before(), cfg.pair_window_after()), (10, 120));
+        assert_eq!(pairs(&cfg, &[-10]), [-10]);
+        assert_eq!(pairs(&cfg, &[-11]), [0i64; 0]);
+        assert_eq!(pairs(&cfg, &[120]), [120]);
+        assert_eq!(pairs(&cfg, &[121]), [0i64; 0]);
+        assert_eq!(pairs(&cfg, &[-5, 5]), [5], "a tie goes to the shot after the death");
+        assert_eq!(pairs(&cfg, &[5, -5]), [5]);
+        assert_eq!(pairs(&cfg, &[-4, 5]), [-4], "nearer still wins");
+
+        // The old single key sets both sides; a side set on its own takes precedence.
+        let old: Config = toml::from_str("pair_window_secs = 30").unwrap();
+        assert_eq!(pairs(&old, &[-31]), [0i64; 0]);
+        assert_eq!(pairs(&old, &[-30]), [-30]);
+        assert_eq!(pairs(&old, &[31]), [0i64; 0]);
+        let mixed: Config = toml::from_str("pair_window_secs = 30\npair_window_after_secs = 300").unwrap();
+        assert_eq!((mixed.pair_window_before(), mixed.pair_window_after()), (30, 300));
+        assert_eq!((mixed.pair_window_key(false), mixed.pair_window_key(true)), ("pair_window_secs", "pair_window_after_secs"));
+    }
+
+    // ---------- Bulk backfill ----------
+
+    #[test]
+    fn backfill_needs_an_api_target() {
+        let mut cfg = Config { bulk_api_url: "http://localhost/bulk".into(), ..Config::default() };
+        assert!(backfill_applies(&cfg));
+        cfg.upload_mode = UploadMode::Discord;
+        assert!(!backfill_applies(&cfg));
+        cfg.upload_mode = UploadMode::Api;
+        cfg.uploads_enabled = false;
+        assert!(!backfill_applies(&cfg));
+        cfg.uploads_enabled = true;
+        cfg.bulk_api_url.clear();
+        assert!(!backfill_applies(&cfg));
+    }
+
+    #[tokio::test]
+    async fn backfill_reaches_every_target() {
+        scratch_dir();
+        let (url, seen) = mock_server(|path, _| match path {
+            "/bulk" => (200, "{\"status\":201}\n{\"status\":503}\n".into()),
+            _ => (200, String::new()),
+        });
+        let endpoint = |name: &str| Endpoint { name: name.into(), url: format!("{url}/{name}"), ..Endpoint::default() };
+        let cfg = Config {
+            endpoints: vec![endpoint("main"), endpoint("mirror")],
+            bulk_api_url: format!("{url}/bulk"),
+            discord_webhook_url: format!("{url}/discord"),
+            ..Config::default()
+        };
+        let http = Http::new(&cfg).unwrap();
+        let mut state = State::default();
+        let deaths = backfill_candidates(&cfg, &mut state, vec![death("Backfill", 100), death("Backfill", 200)]).unwrap();
+        backfill(&cfg, &http, &mut state, deaths).await;
+
+        let seen = paths(&seen);
+        assert_eq!(seen.iter().filter(|p| *p == "/bulk").count(), 1);
+        assert_eq!(seen.iter().filter(|p| *p == "/mirror").count(), 2);
+        assert_eq!(seen.iter().filter(|p| *p == "/discord").count(), 2);
+        assert!(!seen.contains(&"/main".to_string()));
+        // Only the primary refused the second death, so only the primary owes it.
+        assert_eq!(state.retry_queue.len(), 1);
+        assert_eq!(state.retry_queue[0].death.at, 200);
+        assert_eq!(state.retry_queue[0].targets, vec!["main".to_string()]);
+        assert!(state.retry_queue[0].delivered);
+        assert_eq!(state.last_uploaded.get(&death("Backfill", 0).key()), Some(&200));
+    }
+
+    // ---------- Retry queue ----------
+
+    fn queued(player: &str, at: i64) -> RetryEntry {
+        RetryEntry {
+            death: death(player, at),
+            screenshot: None,
+            extra_screenshots: vec![],
+            attempts: 1,
+            next_at: 0,
+            last_error: "503".into(),
+            targets: vec![],
+            delivered: false,
+            idempotency_key: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_keeps_entries_when_cancelled() {
+        scratch_dir();
+        let (url, _) = mock_server(|_, _| {
+            std::thread::sleep(Duration::from_secs(2));
+            (200, String::new())
+        });
+        let cfg = Config { api_url: url, upload_mode: UploadMode::Api, ..Config::default() };
+        let http = Http::new(&cfg).unwrap();
+        let mut state = State { retry_queue: vec![queued("Cancel", 100), queued("Cancel", 200)], ..State::default() };
+        let pass = tokio::time::timeout(Duration::from_millis(300), retry_due(&cfg, &http, &mut state)).await;
+        assert!(pass.is_err());
+        let left: Vec<i64> = state.retry_queue.iter().map(|r| r.death.at).collect();
+        assert_eq!(left, vec![100, 200]);
+    }
+
+    #[tokio::test]
+    async fn retry_settles_each_entry() {
+        scratch_dir();
+        let (url, _) = mock_server(|_, body| {
+            if String::from_utf8_lossy(body).contains("\"at\":100") {
+                (201, String::new())
+            } else {
+                (503, String::new())
+            }
+        });
+        let cfg = Config { api_url: url, upload_mode: UploadMode::Api, ..Config::default() };
+        let http = Http::new(&cfg).unwrap();
+        let mut state = State { retry_queue: vec![queued("Settle", 200), queued("Settle", 100)], ..State::default() };
+        retry_due(&cfg, &http, &mut state).await;
+        assert_eq!(state.retry_queue.len(), 1);
+        assert_eq!(state.retry_queue[0].death.at, 200);
+        assert_eq!(state.retry_queue[0].attempts, 2);
+        assert!(state.retry_queue[0].next_at > Utc::now().timestamp());
+        assert_eq!(state.last_uploaded.get(&death("Settle", 0).key()), Some(&100));
+    }
+
+    #[test]
+    fn staged_death_settles_after_delivery() {
+        scratch_dir();
+        let cfg = Config::default();
+        let mut state = State::default();
+        state.pending_screens.push_back(PendingShot { path: "shot.png".into(), ts_epoch: 100 });
+        let staged = stage_death(&cfg, &mut state, death("Stage", 100), &[]).unwrap().unwrap();
+        assert_eq!(staged.screenshot.as_deref(), Some("shot.png"));
+        // Not settled yet: an interrupted upload leaves both to the next start.
+        assert!(is_new_death(&state, &staged.key, 100, &staged.hash));
+        assert_eq!(state.pending_screens.len(), 1);
+        assert!(find_screenshots(&cfg, &state, 100, &[]).is_empty());
+
+        let key = staged.key.clone();
+        let hash = staged.hash.clone();
+        finish_staged(&mut state, staged, vec![("default".into(), Ok(None))], std::time::Instant::now());
+        assert!(!is_new_death(&state, &key, 100, &hash));
+        assert!(state.pending_screens.is_empty());
+        assert!(state.claimed_screens.is_empty());
+    }
+
+    // ---------- Screenshot hash index ----------
+
+    fn shots_dir(name: &str, files: &[(&str, &[u8])]) -> PathBuf {
+        let dir = scratch_dir().join(name);
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        for (file, bytes) in files {
+            fs::write(dir.join(file), bytes).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn index_build_resumes_after_restart() {
+        let files: Vec<(String, Vec<u8>)> = (0..5).map(|i| (format!("s{i}.png"), vec![i; 8])).collect();
+        let files: Vec<(&str, &[u8])> = files.iter().map(|(n, b)| (n.as_str(), b.as_slice())).collect();
+        let dir = shots_dir("index-resume", &files);
+        let mut index = ScreenshotIndex::default();
+        index.queue_build(&dir);
+        assert_eq!(index.build_step(2), 2);
+        assert_eq!(index.progress(&dir), (2, 5));
+
+        // A restart lists the folder again; the two hashed files are skipped.
+        let mut index = ScreenshotIndex::load();
+        index.queue_build(&dir);
+        assert_eq!(index.build_step(10), 3);
+        assert_eq!(index.progress(&dir), (5, 5));
+        assert_eq!(index.build_step(10), 0);
+    }
+
+    #[test]
+    fn index_skips_only_unchanged_files() {
+        let dir = shots_dir("index-skip", &[("a.png", b"first"), ("b.png", b"second")]);
+        let mut index = ScreenshotIndex::default();
+        index.queue_build(&dir);
+        assert_eq!(index.build_step(10), 2);
+        let old = index.hash_of(&dir.join("a.png")).unwrap();
+
+        // Same size, new mtime: hashed again.
+        fs::write(dir.join("a.png"), b"FIRST").unwrap();
+        let later = SystemTime::now() + Duration::from_secs(5);
+        File::options().write(true).open(dir.join("a.png")).unwrap().set_modified(later).unwrap();
+        index.queue_build(&dir);
+        assert_eq!(index.build_step(10), 1);
+        assert_ne!(index.hash_of(&dir.join("a.png")).unwrap(), old);
+
+        fs::remove_file(dir.join("b.png")).unwrap();
+        index.queue_build(&dir);
+        assert_eq!(index.entries.len(), 1);
+    }
+
+    #[test]
+    fn pairing_attaches_identical_pictures_once() {
+        let dir = shots_dir("index-pair", &[("a.png", b"same"), ("b.png", b"same"), ("c.png", b"other")]);
+        let cfg = Config { attach_all_screenshots_in_window: true, max_screenshots_per_death: 5, ..Config::default() };
+        let mut state = State::default();
+        // a.png is indexed; the others are hashed on demand.
+        state.shot_index.note_file(&dir.join("a.png"));
+        for (name, ts) in [("a.png", 100), ("b.png", 101), ("c.png", 102)] {
+            queue_screenshot(&mut state, dir.join(name).to_string_lossy().to_string(), ts);
+        }
+        let near: Vec<String> = find_screenshots(&cfg, &state, 100, &[]).into_iter().map(|p| p.path).collect();
+        assert_eq!(near, vec![dir.join("a.png").to_string_lossy().to_string(), dir.join("c.png").to_string_lossy().to_string()]);
+    }
+
+    // ---------- Parsing ----------
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+    }
+
+    fn parse_fixture(cfg: &Config, name: &str) -> SvSnapshot {
+        let adapter = DeathLoggerAdapter { file_name: cfg.sv_file_name.clone(), fmt: SvFormat::from_config(cfg) };
+        adapter.parse(&fixture(name), None).unwrap()
+    }
+
+    #[tokio::test]
+    async fn unknown_entry_fields_reach_the_upload_unchanged() {
+        scratch_dir();
+        let (url, seen) = mock_server(|_, _| (201, String::new()));
+        let cfg = Config { api_url: url, upload_mode: UploadMode::Api, upload_format: UploadFormat::Json, ..Config::default() };
+        let mut death = parse_fixture(&cfg, "unknown_fields.lua").latest.unwrap();
+        prepare_payload(&cfg, &mut death).unwrap();
+        let http = Http::new(&cfg).unwrap();
+        let results = deliver(&cfg, &http, &mut BTreeMap::new(), &death, "key", &[], &upload_targets(&cfg)).await;
+        assert!(results[0].1.is_ok());
+
+        let body: serde_json::Value = serde_json::from_slice(&seen.lock().unwrap()[0].2).unwrap();
+        assert_eq!(body["guild"], json!("Tide"));
+        assert_eq!(body["hardcore"], json!(true));
+        assert_eq!(body["playedTime"], json!(123456));
+        assert_eq!(body["selfFound"], json!({ "since": 1699990000, "rules": ["no_trade", "no_mail"] }));
+        assert_eq!(body["level"], json!(31));
+        // Retry entries keep them across restarts.
+        let stored: DeathPayload = serde_json::from_value(serde_json::to_value(&death).unwrap()).unwrap();
+        assert_eq!(stored.extra, death.extra);
+    }
+
+    #[test]
+    fn unknown_entry_fields_can_be_dropped() {
+        let cfg = Config { drop_unknown_fields: true, ..Config::default() };
+        let mut death = parse_fixture(&cfg, "unknown_fields.lua").latest.unwrap();
+        assert_eq!(death.extra.len(), 4);
+        prepare_payload(&cfg, &mut death).unwrap();
+        let body = serde_json::to_value(&death).unwrap();
+        assert!(body.get("guild").is_none());
+        assert_eq!(body["class"], json!("ROGUE"));
+    }
+
+    #[test]
+    fn bom_is_stripped() {
+        let (text, replaced) = read_sv_text(&fixture("bom.lua")).unwrap();
+        assert!(text.starts_with("DeathLoggerDB"));
+        assert_eq!(replaced, 0);
+        let death = parse_fixture(&Config::default(), "bom.lua").latest.unwrap();
+        assert_eq!((death.player.as_str(), death.at), ("Bommy", 1700000100));
+    }
+
+    #[test]
+    fn invalid_utf8_is_replaced_not_fatal() {
+        let (_, replaced) = read_sv_text(&fixture("invalid_utf8.lua")).unwrap();
+        assert_eq!(replaced, 4);
+        let death = parse_fixture(&Config::default(), "invalid_utf8.lua").latest.unwrap();
+        assert_eq!(death.player, "Brokeny");
+        let Killer::Info(killer) = &death.killer else { panic!("killer not read: {:?}", death.killer) };
+        assert!(killer.name.as_deref().unwrap().contains('\u{FFFD}'));
+    }
+
+    /// A DeathLoggerDB file with one entry per `at`, in that order.
+    fn sv_with_deaths(name: &str, ats: impl IntoIterator<Item = i64>) -> PathBuf {
+        let mut text = String::from("DeathLoggerDB = {\n\t[\"deaths\"] = {\n");
+        for at in ats {
+            text.push_str(&format!(
+                "\t\t{{ [\"player\"] = \"Cursor\", [\"realm\"] = \"Realm\", [\"at\"] = {at}, [\"level\"] = 10, \
+                 [\"bags\"] = {{ {{ [\"id\"] = 6948, [\"count\"] = 1 }}, {{ [\"id\"] = 2589, [\"count\"] = 20 }} }} }},\n"
+            ));
+        }
+        text.push_str("\t},\n}\n");
+        let path = scratch_dir().join(name);
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn parse_from(path: &Path, cursor: Option<SvCursor>) -> SvSnapshot {
+        let cfg = Config::default();
+        let adapter = DeathLoggerAdapter { file_name: cfg.sv_file_name.clone(), fmt: SvFormat::from_config(&cfg) };
+        let marks = SvMarks { uploaded: BTreeMap::from([("Cursor@Realm".to_string(), 1_000_800)]), cursor };
+        adapter.parse(path, Some(&marks)).unwrap()
+    }
+
+    #[test]
+    fn cursor_reads_only_new_entries() {
+        let path = sv_with_deaths("cursor-grow.lua", 1_000_001..=1_000_800);
+        let started = std::time::Instant::now();
+        let full = parse_from(&path, None);
+        let full_ms = started.elapsed().as_millis();
+        assert_eq!((full.scanned, full.count), (800, 800));
+        let cursor = full.cursor.unwrap();
+        assert_eq!((cursor.index, cursor.at, cursor.count), (800, 1_000_800, 800));
+
+        let path = sv_with_deaths("cursor-grow.lua", 1_000_001..=1_000_802);
+        let started = std::time::Instant::now();
+        let next = parse_from(&path, Some(cursor));
+        println!("800 entries: {full_ms} ms in full, {} ms from the cursor", started.elapsed().as_millis());
+        assert_eq!((next.scanned, next.count, next.max_index), (2, 802, 802));
+        assert_eq!(next.latest.unwrap().at, 1_000_802);
+        assert_eq!(next.history.iter().map(|d| d.at).collect::<Vec<_>>(), vec![1_000_801]);
+        assert_eq!(next.cursor.unwrap().index, 802);
+
+        // Nothing new: only the newest entry is read.
+        let again = parse_from(&path, Some(SvCursor { index: 802, at: 1_000_802, count: 802, identity: None }));
+        assert_eq!((again.scanned, again.count), (1, 802));
+        assert_eq!(again.latest.unwrap().at, 1_000_802);
+    }
+
+    #[test]
+    fn cursor_is_dropped_when_the_table_changes_under_it() {
+        let cursor = SvCursor { index: 800, at: 1_000_800, count: 800, identity: None };
+        // Wiped and started over.
+        let wiped = parse_from(&sv_with_deaths("cursor-wiped.lua", [2_000_001, 2_000_002]), Some(cursor.clone()));
+        assert_eq!((wiped.scanned, wiped.count), (2, 2));
+        assert_eq!(wiped.latest.unwrap().at, 2_000_002);
+        // Capped at 800 by the addon: everything moved down one slot.
+        let rotated = parse_from(&sv_with_deaths("cursor-rotated.lua", 1_000_002..=1_000_801), Some(cursor));
+        assert_eq!(rotated.scanned, 800);
+        assert_eq!(rotated.latest.unwrap().at, 1_000_801);
+    }
+
+    // ---------- SavedVariables backups ----------
+
+    #[test]
+    fn corrupt_file_falls_back_to_its_backup() {
+        let cfg = Config::default();
+        let adapter = DeathLoggerAdapter { file_name: cfg.sv_file_name.clone(), fmt: SvFormat::from_config(&cfg) };
+        let main = fixture("backup/DeathLogger.lua");
+        assert!(adapter.parse(&main, None).is_err());
+        let marks = SvMarks::default();
+        let snapshot = parse_sv_or_backup(&adapter, &main, Some(&marks)).unwrap();
+        assert!(snapshot.main_error.is_some());
+        let latest = snapshot.latest.unwrap();
+        assert_eq!((latest.player.as_str(), latest.at), ("Backy", 1700000400));
+        assert_eq!(latest.recovered_from_backup, Some(true));
+        assert_eq!(snapshot.history.len(), 1);
+        assert!(snapshot.history.iter().all(|d| d.recovered_from_backup == Some(true)));
+        assert_eq!(serde_json::to_value(&latest).unwrap()["recovered_from_backup"], json!(true));
+        // The same entry read from the intact file later is not a new death.
+        let intact = adapter.parse(&fixture("backup/DeathLogger.lua.bak"), None).unwrap().latest.unwrap();
+        assert_eq!(entry_hash(&intact), entry_hash(&latest));
+    }
+
+    #[test]
+    fn corrupt_file_without_backup_still_fails() {
+        let cfg = Config::default();
+        let adapter = DeathLoggerAdapter { file_name: cfg.sv_file_name.clone(), fmt: SvFormat::from_config(&cfg) };
+        let lone = scratch_dir().join("lone.lua");
+        fs::copy(fixture("backup/DeathLogger.lua"), &lone).unwrap();
+        assert!(parse_sv_or_backup(&adapter, &lone, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn backup_parse_keeps_the_file_deferred() {
+        let root = scratch_dir().join("wow-backup");
+        let sv_dir = root.join("_retail_/WTF/Account/ACC/SavedVariables");
+        fs::create_dir_all(&sv_dir).unwrap();
+        for name in ["DeathLogger.lua", "DeathLogger.lua.bak"] {
+            fs::copy(fixture(&format!("backup/{name}")), sv_dir.join(name)).unwrap();
+        }
+        let cfg = Config { wow_root: root.to_string_lossy().to_string(), uploads_enabled: false, ..Config::default() };
+        let wow = WowPaths::from_config(&cfg);
+        let http = Http::new(&cfg).unwrap();
+        let mut state = State::default();
+        let sv = sv_dir.join("DeathLogger.lua");
+        let err = handle_sv_change(&cfg, &http, &wow, &mut state, &sv).await.unwrap_err();
+        assert!(err.downcast_ref::<SvParseError>().is_some());
+        assert!(!state.sv_seen.contains_key(&sv));
+        assert!(state.sv_cursors.is_empty());
+        assert_eq!(state.sv_tables[&sv.to_string_lossy().to_string()].count, 2);
+    }
+}
@@ -0,0 +1,333 @@
+//! Persisted daily counters and the reliability summary built from them.
+
+use chrono::{Duration as ChronoDuration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// How many days of counters to keep in state.json
+const KEEP_DAYS: i64 = 90;
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DayMetrics {
+    pub deaths_detected: u64,
+    pub deaths_uploaded: u64,
+    pub screenshots_paired: u64,
+    pub parse_errors: u64,
+    /// Death entries left out for failing validation
+    pub entries_skipped: u64,
+    /// Sum of upload durations; divide by `deaths_uploaded` for the average
+    pub upload_latency_ms_total: u64,
+    /// Times the file watcher failed and was recreated
+    pub watcher_restarts: u64,
+    /// Longest gap between two SavedVariables writes seen while the agent was running
+    pub longest_sv_gap_secs: i64,
+    /// Requests refused by `network_allowlist`, per feature
+    pub requests_blocked: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Metrics {
+    pub days: BTreeMap<NaiveDate, DayMetrics>,
+    /// When the last periodic reliability summary was logged
+    pub last_summary_at: Option<i64>,
+    /// Last SavedVariables write observed (epoch secs), for gap tracking
+    pub last_sv_activity: Option<i64>,
+    /// Newest death counted as detected per character, so re-parses don't double count
+    pub last_detected: BTreeMap<String, i64>,
+}
+
+impl Metrics {
+    pub fn today(&mut self) -> &mut DayMetrics {
+        let today = Utc::now().date_naive();
+        let cutoff = today - ChronoDuration::days(KEEP_DAYS);
+        self.days.retain(|d, _| *d > cutoff);
+        self.days.entry(today).or_default()
+    }
+
+    pub fn record_sv_activity(&mut self, now: i64) {
+        if let Some(prev) = self.last_sv_activity {
+            let gap = now - prev;
+            let day = self.today();
+            day.longest_sv_gap_secs = day.longest_sv_gap_secs.max(gap);
+        }
+        self.last_sv_activity = Some(now);
+    }
+
+    /// Count a death as detected once, however many times it gets re-parsed.
+    pub fn record_detected(&mut self, key: &str, at: i64) {
+        if self.last_detected.get(key).map(|prev| at > *prev).unwrap_or(true) {
+            self.last_detected.insert(key.to_string(), at);
+            self.today().deaths_detected += 1;
+        }
+    }
+
+    /// Sum the days in `[start, end)`.
+    pub fn totals(&self, start: NaiveDate, end: NaiveDate) -> PeriodTotals {
+        let mut t = PeriodTotals::default();
+        for (_, d) in self.days.range(start..end) {
+            t.deaths_detected += d.deaths_detected;
+            t.deaths_uploaded += d.deaths_uploaded;
+            t.screenshots_paired += d.screenshots_paired;
+            t.parse_errors += d.parse_errors;
+            t.entries_skipped += d.entries_skipped;
+            t.upload_latency_ms_total += d.upload_latency_ms_total;
+            t.watcher_restarts += d.watcher_restarts;
+            t.longest_sv_gap_secs = t.longest_sv_gap_secs.max(d.longest_sv_gap_secs);
+            for (feature, n) in &d.requests_blocked {
+                *t.requests_blocked.entry(feature.clone()).or_insert(0) += n;
+            }
+        }
+        t
+    }
+
+    /// Compare the `period_days` ending at `end` (exclusive) with the period before it.
+    pub fn reliability_summary(&self, end: NaiveDate, period_days: i64) -> ReliabilitySummary {
+        let period = ChronoDuration::days(period_days.max(1));
+        ReliabilitySummary {
+            period_days: period_days.max(1),
+            current: self.totals(end - period, end),
+            previous: self.totals(end - period - period, end - period),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PeriodTotals {
+    pub deaths_detected: u64,
+    pub deaths_uploaded: u64,
+    pub screenshots_paired: u64,
+    pub parse_errors: u64,
+    pub entries_skipped: u64,
+    pub upload_latency_ms_total: u64,
+    pub watcher_restarts: u64,
+    pub longest_sv_gap_secs: i64,
+    pub requests_blocked: BTreeMap<String, u64>,
+}
+
+impl PeriodTotals {
+    pub fn upload_rate(&self) -> Option<f64> {
+        ratio(self.deaths_uploaded, self.deaths_detected)
+    }
+
+    pub fn pairing_rate(&self) -> Option<f64> {
+        ratio(self.screenshots_paired, self.deaths_uploaded)
+    }
+
+    pub fn avg_upload_latency_ms(&self) -> Option<f64> {
+        ratio(self.upload_latency_ms_total, self.deaths_uploaded)
+    }
+}
+
+fn ratio(n: u64, d: u64) -> Option<f64> {
+    if d == 0 {
+        None
+    } else {
+        Some(n as f64 / d as f64)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReliabilitySummary {
+    pub period_days: i64,
+    pub current: PeriodTotals,
+    pub previous: PeriodTotals,
+}
+
+/// Better/worse marker for a metric. `higher_is_better` picks the direction.
+fn trend(cur: Option<f64>, prev: Option<f64>, higher_is_better: bool) -> &'static str {
+    match (cur, prev) {
+        (Some(c), Some(p)) if (c - p).abs() < f64::EPSILON => "=",
+        (Some(c), Some(p)) if (c > p) == higher_is_better => "better",
+        (Some(_), Some(_)) => "worse",
+        _ => "",
+    }
+}
+
+fn pct(v: Option<f64>) -> String {
+    v.map(|r| format!("{:.0}%", r * 100.0)).unwrap_or_else(|| "n/a".into())
+}
+
+impl ReliabilitySummary {
+    /// One line for a notification: uploads, pairing and parse errors this period.
+    pub fn headline(&self) -> String {
+        let c = &self.current;
+        format!(
+            "Last {} day(s): {}/{} deaths uploaded, {} screenshots paired, {} parse error(s)",
+            self.period_days,
+            c.deaths_uploaded,
+            c.deaths_detected,
+            pct(c.pairing_rate()),
+            c.parse_errors
+        )
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        let (c, p) = (&self.current, &self.previous);
+        let row = |label: &str, cur: String, prev: String, t: &str| {
+            format!("  {:<26} {:>10}   (previous {:>8}) {}", label, cur, prev, t)
+        };
+        let mut out = vec![format!("Reliability over the last {} day(s):", self.period_days)];
+        if c == &PeriodTotals::default() {
+            out.push("  No activity recorded in this period.".into());
+        }
+        out.push(row(
+            "Deaths detected/uploaded",
+            format!("{}/{}", c.deaths_detected, c.deaths_uploaded),
+            format!("{}/{}", p.deaths_detected, p.deaths_uploaded),
+            trend(c.upload_rate(), p.upload_rate(), true),
+        ));
+        out.push(row(
+            "Screenshot pairing rate",
+            pct(c.pairing_rate()),
+            pct(p.pairing_rate()),
+            trend(c.pairing_rate(), p.pairing_rate(), true),
+        ));
+        out.push(row(
+            "Parse errors",
+            c.parse_errors.to_string(),
+            p.parse_errors.to_string(),
+            trend(Some(c.parse_errors as f64), Some(p.parse_errors as f64), false),
+        ));
+        out.push(row(
+            "Malformed entries skipped",
+            c.entries_skipped.to_string(),
+            p.entries_skipped.to_string(),
+            trend(Some(c.entries_skipped as f64), Some(p.entries_skipped as f64), false),
+        ));
+        out.push(row(
+            "Average upload latency",
+            c.avg_upload_latency_ms().map(|ms| format!("{:.0} ms", ms)).unwrap_or_else(|| "n/a".into()),
+            p.avg_upload_latency_ms().map(|ms| format!("{:.0} ms", ms)).unwrap_or_else(|| "n/a".into()),
+            trend(c.avg_upload_latency_ms(), p.avg_upload_latency_ms(), false),
+        ));
+        out.push(row(
+            "Watcher restarts",
+            c.watcher_restarts.to_string(),
+            p.watcher_restarts.to_string(),
+            trend(Some(c.watcher_restarts as f64), Some(p.watcher_restarts as f64), false),
+        ));
+        out.push(row(
+            "Longest gap between SV writes",
+            format!("{}s", c.longest_sv_gap_secs),
+            format!("{}s", p.longest_sv_gap_secs),
+            "",
+        ));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap()
+    }
+
+    fn active(detected: u64, uploaded: u64, paired: u64, latency_ms: u64) -> DayMetrics {
+        DayMetrics {
+            deaths_detected: detected,
+            deaths_uploaded: uploaded,
+            screenshots_paired: paired,
+            upload_latency_ms_total: latency_ms,
+            ..DayMetrics::default()
+        }
+    }
+
+    /// `(day, metrics)` pairs as a history.
+    fn history(days: Vec<(&str, DayMetrics)>) -> Metrics {
+        Metrics { days: days.into_iter().map(|(d, m)| (day(d), m)).collect(), ..Metrics::default() }
+    }
+
+    #[test]
+    fn empty_history_reports_no_activity() {
+        let summary = Metrics::default().reliability_summary(day("2024-03-15"), 7);
+        assert_eq!(summary.current, PeriodTotals::default());
+        assert_eq!(summary.current.upload_rate(), None);
+        assert_eq!(summary.current.avg_upload_latency_ms(), None);
+        let lines = summary.lines();
+        assert_eq!(lines[0], "Reliability over the last 7 day(s):");
+        assert_eq!(lines[1], "  No activity recorded in this period.");
+        assert!(lines.iter().all(|l| !l.ends_with("better") && !l.ends_with("worse")), "{lines:#?}");
+        assert!(lines.iter().any(|l| l.contains("Screenshot pairing rate") && l.contains("n/a")));
+        assert_eq!(summary.headline(), "Last 7 day(s): 0/0 deaths uploaded, n/a screenshots paired, 0 parse error(s)");
+    }
+
+    #[test]
+    fn partial_period_compares_against_an_empty_one() {
+        // Activity only in the last two days of the current week.
+        let metrics = history(vec![("2024-03-13", active(2, 1, 1, 400)), ("2024-03-14", active(2, 2, 0, 200))]);
+        let summary = metrics.reliability_summary(day("2024-03-15"), 7);
+        assert_eq!((summary.current.deaths_detected, summary.current.deaths_uploaded), (4, 3));
+        assert_eq!(summary.previous, PeriodTotals::default());
+        assert_eq!(summary.current.avg_upload_latency_ms(), Some(200.0));
+        let row = summary.lines().into_iter().find(|l| l.contains("Deaths detected/uploaded")).unwrap();
+        assert!(row.contains("4/3") && row.contains("0/0"), "{row}");
+        assert!(row.trim_end().ends_with(')'), "no trend without a previous rate: {row}");
+    }
+
+    #[test]
+    fn full_periods_show_better_and_worse() {
+        let mut days = vec![];
+        // Previous week: 7 detected, 5 uploaded, slow uploads, one parse error a day.
+        for d in 1..=7 {
+            let mut m = active(1, if d <= 5 { 1 } else { 0 }, 0, if d <= 5 { 900 } else { 0 });
+            m.parse_errors = 1;
+            days.push((format!("2024-03-{:02}", d), m));
+        }
+        // Current week: everything uploaded and paired, faster, one watcher restart.
+        for d in 8..=14 {
+            let mut m = active(1, 1, 1, 300);
+            m.watcher_restarts = u64::from(d == 10);
+            m.longest_sv_gap_secs = if d == 12 { 3600 } else { 60 };
+            days.push((format!("2024-03-{:02}", d), m));
+        }
+        let metrics = history(days.iter().map(|(d, m)| (d.as_str(), m.clone())).collect());
+        let summary = metrics.reliability_summary(day("2024-03-15"), 7);
+        assert_eq!(summary.current.deaths_uploaded, 7);
+        assert_eq!(summary.previous.deaths_uploaded, 5);
+        assert_eq!(summary.previous.parse_errors, 7);
+        assert_eq!(summary.current.longest_sv_gap_secs, 3600);
+
+        let lines = summary.lines();
+        let row = |label: &str| lines.iter().find(|l| l.contains(label)).unwrap().clone();
+        assert!(row("Deaths detected/uploaded").contains("7/7"));
+        assert!(row("Deaths detected/uploaded").ends_with("better"));
+        assert!(row("Screenshot pairing rate").contains("100%") && row("Screenshot pairing rate").ends_with("better"));
+        assert!(row("Parse errors").ends_with("better"));
+        assert!(row("Average upload latency").contains("300 ms") && row("Average upload latency").ends_with("better"));
+        assert!(row("Watcher restarts").ends_with("worse"));
+        assert!(row("Longest gap between SV writes").contains("3600s"));
+        assert!(!lines.iter().any(|l| l.contains("No activity")));
+    }
+
+    #[test]
+    fn days_outside_both_periods_are_ignored() {
+        let metrics = history(vec![("2024-02-01", active(9, 9, 9, 9)), ("2024-03-15", active(5, 5, 5, 5))]);
+        let summary = metrics.reliability_summary(day("2024-03-15"), 7);
+        assert_eq!(summary.current, PeriodTotals::default(), "the end day is excluded");
+        assert_eq!(summary.previous, PeriodTotals::default());
+    }
+
+    #[test]
+    fn sv_gaps_start_from_the_first_write_seen() {
+        let mut metrics = Metrics::default();
+        metrics.record_sv_activity(1_000);
+        assert_eq!(metrics.today().longest_sv_gap_secs, 0);
+        metrics.record_sv_activity(1_090);
+        metrics.record_sv_activity(1_100);
+        assert_eq!(metrics.today().longest_sv_gap_secs, 90);
+    }
+
+    #[test]
+    fn a_death_is_detected_once() {
+        let mut metrics = Metrics::default();
+        metrics.record_detected("ACC:A@R", 10);
+        metrics.record_detected("ACC:A@R", 10);
+        metrics.record_detected("ACC:A@R", 5);
+        metrics.record_detected("ACC:A@R", 11);
+        assert_eq!(metrics.today().deaths_detected, 2);
+    }
+}
@@ -0,0 +1,395 @@
+//! Screenshot pairing: the queue of screenshots waiting for a death, their
+//! writes settling, the pairing window and late screenshots.
+
+use crate::*;
+
+// ---------- Screenshot queue ----------
+
+pub(crate) fn screenshot_mime(p: &Path) -> &'static str {
+    match p.extension().and_then(|e| e.to_str()).unwrap_or("").to_ascii_lowercase().as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+// Check typical image extensions WoW uses (jpg, png, and tga on some Classic setups)
+pub(crate) fn is_screenshot_file(p: &Path) -> bool {
+    if !p.is_file() {
+        return false;
+    }
+    matches!(
+        p.extension().and_then(|e| e.to_str()).unwrap_or("").to_ascii_lowercase().as_str(),
+        "jpg" | "jpeg" | "png" | "tga"
+    )
+}
+
+/// TGA screenshots are never sent as they are; `read_screenshot` converts them.
+pub(crate) fn is_tga(p: &Path) -> bool {
+    p.extension().and_then(|e| e.to_str()).is_some_and(|e| e.eq_ignore_ascii_case("tga"))
+}
+
+/// False for a TGA that can't be converted, which is then left out so the
+/// death still goes out. The conversion is cached, so sending it costs no second decode.
+pub(crate) fn screenshot_sendable(cfg: &Config, sc: &Path) -> bool {
+    if !is_tga(sc) {
+        return true;
+    }
+    match read_screenshot(cfg, sc) {
+        Ok(_) => true,
+        Err(e) => {
+            eprintln!("[warn] {e:#}; sending the death without it");
+            false
+        }
+    }
+}
+
+/// When a screenshot was taken, as far as pairing is concerned: the time in a
+/// `WoWScrnShot_MMDDYY_HHMMSS` name, else the file's mtime. Sync clients and
+/// virus scanners rewrite mtimes; the name keeps the capture time.
+pub(crate) fn screenshot_ts(path: &Path) -> i64 {
+    path.file_stem()
+        .and_then(|s| s.to_str())
+        .and_then(|stem| screenshot_name_ts(stem, &Local))
+        .or_else(|| newest_mtime(path).and_then(|st| st.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs() as i64))
+        .unwrap_or_else(|| Utc::now().timestamp())
+}
+
+pub(crate) static SCREENSHOT_NAME: once_cell::sync::Lazy<Regex> =
+    once_cell::sync::Lazy::new(|| Regex::new(r"^WoWScrnShot_(\d{2})(\d{2})(\d{2})_(\d{2})(\d{2})(\d{2})$").unwrap());
+
+/// The capture time in a WoW screenshot name, read as wall-clock time in `tz`
+/// (the game writes local time). Two-digit years 69-99 are 19xx, like `%y`.
+/// None for other names, impossible dates, and times a DST change skips.
+pub(crate) fn screenshot_name_ts<Tz: TimeZone>(stem: &str, tz: &Tz) -> Option<i64> {
+    let caps = SCREENSHOT_NAME.captures(stem)?;
+    let n = |i: usize| caps[i].parse::<u32>().ok();
+    let yy = n(3)? as i32;
+    let year = if yy >= 69 { 1900 + yy } else { 2000 + yy };
+    let date = chrono::NaiveDate::from_ymd_opt(year, n(1)?, n(2)?)?;
+    let local = date.and_hms_opt(n(4)?, n(5)?, n(6)?)?;
+    Some(tz.from_local_datetime(&local).earliest()?.timestamp())
+}
+
+/// Queue a screenshot for pairing. A path already queued (the watcher reports
+/// Create and then one or more Modify for every file) only gets its time
+/// refreshed. Returns whether it is new to the queue.
+pub(crate) fn queue_screenshot(state: &mut State, path: String, ts: i64) -> bool {
+    if let Some(queued) = state.pending_screens.iter_mut().find(|p| p.path == path) {
+        queued.ts_epoch = ts;
+        return false;
+    }
+    state.pending_screens.push_back(PendingShot { path, ts_epoch: ts });
+    // Keep last 50 pending screenshots
+    while state.pending_screens.len() > 50 {
+        state.pending_screens.pop_front();
+    }
+    true
+}
+
+/// Seconds an unpaired screenshot is kept.
+pub(crate) fn screenshot_ttl(cfg: &Config) -> i64 {
+    if cfg.screenshot_ttl_secs > 0 {
+        cfg.screenshot_ttl_secs
+    } else {
+        cfg.pair_window_before().max(cfg.pair_window_after()).max(0) * 4
+    }
+}
+
+/// Newest write to any watched SavedVariables file, in epoch seconds.
+pub(crate) fn last_sv_write(wow: &WowPaths) -> Option<i64> {
+    account_sv_paths(wow)
+        .iter()
+        .filter_map(|p| newest_mtime(p)?.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64)
+        .max()
+}
+
+/// Drop queued screenshots past `screenshot_ttl`, unless a death still to be
+/// written out could pair with them: deaths reach the SavedVariables only on
+/// logout or /reload, so anything taken after `last_sv_write` minus the
+/// before-death window stays, though never longer than the TTL plus the longer
+/// pairing window. Claimed ones wait for their death to settle. Returns how many went.
+pub(crate) fn expire_pending_screens(cfg: &Config, state: &mut State, last_sv_write: Option<i64>, now: i64) -> usize {
+    let ttl = screenshot_ttl(cfg);
+    let mut cutoff = now - ttl;
+    if let Some(written) = last_sv_write {
+        let oldest = now - ttl - cfg.pair_window_before().max(cfg.pair_window_after()).max(0);
+        cutoff = cutoff.min(written - cfg.pair_window_before().max(0)).max(oldest);
+    }
+    let before = state.pending_screens.len();
+    let claimed = &state.claimed_screens;
+    state.pending_screens.retain(|p| p.ts_epoch >= cutoff || claimed.contains(&p.path));
+    let expired = before - state.pending_screens.len();
+    if expired > 0 {
+        println!("[queue] Expired {} screenshot(s) no death can pair with any more", expired);
+    }
+    expired
+}
+
+pub(crate) fn handle_screenshot_created(_wow: &WowPaths, state: &mut State, path: &Path) -> Result<()> {
+    state.shot_index.note_file(path);
+    let name = path.to_string_lossy().to_string();
+    // The same picture saved again under another name is not a new screenshot.
+    // (An empty file is one still being created, not a copy of another.)
+    let written = file_size_mtime(path).is_some_and(|(size, _)| size > 0);
+    if let Some(hash) = state.shot_index.hash_of(path).filter(|_| written) {
+        let index = &state.shot_index;
+        if let Some(twin) = state.pending_screens.iter().find(|p| p.path != name && index.hash_of(Path::new(&p.path)).as_ref() == Some(&hash)) {
+            println!("[queue] {} is the same image as {}; not queued again", path.display(), twin.path);
+            return Ok(());
+        }
+    }
+    note_screenshot_write(state, &name, path, std::time::Instant::now());
+    if queue_screenshot(state, name, screenshot_ts(path)) {
+        println!("[queue] New screenshot queued: {}", path.display());
+    }
+    save_state(state).ok();
+    Ok(())
+}
+
+// ---------- Screenshot writes ----------
+//
+// The game takes a second or two to write a screenshot, and the Create event
+// comes at the start. A queued screenshot only pairs once it is complete, so a
+// death uploaded meanwhile never carries a truncated file.
+
+/// A queued screenshot's size as last seen while it may still be written.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ShotWrite {
+    pub(crate) size: u64,
+    /// When `size` was first seen
+    pub(crate) changed: std::time::Instant,
+    /// When the first event for it came
+    pub(crate) since: std::time::Instant,
+}
+
+/// How long a screenshot's size must hold still before it counts as written
+pub(crate) const SCREENSHOT_SETTLE: Duration = Duration::from_millis(500);
+/// Screenshots still empty or growing after this long are dropped from the queue
+pub(crate) const SCREENSHOT_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub(crate) fn note_screenshot_write(state: &mut State, name: &str, path: &Path, now: std::time::Instant) {
+    let size = file_size_mtime(path).map_or(0, |(size, _)| size);
+    let write = state.unsettled_screens.entry(name.to_string()).or_insert(ShotWrite { size, changed: now, since: now });
+    if write.size != size {
+        write.size = size;
+        write.changed = now;
+    }
+}
+
+/// Whether a JPEG or PNG already ends with its end marker.
+pub(crate) fn has_image_trailer(path: &Path) -> bool {
+    use std::io::{Read, Seek, SeekFrom};
+    let mut tail = [0u8; 12];
+    let read = File::open(path).and_then(|mut f| {
+        f.seek(SeekFrom::End(-(tail.len() as i64)))?;
+        f.read_exact(&mut tail)
+    });
+    match screenshot_mime(path) {
+        _ if read.is_err() => false,
+        "image/jpeg" => tail[10..] == [0xFF, 0xD9],
+        "image/png" => &tail[4..8] == b"IEND",
+        _ => false,
+    }
+}
+
+/// Check the screenshots still being written: those with their end marker, or
+/// whose size held still for SCREENSHOT_SETTLE, become pairable and are
+/// returned. Those gone are forgotten, and those still empty or changing after
+/// SCREENSHOT_WRITE_TIMEOUT leave the queue.
+pub(crate) fn settle_screenshot_writes(state: &mut State, now: std::time::Instant) -> Vec<String> {
+    let mut settled = vec![];
+    let mut dropped = vec![];
+    for (name, write) in &mut state.unsettled_screens {
+        let path = Path::new(name);
+        let Some((size, _)) = file_size_mtime(path) else {
+            dropped.push(name.clone());
+            continue;
+        };
+        if size != write.size {
+            write.size = size;
+            write.changed = now;
+        }
+        if size > 0 && (has_image_trailer(path) || now.saturating_duration_since(write.changed) >= SCREENSHOT_SETTLE) {
+            settled.push(name.clone());
+        } else if now.saturating_duration_since(write.since) >= SCREENSHOT_WRITE_TIMEOUT {
+            eprintln!(
+                "[warn] {} was still being written after {}s; not pairing it with a death",
+                name,
+                SCREENSHOT_WRITE_TIMEOUT.as_secs()
+            );
+            dropped.push(name.clone());
+        }
+    }
+    for name in settled.iter().chain(&dropped) {
+        state.unsettled_screens.remove(name);
+    }
+    if !dropped.is_empty() {
+        state.pending_screens.retain(|p| !dropped.contains(&p.path));
+        save_state(state).ok();
+    }
+    settled
+}
+
+// ---------- Late screenshots ----------
+//
+// WoW may write the SavedVariables (and the agent upload the death) before the
+// player saves a screenshot of it. Such a screenshot follows in a request of
+// its own that names the death instead of carrying it.
+
+/// A death uploaded without a screenshot while one could still turn up.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct AwaitingShot {
+    pub(crate) key: String,
+    pub(crate) at: i64,
+    pub(crate) idempotency_key: String,
+    /// Endpoints that accepted the death
+    pub(crate) targets: Vec<String>,
+}
+
+/// Deaths kept in `awaiting_screenshot`
+pub(crate) const MAX_AWAITING_SHOTS: usize = 20;
+/// How long past the pairing window a death keeps waiting, for screenshots
+/// noticed a little after they were taken
+pub(crate) const LATE_SCREENSHOT_GRACE_SECS: i64 = 60;
+
+/// No grace for characters whose addon has auto-screenshot off: no screenshot
+/// is on its way, and a manual one taken in the window is already in time.
+pub(crate) fn late_screenshot_grace(settings: &BTreeMap<String, AddonSettings>, key: &str) -> i64 {
+    match settings.get(key) {
+        Some(s) if s.auto_screenshot_off() => 0,
+        _ => LATE_SCREENSHOT_GRACE_SECS,
+    }
+}
+
+pub(crate) fn late_screenshots_apply(cfg: &Config) -> bool {
+    cfg.late_screenshots
+        && cfg.upload_mode != UploadMode::Discord
+        && cfg.upload_format == UploadFormat::Multipart
+        && cfg.encrypt_to_public_key.is_empty()
+}
+
+pub(crate) fn await_late_screenshot(state: &mut State, staged: &StagedDeath, targets: Vec<String>) {
+    state.awaiting_screenshot.retain(|a| !(a.key == staged.key && a.at == staged.death.at));
+    if targets.is_empty() {
+        return;
+    }
+    state.awaiting_screenshot.push(AwaitingShot {
+        key: staged.key.clone(),
+        at: staged.death.at,
+        idempotency_key: staged.idempotency_key.clone(),
+        targets,
+    });
+    if state.awaiting_screenshot.len() > MAX_AWAITING_SHOTS {
+        state.awaiting_screenshot.remove(0);
+    }
+}
+
+/// Send a screenshot that just finished writing to the uploaded death whose
+/// pairing window it falls in, if that death went out without one. It leaves the queue once an
+/// endpoint takes it; otherwise it stays there for a death still to come.
+pub(crate) async fn attach_late_screenshot(cfg: &Config, http: &Http, state: &mut State, path: &Path) {
+    if !late_screenshots_apply(cfg) || state.awaiting_screenshot.is_empty() {
+        return;
+    }
+    let name = path.to_string_lossy().to_string();
+    let Some(shot) = state
+        .pending_screens
+        .iter()
+        .find(|p| p.path == name && !state.claimed_screens.contains(&p.path) && !state.unsettled_screens.contains_key(&p.path))
+        .cloned()
+    else {
+        return;
+    };
+    let (before, after) = (cfg.pair_window_before(), cfg.pair_window_after());
+    let now = Utc::now().timestamp();
+    let settings = &state.addon_settings;
+    state.awaiting_screenshot.retain(|a| a.at + after + late_screenshot_grace(settings, &a.key) >= now);
+    let Some(awaiting) = state
+        .awaiting_screenshot
+        .iter()
+        .filter(|a| (-before..=after).contains(&(shot.ts_epoch - a.at)))
+        .min_by_key(|a| (shot.ts_epoch - a.at).abs())
+        .cloned()
+    else {
+        return;
+    };
+    let endpoints = api_endpoints(cfg);
+    let records = state.upload_history.get(&death_ref(&awaiting.key, awaiting.at));
+    let mut attached = vec![];
+    for target in &awaiting.targets {
+        let Some(ep) = endpoints.iter().find(|e| &e.name == target) else { continue };
+        let id = records.and_then(|r| r.iter().find(|r| &r.target == target)).and_then(|r| r.id.clone());
+        match send_late_screenshot(&cfg.for_endpoint(ep), http, &awaiting, id, path).await {
+            Ok(()) => attached.push(target.clone()),
+            Err(e) => eprintln!("[warn] late screenshot for {} at {} to {} failed: {e:#}", awaiting.key, format_epoch(awaiting.at), target),
+        }
+    }
+    if attached.is_empty() {
+        return;
+    }
+    println!(
+        "[upload] Late screenshot {} attached to the death for {} at {} on {}",
+        path.display(),
+        awaiting.key,
+        format_epoch(awaiting.at),
+        attached.join(", ")
+    );
+    state.pending_screens.retain(|p| p.path != name);
+    state.awaiting_screenshot.retain(|a| !(a.key == awaiting.key && a.at == awaiting.at));
+    save_state(state).ok();
+}
+
+pub(crate) async fn send_late_screenshot(cfg: &Config, http: &Http, awaiting: &AwaitingShot, id: Option<String>, path: &Path) -> Result<()> {
+    http.check_offline(&cfg.api_url)?;
+    http.check_throttle(&cfg.api_url)?;
+    http.take_upload_slot()?;
+    let shot = read_screenshot(cfg, path)?;
+    let shot_hash = format!("{:x}", Sha256::digest(&shot.bytes));
+    let json = json!({ "idempotency_key": awaiting.idempotency_key, "id": id, "at": awaiting.at }).to_string();
+    let key = format!("{:x}", Sha256::digest(format!("{}\n{}", awaiting.idempotency_key, shot_hash)));
+    let mut headers = vec![(IDEMPOTENCY_HEADER, key)];
+    headers.extend(signature_headers(cfg, json.as_bytes(), std::slice::from_ref(&shot_hash)));
+    let parts = vec![FormPart::file(PART_SCREENSHOT, shot.file_name, shot.bytes, shot.content_type)];
+    let result = match send_upload_form(cfg, http, PART_LATE_SCREENSHOT, json, parts, &headers).await {
+        Ok(resp) => {
+            let status = resp.status();
+            let retry_after = retry_after_of(&resp);
+            let body = resp.text().await.unwrap_or_default();
+            match classify_status(status.as_u16()) {
+                UploadOutcome::Accepted => Ok(()),
+                UploadOutcome::RetryLater | UploadOutcome::Rejected => Err(UploadError { status, body, retry_after }.into()),
+            }
+        }
+        Err(e) => Err(e),
+    };
+    http.note_throttle(&cfg.api_url, &result);
+    http.note_offline(&cfg.api_url, result)
+}
+
+/// Pending screenshots from `pair_window_before_secs` before the death to
+/// `pair_window_after_secs` after it, nearest first, one after the death
+/// winning a tie: just the nearest, or up to `max_screenshots_per_death` with
+/// `attach_all_screenshots_in_window`. Screenshots closer to one of `later`
+/// (deaths staged after this one) are left for those.
+pub(crate) fn find_screenshots(cfg: &Config, state: &State, death_ts: i64, later: &[i64]) -> Vec<PendingShot> {
+    let limit = if cfg.attach_all_screenshots_in_window { cfg.max_screenshots_per_death } else { 1 };
+    let distance = |p: &PendingShot, at: i64| (p.ts_epoch - at).abs();
+    let window = -cfg.pair_window_before()..=cfg.pair_window_after();
+    let mut near: Vec<PendingShot> = state
+        .pending_screens
+        .iter()
+        .filter(|p| !state.claimed_screens.contains(&p.path) && !state.unsettled_screens.contains_key(&p.path))
+        .filter(|p| window.contains(&(p.ts_epoch - death_ts)))
+        .filter(|p| later.iter().all(|at| distance(p, *at) >= distance(p, death_ts)))
+        .cloned()
+        .collect();
+    near.sort_by_key(|p| (distance(p, death_ts), p.ts_epoch < death_ts));
+    // A file can be queued once per filesystem event, and the same picture can
+    // sit in the folder under two names; either way it is attached once.
+    let mut seen = HashSet::new();
+    near.retain(|p| seen.insert(state.shot_index.hash_of(Path::new(&p.path)).unwrap_or_else(|| p.path.clone())));
+    near.truncate(limit);
+    near
+}
@@ -0,0 +1,1227 @@
+//! The death payload sent upstream and the transforms applied before upload.
+
+use crate::*;
+
+/// Version of the death JSON this agent produces. Bump it whenever a field is
+/// renamed, removed or changes meaning; version 1 is the shape without
+/// `schema_version`, still sent with `payload_schema_version = 1`.
+pub(crate) const SCHEMA_VERSION: u32 = 2;
+
+/// One death as it is uploaded; its JSON is the `death` part of the form.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeathPayload {
+    /// Shape of this JSON (`SCHEMA_VERSION`, or older per `payload_schema_version`); left out in version 1
+    #[serde(default, skip_serializing_if = "schema_v1")]
+    pub(crate) schema_version: u32,
+    pub(crate) at: i64,
+    pub(crate) player: String,
+    pub(crate) realm: String,
+    /// WoW account folder (WTF/Account/<ACCOUNT>) the death was read from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) account: Option<String>,
+    pub(crate) class: Option<String>,
+    pub(crate) level: Option<i64>,
+    #[serde(default, deserialize_with = "stored_location")]
+    pub(crate) location: Option<LocationPayload>,
+    pub(crate) killer: Killer,
+    /// The killer as recorded by the addon, present when remap rules changed it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) killer_raw: Option<Killer>,
+    /// Killer fields that were missing or malformed; logged, not sent
+    #[serde(skip)]
+    pub(crate) killer_issues: Vec<String>,
+    /// Key of the entry in the addon's deaths table, for log messages
+    #[serde(skip)]
+    pub(crate) entry: Option<String>,
+    /// Left out when null: `inventory_detail = "none"`, or replaced by `bags_diff`
+    #[serde(default, skip_serializing_if = "serde_json::Value::is_null")]
+    pub(crate) bags: serde_json::Value,
+    /// In `bags_mode = "diff"`: changes relative to the full snapshot `bags_base`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) bags_diff: Option<serde_json::Value>,
+    /// SHA-256 of the full bag snapshot `bags_diff` applies to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) bags_base: Option<String>,
+    /// Left out when null (`inventory_detail = "none"`)
+    #[serde(default, skip_serializing_if = "serde_json::Value::is_null")]
+    pub(crate) equipped: serde_json::Value,
+    pub(crate) instance: serde_json::Value,
+    /// Version and options of the addon that recorded the death
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) addon: Option<AddonInfo>,
+    #[serde(rename = "moneyCopper")]
+    pub(crate) money_copper: Option<i64>,
+    #[serde(rename = "moneyGold")]
+    pub(crate) money_gold: Option<i64>,
+    #[serde(rename = "moneySilver")]
+    pub(crate) money_silver: Option<i64>,
+    #[serde(rename = "moneyCopperOnly")]
+    pub(crate) money_copper_only: Option<i64>,
+    /// Money in copper reconciled from the fields above (`money_total`); null
+    /// when the addon recorded none of them
+    #[serde(default)]
+    pub(crate) money_total_copper: Option<i64>,
+    /// Screenshot uploaded separately to `media_url`, sent instead of the file part
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) screenshot_media_id: Option<String>,
+    /// One-line note from the player ("disconnect death", "lag spike", ...)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) note: Option<String>,
+    /// Set when parts of the attached screenshot were pixelated (`blur_chat_region`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) screenshot_redacted: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) screenshot_redacted_regions: Option<Vec<BlurRegion>>,
+    /// The agent sending it; set right before each send
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) agent: Option<AgentInfo>,
+    /// Read from the SavedVariables `.bak` because the file itself was corrupt
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) recovered_from_backup: Option<bool>,
+    /// Entry fields without a typed field above (`guild`, `hardcore`, ...), sent as
+    /// the addon wrote them unless `drop_unknown_fields` is on
+    #[serde(flatten)]
+    pub(crate) extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl DeathPayload {
+    /// Character name, without the realm
+    pub fn player(&self) -> &str {
+        &self.player
+    }
+    pub fn realm(&self) -> &str {
+        &self.realm
+    }
+    /// When the addon recorded the death, in seconds since the Unix epoch
+    pub fn at(&self) -> i64 {
+        self.at
+    }
+    pub fn level(&self) -> Option<i64> {
+        self.level
+    }
+    /// Class token, e.g. `WARRIOR`
+    pub fn class(&self) -> Option<&str> {
+        self.class.as_deref()
+    }
+    /// The JSON sent upstream
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// Entry keys read into typed fields, and the payload's own field names; an
+/// unknown entry field by one of these names would clash, so it is not passed through.
+pub(crate) const TYPED_ENTRY_KEYS: [&str; 30] = [
+    "at", "player", "realm", "account", "class", "level", "location", "killer", "killer_raw", "bags", "bags_diff",
+    "bags_base", "equipped", "instance", "instanceID", "instanceName", "instanceDifficulty", "mapDifficultyID",
+    "addon", "moneyCopper", "moneyGold", "moneySilver", "moneyCopperOnly", "money_total_copper",
+    "screenshot_media_id", "note", "screenshot_redacted", "screenshot_redacted_regions", "agent",
+    "recovered_from_backup",
+];
+
+/// `agent` in deaths and milestones.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct AgentInfo {
+    pub(crate) version: String,
+    /// `agent_id` from the config
+    pub(crate) id: String,
+    /// The agent's clock when the request was made (epoch secs)
+    pub(crate) uploaded_at: i64,
+}
+
+pub(crate) fn agent_info(cfg: &Config) -> AgentInfo {
+    AgentInfo { version: env!("CARGO_PKG_VERSION").to_string(), id: cfg.agent_id.clone(), uploaded_at: Utc::now().timestamp() }
+}
+
+/// The death as sent now, with `agent` and `schema_version` filled in.
+pub(crate) fn stamped(cfg: &Config, death: &DeathPayload) -> DeathPayload {
+    DeathPayload { agent: Some(agent_info(cfg)), schema_version: cfg.payload_schema_version, ..death.clone() }
+}
+
+// ---------- Canonical serialization ----------
+//
+// Anything hashed or signed goes through here so equal payloads always give equal
+// bytes. What is sent to servers stays the ordinary serialization.
+
+/// Recursively sort object keys and write integral floats as integers, so `3.0`
+/// from one parse path and `3` from another compare equal.
+pub(crate) fn canonical_value(v: &serde_json::Value) -> serde_json::Value {
+    match v {
+        serde_json::Value::Object(m) => {
+            let sorted: BTreeMap<&String, serde_json::Value> = m.iter().map(|(k, v)| (k, canonical_value(v))).collect();
+            serde_json::Value::Object(sorted.into_iter().map(|(k, v)| (k.clone(), v)).collect())
+        }
+        serde_json::Value::Array(a) => serde_json::Value::Array(a.iter().map(canonical_value).collect()),
+        serde_json::Value::Number(n) => match n.as_f64() {
+            Some(f) if !n.is_i64() && !n.is_u64() && f.fract() == 0.0 && f.abs() < 9.0e15 => json!(f as i64),
+            _ => v.clone(),
+        },
+        _ => v.clone(),
+    }
+}
+
+/// Compact, key-sorted bytes of a death for hashing. `agent` changes on every
+/// send, so it is left out.
+pub(crate) fn canonical_json(death: &DeathPayload) -> Vec<u8> {
+    let value = serde_json::to_value(DeathPayload { agent: None, ..death.clone() }).unwrap_or_default();
+    canonical_value(&value).to_string().into_bytes()
+}
+
+/// Canonical bytes of serialized JSON, as signed. Anything that doesn't parse
+/// is returned unchanged.
+pub(crate) fn canonical_text(json: &[u8]) -> Vec<u8> {
+    match serde_json::from_slice::<serde_json::Value>(json) {
+        Ok(v) => canonical_value(&v).to_string().into_bytes(),
+        Err(_) => json.to_vec(),
+    }
+}
+
+/// SHA-256 of a death as the addon recorded it. What the agent adds (addon
+/// info, notes, where it was read from, the schema version) is left out, so
+/// only a different entry gives a different hash.
+pub(crate) fn entry_hash(death: &DeathPayload) -> String {
+    let recorded = DeathPayload {
+        schema_version: 0,
+        addon: None,
+        note: None,
+        money_total_copper: None,
+        recovered_from_backup: None,
+        ..death.clone()
+    };
+    format!("{:x}", Sha256::digest(canonical_json(&recorded)))
+}
+
+// ---------- Location ----------
+
+/// Where the death happened. Sent under the addon's key names; coordinates are
+/// always percent of the map (0-100), whichever way the addon stored them, and
+/// keys the agent doesn't know travel in `extra`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct LocationPayload {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) zone: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) subzone: Option<String>,
+    #[serde(rename = "mapID", skip_serializing_if = "Option::is_none")]
+    pub(crate) map_id: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) x: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) y: Option<f64>,
+    /// Radians, counter-clockwise from north
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) facing: Option<f64>,
+    #[serde(flatten)]
+    pub(crate) extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl LocationPayload {
+    /// Read the addon's location. Coordinates come as `x`/`y`, `posX`/`posY` or a
+    /// nested `pos`/`position`/`coords` table (`{x=, y=}` or `{x, y}`), either as
+    /// fractions (0-1) or percent. A plain string is taken as the zone name.
+    pub(crate) fn from_json(v: serde_json::Value) -> Option<LocationPayload> {
+        let mut map = match v {
+            serde_json::Value::Object(map) => map,
+            serde_json::Value::String(zone) => return Some(LocationPayload { zone: Some(zone), ..Default::default() }),
+            _ => return None,
+        };
+        let zone = take_first(&mut map, &["zone", "zoneName", "zoneText"], json_text);
+        let subzone = take_first(&mut map, &["subzone", "subZone", "subzoneName", "subZoneText"], json_text);
+        let map_id = take_first(&mut map, &["mapID", "mapId", "map_id", "uiMapID"], json_int);
+        let facing = take_first(&mut map, &["facing"], serde_json::Value::as_f64);
+        let mut pos = [("x", "y"), ("posX", "posY")].iter().find_map(|(kx, ky)| {
+            let xy = (map.get(*kx)?.as_f64()?, map.get(*ky)?.as_f64()?);
+            map.remove(*kx);
+            map.remove(*ky);
+            Some(xy)
+        });
+        for key in ["pos", "position", "coords"] {
+            if pos.is_some() {
+                break;
+            }
+            pos = map.get(key).and_then(nested_xy);
+            if pos.is_some() {
+                map.remove(key);
+            }
+        }
+        let (x, y) = match pos {
+            Some((x, y)) => {
+                // Both within 0-1 means fractions; percent that low is the map's corner.
+                let scale = if x.abs() <= 1.0 && y.abs() <= 1.0 { 100.0 } else { 1.0 };
+                let percent = |v: f64| (v * scale * 100.0).round() / 100.0;
+                (Some(percent(x)), Some(percent(y)))
+            }
+            None => (None, None),
+        };
+        Some(LocationPayload { zone, subzone, map_id, x, y, facing, extra: map })
+    }
+}
+
+/// `{x=, y=}` or `{x, y}`.
+pub(crate) fn nested_xy(v: &serde_json::Value) -> Option<(f64, f64)> {
+    match v {
+        serde_json::Value::Object(m) => Some((m.get("x")?.as_f64()?, m.get("y")?.as_f64()?)),
+        serde_json::Value::Array(a) if a.len() == 2 => Some((a[0].as_f64()?, a[1].as_f64()?)),
+        _ => None,
+    }
+}
+
+/// Remove and convert the first of `keys` whose value converts; values that
+/// don't are left in place.
+pub(crate) fn take_first<T>(
+    map: &mut serde_json::Map<String, serde_json::Value>,
+    keys: &[&str],
+    convert: fn(&serde_json::Value) -> Option<T>,
+) -> Option<T> {
+    let (key, v) = keys.iter().find_map(|k| Some((*k, convert(map.get(*k)?)?)))?;
+    map.remove(key);
+    Some(v)
+}
+
+pub(crate) fn json_text(v: &serde_json::Value) -> Option<String> {
+    match v {
+        serde_json::Value::String(s) => Some(s.clone()),
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+pub(crate) fn json_int(v: &serde_json::Value) -> Option<i64> {
+    match v {
+        serde_json::Value::Number(n) => n.as_i64(),
+        serde_json::Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Addon key names `LocationPayload::from_json` renames or folds away. Any key
+/// from `extra` can deserialize as a LocationPayload, so these are what mark a
+/// location stored the addon's way.
+const RAW_LOCATION_KEYS: [&str; 13] = [
+    "zoneName", "zoneText", "subZone", "subzoneName", "subZoneText", "mapId", "map_id", "uiMapID", "posX", "posY", "pos",
+    "position", "coords",
+];
+
+/// Deaths saved by older agents kept the addon's location as is.
+pub(crate) fn stored_location<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<LocationPayload>, D::Error> {
+    let v = serde_json::Value::deserialize(d)?;
+    match serde_json::from_value::<LocationPayload>(v.clone()) {
+        Ok(l) if !RAW_LOCATION_KEYS.iter().any(|k| l.extra.contains_key(*k)) => Ok(Some(l)),
+        _ => Ok(LocationPayload::from_json(v)),
+    }
+}
+
+// ---------- Killer ----------
+
+/// Keys of the addon's killer table that KillerInfo reads.
+pub(crate) const KILLER_FIELDS: [&str; 14] = [
+    "sourceName", "sourceGUID", "npc_id", "sourceLevel", "creatureType", "subevent", "spellID", "spellName",
+    "amount", "overkill", "detail", "timestamp", "death_type", "environmentalType",
+];
+
+/// The killer the addon recorded, with the fields the agent knows typed. It is
+/// sent under the addon's own key names, so the payload keeps its shape; keys the
+/// agent doesn't know travel in `extra`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct KillerInfo {
+    #[serde(rename = "sourceName", skip_serializing_if = "Option::is_none")]
+    pub(crate) name: Option<String>,
+    #[serde(rename = "sourceGUID", skip_serializing_if = "Option::is_none")]
+    pub(crate) guid: Option<String>,
+    /// From a creature GUID, or set by a `killer_remap` rule
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) npc_id: Option<i64>,
+    #[serde(rename = "sourceLevel", skip_serializing_if = "Option::is_none")]
+    pub(crate) level: Option<i64>,
+    #[serde(rename = "creatureType", skip_serializing_if = "Option::is_none")]
+    pub(crate) creature_type: Option<String>,
+    /// Combat log event of the killing blow, e.g. SPELL_DAMAGE
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) subevent: Option<String>,
+    #[serde(rename = "spellID", skip_serializing_if = "Option::is_none")]
+    pub(crate) spell_id: Option<i64>,
+    #[serde(rename = "spellName", skip_serializing_if = "Option::is_none")]
+    pub(crate) spell_name: Option<String>,
+    #[serde(rename = "environmentalType", skip_serializing_if = "Option::is_none")]
+    pub(crate) environmental_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) amount: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) overkill: Option<i64>,
+    /// What the addon shows, e.g. "Melee" or "Environmental (Falling)"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) detail: Option<String>,
+    /// Combat log time of the hit, in fractional seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) timestamp: Option<f64>,
+    /// Set by a `killer_remap` rule
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) death_type: Option<String>,
+    #[serde(flatten)]
+    pub(crate) extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// A killer the agent could read, or the addon's value as is when it couldn't.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum Killer {
+    Info(Box<KillerInfo>),
+    Raw(serde_json::Value),
+}
+
+impl Default for Killer {
+    fn default() -> Self {
+        Killer::Raw(serde_json::Value::Null)
+    }
+}
+
+impl Killer {
+    /// Read the addon's killer table. Known fields of the wrong type are dropped
+    /// and listed in the returned issues. A value without any known field is
+    /// passed through untouched rather than losing the death over it.
+    pub(crate) fn from_json(v: serde_json::Value) -> (Killer, Vec<String>) {
+        let serde_json::Value::Object(mut map) = v else { return (Killer::Raw(v), vec![]) };
+        if !KILLER_FIELDS.iter().any(|k| map.contains_key(*k)) {
+            return (Killer::Raw(serde_json::Value::Object(map)), vec![]);
+        }
+        let mut issues = vec![];
+        let mut k = KillerInfo {
+            name: take_text(&mut map, &mut issues, "sourceName"),
+            guid: take_text(&mut map, &mut issues, "sourceGUID"),
+            npc_id: take_int(&mut map, &mut issues, "npc_id"),
+            level: take_int(&mut map, &mut issues, "sourceLevel"),
+            creature_type: take_text(&mut map, &mut issues, "creatureType"),
+            subevent: take_text(&mut map, &mut issues, "subevent"),
+            spell_id: take_int(&mut map, &mut issues, "spellID"),
+            spell_name: take_text(&mut map, &mut issues, "spellName"),
+            environmental_type: take_text(&mut map, &mut issues, "environmentalType"),
+            amount: take_int(&mut map, &mut issues, "amount"),
+            overkill: take_int(&mut map, &mut issues, "overkill"),
+            detail: take_text(&mut map, &mut issues, "detail"),
+            timestamp: take_float(&mut map, &mut issues, "timestamp"),
+            death_type: take_text(&mut map, &mut issues, "death_type"),
+            extra: serde_json::Map::new(),
+        };
+        if k.name.is_none() {
+            issues.push("sourceName is missing".into());
+        }
+        if k.npc_id.is_none() {
+            k.npc_id = k.guid.as_deref().and_then(npc_id_from_guid);
+        }
+        k.extra = map;
+        (Killer::Info(Box::new(k)), issues)
+    }
+}
+
+pub(crate) fn take_text(map: &mut serde_json::Map<String, serde_json::Value>, issues: &mut Vec<String>, key: &str) -> Option<String> {
+    match map.remove(key)? {
+        serde_json::Value::String(s) => Some(s),
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        serde_json::Value::Null => None,
+        other => {
+            issues.push(format!("{key} is not text: {other}"));
+            None
+        }
+    }
+}
+
+/// Integers, and floats or strings that hold one.
+pub(crate) fn take_int(map: &mut serde_json::Map<String, serde_json::Value>, issues: &mut Vec<String>, key: &str) -> Option<i64> {
+    let v = map.remove(key)?;
+    let n = match &v {
+        serde_json::Value::Null => return None,
+        serde_json::Value::Number(n) => n.as_i64().or_else(|| n.as_f64().filter(|f| f.fract() == 0.0).map(|f| f as i64)),
+        serde_json::Value::String(s) => s.trim().parse::<i64>().ok(),
+        _ => None,
+    };
+    if n.is_none() {
+        issues.push(format!("{key} is not a whole number: {v}"));
+    }
+    n
+}
+
+pub(crate) fn take_float(map: &mut serde_json::Map<String, serde_json::Value>, issues: &mut Vec<String>, key: &str) -> Option<f64> {
+    let v = map.remove(key)?;
+    let n = match &v {
+        serde_json::Value::Null => return None,
+        serde_json::Value::Number(n) => n.as_f64(),
+        serde_json::Value::String(s) => s.trim().parse::<f64>().ok().filter(|f| f.is_finite()),
+        _ => None,
+    };
+    if n.is_none() {
+        issues.push(format!("{key} is not a number: {v}"));
+    }
+    n
+}
+
+/// NPC id of a creature-like GUID: "Creature-0-1465-0-2105-448-000043F59F" -> 448.
+/// Player GUIDs carry none.
+pub(crate) fn npc_id_from_guid(guid: &str) -> Option<i64> {
+    let parts: Vec<&str> = guid.split('-').collect();
+    match parts.as_slice() {
+        [kind, _, _, _, _, id, _] if matches!(*kind, "Creature" | "Vehicle" | "Pet" | "GameObject" | "Vignette") => {
+            id.parse().ok()
+        }
+        _ => None,
+    }
+}
+
+// ---------- Killer remapping ----------
+
+/// One `[[killer_remap]]` entry. Matches on the killer's `sourceName`, either
+/// exactly (`match`) or by regex (`regex`), then applies every action that is set.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct KillerRule {
+    #[serde(rename = "match", skip_serializing_if = "Option::is_none")]
+    pub(crate) exact: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) regex: Option<String>,
+    /// New name; with `regex`, capture references like "$1" are expanded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) rename: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) npc_id: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) death_type: Option<String>,
+    /// Drop a trailing " <Owner>" decoration
+    pub(crate) strip_owner: bool,
+}
+
+pub(crate) struct CompiledKillerRule {
+    pub(crate) rule: KillerRule,
+    pub(crate) regex: Option<Regex>,
+}
+
+pub(crate) fn builtin_killer_rules() -> Vec<KillerRule> {
+    vec![
+        // "Grimtotem Spirit Guide <Bob>" -> "Grimtotem Spirit Guide"
+        KillerRule { regex: Some(r"\s*<[^>]+>$".into()), strip_owner: true, ..Default::default() },
+        // Player killers from other realms: "Name-Realm" -> "Name"
+        KillerRule { regex: Some(r"^([^\s-]+)-[^\s]+$".into()), rename: Some("$1".into()), ..Default::default() },
+    ]
+}
+
+/// Compile the configured rules (built-ins first). Bad rules fail with the rule named.
+pub(crate) fn compile_killer_rules(cfg: &Config) -> Result<Vec<CompiledKillerRule>> {
+    let builtins = if cfg.killer_remap_builtins { builtin_killer_rules() } else { vec![] };
+    let mut out = vec![];
+    for (i, rule) in cfg.killer_remap.iter().enumerate() {
+        if rule.exact.is_some() == rule.regex.is_some() {
+            return Err(anyhow!("killer_remap rule #{}: set exactly one of `match` or `regex`", i + 1));
+        }
+    }
+    // Numbered as in the config; the built-ins come first but aren't counted.
+    let numbered = builtins
+        .into_iter()
+        .map(|r| (None, r))
+        .chain(cfg.killer_remap.iter().cloned().enumerate().map(|(i, r)| (Some(i + 1), r)));
+    for (number, rule) in numbered {
+        let regex = match &rule.regex {
+            Some(r) => Some(Regex::new(r).with_context(|| match number {
+                Some(n) => format!("killer_remap rule #{n}: regex {r:?} is invalid"),
+                None => format!("built-in killer rule regex {r:?} is invalid"),
+            })?),
+            None => None,
+        };
+        out.push(CompiledKillerRule { rule, regex });
+    }
+    Ok(out)
+}
+
+/// Run the killer through every matching rule in order. Returns true if anything changed.
+pub(crate) fn remap_killer_name(rules: &[CompiledKillerRule], killer: &mut KillerInfo) -> bool {
+    let mut changed = false;
+    for c in rules {
+        let Some(name) = killer.name.clone() else { return changed };
+        let matched = match (&c.rule.exact, &c.regex) {
+            (Some(exact), _) => *exact == name,
+            (None, Some(re)) => re.is_match(&name),
+            (None, None) => false,
+        };
+        if !matched {
+            continue;
+        }
+        let mut new_name = name.clone();
+        if c.rule.strip_owner {
+            if let Some(pos) = new_name.rfind('<').filter(|_| new_name.ends_with('>')) {
+                new_name.truncate(pos);
+                new_name.truncate(new_name.trim_end().len());
+            }
+        }
+        if let Some(rename) = &c.rule.rename {
+            new_name = match &c.regex {
+                Some(re) => re.replace(&new_name, rename.as_str()).into_owned(),
+                None => rename.clone(),
+            };
+        }
+        if new_name != name {
+            killer.name = Some(new_name);
+            changed = true;
+        }
+        if let Some(id) = c.rule.npc_id {
+            killer.npc_id = Some(id);
+            changed = true;
+        }
+        if let Some(t) = &c.rule.death_type {
+            killer.death_type = Some(t.clone());
+            changed = true;
+        }
+    }
+    changed
+}
+
+// ---------- Bag snapshots ----------
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct BagBase {
+    pub(crate) sha256: String,
+    /// Reduced form: "bagID:slot" -> (itemID, count)
+    pub(crate) slots: BTreeMap<String, (i64, i64)>,
+    /// Diffs sent since this full snapshot
+    pub(crate) diffs_since: u32,
+}
+
+/// Hash of a bag snapshot, over its canonical form.
+pub(crate) fn bags_sha256(bags: &serde_json::Value) -> String {
+    format!("{:x}", Sha256::digest(canonical_value(bags).to_string().as_bytes()))
+}
+
+/// Reduce the addon's bag table to "bagID:slot" -> (itemID, count).
+pub(crate) fn reduce_bags(bags: &serde_json::Value) -> BTreeMap<String, (i64, i64)> {
+    let mut out = BTreeMap::new();
+    for bag in bags.as_array().into_iter().flatten() {
+        let bag_id = bag.get("bagID").and_then(|v| v.as_i64()).unwrap_or(-1);
+        for slot in bag.get("slots").and_then(|v| v.as_array()).into_iter().flatten() {
+            let slot_no = slot.get("slot").and_then(|v| v.as_i64()).unwrap_or(-1);
+            let item = slot.get("itemID").and_then(|v| v.as_i64()).unwrap_or(0);
+            let count = slot.get("stackCount").and_then(|v| v.as_i64()).unwrap_or(1);
+            out.insert(format!("{}:{}", bag_id, slot_no), (item, count));
+        }
+    }
+    out
+}
+
+/// Structural diff between two reduced snapshots, ordered by slot key.
+pub(crate) fn diff_bags(base: &BTreeMap<String, (i64, i64)>, new: &BTreeMap<String, (i64, i64)>) -> serde_json::Value {
+    let entry = |slot: &str, (item, count): (i64, i64)| json!({ "slot": slot, "itemID": item, "count": count });
+    let mut added = vec![];
+    let mut removed = vec![];
+    let mut changed = vec![];
+    for (slot, now) in new {
+        match base.get(slot) {
+            None => added.push(entry(slot, *now)),
+            Some(before) if before != now => changed.push(entry(slot, *now)),
+            Some(_) => {}
+        }
+    }
+    for slot in base.keys().filter(|k| !new.contains_key(*k)) {
+        removed.push(json!(slot));
+    }
+    json!({ "added": added, "removed": removed, "changed": changed })
+}
+
+/// In diff mode, replace `bags` with a diff against the character's last full
+/// snapshot, unless a keyframe is due. Returns the base to remember once the
+/// upload succeeds.
+pub(crate) fn apply_bags_mode(cfg: &Config, state: &State, key: &str, death: &mut DeathPayload) -> Option<BagBase> {
+    if cfg.bags_mode != BagsMode::Diff || cfg.inventory_detail != InventoryDetail::Full || death.bags.is_null() {
+        return None;
+    }
+    let slots = reduce_bags(&death.bags);
+    match state.bag_bases.get(key) {
+        Some(base) if base.diffs_since + 1 < cfg.bags_keyframe_every.max(1) => {
+            death.bags_diff = Some(diff_bags(&base.slots, &slots));
+            death.bags_base = Some(base.sha256.clone());
+            death.bags = serde_json::Value::Null;
+            Some(BagBase { diffs_since: base.diffs_since + 1, ..base.clone() })
+        }
+        _ => Some(BagBase { sha256: bags_sha256(&death.bags), slots, diffs_since: 0 }),
+    }
+}
+
+// ---------- Inventory detail ----------
+//
+// A full snapshot of four 20-slot bags is most of a death's JSON. `summary`
+// keeps what leaderboards use: slot counts, item counts and what is equipped.
+
+/// `bags` as totals: slots (when the addon recorded `numSlots` for every bag),
+/// used slots, and `{itemID, count}` summed over all bags, by item id.
+pub(crate) fn summarize_bags(bags: &serde_json::Value) -> serde_json::Value {
+    let list: Vec<&serde_json::Value> = bags.as_array().into_iter().flatten().collect();
+    let total_slots: Option<i64> = list.iter().map(|b| b.get("numSlots").and_then(|v| v.as_i64())).sum();
+    let slots = reduce_bags(bags);
+    let mut counts: BTreeMap<i64, i64> = BTreeMap::new();
+    for (item, count) in slots.values().filter(|(item, _)| *item > 0) {
+        *counts.entry(*item).or_default() += count;
+    }
+    let items: Vec<serde_json::Value> = counts.into_iter().map(|(item, count)| json!({ "itemID": item, "count": count })).collect();
+    let mut out = json!({ "used_slots": slots.len(), "items": items });
+    if let Some(total) = total_slots {
+        out["total_slots"] = json!(total);
+    }
+    out
+}
+
+/// `equipped` as `{slot, itemID}` per slot: the item id from the entry or its
+/// hyperlink, without the enchant and bonus parts of the link.
+pub(crate) fn summarize_equipped(equipped: &serde_json::Value) -> serde_json::Value {
+    let item_id = |e: &serde_json::Value| {
+        e.get("itemID").and_then(|v| v.as_i64()).or_else(|| {
+            let link = e.get("hyperlink")?.as_str()?;
+            let rest = &link[link.find("item:")? + 5..];
+            rest[..rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len())].parse().ok()
+        })
+    };
+    let slots: Vec<serde_json::Value> = equipped
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|e| json!({ "slot": e.get("slot").and_then(|v| v.as_i64()), "itemID": item_id(e) }))
+        .collect();
+    json!(slots)
+}
+
+fn apply_inventory_detail(cfg: &Config, death: &mut DeathPayload) {
+    match cfg.inventory_detail {
+        InventoryDetail::Full => {}
+        InventoryDetail::Summary => {
+            if !death.bags.is_null() {
+                death.bags = summarize_bags(&death.bags);
+            }
+            if !death.equipped.is_null() {
+                death.equipped = summarize_equipped(&death.equipped);
+            }
+        }
+        InventoryDetail::None => {
+            death.bags = serde_json::Value::Null;
+            death.equipped = serde_json::Value::Null;
+        }
+    }
+}
+
+// ---------- Privacy ----------
+
+/// `[privacy]`: parts of a death that never leave the machine. Redacted fields
+/// are sent as null (money) or left out (the rest), so the payload keeps the
+/// shape the server validates against.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct Privacy {
+    /// moneyCopper, its gold/silver/copper breakdown and money_total_copper
+    pub(crate) redact_money: bool,
+    pub(crate) redact_bags: bool,
+    pub(crate) redact_equipped: bool,
+    /// x/y map position; zone, subzone and mapID stay
+    pub(crate) redact_coordinates: bool,
+}
+
+impl Privacy {
+    pub(crate) fn any(&self) -> bool {
+        self.redact_money || self.redact_bags || self.redact_equipped || self.redact_coordinates
+    }
+
+    fn apply(&self, death: &mut DeathPayload) {
+        if self.redact_money {
+            death.money_copper = None;
+            death.money_gold = None;
+            death.money_silver = None;
+            death.money_copper_only = None;
+            death.money_total_copper = None;
+        }
+        if self.redact_bags {
+            death.bags = serde_json::Value::Null;
+        }
+        if self.redact_equipped {
+            death.equipped = serde_json::Value::Null;
+        }
+        if let (true, Some(location)) = (self.redact_coordinates, death.location.as_mut()) {
+            location.x = None;
+            location.y = None;
+        }
+    }
+}
+
+// ---------- Payload pipeline ----------
+
+/// Everything applied to a parsed death before it is sent anywhere.
+/// Shared by the upload path and `preview` so both show the same thing.
+pub(crate) fn prepare_payload(cfg: &Config, death: &mut DeathPayload) -> Result<()> {
+    let rules = compile_killer_rules(cfg)?;
+    if let Killer::Info(killer) = &mut death.killer {
+        let recorded = killer.clone();
+        if remap_killer_name(&rules, killer) {
+            death.killer_raw = Some(Killer::Info(recorded));
+        }
+    }
+    death.money_total_copper = money_total(death);
+    apply_inventory_detail(cfg, death);
+    cfg.privacy.apply(death);
+    if cfg.drop_unknown_fields {
+        death.extra.clear();
+    }
+    death.schema_version = cfg.payload_schema_version;
+    Ok(())
+}
+
+/// Version 1 payloads had no `schema_version`; unset (0) counts as version 1.
+fn schema_v1(version: &u32) -> bool {
+    *version < 2
+}
+
+// ---------- Money ----------
+//
+// Addon versions record different subsets of moneyCopper (the raw GetMoney()
+// value) and its gold/silver/copper breakdown; the agent settles on one total.
+
+/// Total copper: `moneyCopper` when recorded, else the breakdown with missing
+/// parts as 0. None when none of the four fields is there.
+pub(crate) fn money_total(death: &DeathPayload) -> Option<i64> {
+    death.money_copper.or_else(|| money_from_breakdown(death))
+}
+
+pub(crate) fn money_from_breakdown(death: &DeathPayload) -> Option<i64> {
+    if death.money_gold.is_none() && death.money_silver.is_none() && death.money_copper_only.is_none() {
+        return None;
+    }
+    let part = |n: Option<i64>, unit: i64| n.unwrap_or(0).saturating_mul(unit);
+    Some(part(death.money_gold, 10_000).saturating_add(part(death.money_silver, 100)).saturating_add(part(death.money_copper_only, 1)))
+}
+
+/// How `moneyCopper` and the breakdown disagree, when the addon recorded both.
+pub(crate) fn money_mismatch(death: &DeathPayload) -> Option<String> {
+    let copper = death.money_copper?;
+    let breakdown = money_from_breakdown(death)?;
+    (copper != breakdown).then(|| {
+        format!(
+            "moneyCopper {copper} but moneyGold/moneySilver/moneyCopperOnly {}/{}/{} make {breakdown}; using moneyCopper",
+            death.money_gold.map_or("-".into(), |n| n.to_string()),
+            death.money_silver.map_or("-".into(), |n| n.to_string()),
+            death.money_copper_only.map_or("-".into(), |n| n.to_string()),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(exact: Option<&str>, regex: Option<&str>) -> KillerRule {
+        KillerRule { exact: exact.map(Into::into), regex: regex.map(Into::into), ..Default::default() }
+    }
+
+    fn remapped(cfg: &Config, name: &str) -> KillerInfo {
+        let mut killer = KillerInfo { name: Some(name.into()), ..Default::default() };
+        remap_killer_name(&compile_killer_rules(cfg).unwrap(), &mut killer);
+        killer
+    }
+
+    #[test]
+    fn builtin_rules_strip_owner_and_realm() {
+        let on = Config::default();
+        let off = Config { killer_remap_builtins: false, ..Config::default() };
+        let cases = [
+            ("Grimtotem Spirit Guide <Bob>", "Grimtotem Spirit Guide", "Grimtotem Spirit Guide <Bob>"),
+            ("Bob-Firemaw", "Bob", "Bob-Firemaw"),
+            ("Bob-Area 52", "Bob-Area 52", "Bob-Area 52"),
+            ("Hogger", "Hogger", "Hogger"),
+            ("Defias Pillager", "Defias Pillager", "Defias Pillager"),
+        ];
+        for (name, with_builtins, without) in cases {
+            assert_eq!(remapped(&on, name).name.as_deref(), Some(with_builtins), "{name}");
+            assert_eq!(remapped(&off, name).name.as_deref(), Some(without), "{name}");
+        }
+    }
+
+    #[test]
+    fn rules_apply_in_order() {
+        let a_to_b = KillerRule { rename: Some("B".into()), ..rule(Some("A"), None) };
+        let b_to_c = KillerRule { rename: Some("C".into()), ..rule(Some("B"), None) };
+        let cases = [(vec![a_to_b.clone(), b_to_c.clone()], "C"), (vec![b_to_c, a_to_b], "B")];
+        for (rules, expected) in cases {
+            let cfg = Config { killer_remap: rules, ..Config::default() };
+            assert_eq!(remapped(&cfg, "A").name.as_deref(), Some(expected));
+        }
+    }
+
+    #[test]
+    fn regex_rules_expand_captures_and_set_fields() {
+        let totem = KillerRule {
+            rename: Some("$1 Totem".into()),
+            npc_id: Some(5925),
+            death_type: Some("totem".into()),
+            ..rule(None, Some(r"^(\w+) Totem [IVX]+$"))
+        };
+        let cfg = Config { killer_remap: vec![totem], ..Config::default() };
+        let killer = remapped(&cfg, "Searing Totem IV");
+        assert_eq!(killer.name.as_deref(), Some("Searing Totem"));
+        assert_eq!(killer.npc_id, Some(5925));
+        assert_eq!(killer.death_type.as_deref(), Some("totem"));
+
+        let untouched = remapped(&cfg, "Totem Tender");
+        assert_eq!((untouched.npc_id, untouched.death_type), (None, None));
+        // Built-ins run first, so a user rule sees the cleaned-up name.
+        assert_eq!(remapped(&cfg, "Searing Totem IV <Thrall>").name.as_deref(), Some("Searing Totem"));
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected_by_number() {
+        let cases = [
+            (rule(Some("A"), None), rule(None, Some("(unclosed")), "killer_remap rule #2: regex \"(unclosed\" is invalid"),
+            (rule(Some("A"), None), rule(None, None), "killer_remap rule #2: set exactly one of `match` or `regex`"),
+            (rule(Some("A"), Some("A")), rule(Some("B"), None), "killer_remap rule #1: set exactly one of `match` or `regex`"),
+        ];
+        for (first, second, expected) in cases {
+            let cfg = Config { killer_remap: vec![first, second], ..Config::default() };
+            let err = compile_killer_rules(&cfg).err().expect(expected);
+            assert_eq!(err.to_string(), expected);
+        }
+    }
+
+    /// xorshift64: a fixed sequence per seed, so a failure names the seed that reproduces it.
+    struct Gen(u64);
+
+    impl Gen {
+        fn below(&mut self, n: i64) -> i64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as i64
+        }
+    }
+
+    /// An addon `bags` table: up to 5 bags of up to 16 slots, some empty.
+    fn inventory(g: &mut Gen) -> serde_json::Value {
+        let bags: Vec<serde_json::Value> = (0..=g.below(5))
+            .map(|bag| {
+                let mut slots = vec![];
+                for slot in (1..=16).filter(|_| g.below(3) > 0).collect::<Vec<_>>() {
+                    slots.push(json!({ "slot": slot, "itemID": 1 + g.below(40), "stackCount": 1 + g.below(20) }));
+                }
+                json!({ "bagID": bag, "slots": slots })
+            })
+            .collect();
+        json!(bags)
+    }
+
+    /// The same inventory after a fight: some slots emptied, filled or restacked.
+    fn mutate(g: &mut Gen, bags: &serde_json::Value) -> serde_json::Value {
+        let mut bags = bags.clone();
+        for bag in bags.as_array_mut().unwrap() {
+            let slots = bag["slots"].as_array_mut().unwrap();
+            slots.retain(|_| g.below(8) > 0);
+            for slot in slots.iter_mut() {
+                if g.below(4) == 0 {
+                    slot["stackCount"] = json!(1 + g.below(20));
+                }
+            }
+            for slot in 17..=17 + g.below(3) {
+                slots.push(json!({ "slot": slot, "itemID": 1 + g.below(40), "stackCount": 1 }));
+            }
+        }
+        bags
+    }
+
+    /// What a server does with `bags_diff`.
+    fn apply_diff(base: &BTreeMap<String, (i64, i64)>, diff: &serde_json::Value) -> BTreeMap<String, (i64, i64)> {
+        let mut out = base.clone();
+        for slot in diff["removed"].as_array().unwrap() {
+            out.remove(slot.as_str().unwrap());
+        }
+        for e in diff["added"].as_array().unwrap().iter().chain(diff["changed"].as_array().unwrap()) {
+            out.insert(e["slot"].as_str().unwrap().into(), (e["itemID"].as_i64().unwrap(), e["count"].as_i64().unwrap()));
+        }
+        out
+    }
+
+    #[test]
+    fn bags_diff_applied_to_its_base_gives_the_new_snapshot() {
+        for seed in 1..=500 {
+            let mut g = Gen(seed);
+            let before = inventory(&mut g);
+            let after = if seed % 10 == 0 { inventory(&mut g) } else { mutate(&mut g, &before) };
+            let (base, new) = (reduce_bags(&before), reduce_bags(&after));
+            let diff = diff_bags(&base, &new);
+            assert_eq!(apply_diff(&base, &diff), new, "seed {seed}");
+            assert_eq!(diff_bags(&base, &new).to_string(), diff.to_string(), "seed {seed}: not deterministic");
+            assert_eq!(diff_bags(&new, &new), json!({ "added": [], "removed": [], "changed": [] }));
+        }
+    }
+
+    #[test]
+    fn bags_diff_ignores_slot_order_in_the_addon_table() {
+        let mut g = Gen(42);
+        let bags = inventory(&mut g);
+        let mut reversed = bags.clone();
+        for bag in reversed.as_array_mut().unwrap() {
+            bag["slots"].as_array_mut().unwrap().reverse();
+        }
+        reversed.as_array_mut().unwrap().reverse();
+        assert_eq!(reduce_bags(&reversed), reduce_bags(&bags));
+        assert_eq!(bags_sha256(&bags), bags_sha256(&bags.clone()));
+    }
+
+    #[test]
+    fn bags_mode_sends_keyframes() {
+        let cfg = Config { bags_mode: BagsMode::Diff, bags_keyframe_every: 3, ..Config::default() };
+        let mut state = State::default();
+        let mut g = Gen(7);
+        let mut bags = inventory(&mut g);
+        let mut sent = vec![];
+        for _ in 0..7 {
+            let mut death = DeathPayload { bags: bags.clone(), ..DeathPayload::default() };
+            let base = apply_bags_mode(&cfg, &state, "A@R", &mut death).unwrap();
+            sent.push(if death.bags_diff.is_some() { "diff" } else { "full" });
+            if let Some(diff) = &death.bags_diff {
+                let known = &state.bag_bases["A@R"];
+                assert_eq!(death.bags_base.as_deref(), Some(known.sha256.as_str()));
+                assert!(death.bags.is_null());
+                assert_eq!(apply_diff(&known.slots, diff), reduce_bags(&bags));
+            }
+            state.bag_bases.insert("A@R".into(), base);
+            bags = mutate(&mut g, &bags);
+        }
+        assert_eq!(sent, ["full", "diff", "diff", "full", "diff", "diff", "full"]);
+
+        // Full mode, the default, never touches the snapshot.
+        let mut death = DeathPayload { bags: bags.clone(), ..DeathPayload::default() };
+        assert!(apply_bags_mode(&Config::default(), &state, "A@R", &mut death).is_none());
+        assert_eq!((death.bags, death.bags_diff), (bags, None));
+    }
+
+    #[test]
+    fn inventory_detail_summarizes_or_drops_bags_and_equipment() {
+        let bags = json!([
+            { "bagID": 0, "numSlots": 16, "slots": [
+                { "slot": 1, "itemID": 117, "stackCount": 6, "hyperlink": "|Hitem:117::::|h[Tough Jerky]|h" },
+                { "slot": 2, "itemID": 2589, "stackCount": 20 },
+            ] },
+            { "bagID": 1, "numSlots": 6, "slots": [{ "slot": 4, "itemID": 117, "stackCount": 4 }] },
+        ]);
+        let equipped = json!([
+            { "slot": 4, "hyperlink": "|cff1eff00|Hitem:2575::::::::24:::::::::|h[Red Linen Shirt]|h|r" },
+            { "slot": 16, "hyperlink": "|cffffffff|Hitem:25:1897:::::::24:::1:3524::::|h[Worn Shortsword]|h|r" },
+        ]);
+        let death = || DeathPayload { bags: bags.clone(), equipped: equipped.clone(), ..DeathPayload::default() };
+
+        let cfg = Config { inventory_detail: InventoryDetail::Summary, ..Config::default() };
+        let mut summary = death();
+        prepare_payload(&cfg, &mut summary).unwrap();
+        assert_eq!(
+            summary.bags,
+            json!({ "total_slots": 22, "used_slots": 3, "items": [{ "itemID": 117, "count": 10 }, { "itemID": 2589, "count": 20 }] })
+        );
+        assert_eq!(summary.equipped, json!([{ "slot": 4, "itemID": 2575 }, { "slot": 16, "itemID": 25 }]));
+        let mut old_addon = bags.clone();
+        old_addon[1].as_object_mut().unwrap().remove("numSlots");
+        assert!(summarize_bags(&old_addon).get("total_slots").is_none(), "unknown unless every bag has numSlots");
+        let diff = Config { bags_mode: BagsMode::Diff, ..cfg };
+        assert!(apply_bags_mode(&diff, &State::default(), "A@R", &mut summary).is_none(), "diffs are of full snapshots only");
+
+        let mut none = death();
+        prepare_payload(&Config { inventory_detail: InventoryDetail::None, ..Config::default() }, &mut none).unwrap();
+        let sent = serde_json::to_value(&none).unwrap();
+        assert!(sent.get("bags").is_none() && sent.get("equipped").is_none(), "{sent}");
+
+        let mut full = death();
+        prepare_payload(&Config::default(), &mut full).unwrap();
+        assert_eq!((full.bags, full.equipped), (bags, equipped));
+    }
+
+    #[test]
+    fn killer_raw_keeps_the_recorded_killer() {
+        let killer = |name: &str| Killer::Info(Box::new(KillerInfo { name: Some(name.into()), ..Default::default() }));
+        let mut death = DeathPayload { killer: killer("Wolf <Rexxar>"), ..DeathPayload::default() };
+        prepare_payload(&Config::default(), &mut death).unwrap();
+        assert_eq!(death.killer, killer("Wolf"));
+        assert_eq!(death.killer_raw, Some(killer("Wolf <Rexxar>")));
+
+        let mut plain = DeathPayload { killer: killer("Hogger"), ..DeathPayload::default() };
+        prepare_payload(&Config::default(), &mut plain).unwrap();
+        assert_eq!(plain.killer_raw, None, "only set when a rule changed something");
+    }
+
+    fn location(v: serde_json::Value) -> Option<LocationPayload> {
+        LocationPayload::from_json(v)
+    }
+
+    #[test]
+    fn location_edge_cases() {
+        let xy = |v| location(v).map(|l| (l.x, l.y));
+        assert_eq!(location(json!("Duskwood")), Some(LocationPayload { zone: Some("Duskwood".into()), ..Default::default() }));
+        assert_eq!(location(json!(12)), None);
+        assert_eq!(location(json!(null)), None);
+        assert_eq!(xy(json!({ "x": 1, "y": 1 })), Some((Some(100.0), Some(100.0))), "both within 0-1 are fractions");
+        assert_eq!(xy(json!({ "x": 0.5, "y": 50 })), Some((Some(0.5), Some(50.0))), "one above 1 means percent");
+        assert_eq!(xy(json!({ "x": 0.123456, "y": 0.98765 })), Some((Some(12.35), Some(98.77))));
+        assert_eq!(xy(json!({ "x": 0, "y": 0 })), Some((Some(0.0), Some(0.0))));
+        // x/y win over posX/posY, which win over a nested table.
+        assert_eq!(xy(json!({ "posX": 10, "posY": 20, "x": 0.3, "y": 0.4, "pos": [5, 6] })), Some((Some(30.0), Some(40.0))));
+
+        // Values that don't convert stay in extra rather than being lost.
+        let odd = location(json!({ "zone": 14, "mapID": "abc", "x": "west", "y": 0.5, "pos": [1, 2, 3], "facing": "north" })).unwrap();
+        assert_eq!(odd.zone.as_deref(), Some("14"));
+        assert_eq!((odd.map_id, odd.x, odd.y, odd.facing), (None, None, None, None));
+        let kept: Vec<&str> = odd.extra.keys().map(String::as_str).collect();
+        assert_eq!(kept, ["facing", "mapID", "pos", "x", "y"]);
+    }
+
+    #[test]
+    fn stored_locations_are_read_in_either_form() {
+        let stored = |location: serde_json::Value| {
+            let mut saved = serde_json::to_value(DeathPayload::default()).unwrap();
+            saved["location"] = location;
+            serde_json::from_value::<DeathPayload>(saved).unwrap().location
+        };
+        // Saved by an older agent: the addon's raw table.
+        let raw = stored(json!({ "zoneName": "Elwynn Forest", "posX": 0.25, "posY": 0.75, "uiMapID": 37 })).unwrap();
+        assert_eq!((raw.zone.as_deref(), raw.map_id, raw.x, raw.y), (Some("Elwynn Forest"), Some(37), Some(25.0), Some(75.0)));
+        // Already normalized: small percentages are not scaled again.
+        let normalized = LocationPayload { zone: Some("Corner".into()), x: Some(0.5), y: Some(0.25), ..Default::default() };
+        assert_eq!(stored(serde_json::to_value(&normalized).unwrap()), Some(normalized));
+        assert_eq!(stored(json!(null)), None);
+    }
+
+    #[test]
+    fn money_total_over_every_combination_of_fields() {
+        // 12g 34s 56c, consistent when all four are there.
+        let (copper, gold, silver, copper_only) = (123_456, 12, 34, 56);
+        for mask in 0..16u8 {
+            let has = |bit: u8| mask & (1 << bit) != 0;
+            let death = DeathPayload {
+                money_copper: has(0).then_some(copper),
+                money_gold: has(1).then_some(gold),
+                money_silver: has(2).then_some(silver),
+                money_copper_only: has(3).then_some(copper_only),
+                ..DeathPayload::default()
+            };
+            let parts = [(has(1), gold * 10_000), (has(2), silver * 100), (has(3), copper_only)];
+            let breakdown = parts.iter().any(|(present, _)| *present).then(|| parts.iter().filter(|(p, _)| *p).map(|(_, v)| v).sum::<i64>());
+            let expected = if has(0) { Some(copper) } else { breakdown };
+            assert_eq!(money_from_breakdown(&death), breakdown, "mask {mask:04b}");
+            assert_eq!(money_total(&death), expected, "mask {mask:04b}");
+            // Only a full breakdown adds up to moneyCopper here; anything else next to it disagrees.
+            let mismatch = has(0) && breakdown.is_some() && mask != 0b1111;
+            assert_eq!(money_mismatch(&death).is_some(), mismatch, "mask {mask:04b}");
+
+            let mut prepared = death.clone();
+            prepare_payload(&Config::default(), &mut prepared).unwrap();
+            assert_eq!(prepared.money_total_copper, expected, "mask {mask:04b}");
+            let sent = serde_json::to_value(&prepared).unwrap();
+            assert_eq!(sent["money_total_copper"], json!(expected), "null, not 0, when nothing was recorded");
+        }
+    }
+
+    #[test]
+    fn money_mismatch_names_the_fields() {
+        let death = DeathPayload { money_copper: Some(500), money_gold: Some(1), money_copper_only: Some(7), ..DeathPayload::default() };
+        assert_eq!(
+            money_mismatch(&death).unwrap(),
+            "moneyCopper 500 but moneyGold/moneySilver/moneyCopperOnly 1/-/7 make 10007; using moneyCopper"
+        );
+        assert_eq!(money_total(&death), Some(500));
+        let huge = DeathPayload { money_gold: Some(i64::MAX), money_silver: Some(1), ..DeathPayload::default() };
+        assert_eq!(money_total(&huge), Some(i64::MAX), "saturates instead of overflowing");
+    }
+
+    /// Random JSON up to three levels deep. Floats are multiples of 1/64, so
+    /// every spelling `spell` picks parses back to the same f64.
+    fn any_json(g: &mut Gen, depth: u32) -> serde_json::Value {
+        let keys = ["zone", "x", "y", "Level", "level", "a", "b", "killer", "ä", "10", "2"];
+        match g.below(if depth == 0 { 5 } else { 7 }) {
+            0 => json!(null),
+            1 => json!(g.below(2) == 0),
+            2 => json!(g.below(2_000_000) - 1_000_000),
+            3 => json!((g.below(20_000) - 10_000) as f64 / 64.0),
+            4 => json!(format!("s{}\"\n", g.below(100))),
+            5 => json!((0..g.below(4)).map(|_| any_json(g, depth - 1)).collect::<Vec<_>>()),
+            _ => {
+                let mut m = serde_json::Map::new();
+                for _ in 0..g.below(6) {
+                    m.insert(keys[g.below(keys.len() as i64) as usize].into(), any_json(g, depth - 1));
+                }
+                serde_json::Value::Object(m)
+            }
+        }
+    }
+
+    /// `v` as text another serializer might produce: keys in any order, spaces
+    /// anywhere, and numbers spelled any way that parses to the same value.
+    /// Integers are only respelled as floats when `respell_ints` is set.
+    fn spell(g: &mut Gen, v: &serde_json::Value, respell_ints: bool) -> String {
+        let ws = |g: &mut Gen| [" ", "", "\n  ", ""][g.below(4) as usize];
+        match v {
+            serde_json::Value::Object(m) => {
+                let mut entries: Vec<_> = m.iter().collect();
+                for i in (1..entries.len()).rev() {
+                    entries.swap(i, g.below(i as i64 + 1) as usize);
+                }
+                let body: Vec<String> = entries
+                    .into_iter()
+                    .map(|(k, v)| format!("{}{}:{}{}", ws(g), serde_json::to_string(k).unwrap(), ws(g), spell(g, v, respell_ints)))
+                    .collect();
+                format!("{{{}{}}}", body.join(","), ws(g))
+            }
+            serde_json::Value::Array(a) => {
+                let body: Vec<String> = a.iter().map(|v| format!("{}{}", ws(g), spell(g, v, respell_ints))).collect();
+                format!("[{}]", body.join(","))
+            }
+            serde_json::Value::Number(n) if n.is_i64() && !respell_ints => n.to_string(),
+            serde_json::Value::Number(n) => {
+                let f = n.as_f64().unwrap();
+                match g.below(4) {
+                    0 => n.to_string(),
+                    1 => format!("{f:e}"),
+                    2 => format!("{f:.8}"),
+                    _ => format!("{}E+0", f),
+                }
+            }
+            other => other.to_string(),
+        }
+    }
+
+    #[test]
+    fn canonical_bytes_ignore_key_order_spacing_and_number_spelling() {
+        for seed in 1..=500 {
+            let mut g = Gen(seed);
+            let v = any_json(&mut g, 3);
+            let expected = canonical_value(&v).to_string().into_bytes();
+            for _ in 0..3 {
+                let text = spell(&mut g, &v, true);
+                assert_eq!(canonical_text(text.as_bytes()), expected, "seed {seed}: {text}");
+            }
+            assert_eq!(canonical_text(&expected), expected, "seed {seed}: canonical form is a fixed point");
+        }
+        assert_eq!(canonical_text(b"{\"b\":3.0,\"a\":[1.50,2e1]}"), b"{\"a\":[1.5,20],\"b\":3}");
+        assert_eq!(canonical_text(b"not json"), b"not json");
+    }
+
+    #[test]
+    fn entry_hash_is_stable_across_serializations() {
+        for seed in 1..=200 {
+            let mut g = Gen(seed);
+            let death = DeathPayload {
+                player: "Hash".into(),
+                realm: "Realm".into(),
+                at: 1_700_000_000 + g.below(1000),
+                level: Some(1 + g.below(80)),
+                location: Some(LocationPayload {
+                    zone: Some("Duskwood".into()),
+                    x: Some(g.below(6400) as f64 / 64.0),
+                    y: Some(g.below(64) as f64),
+                    ..Default::default()
+                }),
+                instance: any_json(&mut g, 2),
+                bags: inventory(&mut g),
+                extra: [("guild".to_string(), any_json(&mut g, 2))].into_iter().collect(),
+                ..DeathPayload::default()
+            };
+            let value = serde_json::to_value(&death).unwrap();
+            let reparsed: DeathPayload = serde_json::from_str(&spell(&mut g, &value, false)).unwrap();
+            assert_eq!(canonical_json(&reparsed), canonical_json(&death), "seed {seed}");
+            assert_eq!(entry_hash(&reparsed), entry_hash(&death), "seed {seed}");
+        }
+    }
+}
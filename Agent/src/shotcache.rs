@@ -0,0 +1,366 @@
+//! Processed screenshots (resized, blurred and/or recompressed) kept on disk so
+//! a retry or a second target sends the same bytes without re-encoding them.
+//!
+//! Entries are keyed by the source file's SHA-256 plus the processing
+//! parameters, so changing `screenshot_max_dimension`, `screenshot_quality` or
+//! the blur settings never reuses an old artifact. `index.json` records each
+//! entry's size and when it was last used; the cache is held under
+//! `screenshot_cache_max_mb` by dropping the least recently used entries. An
+//! entry being read is pinned and survives any eviction meanwhile.
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::state::write_atomic;
+
+const INDEX_FILE: &str = "index.json";
+
+/// Files younger than this are left alone by the orphan cleanup: another
+/// thread may be about to add them to the index.
+const ORPHAN_GRACE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct CacheEntry {
+    /// `jpg` or `png`; the artifact is `<key>.<ext>`
+    pub(crate) ext: String,
+    pub(crate) size: u64,
+    /// Epoch millis
+    pub(crate) last_used: i64,
+    /// `ScreenshotCache::params` the artifact was made with
+    pub(crate) params: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct CacheIndex {
+    pub(crate) entries: BTreeMap<String, CacheEntry>,
+}
+
+/// What a sweep removed.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct SweepReport {
+    pub(crate) stale: usize,
+    pub(crate) missing: usize,
+    pub(crate) orphans: usize,
+    pub(crate) evicted: usize,
+}
+
+pub(crate) struct ScreenshotCache {
+    dir: PathBuf,
+    index: Mutex<CacheIndex>,
+    /// Readers per key; pinned entries are never evicted
+    pins: Mutex<HashMap<String, usize>>,
+    last_sweep: Mutex<Option<Instant>>,
+}
+
+/// Keeps an entry from being evicted while it is alive.
+pub(crate) struct CachePin<'a> {
+    cache: &'a ScreenshotCache,
+    key: String,
+}
+
+impl Drop for CachePin<'_> {
+    fn drop(&mut self) {
+        let mut pins = self.cache.pins.lock().unwrap();
+        if let Some(n) = pins.get_mut(&self.key) {
+            *n -= 1;
+            if *n == 0 {
+                pins.remove(&self.key);
+            }
+        }
+    }
+}
+
+static SHARED: once_cell::sync::OnceCell<ScreenshotCache> = once_cell::sync::OnceCell::new();
+
+/// The cache under the config directory, opened on first use.
+pub(crate) fn shared() -> Result<&'static ScreenshotCache> {
+    SHARED.get_or_try_init(|| ScreenshotCache::open(&crate::config::config_dir()?.join("screenshot_cache")))
+}
+
+/// Text identifying how an artifact was made; part of its key.
+pub(crate) fn params(max_dimension: u32, quality: u8, recompress: bool, blur: Option<&str>) -> String {
+    format!("v1 max={max_dimension} quality={quality} recompress={recompress} blur={}", blur.unwrap_or("off"))
+}
+
+pub(crate) fn key(source_sha256: &str, params: &str) -> String {
+    format!("{:x}", Sha256::digest(format!("{source_sha256}\n{params}").as_bytes()))
+}
+
+fn now_ms() -> i64 {
+    Utc::now().timestamp_millis()
+}
+
+impl ScreenshotCache {
+    pub(crate) fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        let index = fs::read_to_string(dir.join(INDEX_FILE)).ok().and_then(|s| serde_json::from_str(&s).ok()).unwrap_or_default();
+        Ok(Self { dir: dir.to_path_buf(), index: Mutex::new(index), pins: Mutex::default(), last_sweep: Mutex::new(None) })
+    }
+
+    fn artifact(&self, key: &str, ext: &str) -> PathBuf {
+        self.dir.join(format!("{key}.{ext}"))
+    }
+
+    fn save(&self, index: &CacheIndex) -> Result<()> {
+        write_atomic(&self.dir.join(INDEX_FILE), serde_json::to_string(index)?.as_bytes())
+    }
+
+    pub(crate) fn pin(&self, key: &str) -> CachePin<'_> {
+        *self.pins.lock().unwrap().entry(key.to_string()).or_default() += 1;
+        CachePin { cache: self, key: key.to_string() }
+    }
+
+    fn is_pinned(&self, key: &str) -> bool {
+        self.pins.lock().unwrap().contains_key(key)
+    }
+
+    /// The artifact's bytes and extension, marking it used. None when it isn't
+    /// cached, or its file has gone.
+    pub(crate) fn get(&self, key: &str) -> Option<(Vec<u8>, String)> {
+        let _pin = self.pin(key);
+        let ext = self.index.lock().unwrap().entries.get(key)?.ext.clone();
+        let read = fs::read(self.artifact(key, &ext));
+        let mut index = self.index.lock().unwrap();
+        match read {
+            Ok(bytes) => {
+                if let Some(entry) = index.entries.get_mut(key) {
+                    entry.last_used = now_ms();
+                }
+                self.save(&index).ok();
+                Some((bytes, ext))
+            }
+            Err(_) => {
+                index.entries.remove(key);
+                self.save(&index).ok();
+                None
+            }
+        }
+    }
+
+    /// Store an artifact, then drop least recently used entries until the cache
+    /// fits in `max_bytes`.
+    pub(crate) fn insert(&self, key: &str, params: &str, ext: &str, bytes: &[u8], max_bytes: u64) -> Result<()> {
+        let _pin = self.pin(key);
+        write_atomic(&self.artifact(key, ext), bytes)?;
+        let mut index = self.index.lock().unwrap();
+        let entry = CacheEntry { ext: ext.to_string(), size: bytes.len() as u64, last_used: now_ms(), params: params.to_string() };
+        index.entries.insert(key.to_string(), entry);
+        self.evict_to(&mut index, max_bytes);
+        self.save(&index)
+    }
+
+    fn evict_to(&self, index: &mut CacheIndex, max_bytes: u64) -> usize {
+        let mut total: u64 = index.entries.values().map(|e| e.size).sum();
+        if total <= max_bytes {
+            return 0;
+        }
+        let mut by_age: Vec<(i64, String)> = index.entries.iter().map(|(k, e)| (e.last_used, k.clone())).collect();
+        by_age.sort();
+        let mut evicted = 0;
+        for (_, key) in by_age {
+            if total <= max_bytes {
+                break;
+            }
+            if self.is_pinned(&key) {
+                continue;
+            }
+            if let Some(entry) = index.entries.remove(&key) {
+                fs::remove_file(self.artifact(&key, &entry.ext)).ok();
+                total -= entry.size;
+                evicted += 1;
+            }
+        }
+        evicted
+    }
+
+    /// Drop entries made with other parameters than `current`, forget entries
+    /// whose file has gone, delete files the index doesn't know, and evict down
+    /// to `max_bytes`. Pinned entries are kept.
+    pub(crate) fn sweep(&self, current: &str, max_bytes: u64) -> Result<SweepReport> {
+        let mut report = SweepReport::default();
+        let mut index = self.index.lock().unwrap();
+        let keys: Vec<String> = index.entries.keys().cloned().collect();
+        for key in keys {
+            let entry = &index.entries[&key];
+            let path = self.artifact(&key, &entry.ext);
+            if !path.exists() {
+                index.entries.remove(&key);
+                report.missing += 1;
+            } else if entry.params != current && !self.is_pinned(&key) {
+                fs::remove_file(&path).ok();
+                index.entries.remove(&key);
+                report.stale += 1;
+            }
+        }
+        for file in fs::read_dir(&self.dir)?.filter_map(|e| e.ok()).map(|e| e.path()) {
+            let Some(name) = file.file_name().and_then(|n| n.to_str()) else { continue };
+            let known = name == INDEX_FILE
+                || name.split_once('.').is_some_and(|(key, ext)| index.entries.get(key).is_some_and(|e| e.ext == ext));
+            let young = file
+                .metadata()
+                .and_then(|m| m.modified())
+                .is_ok_and(|t| SystemTime::now().duration_since(t).unwrap_or_default() < ORPHAN_GRACE);
+            if !known && !young && file.is_file() && fs::remove_file(&file).is_ok() {
+                report.orphans += 1;
+            }
+        }
+        report.evicted = self.evict_to(&mut index, max_bytes);
+        self.save(&index)?;
+        *self.last_sweep.lock().unwrap() = Some(Instant::now());
+        Ok(report)
+    }
+
+    /// Whether `interval` has passed since the last sweep (or none was made yet).
+    pub(crate) fn sweep_due(&self, interval: Duration) -> bool {
+        self.last_sweep.lock().unwrap().is_none_or(|t| t.elapsed() >= interval)
+    }
+}
+
+/// Entries and bytes recorded in the index under `dir`, without opening the
+/// cache; for `status`, which runs beside the agent.
+pub(crate) fn usage_on_disk(dir: &Path) -> (usize, u64) {
+    let index: CacheIndex =
+        fs::read_to_string(dir.join(INDEX_FILE)).ok().and_then(|s| serde_json::from_str(&s).ok()).unwrap_or_default();
+    (index.entries.len(), index.entries.values().map(|e| e.size).sum())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn cache(name: &str) -> ScreenshotCache {
+        let dir = std::env::temp_dir().join(format!("deathlogger-shotcache-{}-{name}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        ScreenshotCache::open(&dir).unwrap()
+    }
+
+    fn used_at(cache: &ScreenshotCache, key: &str, at: i64) {
+        cache.index.lock().unwrap().entries.get_mut(key).unwrap().last_used = at;
+    }
+
+    fn keys(cache: &ScreenshotCache) -> Vec<String> {
+        cache.index.lock().unwrap().entries.keys().cloned().collect()
+    }
+
+    /// Make a file look older than the orphan grace period.
+    fn age(path: &Path) {
+        let old = SystemTime::now() - ORPHAN_GRACE * 2;
+        File::options().write(true).open(path).unwrap().set_modified(old).unwrap();
+    }
+
+    #[test]
+    fn least_recently_used_entries_go_first() {
+        let c = cache("lru");
+        for (i, k) in ["a", "b", "c"].iter().enumerate() {
+            c.insert(k, "p", "jpg", &[0; 10], 100).unwrap();
+            used_at(&c, k, i as i64 + 1);
+        }
+        // Reading "a" makes "b" the oldest.
+        assert_eq!(c.get("a").unwrap(), (vec![0; 10], "jpg".to_string()));
+        c.insert("d", "p", "jpg", &[1; 10], 30).unwrap();
+        assert_eq!(keys(&c), ["a", "c", "d"]);
+        assert!(!c.dir.join("b.jpg").exists());
+        // "a" was read before "d" went in.
+        c.insert("e", "p", "png", &[2; 15], 30).unwrap();
+        assert_eq!(keys(&c), ["d", "e"]);
+        assert_eq!(usage_on_disk(&c.dir), (2, 25));
+
+        // The index survives a restart.
+        let reopened = ScreenshotCache::open(&c.dir).unwrap();
+        assert_eq!(reopened.get("e").unwrap(), (vec![2; 15], "png".to_string()));
+        assert!(reopened.get("b").is_none());
+    }
+
+    #[test]
+    fn changed_parameters_invalidate_entries() {
+        let c = cache("params");
+        let old = params(1920, 90, true, None);
+        let new = params(1920, 80, true, None);
+        assert_ne!(key("abc", &old), key("abc", &new));
+        assert_ne!(key("abc", &old), key("abd", &old));
+        assert_ne!(params(0, 90, false, Some("[]")), params(0, 90, false, None));
+        c.insert(&key("abc", &old), &old, "jpg", b"old", 1 << 20).unwrap();
+        c.insert(&key("abc", &new), &new, "jpg", b"new", 1 << 20).unwrap();
+        let report = c.sweep(&new, 1 << 20).unwrap();
+        assert_eq!(report, SweepReport { stale: 1, ..SweepReport::default() });
+        assert_eq!(keys(&c), [key("abc", &new)]);
+        assert!(!c.dir.join(format!("{}.jpg", key("abc", &old))).exists());
+    }
+
+    #[test]
+    fn sweep_removes_orphans_and_forgets_missing_files() {
+        let c = cache("orphans");
+        c.insert("kept", "p", "jpg", b"x", 100).unwrap();
+        c.insert("gone", "p", "jpg", b"y", 100).unwrap();
+        fs::remove_file(c.dir.join("gone.jpg")).unwrap();
+        fs::write(c.dir.join("stray.jpg"), b"z").unwrap();
+        age(&c.dir.join("stray.jpg"));
+        // Same key, other extension: not what the index points at.
+        fs::write(c.dir.join("kept.png"), b"z").unwrap();
+        age(&c.dir.join("kept.png"));
+        // Maybe still being written.
+        fs::write(c.dir.join("fresh.tmp"), b"z").unwrap();
+
+        assert!(c.sweep_due(Duration::from_secs(600)));
+        let report = c.sweep("p", 100).unwrap();
+        assert_eq!(report, SweepReport { missing: 1, orphans: 2, ..SweepReport::default() });
+        assert_eq!(keys(&c), ["kept"]);
+        let mut left: Vec<String> =
+            fs::read_dir(&c.dir).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        left.sort();
+        assert_eq!(left, ["fresh.tmp", INDEX_FILE, "kept.jpg"]);
+        assert!(!c.sweep_due(Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn pinned_entries_survive_eviction_under_concurrent_use() {
+        let c = Arc::new(cache("pins"));
+        c.insert("busy", "p", "jpg", &[7; 100], 1000).unwrap();
+        used_at(&c, "busy", 0);
+        let pin = c.pin("busy");
+        let writers: Vec<_> = (0..4)
+            .map(|t| {
+                let c = c.clone();
+                thread::spawn(move || {
+                    for i in 0..25 {
+                        c.insert(&format!("w{t}-{i}"), "p", "jpg", &[t as u8; 100], 300).unwrap();
+                    }
+                })
+            })
+            .collect();
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let c = c.clone();
+                thread::spawn(move || {
+                    for _ in 0..25 {
+                        assert_eq!(c.get("busy").unwrap().0, vec![7; 100]);
+                        c.sweep("other params", 300).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for t in writers.into_iter().chain(readers) {
+            t.join().unwrap();
+        }
+        assert!(keys(&c).contains(&"busy".to_string()));
+        assert!(c.dir.join("busy.jpg").exists());
+
+        // Unpinned, it goes like any other entry.
+        drop(pin);
+        assert!(c.pins.lock().unwrap().is_empty());
+        c.sweep("p", 0).unwrap();
+        assert!(keys(&c).is_empty());
+        assert_eq!(usage_on_disk(&c.dir), (0, 0));
+    }
+}
@@ -0,0 +1,380 @@
+//! Persistent agent state, its on-disk versioning and the cross-process locks.
+
+use crate::*;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct State {
+    /// Last uploaded death timestamp (the "at" field) per account/realm/player
+    pub(crate) last_uploaded: BTreeMap<String, i64>,
+    /// Queue of screenshots we saw but didn't match yet
+    pub(crate) pending_screens: VecDeque<PendingShot>,
+    /// Daily counters feeding the reliability summary
+    pub(crate) metrics: Metrics,
+    /// Deaths table shape per SavedVariables file as of the last parse
+    pub(crate) sv_tables: BTreeMap<String, SvTableInfo>,
+    /// Where the next parse of each SavedVariables file may start
+    pub(crate) sv_cursors: BTreeMap<String, SvCursor>,
+    /// Deaths held back because their identity couldn't be resolved
+    pub(crate) quarantine: Vec<QuarantinedDeath>,
+    /// Last full bag snapshot uploaded per character (for `bags_mode = "diff"`)
+    pub(crate) bag_bases: BTreeMap<String, BagBase>,
+    /// Latest in-game addon settings seen per character
+    pub(crate) addon_settings: BTreeMap<String, AddonSettings>,
+    /// Deaths whose upload failed and will be tried again
+    pub(crate) retry_queue: Vec<RetryEntry>,
+    /// Deaths the server rejected outright (newest last)
+    pub(crate) failed_uploads: Vec<FailedUpload>,
+    /// Highest milestone level announced (or already passed when first seen) per character
+    pub(crate) milestone_marks: BTreeMap<String, i64>,
+    /// Install id and schedule for the opt-in usage ping
+    pub(crate) telemetry: TelemetryState,
+    /// Media ids the server gave for screenshots, keyed by the file's SHA-256
+    pub(crate) media_ids: BTreeMap<String, MediaRef>,
+    /// Idempotency key of the newest not-yet-acknowledged death per character,
+    /// so a restart resends it under the same key
+    pub(crate) idempotency_keys: BTreeMap<String, IdempotencyKey>,
+    /// Death rate and held deaths per character that died in quick succession
+    pub(crate) surges: BTreeMap<String, Surge>,
+    /// What the servers returned for accepted deaths, keyed by "Player@Realm#at"
+    pub(crate) upload_history: BTreeMap<String, Vec<UploadRecord>>,
+    /// Deaths uploaded without a screenshot that a late one may still follow, oldest first
+    pub(crate) awaiting_screenshot: Vec<AwaitingShot>,
+    /// Hashes of the deaths handled recently per character, for deaths that
+    /// share a second with one already uploaded
+    pub(crate) seen_deaths: BTreeMap<String, SeenDeaths>,
+    /// `[filters]` as of the last start, to notice when they change
+    pub(crate) applied_filters: Option<DeathFilters>,
+    /// (size, mtime) of each SV file when it was last handled completely. Memory
+    /// only, so every file is parsed again after a restart.
+    #[serde(skip)]
+    pub(crate) sv_seen: BTreeMap<PathBuf, (u64, SystemTime)>,
+    /// Outdated addon version last warned about per SV file
+    #[serde(skip)]
+    pub(crate) outdated_addon_seen: BTreeMap<PathBuf, String>,
+    /// Entries without a usable `at` last warned about per SV file
+    #[serde(skip)]
+    pub(crate) undated_seen: BTreeMap<PathBuf, Vec<String>>,
+    /// Malformed entries currently skipped per SV file, as "<entry>: <violation>",
+    /// so each is reported and counted once
+    pub(crate) skipped_entries: BTreeMap<PathBuf, BTreeSet<String>>,
+    /// Screenshots paired with a death that is being sent but not settled yet.
+    /// Memory only: after an interrupted upload they are pending again.
+    #[serde(skip)]
+    pub(crate) claimed_screens: BTreeSet<String>,
+    /// Queued screenshots the game may still be writing, left out of pairing
+    /// until they settle. Memory only: after a restart they are long finished.
+    #[serde(skip)]
+    pub(crate) unsettled_screens: BTreeMap<String, ShotWrite>,
+    /// Loaded from screenshot_index.json by the watch loop
+    #[serde(skip)]
+    pub(crate) shot_index: ScreenshotIndex,
+}
+
+/// Content hashes of a character's recently handled deaths. Deaths at or before
+/// `since` predate them and are judged by the watermark alone.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct SeenDeaths {
+    pub(crate) since: i64,
+    /// (at, entry hash), oldest first
+    pub(crate) recent: VecDeque<(i64, String)>,
+}
+
+/// Hashes kept per character in `seen_deaths`
+pub(crate) const MAX_SEEN_DEATHS: usize = 200;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct IdempotencyKey {
+    pub(crate) at: i64,
+    pub(crate) key: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PendingShot {
+    pub(crate) path: String,
+    pub(crate) ts_epoch: i64,
+}
+
+// ---------- SV & screenshot watching ----------
+
+pub(crate) fn load_state() -> Result<State> {
+    load_state_from(&state_path()?)
+}
+
+pub(crate) fn load_state_from(p: &Path) -> Result<State> {
+    if !p.exists() {
+        return Ok(State::default());
+    }
+    let mut value: serde_json::Value = serde_json::from_str(&fs::read_to_string(p)?)?;
+    let (found, needs) = state_versions(&value);
+    if needs > STATE_VERSION {
+        return Err(StateTooNew { path: p.display().to_string(), found, needs }.into());
+    }
+    migrate_state(&mut value);
+    Ok(serde_json::from_value(value)?)
+}
+
+/// State for a process that will write it back. A state.json from a newer agent
+/// stops us unless `force_downgrade`, which archives that file first and keeps
+/// whatever this version understands of it.
+pub(crate) fn load_state_for_write(force_downgrade: bool) -> Result<State> {
+    load_state_for_write_from(&state_path()?, force_downgrade)
+}
+
+pub(crate) fn load_state_for_write_from(p: &Path, force_downgrade: bool) -> Result<State> {
+    match load_state_from(p) {
+        Ok(state) => Ok(state),
+        Err(e) if e.is::<StateTooNew>() => {
+            if !force_downgrade {
+                return Err(e);
+            }
+            let archive = archive_state(p)?;
+            println!("[state] Archived the newer state to {}; continuing with the parts this version understands", archive.display());
+            Ok(serde_json::from_str(&fs::read_to_string(&archive)?)?)
+        }
+        Err(e) => {
+            eprintln!("[warn] state.json could not be read ({e:#}); starting from an empty state");
+            Ok(State::default())
+        }
+    }
+}
+
+pub(crate) fn save_state(state: &State) -> Result<()> {
+    fs::create_dir_all(config_dir()?)?;
+    let lock = ProcessLock::acquire(LockName::State, STATE_LOCK_TIMEOUT)?;
+    let mut value = serde_json::to_value(state)?;
+    if let Some(map) = value.as_object_mut() {
+        map.insert("state_version".into(), STATE_VERSION.into());
+        map.insert("min_compatible_version".into(), MIN_COMPATIBLE_STATE_VERSION.into());
+    }
+    write_atomic(&state_path()?, serde_json::to_string_pretty(&value)?.as_bytes())?;
+    drop(lock);
+    // What the events report is on disk now.
+    flush_events();
+    Ok(())
+}
+
+// ---------- State versioning ----------
+//
+// state.json carries the layout version that wrote it and the oldest agent
+// version allowed to write it again. Loading runs the migrations from the file's
+// version up; an agent older than `min_compatible_version` refuses to start
+// rather than drop what it doesn't understand on the next save.
+
+/// Layout of state.json this agent writes
+pub(crate) const STATE_VERSION: u32 = 2;
+/// Oldest agent state version that can rewrite our state.json without losing data
+pub(crate) const MIN_COMPATIBLE_STATE_VERSION: u32 = 2;
+
+pub(crate) type StateMigration = fn(&mut serde_json::Map<String, serde_json::Value>);
+
+/// Entry `i` turns a version-`i` file into version `i + 1`.
+pub(crate) const STATE_MIGRATIONS: &[StateMigration] = &[
+    // 0 -> 1: files from before versioning, often just last_uploaded and
+    // pending_screens. Every later field has a default, so nothing to rewrite.
+    |_| {},
+    // 1 -> 2: per-character keys gain the account folder. Which account a
+    // character is on comes from the WoW folder, so `migrate_account_keys`
+    // rewrites them once the agent knows where that is. Older agents would read
+    // the new keys as unknown characters and upload everything again.
+    |_| {},
+];
+
+/// Move per-character state saved under "Player@Realm" to "Account:Player@Realm",
+/// going by the character folders under WTF/Account. A character found on more
+/// than one account keeps its watermark on each, so none of them uploads old
+/// deaths again. One found on none goes to the only account when there is just
+/// one, and otherwise keeps the old key. Returns the keys moved.
+pub(crate) fn migrate_account_keys(state: &mut State, wow: &WowPaths) -> usize {
+    let accounts: Vec<String> = fs::read_dir(wow.wtf_account_dir())
+        .map(|rd| rd.flatten().filter(|e| e.path().is_dir()).map(|e| e.file_name().to_string_lossy().to_string()).collect())
+        .unwrap_or_default();
+    let mut owners: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let pattern = wow.wtf_account_dir().join("*").join("*").join("*");
+    for dir in glob(&pattern.to_string_lossy()).into_iter().flatten().flatten().filter(|p| p.is_dir()) {
+        let parts: Vec<String> = dir.components().rev().take(3).map(|c| c.as_os_str().to_string_lossy().to_string()).collect();
+        let [character, realm, account] = parts.as_slice() else { continue };
+        if realm.eq_ignore_ascii_case("SavedVariables") {
+            continue;
+        }
+        owners.entry(to_key(character, realm)).or_default().push(account.clone());
+    }
+
+    let only = match accounts.as_slice() {
+        [only] => Some(only.clone()),
+        _ => None,
+    };
+    let owners_of = |character: &str| -> Vec<String> {
+        owners.get(character).cloned().or_else(|| only.clone().map(|a| vec![a])).unwrap_or_default()
+    };
+
+    fn rekey<V: Clone>(map: &mut BTreeMap<String, V>, owners_of: &dyn Fn(&str) -> Vec<String>) -> usize {
+        let legacy: Vec<String> = map.keys().filter(|k| !k.contains(':')).cloned().collect();
+        let mut moved = 0;
+        for key in legacy {
+            // upload_history keys carry "#<at>" after the character
+            let accounts = owners_of(key.split('#').next().unwrap_or(&key));
+            if accounts.is_empty() {
+                continue;
+            }
+            let Some(value) = map.remove(&key) else { continue };
+            for account in &accounts {
+                map.insert(format!("{}:{}", account, key), value.clone());
+            }
+            moved += 1;
+        }
+        moved
+    }
+    let moved = rekey(&mut state.last_uploaded, &owners_of)
+        + rekey(&mut state.milestone_marks, &owners_of)
+        + rekey(&mut state.addon_settings, &owners_of)
+        + rekey(&mut state.bag_bases, &owners_of)
+        + rekey(&mut state.idempotency_keys, &owners_of)
+        + rekey(&mut state.surges, &owners_of)
+        + rekey(&mut state.upload_history, &owners_of)
+        + rekey(&mut state.metrics.last_detected, &owners_of);
+
+    // Deaths still waiting to go out follow their character when it is on a single account.
+    let claim = |death: &mut DeathPayload| {
+        if let (None, [account]) = (&death.account, owners_of(&death.key()).as_slice()) {
+            death.account = Some(account.clone());
+        }
+    };
+    state.retry_queue.iter_mut().for_each(|r| claim(&mut r.death));
+    state.failed_uploads.iter_mut().for_each(|f| claim(&mut f.death));
+    for q in &mut state.quarantine {
+        q.death.account = q.death.account.take().or_else(|| account_from_sv_path(Path::new(&q.sv_path)));
+    }
+    for staged in state.surges.values_mut().flat_map(|s| s.held.iter_mut()) {
+        claim(&mut staged.death);
+        staged.key = staged.death.key();
+    }
+    moved
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "{path} was written by a newer agent (state version {found}, readable from version {needs}; this agent is version \
+     {ours}). Update the agent, or start it once with --force-downgrade-state to archive that file and continue with \
+     what this version understands",
+    ours = STATE_VERSION
+)]
+pub(crate) struct StateTooNew {
+    pub(crate) path: String,
+    pub(crate) found: u32,
+    pub(crate) needs: u32,
+}
+
+/// (state_version, min_compatible_version) of a state.json; 0 for files written before versioning.
+pub(crate) fn state_versions(value: &serde_json::Value) -> (u32, u32) {
+    let field = |name: &str| value.get(name).and_then(|v| v.as_u64()).unwrap_or(0) as u32;
+    (field("state_version"), field("min_compatible_version"))
+}
+
+pub(crate) fn migrate_state(value: &mut serde_json::Value) {
+    let (from, _) = state_versions(value);
+    let Some(map) = value.as_object_mut() else { return };
+    for step in STATE_MIGRATIONS.iter().skip(from as usize) {
+        step(map);
+    }
+}
+
+/// Move state.json aside as state.v<version>.<timestamp>.json and return the new path.
+pub(crate) fn archive_state(p: &Path) -> Result<PathBuf> {
+    let value: serde_json::Value = serde_json::from_str(&fs::read_to_string(p)?)?;
+    let (found, _) = state_versions(&value);
+    let archive = p.with_file_name(format!("state.v{}.{}.json", found, Utc::now().format("%Y%m%d%H%M%S")));
+    fs::rename(p, &archive).with_context(|| format!("archiving {}", p.display()))?;
+    Ok(archive)
+}
+
+/// Write via a temp file and rename, so readers never see a half-written file.
+pub(crate) fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, bytes).with_context(|| format!("writing {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("replacing {}", path.display()))?;
+    Ok(())
+}
+
+// ---------- Cross-process locks ----------
+
+pub(crate) const STATE_LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// OS file locks in the config dir. When more than one is needed they are taken
+/// in declaration order, so two processes can't deadlock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum LockName {
+    /// Held for its whole lifetime by the resident agent
+    Agent,
+    /// Held while state.json is rewritten
+    State,
+}
+
+impl LockName {
+    pub(crate) fn file_name(self) -> &'static str {
+        match self {
+            LockName::Agent => "agent.lock",
+            LockName::State => "state.lock",
+        }
+    }
+}
+
+/// Locks each command needs. Commands not listed only read and take none.
+pub(crate) const COMMAND_LOCKS: &[(&str, &[LockName])] = &[
+    ("run", &[LockName::Agent]),
+    // The running agent would overwrite the new id with its in-memory state.
+    ("telemetry reset-id", &[LockName::Agent]),
+];
+
+pub(crate) fn locks_for(command: &str) -> &'static [LockName] {
+    COMMAND_LOCKS.iter().find(|(c, _)| *c == command).map(|(_, l)| *l).unwrap_or(&[])
+}
+
+pub(crate) struct ProcessLock {
+    pub(crate) _file: File,
+}
+
+impl ProcessLock {
+    pub(crate) fn acquire(name: LockName, timeout: Duration) -> Result<Self> {
+        fs::create_dir_all(config_dir()?)?;
+        let path = config_dir()?.join(name.file_name());
+        let mut file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+        let started = std::time::Instant::now();
+        loop {
+            match file.try_lock() {
+                Ok(()) => break,
+                Err(std::fs::TryLockError::WouldBlock) if started.elapsed() < timeout => {
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+                Err(std::fs::TryLockError::WouldBlock) => {
+                    let holder = fs::read_to_string(&path).unwrap_or_default();
+                    let holder = holder.trim();
+                    return Err(anyhow!(
+                        "{} is held by another DeathLogger agent process{}",
+                        name.file_name(),
+                        if holder.is_empty() { String::new() } else { format!(" (pid {})", holder) }
+                    ));
+                }
+                Err(std::fs::TryLockError::Error(e)) => return Err(e).with_context(|| format!("locking {}", path.display())),
+            }
+        }
+        // Record the holder for the diagnostic above.
+        use std::io::{Seek, Write};
+        file.set_len(0).ok();
+        file.rewind().ok();
+        write!(file, "{}", std::process::id()).ok();
+        Ok(Self { _file: file })
+    }
+
+    /// Take every lock a command declares, in hierarchy order.
+    pub(crate) fn acquire_for(command: &str, timeout: Duration) -> Result<Vec<Self>> {
+        let mut names = locks_for(command).to_vec();
+        names.sort();
+        names.into_iter().map(|n| Self::acquire(n, timeout)).collect()
+    }
+}
+
+pub(crate) fn newest_mtime(path: &Path) -> Option<SystemTime> {
+    path.metadata().and_then(|m| m.modified()).ok()
+}
@@ -0,0 +1,1030 @@
+//! SavedVariables parsing: identity resolution, snapshots and the addon adapters.
+
+use crate::*;
+
+// ---------- SavedVariables format ----------
+
+/// SavedVariables file the addon writes, per account or per character, unless
+/// `sv_file_name` names another.
+pub(crate) const SV_FILE_NAME: &str = "DeathLogger.lua";
+/// Global the addon keeps its data in, unless `sv_global_names` lists others.
+pub(crate) const SV_GLOBAL_NAME: &str = "DeathLoggerDB";
+
+/// Marker put in place of a table nested too deep, or inside itself.
+pub(crate) const RECURSION_MARKER: &str = "<recursion>";
+
+/// How the contents of a SavedVariables file are read.
+#[derive(Debug, Clone)]
+pub(crate) struct SvFormat {
+    /// Globals to look for; the first one holding a table is used
+    pub(crate) globals: Vec<String>,
+    pub(crate) clock: TimestampMode,
+    /// Tables nested deeper than this are replaced by RECURSION_MARKER
+    pub(crate) max_depth: usize,
+    /// Parser tried first
+    pub(crate) parser: SvParser,
+}
+
+impl SvFormat {
+    pub(crate) fn from_config(cfg: &Config) -> Self {
+        Self {
+            globals: cfg.sv_global_names.clone(),
+            clock: cfg.timestamp_mode,
+            max_depth: cfg.sv_max_table_depth,
+            parser: cfg.sv_parser,
+        }
+    }
+}
+
+// ---------- Identity ----------
+
+/// A death whose player/realm couldn't be determined. Held back instead of
+/// uploading an anonymous payload, and retried whenever its file is parsed again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct QuarantinedDeath {
+    pub(crate) sv_path: String,
+    pub(crate) since: i64,
+    pub(crate) death: DeathPayload,
+}
+
+pub(crate) const MAX_QUARANTINE: usize = 50;
+
+/// "Name-Realm" -> ("Name", Some("Realm")); anything else is returned as-is.
+pub(crate) fn split_player_realm(player: &str) -> (String, Option<String>) {
+    let player = player.trim();
+    match player.split_once('-') {
+        Some((name, realm)) if !name.is_empty() && !realm.is_empty() => (name.to_string(), Some(realm.to_string())),
+        _ => (player.to_string(), None),
+    }
+}
+
+/// Account folder of a SavedVariables file in either layout under WTF/Account.
+pub(crate) fn account_from_sv_path(sv_path: &Path) -> Option<String> {
+    let parts: Vec<String> = sv_path
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect();
+    let i = parts.iter().rposition(|p| p.eq_ignore_ascii_case("Account"))?;
+    match &parts[i + 1..] {
+        [account, sv_dir, _file] | [account, _, _, sv_dir, _file] if sv_dir.eq_ignore_ascii_case("SavedVariables") => {
+            Some(account.clone())
+        }
+        _ => None,
+    }
+}
+
+/// (player, realm) of the characters a SavedVariables file belongs to: its own for
+/// a per-character file, else every character folder of its account.
+pub(crate) fn sv_path_characters(sv_path: &Path) -> Vec<(String, String)> {
+    if let Some(identity) = identity_from_sv_path(sv_path) {
+        return vec![identity];
+    }
+    let Some(account_dir) = sv_path.parent().and_then(Path::parent) else { return vec![] };
+    let mut characters = vec![];
+    for realm in fs::read_dir(account_dir).into_iter().flatten().flatten().filter(|e| e.path().is_dir()) {
+        let realm_name = realm.file_name().to_string_lossy().to_string();
+        if realm_name.eq_ignore_ascii_case("SavedVariables") {
+            continue;
+        }
+        for character in fs::read_dir(realm.path()).into_iter().flatten().flatten().filter(|e| e.path().is_dir()) {
+            characters.push((character.file_name().to_string_lossy().to_string(), realm_name.clone()));
+        }
+    }
+    characters
+}
+
+/// (realm, character) for character-level files:
+/// WTF/Account/<ACCOUNT>/<Realm>/<Character>/SavedVariables/<file>
+pub(crate) fn identity_from_sv_path(sv_path: &Path) -> Option<(String, String)> {
+    let parts: Vec<String> = sv_path
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect();
+    let i = parts.iter().rposition(|p| p.eq_ignore_ascii_case("Account"))?;
+    match &parts[i + 1..] {
+        [_account, realm, character, sv_dir, _file] if sv_dir.eq_ignore_ascii_case("SavedVariables") => {
+            Some((realm.clone(), character.clone()))
+        }
+        _ => None,
+    }
+}
+
+/// Normalize player/realm in place. Precedence: the entry itself (splitting an
+/// embedded "-Realm"), then the character-level SV path, then the newest entry in
+/// the same file that had a complete identity. Returns false if still incomplete.
+pub(crate) fn resolve_identity(death: &mut DeathPayload, sv_path: &Path, recent: Option<&(String, String)>) -> bool {
+    let (name, embedded_realm) = split_player_realm(&death.player);
+    death.player = name;
+    death.realm = death.realm.trim().to_string();
+    if death.realm.is_empty() {
+        death.realm = embedded_realm.unwrap_or_default();
+    }
+
+    let from_path = identity_from_sv_path(sv_path);
+    if death.player.is_empty() {
+        if let Some((_, character)) = &from_path {
+            death.player = character.clone();
+        } else if let Some((player, _)) = recent {
+            death.player = player.clone();
+        }
+    }
+    if death.realm.is_empty() {
+        if let Some((realm, _)) = &from_path {
+            death.realm = realm.clone();
+        } else if let Some((_, realm)) = recent {
+            death.realm = realm.clone();
+        }
+    }
+    !death.player.is_empty() && !death.realm.is_empty()
+}
+
+pub(crate) fn quarantine_death(state: &mut State, sv_path: &Path, death: DeathPayload) {
+    let path = sv_path.to_string_lossy().to_string();
+    if state.quarantine.iter().any(|q| q.sv_path == path && q.death.at == death.at) {
+        return;
+    }
+    println!(
+        "[quarantine] Death at {} in {} has no usable player/realm; holding it until identity can be resolved",
+        format_epoch(death.at),
+        sv_path.display()
+    );
+    state.quarantine.push(QuarantinedDeath { sv_path: path, since: Utc::now().timestamp(), death });
+    while state.quarantine.len() > MAX_QUARANTINE {
+        state.quarantine.remove(0);
+    }
+    save_state(state).ok();
+}
+
+pub(crate) fn to_key(player: &str, realm: &str) -> String {
+    format!("{}@{}", player, realm)
+}
+
+/// Key for per-character state: "Account:Player@Realm", so same-named characters
+/// on two accounts don't share a watermark. Without an account folder it is the
+/// plain "Player@Realm".
+pub(crate) fn character_key(account: Option<&str>, player: &str, realm: &str) -> String {
+    match account {
+        Some(account) => format!("{}:{}", account, to_key(player, realm)),
+        None => to_key(player, realm),
+    }
+}
+
+impl DeathPayload {
+    pub(crate) fn key(&self) -> String {
+        character_key(self.account.as_deref(), &self.player, &self.realm)
+    }
+}
+
+/// How far back to look for unsent deaths of one account's characters, keyed
+/// "Player@Realm" like the entries in its SavedVariables files: the watermark, or
+/// for characters with hashed deaths the point their hashes go back to.
+/// What earlier parses of a SavedVariables file already took care of.
+#[derive(Debug, Default)]
+pub(crate) struct SvMarks {
+    /// Last uploaded `at` per character (`account_marks`)
+    pub(crate) uploaded: BTreeMap<String, i64>,
+    /// Where the last completely handled parse of the file ended
+    pub(crate) cursor: Option<SvCursor>,
+}
+
+pub(crate) fn account_marks(state: &State, account: Option<&str>) -> BTreeMap<String, i64> {
+    state
+        .last_uploaded
+        .iter()
+        .map(|(key, at)| (key, state.seen_deaths.get(key).map_or(*at, |seen| seen.since.min(*at))))
+        .filter_map(|(key, at)| {
+            let character = match (account, key.split_once(':')) {
+                (Some(account), Some((owner, character))) if owner == account => character,
+                (None, None) => key,
+                _ => return None,
+            };
+            Some((character.to_string(), at))
+        })
+        .collect()
+}
+
+/// Result of parsing one SavedVariables file: the newest death plus the shape of
+/// the deaths table, used to notice when the addon clears it.
+#[derive(Debug, Default)]
+pub(crate) struct SvSnapshot {
+    pub(crate) latest: Option<DeathPayload>,
+    /// Earlier entries that may not have been uploaded yet, oldest first; only
+    /// filled when asked for
+    pub(crate) history: Vec<DeathPayload>,
+    pub(crate) max_index: i64,
+    pub(crate) count: usize,
+    /// Keys of entries skipped for lack of a usable `at`, string keys quoted
+    pub(crate) undated: Vec<String>,
+    /// (player, realm) of the newest entry that has both, for identity fallback
+    pub(crate) recent_identity: Option<(String, String)>,
+    pub(crate) settings: Option<AddonSettings>,
+    /// `DeathLoggerDB.version`, from addon versions that record it
+    pub(crate) addon_version: Option<String>,
+    /// Current level per character from `DeathLoggerDB.levels`
+    pub(crate) levels: Vec<LevelRecord>,
+    /// Addon that wrote the file, when it isn't DeathLogger
+    pub(crate) addon_name: Option<String>,
+    /// Where this parse ended, when the deaths table is a plain array
+    pub(crate) cursor: Option<SvCursor>,
+    /// Entries looked at, fewer than `count` when the parse resumed from a cursor
+    pub(crate) scanned: usize,
+    /// Why the file itself didn't parse, when this was read from its `.bak`
+    pub(crate) main_error: Option<String>,
+}
+
+impl SvSnapshot {
+    pub(crate) fn addon_info(&self) -> Option<AddonInfo> {
+        let info = AddonInfo {
+            name: self.addon_name.clone(),
+            version: self.addon_version.clone(),
+            settings: self.settings.clone(),
+        };
+        (info != AddonInfo::default()).then_some(info)
+    }
+}
+
+/// Oldest addon version whose SavedVariables this agent reads completely.
+pub(crate) const MIN_ADDON_VERSION: &str = "1.2.0";
+
+/// `addon` in deaths.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct AddonInfo {
+    /// Set for deaths read from another addon's SavedVariables
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) settings: Option<AddonSettings>,
+}
+
+/// True for versions like "1.1.0" that sort below MIN_ADDON_VERSION. Anything
+/// that doesn't look like a dotted version number is given the benefit of the doubt.
+pub(crate) fn addon_outdated(version: &str) -> bool {
+    fn parts(v: &str) -> Option<Vec<u64>> {
+        let mut parts = v.trim().trim_start_matches('v').split('.').map(|p| p.parse().ok()).collect::<Option<Vec<u64>>>()?;
+        while parts.last() == Some(&0) {
+            parts.pop();
+        }
+        Some(parts)
+    }
+    matches!((parts(version), parts(MIN_ADDON_VERSION)), (Some(v), Some(min)) if v < min)
+}
+
+/// The addon's in-game options. Older addon versions keep them at the top level
+/// of `DeathLoggerDB`, newer ones in `DeathLoggerDB.settings`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct AddonSettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) auto_screenshot: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) screenshot_delay: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) max_entries: Option<i64>,
+}
+
+impl AddonSettings {
+    /// Read known keys, ignoring anything else and coercing values saved with the wrong type.
+    pub(crate) fn from_db<T: svdata::Table>(db: &T) -> Option<Self> {
+        let src = db.field("settings").into_table().unwrap_or_else(|| db.clone());
+        let get = |k: &str| src.field(k);
+        let num = |v: SvValue<T>| match v {
+            SvValue::Integer(i) => Some(i as f64),
+            SvValue::Number(n) => Some(n),
+            SvValue::String(s) => s.trim().parse().ok(),
+            _ => None,
+        };
+        let flag = |v: SvValue<T>| match v {
+            SvValue::Boolean(b) => Some(b),
+            SvValue::Integer(i) => Some(i != 0),
+            SvValue::Number(n) => Some(n != 0.0),
+            SvValue::String(s) => match s.trim().to_ascii_lowercase().as_str() {
+                "true" | "on" | "yes" | "1" => Some(true),
+                "false" | "off" | "no" | "0" => Some(false),
+                _ => None,
+            },
+            _ => None,
+        };
+        let settings = Self {
+            auto_screenshot: flag(get("screenshotOn")),
+            screenshot_delay: num(get("screenshotDelay")),
+            max_entries: num(get("maxEntries")).map(|n| n as i64),
+        };
+        (settings != Self::default()).then_some(settings)
+    }
+
+    pub(crate) fn auto_screenshot_off(&self) -> bool {
+        self.auto_screenshot == Some(false)
+    }
+}
+
+/// What we remember about a SavedVariables file's deaths table between parses.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct SvTableInfo {
+    pub(crate) max_index: i64,
+    pub(crate) count: usize,
+}
+
+/// The last entry of a deaths array as of a parse whose deaths were all handled.
+/// The next parse only reads the entries after it, as long as the array still
+/// starts the same way: an entry at `index` with the same `at`, and at least
+/// `count` entries. A cleared or rotated table fails that check and is read
+/// whole, leaving the watermarks and entry hashes to skip what was sent.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct SvCursor {
+    pub(crate) index: i64,
+    pub(crate) at: i64,
+    pub(crate) count: usize,
+    /// `recent_identity` as of that parse, for entries after it that lack one
+    #[serde(default)]
+    pub(crate) identity: Option<(String, String)>,
+}
+
+/// Turns an addon's data table into a snapshot, whichever parser read the file.
+pub(crate) trait SvExtract {
+    fn extract<T: svdata::Table>(&self, db: &T, sv_path: &Path, history_after: Option<&SvMarks>) -> SvSnapshot;
+}
+
+// Read SavedVariables file with the configured parser and hand the first of
+// `globals` that holds a table to `extractor`, for the last entry and, with
+// `history_after` (last uploaded `at` per character), every earlier entry newer
+// than its character's mark. A file one parser refuses is read again with the
+// other: the data parser takes the same table syntax as Lua but nothing else.
+pub(crate) fn parse_sv(
+    sv_path: &Path,
+    fmt: &SvFormat,
+    extractor: &impl SvExtract,
+    history_after: Option<&SvMarks>,
+) -> Result<SvSnapshot> {
+    let (content, replaced) = read_sv_text(sv_path)?;
+    let snapshot = parse_sv_text(&content, sv_path, fmt, extractor, history_after)?;
+    if replaced > 0 {
+        note_invalid_utf8(sv_path, replaced, &snapshot);
+    }
+    Ok(snapshot)
+}
+
+/// The file as text, without a UTF-8 BOM. Byte sequences that aren't valid
+/// UTF-8 (old clients writing names in a legacy code page) become U+FFFD
+/// instead of failing the whole file; returns how many were replaced.
+pub(crate) fn read_sv_text(path: &Path) -> Result<(String, usize)> {
+    let bytes = fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(&bytes);
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Ok((text.to_string(), 0)),
+        Err(_) => {
+            let replaced = bytes.utf8_chunks().filter(|c| !c.invalid().is_empty()).count();
+            Ok((String::from_utf8_lossy(bytes).into_owned(), replaced))
+        }
+    }
+}
+
+/// Say which of the deaths about to be sent carry replaced text.
+pub(crate) fn note_invalid_utf8(sv_path: &Path, replaced: usize, snapshot: &SvSnapshot) {
+    let msg = format!("{}: {replaced} byte sequence(s) that aren't valid UTF-8 were replaced with U+FFFD", sv_path.display());
+    println!("[warn] {msg}");
+    eventlog::report(Level::Warning, EventClass::Parse, &msg);
+    for death in snapshot.history.iter().chain(&snapshot.latest) {
+        if serde_json::to_string(death).is_ok_and(|json| json.contains('\u{FFFD}')) {
+            println!(
+                "[warn] {}: the death of {} at {} has replaced text; it is uploaded anyway",
+                sv_path.display(),
+                to_key(&death.player, &death.realm),
+                format_epoch(death.at)
+            );
+        }
+    }
+}
+
+/// The `.bak` WoW keeps next to a SavedVariables file.
+pub(crate) fn sv_backup_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".bak");
+    path.with_file_name(name)
+}
+
+/// Parse `path`, or its `.bak` when `path` doesn't parse (a crash mid-write) and
+/// the backup does. Deaths read from the backup carry `recovered_from_backup`,
+/// and the snapshot keeps the main file's error so it is tried again later.
+pub(crate) fn parse_sv_or_backup(adapter: &dyn SvAdapter, path: &Path, history_after: Option<&SvMarks>) -> Result<SvSnapshot> {
+    let err = match adapter.parse(path, history_after) {
+        Ok(snapshot) => return Ok(snapshot),
+        Err(e) => e,
+    };
+    let backup = sv_backup_path(path);
+    if !backup.is_file() {
+        return Err(err);
+    }
+    // The cursor is a position in the main file, not in the backup.
+    let marks = history_after.map(|m| SvMarks { uploaded: m.uploaded.clone(), cursor: None });
+    let mut snapshot = match adapter.parse(&backup, marks.as_ref()) {
+        Ok(snapshot) => snapshot,
+        Err(e) => return Err(err.context(format!("{} did not parse either: {e:#}", backup.display()))),
+    };
+    let msg = format!("{} did not parse ({err:#}); read {} instead", path.display(), backup.display());
+    println!("[warn] {msg}");
+    eventlog::report(Level::Warning, EventClass::Parse, &msg);
+    for death in snapshot.history.iter_mut().chain(&mut snapshot.latest) {
+        death.recovered_from_backup = Some(true);
+    }
+    snapshot.main_error = Some(format!("{err:#}"));
+    Ok(snapshot)
+}
+
+pub(crate) fn parse_sv_text(
+    content: &str,
+    sv_path: &Path,
+    fmt: &SvFormat,
+    extractor: &impl SvExtract,
+    history_after: Option<&SvMarks>,
+) -> Result<SvSnapshot> {
+    if fmt.parser == SvParser::Data {
+        // Files with more than table assignments in them are left to Lua.
+        let data_err = match read_sv_data(content, sv_path, fmt, extractor, history_after) {
+            Ok(snapshot) => return Ok(snapshot),
+            Err(e) => e,
+        };
+        return evaluate_sv_scoped(content, sv_path, fmt, extractor, history_after)
+            .map_err(|lua_err| anyhow!("data parser: {data_err:#}; {lua_err:#}"));
+    }
+    let lua_err = match evaluate_sv_scoped(content, sv_path, fmt, extractor, history_after) {
+        Ok(snapshot) => return Ok(snapshot),
+        Err(e) => e,
+    };
+    match read_sv_data(content, sv_path, fmt, extractor, history_after) {
+        Ok(snapshot) => {
+            eprintln!("[warn] {} is not valid Lua ({lua_err:#}); read it as plain data instead", sv_path.display());
+            Ok(snapshot)
+        }
+        Err(e) => Err(anyhow!("{lua_err:#}; data parser: {e:#}")),
+    }
+}
+
+/// `evaluate_sv` in a clean Lua state of its own. Only owned Rust data comes
+/// back out; collect explicitly so the (often multi-megabyte) tables are freed
+/// now rather than whenever the allocator gets to it.
+fn evaluate_sv_scoped(
+    content: &str,
+    sv_path: &Path,
+    fmt: &SvFormat,
+    extractor: &impl SvExtract,
+    history_after: Option<&SvMarks>,
+) -> Result<SvSnapshot> {
+    let lua = Lua::new();
+    let evaluated = evaluate_sv(&lua, content, sv_path, fmt, extractor, history_after);
+    lua.gc_collect().ok();
+    drop(lua);
+    evaluated
+}
+
+/// The file read by the data parser, which takes the same table syntax as Lua but nothing else.
+fn read_sv_data(
+    content: &str,
+    sv_path: &Path,
+    fmt: &SvFormat,
+    extractor: &impl SvExtract,
+    history_after: Option<&SvMarks>,
+) -> Result<SvSnapshot> {
+    let mut assigned = svdata::parse_globals(content)?;
+    let db = fmt.globals.iter().find_map(|name| assigned.remove(name)?.into_table());
+    Ok(db.map(|db| extractor.extract(&db, sv_path, history_after)).unwrap_or_default())
+}
+
+pub(crate) fn evaluate_sv(
+    lua: &Lua,
+    content: &str,
+    sv_path: &Path,
+    fmt: &SvFormat,
+    extractor: &impl SvExtract,
+    history_after: Option<&SvMarks>,
+) -> Result<SvSnapshot> {
+    // The SV file assigns globals like: DeathLoggerDB = { ... }
+    lua.load(content).exec().context("executing SV lua")?;
+
+    // Fetch DeathLoggerDB, or whichever configured global is there
+    for name in &fmt.globals {
+        if let LuaValue::Table(t) = lua.globals().get::<_, LuaValue>(name.as_str())? {
+            return Ok(extractor.extract(&t, sv_path, history_after));
+        }
+    }
+    Ok(SvSnapshot::default())
+}
+
+pub(crate) fn extract_sv<T: svdata::Table>(db_tbl: &T, fmt: &SvFormat, history_after: Option<&SvMarks>) -> SvSnapshot {
+    let clock = fmt.clock;
+    let settings = AddonSettings::from_db(db_tbl);
+    let addon_version = db_tbl.field("version").to_text();
+    let levels = read_levels(db_tbl);
+
+    // deaths is normally an array, but some addon builds key it by GUID. Newer
+    // ones keep one per character instead, as characters["Player-Realm"].deaths,
+    // and may leave player/realm off the entries since the key says.
+    let mut sources: Vec<(T, Option<(String, String)>)> = vec![];
+    if let Some(deaths) = db_tbl.field("deaths").into_table() {
+        sources.push((deaths, None));
+    } else if let Some(chars) = ["characters", "chars"].iter().find_map(|k| db_tbl.field(k).into_table()) {
+        for (k, v) in chars.entries() {
+            let (Some(key), SvValue::Table(c)) = (k.to_text(), v) else { continue };
+            let Some(deaths) = c.field("deaths").into_table() else { continue };
+            let (player, realm) = split_player_realm(&key);
+            sources.push((deaths, Some((player, realm.unwrap_or_default()))));
+        }
+    }
+    if sources.is_empty() {
+        return SvSnapshot { settings, addon_version, levels, ..SvSnapshot::default() };
+    }
+
+    // `at` is normally integer seconds, but floats ("1700000000.0") and strings
+    // (seconds, or RFC 3339) turn up too. None when it isn't a usable time.
+    // Seconds go through `clock`; RFC 3339 carries its own offset.
+    fn entry_at<T: svdata::Table>(t: &T, clock: TimestampMode) -> Option<i64> {
+        let secs = match t.field("at") {
+            SvValue::Integer(i) => i,
+            SvValue::Number(n) if n.is_finite() => n.round() as i64,
+            SvValue::String(s) => {
+                let s = s.trim();
+                let secs = s
+                    .parse::<i64>()
+                    .ok()
+                    .or_else(|| s.parse::<f64>().ok().filter(|n| n.is_finite()).map(|n| n.round() as i64));
+                match secs {
+                    Some(secs) => secs,
+                    None => return DateTime::parse_from_rfc3339(s).ok().map(|d| d.timestamp()).filter(|at| *at > 0),
+                }
+            }
+            _ => return None,
+        };
+        (secs > 0).then(|| clock.to_utc(secs))
+    }
+
+    fn with_owner(player: String, realm: String, owner: Option<&(String, String)>) -> (String, String) {
+        let Some((owner_player, owner_realm)) = owner else { return (player, realm) };
+        let player = if player.trim().is_empty() { owner_player.clone() } else { player };
+        let realm = if realm.trim().is_empty() && split_player_realm(&player).1.is_none() {
+            owner_realm.clone()
+        } else {
+            realm
+        };
+        (player, realm)
+    }
+
+    // Entries are ordered by their integer index. Tables keyed by strings (GUIDs,
+    // "Player-Realm-time" ids) have no such order, and neither do entries spread
+    // over several characters, so then all entries are ordered by `at` instead,
+    // the index breaking ties.
+    // Earlier entries are only kept (and converted below) when the history was
+    // asked for and they may not have been uploaded yet; entries without a full
+    // identity can't be checked here, so they are kept.
+    struct Entry<T> {
+        at: i64,
+        index: Option<i64>,
+        label: String,
+        owner: Option<(String, String)>,
+        table: T,
+    }
+    let mut max_i: i64 = 0;
+    let mut count = 0;
+    let mut scanned = 0;
+    let mut all_indexed = true;
+    let mut dated: Vec<Entry<T>> = vec![];
+    let mut undated: Vec<(Option<i64>, String)> = vec![];
+    let single_table = sources.len() == 1;
+    // An array that only grew since the cursor is read from the cursor on (the
+    // newest entry always, even when nothing was added).
+    let resume = match (history_after.and_then(|m| m.cursor.as_ref()), sources.as_slice()) {
+        (Some(c), [(deaths, None)])
+            if deaths.array_len() >= c.index
+                && deaths.index(c.index).into_table().and_then(|t| entry_at(&t, clock)) == Some(c.at) =>
+        {
+            Some((c, deaths.array_len()))
+        }
+        _ => None,
+    };
+    for (deaths_tbl, owner) in &sources {
+        let pairs = match resume {
+            Some((c, len)) => ((c.index + 1).min(len)..=len).map(|i| (SvValue::Integer(i), deaths_tbl.index(i))).collect(),
+            None => deaths_tbl.entries(),
+        };
+        for (k, v) in pairs {
+            let SvValue::Table(table) = v else { continue };
+            count += 1;
+            scanned += 1;
+            let index = match k {
+                SvValue::Integer(i) => Some(i),
+                _ => None,
+            };
+            all_indexed &= index.is_some();
+            let mut label = match (index, k.to_text()) {
+                (Some(i), _) => i.to_string(),
+                (None, Some(s)) => format!("{s:?}"),
+                (None, None) => "?".to_string(),
+            };
+            if let Some((player, realm)) = owner {
+                label = format!("{player}-{realm}[{label}]");
+            }
+            max_i = max_i.max(index.unwrap_or(0));
+            match entry_at(&table, clock) {
+                Some(at) => dated.push(Entry { at, index, label, owner: owner.clone(), table }),
+                None => undated.push((index.filter(|_| single_table), label)),
+            }
+        }
+    }
+    if let Some((c, len)) = resume {
+        count = c.count + usize::try_from(len - c.index).unwrap_or(0);
+        max_i = len;
+    }
+    if single_table && dated.iter().all(|e| e.index.is_some()) {
+        dated.sort_by_key(|e| e.index);
+    } else {
+        dated.sort_by(|a, b| {
+            (a.at, a.index.is_none(), a.index, &a.label).cmp(&(b.at, b.index.is_none(), b.index, &b.label))
+        });
+    }
+    undated.sort_by(|a, b| (a.0.is_none(), a.0, &a.1).cmp(&(b.0.is_none(), b.0, &b.1)));
+    let undated: Vec<String> = undated.into_iter().map(|(_, label)| label).collect();
+
+    let mut recent_identity: Option<(String, String)> = resume.and_then(|(c, _)| c.identity.clone());
+    let mut earlier: Vec<usize> = vec![];
+    for (n, entry) in dated.iter().enumerate() {
+        let (player, realm) = with_owner(
+            entry.table.field("player").to_text().unwrap_or_default(),
+            entry.table.field("realm").to_text().unwrap_or_default(),
+            entry.owner.as_ref(),
+        );
+        let (player, embedded) = split_player_realm(&player);
+        let realm = if realm.trim().is_empty() { embedded.unwrap_or_default() } else { realm };
+        let is_latest = n + 1 == dated.len();
+        let unsent = history_after.is_some_and(|marks| {
+            player.is_empty()
+                || realm.is_empty()
+                || entry.at > marks.uploaded.get(&to_key(&player, realm.trim())).copied().unwrap_or(0)
+        });
+        if unsent && !is_latest && entry.index.is_none_or(|i| i > 0) {
+            earlier.push(n);
+        }
+        if !player.is_empty() && !realm.is_empty() {
+            recent_identity = Some((player, realm));
+        }
+    }
+
+    // Helper to convert any Lua value to JSON. `path` holds the tables being
+    // converted, outermost first: a table found on it again is a cycle, and a
+    // path as long as `max_depth` is as deep as it goes.
+    fn lua_to_json<T: svdata::Table>(v: SvValue<T>, max_depth: usize, path: &mut Vec<usize>) -> serde_json::Value {
+        match v {
+            SvValue::Nil => serde_json::Value::Null,
+            SvValue::Boolean(b) => json!(b),
+            SvValue::Integer(i) => json!(i),
+            // Lua 5.4 keeps `19019.0` a float; item ids and copper amounts written
+            // that way should still reach the server as integers.
+            SvValue::Number(n) if n.fract() == 0.0 && (i64::MIN as f64..i64::MAX as f64).contains(&n) => json!(n as i64),
+            SvValue::Number(n) => json!(n),
+            SvValue::String(s) => json!(s),
+            SvValue::Table(t) => {
+                if path.len() >= max_depth || path.contains(&t.id()) {
+                    return json!(RECURSION_MARKER);
+                }
+                path.push(t.id());
+                let pairs = t.entries();
+                // Decide array or object by checking for 1..n integer keys
+                let is_array = pairs.iter().all(|(k, _)| matches!(k, SvValue::Integer(_)));
+                if is_array && !pairs.is_empty() {
+                    let mut entries: Vec<(i64, serde_json::Value)> = pairs
+                        .into_iter()
+                        .filter_map(|(k, v)| Some((k.as_int()?, lua_to_json(v, max_depth, path))))
+                        .collect();
+                    entries.sort_by_key(|(i, _)| *i);
+                    path.pop();
+                    serde_json::Value::Array(entries.into_iter().map(|(_, v)| v).collect())
+                } else {
+                    // serde_json::Map is BTreeMap-backed here (no preserve_order),
+                    // so the key order doesn't depend on Lua's table iteration.
+                    let mut map = serde_json::Map::new();
+                    for (k, v) in pairs {
+                        let key = match k {
+                            SvValue::String(s) => s,
+                            SvValue::Integer(i) => i.to_string(),
+                            _ => "key".into(),
+                        };
+                        map.insert(key, lua_to_json(v, max_depth, path));
+                    }
+                    path.pop();
+                    serde_json::Value::Object(map)
+                }
+            }
+            SvValue::Other => serde_json::Value::Null,
+        }
+    }
+
+    // One deaths-table entry as a payload
+    fn death_from_table<T: svdata::Table>(t: &T, fmt: &SvFormat) -> DeathPayload {
+        let at = entry_at(t, fmt.clock).unwrap_or(0);
+        let mut path = vec![t.id()];
+        let mut lua_to_json = |v| lua_to_json(v, fmt.max_depth, &mut path);
+
+        let player = t.field("player").as_str().map(str::to_string).unwrap_or_default();
+        let realm  = t.field("realm").as_str().map(str::to_string).unwrap_or_default();
+        let class  = t.field("class").as_str().map(str::to_string);
+        let level  = t.field("level").as_int();
+
+        let location = LocationPayload::from_json(lua_to_json(t.field("location")));
+        let (killer, killer_issues) = Killer::from_json(lua_to_json(t.field("killer")));
+        let bags     = lua_to_json(t.field("bags"));
+        let equipped = lua_to_json(t.field("equipped"));
+
+        let inst = {
+            let mut m = serde_json::Map::new();
+            for k in ["instanceID","instanceName","instanceDifficulty","mapDifficultyID"].iter() {
+                m.insert((*k).into(), lua_to_json(t.field(k)));
+            }
+            serde_json::Value::Object(m)
+        };
+
+        let money_c  = t.field("moneyCopper").as_int();
+        let money_g  = t.field("moneyGold").as_int();
+        let money_s  = t.field("moneySilver").as_int();
+        let money_co = t.field("moneyCopperOnly").as_int();
+
+        let mut extra = serde_json::Map::new();
+        for (k, v) in t.entries() {
+            let SvValue::String(key) = k else { continue };
+            if !TYPED_ENTRY_KEYS.contains(&key.as_str()) {
+                extra.insert(key, lua_to_json(v));
+            }
+        }
+
+        DeathPayload {
+            schema_version: 0,
+            at,
+            player,
+            realm,
+            account: None,
+            class,
+            level,
+            location,
+            killer,
+            killer_raw: None,
+            killer_issues,
+            entry: None,
+            bags,
+            bags_diff: None,
+            bags_base: None,
+            equipped,
+            instance: inst,
+            addon: None,
+            money_copper: money_c,
+            money_gold: money_g,
+            money_silver: money_s,
+            money_copper_only: money_co,
+            money_total_copper: None,
+            screenshot_media_id: None,
+            note: None,
+            screenshot_redacted: None,
+            screenshot_redacted_regions: None,
+            agent: None,
+            recovered_from_backup: None,
+            extra,
+        }
+    }
+
+    let cursor = dated
+        .last()
+        .filter(|last| single_table && all_indexed && last.index == Some(max_i) && usize::try_from(max_i) == Ok(count))
+        .map(|last| SvCursor { index: max_i, at: last.at, count, identity: recent_identity.clone() });
+
+    let convert = |entry: &Entry<T>| {
+        let mut death = death_from_table(&entry.table, fmt);
+        death.entry = Some(entry.label.clone());
+        (death.player, death.realm) = with_owner(death.player, death.realm, entry.owner.as_ref());
+        death
+    };
+    let Some(latest) = dated.last() else {
+        return SvSnapshot { max_index: max_i, count, undated, settings, addon_version, levels, scanned, ..SvSnapshot::default() };
+    };
+    let latest = convert(latest);
+    let history = earlier.iter().map(|&n| convert(&dated[n])).collect();
+
+    SvSnapshot {
+        latest: Some(latest),
+        history,
+        max_index: max_i,
+        count,
+        undated,
+        recent_identity,
+        settings,
+        addon_version,
+        levels,
+        addon_name: None,
+        cursor,
+        scanned,
+        main_error: None,
+    }
+}
+
+// ---------- SavedVariables adapters ----------
+//
+// An adapter reads one addon's SavedVariables file into an SvSnapshot, so deaths
+// recorded by other hardcore addons go through the same pipeline as our own.
+// `sv_adapters` in the config picks which ones run.
+
+/// Names accepted in `sv_adapters`.
+pub(crate) const SV_ADAPTER_NAMES: [&str; 2] = ["deathlogger", "deathlog"];
+
+pub(crate) trait SvAdapter: std::fmt::Debug + Send + Sync {
+    /// Name in `sv_adapters`
+    fn name(&self) -> &'static str;
+    /// SavedVariables file the addon writes
+    fn file_name(&self) -> &str;
+    /// True for the addon's file in either layout under WTF/Account.
+    fn matches(&self, path: &Path) -> bool {
+        sv_layout_file_name(path).as_deref() == Some(self.file_name())
+    }
+    /// The deaths in `path`; `history_after` as for `parse_sv`.
+    fn parse(&self, path: &Path, history_after: Option<&SvMarks>) -> Result<SvSnapshot>;
+}
+
+/// File name of a SavedVariables file under WTF/Account, in the account's or a
+/// character's SavedVariables folder. None for any other path.
+pub(crate) fn sv_layout_file_name(path: &Path) -> Option<String> {
+    let parts: Vec<String> = path.components().map(|c| c.as_os_str().to_string_lossy().to_string()).collect();
+    let i = parts.iter().rposition(|p| p.eq_ignore_ascii_case("Account"))?;
+    match &parts[i + 1..] {
+        [_, sv_dir, file] | [_, _, _, sv_dir, file] if sv_dir.eq_ignore_ascii_case("SavedVariables") => Some(file.clone()),
+        _ => None,
+    }
+}
+
+/// The adapters `sv_adapters` enables, in its order. Unknown names are left to
+/// config validation.
+pub(crate) fn sv_adapters(cfg: &Config) -> Vec<Arc<dyn SvAdapter>> {
+    let fmt = SvFormat::from_config(cfg);
+    let mut adapters: Vec<Arc<dyn SvAdapter>> = vec![];
+    for name in &cfg.sv_adapters {
+        match name.as_str() {
+            "deathlogger" => adapters.push(Arc::new(DeathLoggerAdapter { file_name: cfg.sv_file_name.clone(), fmt: fmt.clone() })),
+            "deathlog" => adapters.push(Arc::new(DeathlogAdapter {
+                fmt: SvFormat { globals: vec![DEATHLOG_GLOBAL.into()], ..fmt.clone() },
+            })),
+            _ => {}
+        }
+    }
+    adapters
+}
+
+/// This addon's own SavedVariables (`DeathLoggerDB`).
+#[derive(Debug)]
+pub(crate) struct DeathLoggerAdapter {
+    pub(crate) file_name: String,
+    pub(crate) fmt: SvFormat,
+}
+
+impl SvAdapter for DeathLoggerAdapter {
+    fn name(&self) -> &'static str {
+        "deathlogger"
+    }
+    fn file_name(&self) -> &str {
+        &self.file_name
+    }
+    fn parse(&self, path: &Path, history_after: Option<&SvMarks>) -> Result<SvSnapshot> {
+        parse_sv(path, &self.fmt, self, history_after)
+    }
+}
+
+impl SvExtract for DeathLoggerAdapter {
+    fn extract<T: svdata::Table>(&self, db: &T, _sv_path: &Path, history_after: Option<&SvMarks>) -> SvSnapshot {
+        extract_sv(db, &self.fmt, history_after)
+    }
+}
+
+pub(crate) const DEATHLOG_FILE_NAME: &str = "Deathlog.lua";
+pub(crate) const DEATHLOG_GLOBAL: &str = "deathlog_data";
+
+/// Class ids as the game numbers them, for Deathlog's `class_id`.
+pub(crate) const CLASS_TOKENS: [&str; 13] = [
+    "WARRIOR", "PALADIN", "HUNTER", "ROGUE", "PRIEST", "DEATHKNIGHT", "SHAMAN", "MAGE", "WARLOCK", "MONK", "DRUID",
+    "DEMONHUNTER", "EVOKER",
+];
+
+/// The Deathlog addon: `deathlog_data[realm][checksum] = { name, level, class_id,
+/// source_id, map_id, map_pos = "x,y", instance_id, date, ... }`. It records every
+/// hardcore death the client hears about, so only deaths of characters on the
+/// file's own account (or its character, for a per-character file) are read.
+#[derive(Debug)]
+pub(crate) struct DeathlogAdapter {
+    pub(crate) fmt: SvFormat,
+}
+
+impl SvAdapter for DeathlogAdapter {
+    fn name(&self) -> &'static str {
+        "deathlog"
+    }
+    fn file_name(&self) -> &str {
+        DEATHLOG_FILE_NAME
+    }
+    fn parse(&self, path: &Path, history_after: Option<&SvMarks>) -> Result<SvSnapshot> {
+        parse_sv(path, &self.fmt, self, history_after)
+    }
+}
+
+impl SvExtract for DeathlogAdapter {
+    fn extract<T: svdata::Table>(&self, db: &T, sv_path: &Path, history_after: Option<&SvMarks>) -> SvSnapshot {
+        let fold = |s: &str| s.replace(' ', "").to_lowercase();
+        let own: HashSet<String> = sv_path_characters(sv_path).iter().map(|(p, r)| fold(&to_key(p, r))).collect();
+        let mut deaths = vec![];
+        for (realm, entries) in db.entries() {
+            let (Some(realm), SvValue::Table(entries)) = (realm.to_text(), entries) else { continue };
+            for (key, e) in entries.entries() {
+                let SvValue::Table(e) = e else { continue };
+                let Some(name) = e.field("name").to_text() else { continue };
+                let (name, _) = split_player_realm(&name);
+                if !own.contains(&fold(&to_key(&name, &realm))) {
+                    continue;
+                }
+                let Some(at) = e.field("date").as_int().filter(|at| *at > 0) else { continue };
+                let mut death = deathlog_death(&e, name, realm.clone(), self.fmt.clock.to_utc(at));
+                death.entry = key.to_text().map(|k| format!("{realm}[{k:?}]"));
+                deaths.push(death);
+            }
+        }
+        deaths.sort_by_key(|d| d.at);
+        let count = deaths.len();
+        let latest = deaths.pop();
+        let history = deaths
+            .into_iter()
+            .filter(|d| history_after.is_some_and(|marks| d.at > marks.uploaded.get(&to_key(&d.player, &d.realm)).copied().unwrap_or(0)))
+            .collect();
+        SvSnapshot { latest, history, count, addon_name: Some("Deathlog".into()), ..SvSnapshot::default() }
+    }
+}
+
+pub(crate) fn deathlog_death<T: svdata::Table>(e: &T, player: String, realm: String, at: i64) -> DeathPayload {
+    let class = e.field("class_id").as_int().and_then(|id| CLASS_TOKENS.get(usize::try_from(id).ok()?.checked_sub(1)?));
+    // Creatures are sent as their npc id; the negative ids are Deathlog's own
+    // codes for environmental deaths.
+    let source_id = e.field("source_id").as_int();
+    let killer = KillerInfo {
+        npc_id: source_id.filter(|id| *id > 0),
+        extra: source_id.map(|id| serde_json::Map::from_iter([("source_id".to_string(), json!(id))])).unwrap_or_default(),
+        ..KillerInfo::default()
+    };
+    let coords: Option<Vec<f64>> = e
+        .field("map_pos")
+        .to_text()
+        .map(|pos| pos.split(',').filter_map(|n| n.trim().parse().ok()).collect());
+    let mut location = json!({ "mapID": e.field("map_id").as_int() });
+    if let Some([x, y]) = coords.as_deref() {
+        location["coords"] = json!([x, y]);
+    }
+    DeathPayload {
+        at,
+        player,
+        realm,
+        class: class.map(|c| c.to_string()),
+        level: e.field("level").as_int(),
+        location: LocationPayload::from_json(location),
+        killer: Killer::Info(Box::new(killer)),
+        instance: json!({ "instanceID": e.field("instance_id").as_int() }),
+        ..DeathPayload::default()
+    }
+}
+
+// ---------- Library entry points ----------
+
+/// Every death in the text of a DeathLogger SavedVariables file, oldest first,
+/// as the agent would send it with the default configuration.
+pub fn parse_deaths(content: &str) -> Result<Vec<DeathPayload>> {
+    let adapter = DeathLoggerAdapter { file_name: SV_FILE_NAME.into(), fmt: SvFormat::from_config(&Config::default()) };
+    let path = Path::new(SV_FILE_NAME);
+    let snapshot = parse_sv_text(content, path, &adapter.fmt, &adapter, Some(&SvMarks::default()))?;
+    finished_deaths(snapshot, path)
+}
+
+/// `parse_deaths` for a file on disk. A BOM or invalid UTF-8 is tolerated, a
+/// file that doesn't parse is read from its `.bak`, and entries without a
+/// player or realm take them from the file's place under `WTF/Account`.
+pub fn parse_deaths_file(path: impl AsRef<Path>) -> Result<Vec<DeathPayload>> {
+    let path = path.as_ref();
+    let adapter = DeathLoggerAdapter { file_name: SV_FILE_NAME.into(), fmt: SvFormat::from_config(&Config::default()) };
+    let snapshot = parse_sv_or_backup(&adapter, path, Some(&SvMarks::default()))?;
+    finished_deaths(snapshot, path)
+}
+
+fn finished_deaths(snapshot: SvSnapshot, sv_path: &Path) -> Result<Vec<DeathPayload>> {
+    let cfg = Config::default();
+    let account = account_from_sv_path(sv_path);
+    let addon = snapshot.addon_info();
+    snapshot
+        .history
+        .iter()
+        .chain(&snapshot.latest)
+        .cloned()
+        .map(|mut death| {
+            death.addon = addon.clone();
+            death.account = account.clone();
+            resolve_identity(&mut death, sv_path, snapshot.recent_identity.as_ref());
+            prepare_payload(&cfg, &mut death)?;
+            Ok(death)
+        })
+        .collect()
+}
@@ -0,0 +1,521 @@
+//! Values read out of a SavedVariables file.
+//!
+//! The embedded Lua interpreter normally evaluates the file. When it refuses
+//! (a byte order mark, a stray token from a hand edit), `parse_globals` reads the
+//! table constructors directly as data instead. Both hand their tables out
+//! through the `Table` trait, so the extraction code doesn't care which one ran.
+
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Deepest table nesting the data parser follows, about where Lua's own
+/// parser gives up too.
+const MAX_DEPTH: usize = 200;
+
+/// A Lua value whose tables stay behind `T`, so nested tables are only walked
+/// when something asks for them.
+#[derive(Debug, Clone)]
+pub enum Value<T> {
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Number(f64),
+    /// Strings that aren't valid UTF-8 come out empty.
+    String(String),
+    Table(T),
+    /// Functions, userdata and the like; data files never hold them.
+    Other,
+}
+
+impl<T> Value<T> {
+    /// Whole number, truncating finite floats.
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Integer(i) => Some(*i),
+            Value::Number(n) if n.is_finite() => Some(*n as i64),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Text the way Lua coerces it: strings as they are, numbers formatted.
+    pub fn to_text(&self) -> Option<String> {
+        match self {
+            Value::String(s) => Some(s.clone()),
+            Value::Integer(i) => Some(i.to_string()),
+            Value::Number(n) => Some(n.to_string()),
+            _ => None,
+        }
+    }
+
+    pub fn into_table(self) -> Option<T> {
+        match self {
+            Value::Table(t) => Some(t),
+            _ => None,
+        }
+    }
+}
+
+/// Read access to a Lua table, wherever it came from.
+pub trait Table: Clone {
+    /// Value under a string key; Nil when there is none.
+    fn field(&self, key: &str) -> Value<Self>;
+    /// Every key/value pair, in no particular order.
+    fn entries(&self) -> Vec<(Value<Self>, Value<Self>)>;
+    /// Value under an integer key; Nil when there is none.
+    fn index(&self, i: i64) -> Value<Self>;
+    /// Length of the array part, as Lua's `#` gives it.
+    fn array_len(&self) -> i64;
+    /// Identity of the table, the same for every reference to it.
+    fn id(&self) -> usize;
+}
+
+// ---------- Interpreter tables ----------
+
+impl<'lua> Table for mlua::Table<'lua> {
+    fn field(&self, key: &str) -> Value<Self> {
+        self.get::<_, mlua::Value>(key).map(from_lua).unwrap_or(Value::Nil)
+    }
+
+    fn entries(&self) -> Vec<(Value<Self>, Value<Self>)> {
+        self.clone()
+            .pairs::<mlua::Value, mlua::Value>()
+            .flatten()
+            .map(|(k, v)| (from_lua(k), from_lua(v)))
+            .collect()
+    }
+
+    fn index(&self, i: i64) -> Value<Self> {
+        self.raw_get::<_, mlua::Value>(i).map(from_lua).unwrap_or(Value::Nil)
+    }
+
+    fn array_len(&self) -> i64 {
+        self.raw_len() as i64
+    }
+
+    fn id(&self) -> usize {
+        self.to_pointer() as usize
+    }
+}
+
+fn from_lua(v: mlua::Value) -> Value<mlua::Table> {
+    match v {
+        mlua::Value::Nil => Value::Nil,
+        mlua::Value::Boolean(b) => Value::Boolean(b),
+        mlua::Value::Integer(i) => Value::Integer(i),
+        mlua::Value::Number(n) => Value::Number(n),
+        mlua::Value::String(s) => Value::String(s.to_str().unwrap_or_default().to_string()),
+        mlua::Value::Table(t) => Value::Table(t),
+        _ => Value::Other,
+    }
+}
+
+// ---------- Data parser ----------
+
+/// A table read by `parse_globals`. Clones share the entries.
+#[derive(Debug, Clone, Default)]
+pub struct DataTable(Rc<Vec<(Value<DataTable>, Value<DataTable>)>>);
+
+impl Table for DataTable {
+    fn field(&self, key: &str) -> Value<Self> {
+        self.0
+            .iter()
+            .find(|(k, _)| matches!(k, Value::String(s) if s == key))
+            .map(|(_, v)| v.clone())
+            .unwrap_or(Value::Nil)
+    }
+
+    fn entries(&self) -> Vec<(Value<Self>, Value<Self>)> {
+        self.0.as_ref().clone()
+    }
+
+    fn index(&self, i: i64) -> Value<Self> {
+        self.0
+            .iter()
+            .find(|(k, _)| matches!(k, Value::Integer(n) if *n == i))
+            .map(|(_, v)| v.clone())
+            .unwrap_or(Value::Nil)
+    }
+
+    fn array_len(&self) -> i64 {
+        let keys: std::collections::HashSet<i64> = self
+            .0
+            .iter()
+            .filter(|(_, v)| !matches!(v, Value::Nil))
+            .filter_map(|(k, _)| match k {
+                Value::Integer(i) => Some(*i),
+                _ => None,
+            })
+            .collect();
+        (0..).find(|n| !keys.contains(&(n + 1))).unwrap_or(0)
+    }
+
+    fn id(&self) -> usize {
+        Rc::as_ptr(&self.0) as usize
+    }
+}
+
+/// Globals a file of `Name = <value>` statements assigns, read without running
+/// anything. Only literals and table constructors are accepted; the last
+/// assignment to a name wins, as it would in Lua.
+pub fn parse_globals(content: &str) -> Result<HashMap<String, Value<DataTable>>> {
+    let mut p = Parser { src: content.as_bytes(), pos: 0 };
+    p.eat(b"\xEF\xBB\xBF");
+    let mut globals = HashMap::new();
+    loop {
+        p.skip_space()?;
+        if p.pos == p.src.len() {
+            return Ok(globals);
+        }
+        let Some(global) = p.name() else { return Err(p.error("expected a global name")) };
+        p.skip_space()?;
+        if !p.eat(b"=") {
+            return Err(p.error("expected '='"));
+        }
+        let value = p.value(0)?;
+        globals.insert(global.to_string(), value);
+        p.skip_space()?;
+        p.eat(b";");
+    }
+}
+
+/// Identity of a table key, for letting a repeated key replace the earlier one.
+#[derive(PartialEq, Eq, Hash)]
+enum KeyId {
+    Boolean(bool),
+    Integer(i64),
+    Number(u64),
+    String(String),
+    Table(usize),
+}
+
+impl KeyId {
+    fn of(key: &Value<DataTable>) -> Option<Self> {
+        Some(match key {
+            Value::Boolean(b) => KeyId::Boolean(*b),
+            Value::Integer(i) => KeyId::Integer(*i),
+            Value::Number(n) => KeyId::Number(n.to_bits()),
+            Value::String(s) => KeyId::String(s.clone()),
+            Value::Table(t) => KeyId::Table(Rc::as_ptr(&t.0) as usize),
+            Value::Nil | Value::Other => return None,
+        })
+    }
+}
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, msg: &str) -> anyhow::Error {
+        let line = self.src[..self.pos].iter().filter(|&&b| b == b'\n').count() + 1;
+        anyhow!("line {line}: {msg}")
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, ahead: usize) -> Option<u8> {
+        self.src.get(self.pos + ahead).copied()
+    }
+
+    fn eat(&mut self, s: &[u8]) -> bool {
+        let found = self.src[self.pos..].starts_with(s);
+        if found {
+            self.pos += s.len();
+        }
+        found
+    }
+
+    fn skip_space(&mut self) -> Result<()> {
+        loop {
+            while self.peek().is_some_and(|b| b.is_ascii_whitespace()) {
+                self.pos += 1;
+            }
+            if !self.eat(b"--") {
+                return Ok(());
+            }
+            if self.long_bracket_level().is_some() {
+                self.long_string()?;
+            } else {
+                while self.peek().is_some_and(|b| b != b'\n') {
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    fn name(&mut self) -> Option<&'a str> {
+        let start = self.pos;
+        if !self.peek().is_some_and(|b| b.is_ascii_alphabetic() || b == b'_') {
+            return None;
+        }
+        while self.peek().is_some_and(|b| b.is_ascii_alphanumeric() || b == b'_') {
+            self.pos += 1;
+        }
+        let src: &'a [u8] = self.src;
+        std::str::from_utf8(&src[start..self.pos]).ok()
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value<DataTable>> {
+        self.skip_space()?;
+        match self.peek() {
+            Some(b'{') => {
+                if depth >= MAX_DEPTH {
+                    return Err(self.error("tables nested too deeply"));
+                }
+                self.pos += 1;
+                self.table(depth + 1)
+            }
+            Some(b'"' | b'\'') => self.quoted_string(),
+            Some(b'[') if self.long_bracket_level().is_some() => self.long_string().map(Value::String),
+            Some(b'-') => {
+                self.pos += 1;
+                match self.value(depth)? {
+                    Value::Integer(i) => Ok(Value::Integer(i.wrapping_neg())),
+                    Value::Number(n) => Ok(Value::Number(-n)),
+                    _ => Err(self.error("'-' applied to something other than a number")),
+                }
+            }
+            Some(b'0'..=b'9') => self.number(),
+            Some(b'.') if self.peek_at(1).is_some_and(|b| b.is_ascii_digit()) => self.number(),
+            Some(_) => match self.name() {
+                Some("true") => Ok(Value::Boolean(true)),
+                Some("false") => Ok(Value::Boolean(false)),
+                Some("nil") => Ok(Value::Nil),
+                Some(other) => Err(self.error(&format!("'{other}' is not data"))),
+                None => Err(self.error("unexpected character")),
+            },
+            None => Err(self.error("unexpected end of file")),
+        }
+    }
+
+    /// Body of a table constructor, after the opening brace. Positional items
+    /// are stored after the keyed ones, so they win a clash like they do in Lua.
+    fn table(&mut self, depth: usize) -> Result<Value<DataTable>> {
+        let mut keyed: Vec<(Value<DataTable>, Value<DataTable>)> = vec![];
+        let mut positional: Vec<Value<DataTable>> = vec![];
+        loop {
+            self.skip_space()?;
+            if self.eat(b"}") {
+                break;
+            }
+            if self.peek() == Some(b'[') && self.long_bracket_level().is_none() {
+                self.pos += 1;
+                let key = self.value(depth)?;
+                self.skip_space()?;
+                if !self.eat(b"]") {
+                    return Err(self.error("expected ']'"));
+                }
+                self.skip_space()?;
+                if !self.eat(b"=") {
+                    return Err(self.error("expected '='"));
+                }
+                keyed.push((key, self.value(depth)?));
+            } else {
+                let start = self.pos;
+                let key = self.name();
+                self.skip_space()?;
+                match key {
+                    Some(key) if self.peek() == Some(b'=') && self.peek_at(1) != Some(b'=') => {
+                        self.pos += 1;
+                        keyed.push((Value::String(key.to_string()), self.value(depth)?));
+                    }
+                    _ => {
+                        self.pos = start;
+                        positional.push(self.value(depth)?);
+                    }
+                }
+            }
+            self.skip_space()?;
+            if !self.eat(b",") && !self.eat(b";") {
+                if self.eat(b"}") {
+                    break;
+                }
+                return Err(self.error("expected ',' or '}'"));
+            }
+        }
+
+        let positional = positional.into_iter().zip(1..).map(|(v, i)| (Value::Integer(i), v));
+        let mut slots: Vec<Option<(Value<DataTable>, Value<DataTable>)>> = vec![];
+        let mut index: HashMap<KeyId, usize> = HashMap::new();
+        for (key, value) in keyed.into_iter().chain(positional) {
+            let key = match key {
+                Value::Number(n) if n.is_nan() => return Err(self.error("table index is NaN")),
+                Value::Number(n) if n.fract() == 0.0 && n.abs() < 9.2e18 => Value::Integer(n as i64),
+                key => key,
+            };
+            let Some(id) = KeyId::of(&key) else { return Err(self.error("table index is nil")) };
+            let entry = (!matches!(value, Value::Nil)).then_some((key, value));
+            match index.get(&id) {
+                Some(&slot) => slots[slot] = entry,
+                None if entry.is_some() => {
+                    index.insert(id, slots.len());
+                    slots.push(entry);
+                }
+                None => {}
+            }
+        }
+        Ok(Value::Table(DataTable(Rc::new(slots.into_iter().flatten().collect()))))
+    }
+
+    fn number(&mut self) -> Result<Value<DataTable>> {
+        let start = self.pos;
+        if self.eat(b"0x") || self.eat(b"0X") {
+            let digits = self.pos;
+            while self.peek().is_some_and(|b| b.is_ascii_hexdigit()) {
+                self.pos += 1;
+            }
+            if self.pos == digits || self.peek().is_some_and(|b| b.is_ascii_alphanumeric() || b == b'.') {
+                return Err(self.error("malformed number"));
+            }
+            // Hex integers wrap around like they do in Lua.
+            let n = self.src[digits..self.pos].iter().fold(0u64, |n, &b| {
+                n.wrapping_mul(16).wrapping_add((b as char).to_digit(16).unwrap_or(0) as u64)
+            });
+            return Ok(Value::Integer(n as i64));
+        }
+        let mut float = false;
+        while let Some(b) = self.peek() {
+            match b {
+                b'0'..=b'9' => {}
+                b'.' => float = true,
+                b'e' | b'E' => {
+                    float = true;
+                    if matches!(self.peek_at(1), Some(b'+' | b'-')) {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+            self.pos += 1;
+        }
+        if self.peek().is_some_and(|b| b.is_ascii_alphanumeric() || b == b'_') {
+            return Err(self.error("malformed number"));
+        }
+        let text = std::str::from_utf8(&self.src[start..self.pos]).unwrap_or_default();
+        if !float {
+            if let Ok(i) = text.parse::<i64>() {
+                return Ok(Value::Integer(i));
+            }
+        }
+        text.parse::<f64>().map(Value::Number).map_err(|_| self.error("malformed number"))
+    }
+
+    fn quoted_string(&mut self) -> Result<Value<DataTable>> {
+        let quote = self.src[self.pos];
+        self.pos += 1;
+        let mut out: Vec<u8> = vec![];
+        loop {
+            let Some(b) = self.peek() else { return Err(self.error("unfinished string")) };
+            self.pos += 1;
+            match b {
+                _ if b == quote => break,
+                b'\n' | b'\r' => return Err(self.error("unfinished string")),
+                b'\\' => self.escape(&mut out)?,
+                _ => out.push(b),
+            }
+        }
+        Ok(Value::String(String::from_utf8(out).unwrap_or_default()))
+    }
+
+    /// One escape sequence, after its backslash.
+    fn escape(&mut self, out: &mut Vec<u8>) -> Result<()> {
+        let Some(b) = self.peek() else { return Err(self.error("unfinished string")) };
+        self.pos += 1;
+        match b {
+            b'n' => out.push(b'\n'),
+            b't' => out.push(b'\t'),
+            b'r' => out.push(b'\r'),
+            b'a' => out.push(0x07),
+            b'b' => out.push(0x08),
+            b'f' => out.push(0x0c),
+            b'v' => out.push(0x0b),
+            b'\\' | b'"' | b'\'' => out.push(b),
+            b'\n' | b'\r' => {
+                out.push(b'\n');
+                let pair = if b == b'\n' { b'\r' } else { b'\n' };
+                if self.peek() == Some(pair) {
+                    self.pos += 1;
+                }
+            }
+            b'x' => {
+                let hex = self.src.get(self.pos..self.pos + 2).and_then(|h| std::str::from_utf8(h).ok());
+                let Some(byte) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) else {
+                    return Err(self.error("invalid \\x escape"));
+                };
+                self.pos += 2;
+                out.push(byte);
+            }
+            b'z' => {
+                while self.peek().is_some_and(|b| b.is_ascii_whitespace()) {
+                    self.pos += 1;
+                }
+            }
+            b'u' => {
+                let close = self.src[self.pos..].iter().position(|&b| b == b'}');
+                let code = match (self.peek(), close) {
+                    (Some(b'{'), Some(close)) => std::str::from_utf8(&self.src[self.pos + 1..self.pos + close])
+                        .ok()
+                        .and_then(|h| u32::from_str_radix(h, 16).ok())
+                        .and_then(char::from_u32),
+                    _ => None,
+                };
+                let Some(c) = code else { return Err(self.error("invalid \\u escape")) };
+                self.pos += close.unwrap_or(0) + 1;
+                out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+            }
+            b'0'..=b'9' => {
+                let mut n = (b - b'0') as u32;
+                for _ in 0..2 {
+                    match self.peek() {
+                        Some(d @ b'0'..=b'9') => {
+                            n = n * 10 + (d - b'0') as u32;
+                            self.pos += 1;
+                        }
+                        _ => break,
+                    }
+                }
+                let Ok(byte) = u8::try_from(n) else { return Err(self.error("decimal escape too large")) };
+                out.push(byte);
+            }
+            _ => return Err(self.error("invalid escape sequence")),
+        }
+        Ok(())
+    }
+
+    /// Level of a long bracket (`[[`, `[==[`) starting here, if one does.
+    fn long_bracket_level(&self) -> Option<usize> {
+        if self.peek() != Some(b'[') {
+            return None;
+        }
+        let level = self.src[self.pos + 1..].iter().take_while(|&&b| b == b'=').count();
+        (self.peek_at(level + 1) == Some(b'[')).then_some(level)
+    }
+
+    /// Long string or comment body, from its opening bracket. A newline right
+    /// after the opening bracket isn't part of the text.
+    fn long_string(&mut self) -> Result<String> {
+        let level = self.long_bracket_level().unwrap_or(0);
+        self.pos += level + 2;
+        if !self.eat(b"\r\n") && !self.eat(b"\n\r") && !self.eat(b"\n") {
+            self.eat(b"\r");
+        }
+        let close = format!("]{}]", "=".repeat(level));
+        let Some(len) = self.src[self.pos..].windows(close.len()).position(|w| w == close.as_bytes()) else {
+            return Err(self.error("unfinished long string"));
+        };
+        let text = String::from_utf8_lossy(&self.src[self.pos..self.pos + len]).replace("\r\n", "\n").replace('\r', "\n");
+        self.pos += len + close.len();
+        Ok(text)
+    }
+}
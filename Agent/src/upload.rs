@@ -0,0 +1,1866 @@
+//! Sending deaths: the upload request and its variants (resumable, presigned,
+//! Discord, media, grouped and bulk), and the retry queue behind them.
+
+use crate::*;
+
+// ---------- Server API description ----------
+//
+// upload() builds its request from these tables, and `apidoc` prints them, so the
+// generated server documentation can't drift from what the agent actually sends.
+
+pub(crate) const PART_DEATH: &str = "death";
+pub(crate) const PART_SCREENSHOT: &str = "screenshot";
+pub(crate) const PART_ENVELOPE: &str = "envelope";
+pub(crate) const PART_DEATH_ENCRYPTED: &str = "death_encrypted";
+pub(crate) const PART_SCREENSHOT_ENCRYPTED: &str = "screenshot_encrypted";
+pub(crate) const PART_DEATHS: &str = "deaths";
+pub(crate) const PART_SCREENSHOT_CHALLENGE: &str = "screenshot_challenge";
+pub(crate) const PART_SCREENSHOT_META: &str = "screenshot_meta";
+pub(crate) const PART_SCREENSHOT_INDEXED: &str = "screenshot_";
+pub(crate) const PART_SCREENSHOT_RESUMABLE: &str = "screenshot_resumable";
+pub(crate) const PART_THUMBNAIL: &str = "thumbnail";
+pub(crate) const PART_LATE_SCREENSHOT: &str = "late_screenshot";
+
+pub(crate) struct ApiPart {
+    pub(crate) name: &'static str,
+    pub(crate) content_type: &'static str,
+    pub(crate) when: &'static str,
+    pub(crate) description: &'static str,
+}
+
+pub(crate) const UPLOAD_PARTS: &[ApiPart] = &[
+    ApiPart {
+        name: PART_DEATH,
+        content_type: "application/json",
+        when: "encryption off",
+        description: "The death record (see the Death schema). With compress_uploads it is gzipped and the part carries Content-Encoding: gzip; a 400/415 answer makes the agent resend it uncompressed.",
+    },
+    ApiPart {
+        name: PART_SCREENSHOT,
+        content_type: "image/jpeg, image/png",
+        when: "a screenshot was paired and is not encrypted",
+        description: "The original screenshot file.",
+    },
+    ApiPart {
+        name: "screenshot_<n>",
+        content_type: "image/jpeg, image/png",
+        when: "attach_all_screenshots_in_window = true and more screenshots fell in the pairing window",
+        description: "Further screenshots, n = 2, 3, ... in order of distance from the death. Encrypted ones are sent as `screenshot_encrypted_<n>`. Not sent with media_url, resumable uploads or JSON uploads, which carry only the nearest.",
+    },
+    ApiPart {
+        name: PART_THUMBNAIL,
+        content_type: "image/jpeg",
+        when: "screenshot_thumbnail = true and a screenshot is sent unencrypted in this form",
+        description: "The nearest screenshot (as sent, after blurring) shrunk to thumbnail_max_dimension pixels on its longer side. Not covered by the signature.",
+    },
+    ApiPart {
+        name: PART_LATE_SCREENSHOT,
+        content_type: "application/json",
+        when: "late_screenshots = true and a screenshot was saved after its death went out without one (replaces `death`)",
+        description: "{\"idempotency_key\", \"id\", \"at\"}: the Idempotency-Key the death was uploaded under and the id the server returned for it (null if none). Comes with `screenshot` only; the request has an Idempotency-Key of its own and the signature covers this part. Any non-2xx answer is logged and not retried.",
+    },
+    ApiPart {
+        name: PART_ENVELOPE,
+        content_type: "application/json",
+        when: "encryption on",
+        description: "Cleartext routing data: schema_version, character_hash (SHA-256 of \"Player@Realm\"), payload_sha256 (SHA-256 of the canonical plaintext death, for dedupe), at, screenshot_encrypted.",
+    },
+    ApiPart {
+        name: PART_DEATH_ENCRYPTED,
+        content_type: "application/octet-stream",
+        when: "encryption on",
+        description: "The Death JSON as an X25519 sealed box to encrypt_to_public_key.",
+    },
+    ApiPart {
+        name: PART_SCREENSHOT_ENCRYPTED,
+        content_type: "application/octet-stream",
+        when: "encryption on, encrypt_screenshot = true and a screenshot was paired",
+        description: "The screenshot file as a sealed box.",
+    },
+    ApiPart {
+        name: PART_SCREENSHOT_CHALLENGE,
+        content_type: "text/plain",
+        when: "screenshot_challenge_url is set, a screenshot was paired and a nonce was obtained",
+        description: "The nonce from the challenge endpoint (also sent as the X-DeathLogger-Challenge header).",
+    },
+    ApiPart {
+        name: PART_SCREENSHOT_META,
+        content_type: "application/json",
+        when: "with screenshot_challenge, when a screenshot is attached",
+        description: "Freshness hints: created, modified (epoch secs), sha256 of the file, death_to_upload_secs. Heuristic, not proof.",
+    },
+    ApiPart {
+        name: PART_SCREENSHOT_RESUMABLE,
+        content_type: "application/json",
+        when: "resumable_uploads = true and a screenshot was paired (replaces `screenshot`)",
+        description: "The screenshot to expect: file_name, content_type, size, sha256. A 2xx answer must return {\"upload_url\": \"...\"} (see PUT <upload_url>).",
+    },
+    ApiPart {
+        name: PART_DEATHS,
+        content_type: "application/json",
+        when: "batch_uploads = true and several deaths are ready (replaces `death`)",
+        description: "JSON array of Death records. A 2xx response may return {\"failed\": [indices]} to have those retried.",
+    },
+    ApiPart {
+        name: "screenshot_<i>",
+        content_type: "image/jpeg, image/png",
+        when: "batch uploads, for each entry i of `deaths` with a paired screenshot",
+        description: "The screenshot for deaths[i].",
+    },
+    ApiPart {
+        name: "screenshot_<i>_<n>",
+        content_type: "image/jpeg, image/png",
+        when: "batch uploads with attach_all_screenshots_in_window = true",
+        description: "Further screenshots for deaths[i], n = 2, 3, ... in order of distance from the death.",
+    },
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum UploadOutcome {
+    /// Stored; the watermark advances and the death is not sent again
+    Accepted,
+    /// Queued and retried with backoff
+    RetryLater,
+    /// Recorded as failed and never retried
+    Rejected,
+}
+
+impl UploadOutcome {
+    pub(crate) fn describe(self) -> &'static str {
+        match self {
+            UploadOutcome::Accepted => "accepted: the death is marked uploaded and not sent again",
+            UploadOutcome::RetryLater => "transient: the death is retried after 5s, 30s, 2m, 5m, then every 15m",
+            UploadOutcome::Rejected => "rejected: the death is recorded as failed and not retried",
+        }
+    }
+}
+
+/// A non-success answer from the upload endpoint.
+#[derive(Debug, thiserror::Error)]
+#[error("Upload failed: {status} - {}", schema_mismatch(*.status, .body).unwrap_or_else(|| .body.clone()))]
+pub(crate) struct UploadError {
+    pub(crate) status: StatusCode,
+    pub(crate) body: String,
+    /// Seconds the server asked us to wait (Retry-After on 429/503), when usable
+    pub(crate) retry_after: Option<i64>,
+}
+
+impl UploadError {
+    pub(crate) fn outcome(&self) -> UploadOutcome {
+        classify_status(self.status.as_u16())
+    }
+}
+
+// ---------- Payload schema ----------
+//
+// Deaths carry `schema_version` and uploads an X-DeathLogger-Schema header
+// (version 2 on). A server that can't take that version answers 406, ideally
+// with `{"supported_versions": [..]}`; the error then says what to change
+// instead of showing the raw body.
+
+pub(crate) const SCHEMA_HEADER: &str = "X-DeathLogger-Schema";
+
+/// Add the schema header, except for version 1 which predates it.
+pub(crate) fn with_schema_header(cfg: &Config, req: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    if cfg.payload_schema_version < 2 {
+        return req;
+    }
+    req.header(SCHEMA_HEADER, cfg.payload_schema_version.to_string())
+}
+
+/// What to do about a 406 that lists the schema versions the server takes.
+/// None for other answers, or a 406 without a usable list.
+pub(crate) fn schema_mismatch(status: StatusCode, body: &str) -> Option<String> {
+    if status != StatusCode::NOT_ACCEPTABLE {
+        return None;
+    }
+    let value: serde_json::Value = serde_json::from_str(body).ok()?;
+    let supported: Vec<u32> = value
+        .get("supported_versions")?
+        .as_array()?
+        .iter()
+        .filter_map(|v| v.as_u64().and_then(|n| u32::try_from(n).ok()))
+        .collect();
+    let newest = *supported.iter().max()?;
+    if let Some(ours) = supported.iter().copied().filter(|v| (1..=SCHEMA_VERSION).contains(v)).max() {
+        return Some(format!(
+            "server takes payload schema {} and the agent can produce {ours}; set payload_schema_version = {ours} in config.toml",
+            join_versions(&supported)
+        ));
+    }
+    Some(if newest > SCHEMA_VERSION {
+        format!("server requires payload schema {newest}, agent produces {SCHEMA_VERSION}; please update the agent")
+    } else {
+        format!("server requires payload schema {newest}, agent produces {SCHEMA_VERSION} and can't go back that far")
+    })
+}
+
+pub(crate) fn join_versions(versions: &[u32]) -> String {
+    versions.iter().map(u32::to_string).collect::<Vec<_>>().join(", ")
+}
+
+/// Longest Retry-After we follow; anything beyond gets the normal backoff.
+pub(crate) const MAX_RETRY_AFTER_SECS: i64 = 3600;
+
+/// Retry-After of a 429/503 response, in seconds from now.
+pub(crate) fn retry_after_of(resp: &reqwest::Response) -> Option<i64> {
+    if !matches!(resp.status().as_u16(), 429 | 503) {
+        return None;
+    }
+    parse_retry_after(resp.headers().get("retry-after")?.to_str().ok()?, Utc::now())
+}
+
+/// Both forms of Retry-After: delay seconds or an HTTP date. None when
+/// unparseable or longer than MAX_RETRY_AFTER_SECS.
+pub(crate) fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<i64> {
+    let value = value.trim();
+    let secs = match value.parse::<i64>() {
+        Ok(secs) => secs,
+        Err(_) => (DateTime::parse_from_rfc2822(value).ok()?.with_timezone(&Utc) - now).num_seconds().max(0),
+    };
+    (0..=MAX_RETRY_AFTER_SECS).contains(&secs).then_some(secs)
+}
+
+/// What the server said about an accepted death, when it answered with JSON.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct UploadReceipt {
+    pub(crate) id: Option<String>,
+    /// Public page for the death
+    pub(crate) url: Option<String>,
+    /// The server already had this death: a 409, or `"duplicate": true` in the body
+    pub(crate) duplicate: bool,
+    /// `"bags_base_missing": true`: the server has no copy of the `bags_base` the diff referred to
+    pub(crate) bags_base_missing: bool,
+}
+
+/// `{"id": ..., "url": ..., "duplicate": true, "bags_base_missing": true}` from an accepted upload. None for
+/// a plain 2xx with an empty or non-JSON body or JSON without those fields;
+/// numeric ids are kept as text. A 409 is a duplicate whatever its body says.
+pub(crate) fn parse_receipt(status: StatusCode, body: &str) -> Option<UploadReceipt> {
+    let v: serde_json::Value = serde_json::from_str(body).unwrap_or_default();
+    let text = |key: &str| match v.get(key)? {
+        serde_json::Value::String(s) if !s.is_empty() => Some(s.clone()),
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    };
+    let duplicate = status == StatusCode::CONFLICT || v.get("duplicate") == Some(&serde_json::Value::Bool(true));
+    let bags_base_missing = v.get("bags_base_missing") == Some(&serde_json::Value::Bool(true));
+    let receipt = UploadReceipt { id: text("id"), url: text("url"), duplicate, bags_base_missing };
+    (receipt != UploadReceipt::default()).then_some(receipt)
+}
+
+/// One accepted upload in `State.upload_history`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct UploadRecord {
+    /// Endpoint name
+    pub(crate) target: String,
+    pub(crate) id: Option<String>,
+    pub(crate) url: Option<String>,
+    pub(crate) uploaded_at: i64,
+}
+
+/// Deaths kept in `upload_history`
+pub(crate) const MAX_UPLOAD_HISTORY: usize = 500;
+
+pub(crate) struct StatusRule {
+    pub(crate) codes: std::ops::RangeInclusive<u16>,
+    pub(crate) outcome: UploadOutcome,
+    pub(crate) note: &'static str,
+}
+
+/// How upload responses are treated, first match wins.
+pub(crate) const STATUS_RULES: &[StatusRule] = &[
+    StatusRule { codes: 100..=199, outcome: UploadOutcome::RetryLater, note: "never a final answer; treated like a dropped connection" },
+    StatusRule { codes: 200..=299, outcome: UploadOutcome::Accepted, note: "a JSON body with `id` and/or `url` is kept and the url logged; `\"duplicate\": true` is logged as `[skip]`; anything else is ignored" },
+    StatusRule { codes: 300..=399, outcome: UploadOutcome::RetryLater, note: "redirects are followed (up to 10 hops, allowlist applies); a final 3xx is a failure" },
+    StatusRule { codes: 400..=400, outcome: UploadOutcome::Rejected, note: "the response body is logged" },
+    StatusRule { codes: 401..=401, outcome: UploadOutcome::Rejected, note: "with `[oauth]` the token is refreshed and the upload sent once more first" },
+    StatusRule { codes: 402..=405, outcome: UploadOutcome::Rejected, note: "the response body is logged" },
+    StatusRule { codes: 406..=406, outcome: UploadOutcome::Rejected, note: "payload schema not accepted: a JSON body `{\"supported_versions\": [..]}` is turned into a message saying whether to update the agent or set `payload_schema_version`" },
+    StatusRule { codes: 407..=408, outcome: UploadOutcome::Rejected, note: "the response body is logged" },
+    StatusRule { codes: 409..=409, outcome: UploadOutcome::Accepted, note: "the server already has the death (same Idempotency-Key, or its own deduplication); logged as `[skip]`" },
+    StatusRule { codes: 410..=428, outcome: UploadOutcome::Rejected, note: "the response body is logged" },
+    StatusRule { codes: 429..=429, outcome: UploadOutcome::RetryLater, note: "rate limited; a Retry-After of up to an hour (seconds or HTTP date) sets the retry time and pauses uploads to that URL" },
+    StatusRule { codes: 430..=499, outcome: UploadOutcome::Rejected, note: "the response body is logged" },
+    StatusRule { codes: 500..=599, outcome: UploadOutcome::RetryLater, note: "the response body is logged; Retry-After on 503 is honored like on 429" },
+];
+
+pub(crate) fn classify_status(code: u16) -> UploadOutcome {
+    STATUS_RULES
+        .iter()
+        .find(|r| r.codes.contains(&code))
+        .map(|r| r.outcome)
+        .unwrap_or(UploadOutcome::RetryLater)
+}
+
+/// A death with every optional field filled, so all keys show up in the schema.
+pub(crate) fn sample_death(full: bool) -> DeathPayload {
+    let some = |v: serde_json::Value| full.then_some(v);
+    DeathPayload {
+        schema_version: SCHEMA_VERSION,
+        at: 0,
+        player: String::new(),
+        realm: String::new(),
+        account: full.then(String::new),
+        class: None,
+        level: None,
+        location: full.then(LocationPayload::default),
+        killer: if full { Killer::Info(Box::default()) } else { Killer::default() },
+        killer_raw: full.then(Killer::default),
+        killer_issues: vec![],
+        entry: None,
+        bags: some(json!([])).unwrap_or_default(),
+        bags_diff: some(serde_json::Value::Null),
+        bags_base: full.then(String::new),
+        equipped: some(json!([])).unwrap_or_default(),
+        instance: serde_json::Value::Null,
+        addon: full.then(|| AddonInfo {
+            name: Some(String::new()),
+            version: Some(String::new()),
+            settings: Some(AddonSettings::default()),
+        }),
+        money_copper: None,
+        money_gold: None,
+        money_silver: None,
+        money_copper_only: None,
+        money_total_copper: None,
+        screenshot_media_id: full.then(String::new),
+        note: full.then(String::new),
+        screenshot_redacted: full.then_some(true),
+        screenshot_redacted_regions: full.then(Vec::new),
+        agent: Some(AgentInfo::default()),
+        recovered_from_backup: full.then_some(true),
+        extra: serde_json::Map::new(),
+    }
+}
+
+pub(crate) fn sample_milestone() -> MilestonePayload {
+    MilestonePayload {
+        player: "Player".into(),
+        realm: "Realm".into(),
+        level: 60,
+        at: 0,
+        time_played_secs: Some(0),
+        branch: "_classic_era_".into(),
+        agent: AgentInfo::default(),
+    }
+}
+
+/// JSON Schema for the `death` part, derived from how DeathPayload serializes.
+/// Keys that appear even in a minimal death are required; values that may be
+/// null or free-form addon data are left untyped.
+pub(crate) fn death_schema() -> serde_json::Value {
+    let minimal = serde_json::to_value(sample_death(false)).unwrap_or_default();
+    let full = serde_json::to_value(sample_death(true)).unwrap_or_default();
+    let mut properties = serde_json::Map::new();
+    for (k, v) in full.as_object().into_iter().flatten() {
+        let schema = match v {
+            serde_json::Value::Number(_) => json!({ "type": "integer" }),
+            serde_json::Value::String(_) => json!({ "type": "string" }),
+            serde_json::Value::Object(_) => json!({ "type": "object" }),
+            _ => json!({}),
+        };
+        properties.insert(k.clone(), schema);
+    }
+    let required: Vec<&String> = minimal.as_object().map(|m| m.keys().collect()).unwrap_or_default();
+    json!({ "type": "object", "properties": properties, "required": required })
+}
+
+pub(crate) fn apidoc_markdown(cfg: &Config) -> String {
+    let mut out = String::new();
+    out.push_str("# DeathLogger agent: server API\n\n");
+    out.push_str(&format!("## POST {}\n\n", cfg.api_url));
+    match cfg.upload_format {
+        UploadFormat::Multipart => out.push_str("One request per death, `multipart/form-data`.\n\n"),
+        UploadFormat::Json => out.push_str(
+            "One request per death with `upload_format = \"json\"`: the body is the Death record as \
+             `application/json`. With `embed_screenshot_base64` it also has `screenshot` (base64), \
+             `screenshot_filename` and `screenshot_content_type`. The signature covers that body. The parts \
+             below apply to `multipart` only.\n\n",
+        ),
+    }
+    out.push_str("Headers:\n\n");
+    out.push_str("- `Authorization: Bearer <api_token>` when `api_token` is set, otherwise a token from `[oauth]` if configured\n");
+    out.push_str("- `User-Agent: DeathLoggerAgent/<version>`\n");
+    out.push_str("- Every `[headers]` entry, on all requests to the upload server\n");
+    out.push_str("- `X-DeathLogger-Schema`: the payload `schema_version`, left out with `payload_schema_version = 1`\n");
+    out.push_str("- `Idempotency-Key`: stable per death (hex SHA-256 of player, realm, at and killer), the same on \
+                  every retry; an uploaded death `annotate` sends again with a note gets a new one; batches send the SHA-256 of their deaths' keys joined by `,`\n");
+    out.push_str("- `X-DeathLogger-Timestamp` and `X-DeathLogger-Signature` when `hmac_secret` is set. The signature \
+                  is `v1=` + hex HMAC-SHA256 over `v1\\n<timestamp>\\n<canonical death JSON>\\n<comma-separated \
+                  screenshot SHA-256 hex>`, where canonical means object keys sorted at every level, no \
+                  whitespace and integral floats written as integers\n\n");
+    out.push_str("Parts:\n\n| Part | Content type | Sent when | Description |\n|---|---|---|---|\n");
+    for p in UPLOAD_PARTS {
+        out.push_str(&format!("| `{}` | {} | {} | {} |\n", p.name, p.content_type, p.when, p.description));
+    }
+    out.push_str("\nResponses:\n\n| Status | Outcome | Notes |\n|---|---|---|\n");
+    for r in STATUS_RULES {
+        out.push_str(&format!("| {}-{} | {} | {} |\n", r.codes.start(), r.codes.end(), r.outcome.describe(), r.note));
+    }
+    out.push_str(&format!("\n## HEAD {}\n\n", cfg.api_url));
+    out.push_str("Sent at startup and by the setup wizard as a connectivity check, with the same headers as an \
+                  upload. 401, 403, 404 and 5xx are reported to the user as configuration problems; any other \
+                  answer, including 405, counts as reachable. Nothing needs to be implemented for it.\n");
+    out.push_str("\n## POST <screenshot_challenge_url>\n\n");
+    out.push_str("Only when `screenshot_challenge_url` is set. Called before an upload with a screenshot; must answer \
+                  `{\"nonce\": \"...\", \"max_age_secs\": 300}` (`max_age_secs` optional). Tried twice; on failure \
+                  the upload goes ahead without a nonce. An upload whose nonce has expired can be answered with a 400 or 403 and \
+                  `{\"error\": \"challenge_expired\"}`; the agent then fetches a new nonce and uploads once more.\n");
+    out.push_str("\n## POST <media_url>\n\n");
+    out.push_str("Only when `media_url` is set (and encryption is off). The body is the screenshot file itself, with its \
+                  `Content-Type` (image/jpeg or image/png) and `Content-Disposition: attachment; filename=\"...\"`; it must \
+                  answer `{\"media_id\": \"...\"}`. The death is then sent without a file part and with \
+                  `screenshot_media_id` set. Ids are reused for the same file; answering 404/410 to a death that \
+                  references one makes the agent upload the screenshot again once, then fall back to sending it inline.\n");
+    out.push_str("\n## PUT <upload_url>\n\n");
+    out.push_str("Only when `resumable_uploads` is set. The URL comes from the death's response (relative URLs resolve \
+                  against the upload URL) and must stay the same when the death is sent again with the same \
+                  Idempotency-Key. The screenshot arrives in `upload_chunk_bytes` pieces, each with \
+                  `Content-Range: bytes <first>-<last>/<size>` and a 2xx answer once stored. `HEAD <upload_url>` \
+                  must answer with `Upload-Offset: <bytes stored>`; the agent asks before starting and after a \
+                  failed chunk, and continues from there.\n");
+    out.push_str("\n## PUT <presigned_url>\n\n");
+    out.push_str("Only with `upload_flow = \"presigned\"`. The death is POSTed as `application/json` with \
+                  `\"screenshot_pending\": true` and `\"screenshot\": {\"file_name\", \"content_type\", \"size\", \"sha256\"}` \
+                  when it has a screenshot. A 2xx answer should carry `{\"presigned_url\": \"...\"}`, optionally with \
+                  `content_type`, `headers` (sent with the PUT, for signed headers), `object_key` and `confirm_url`. \
+                  The screenshot is PUT there without the upload headers. With `confirm_url` (relative URLs resolve \
+                  against the upload URL) the agent then POSTs `{\"object_key\", \"sha256\", \"size\"}` with the upload \
+                  headers. A failed PUT or confirmation is retried without resending the death, except that a \
+                  400/403 from storage (an expired URL) makes the next attempt send the death again, same \
+                  Idempotency-Key, for a new URL.\n");
+    out.push_str(&format!("\n## POST {}\n\n", milestones_url(cfg)));
+    out.push_str("One JSON body per survivor milestone, same headers and response handling:\n\n```json\n");
+    out.push_str(&serde_json::to_string_pretty(&sample_milestone()).unwrap_or_default());
+    out.push_str("\n```\n");
+    out.push_str("\n## POST <bulk_api_url>\n\n");
+    out.push_str("Only when `bulk_api_url` is set. When a SavedVariables file holds deaths older than the newest one \
+                  that were never sent (a new character's history, or everything after enabling the agent), they \
+                  go out together before the newest death, with the upload headers. The body is \
+                  `application/x-ndjson`: one Death record per line, oldest first, without screenshots. \
+                  Idempotency-Key is the hex SHA-256 of the deaths' keys joined with `,`; the signature covers \
+                  the whole body. A 2xx answer must be NDJSON too, one line per death in the same order: \
+                  `{\"status\": <HTTP status for that death>}`, optionally with `id`, `url` and `error`. Each line \
+                  is handled as if that death's own upload had answered it (409 counts as already stored); deaths \
+                  without a line are retried one by one through the upload URL, as is every death when the \
+                  request as a whole fails.\n");
+    out.push_str("\n## POST <heartbeat_url>\n\n");
+    out.push_str("Only when `heartbeat_url` is set: every `heartbeat_interval_secs`, with the upload headers. The body \
+                  is `{\"agent\": {\"version\", \"id\", \"uploaded_at\"}, \"branch\", \"sv_files\", \"last_uploaded\": \
+                  {\"Player@Realm\": at}, \"retry_queue\"}`. Any 2xx counts; nothing is retried.\n");
+    out.push_str("\n## Death schema\n\nEntry fields the agent has no field of its own for (`guild`, `hardcore`, ...) are \
+                  passed through at the top level as the addon recorded them, unless `drop_unknown_fields` is set.\n\n```json\n");
+    out.push_str(&serde_json::to_string_pretty(&death_schema()).unwrap_or_default());
+    out.push_str("\n```\n");
+    out
+}
+
+pub(crate) fn apidoc_openapi(cfg: &Config) -> serde_json::Value {
+    let (server, path) = match reqwest::Url::parse(&cfg.api_url) {
+        Ok(u) => (u.origin().ascii_serialization(), u.path().to_string()),
+        Err(_) => (String::new(), "/upload".to_string()),
+    };
+    let mut properties = serde_json::Map::new();
+    let mut encoding = serde_json::Map::new();
+    for p in UPLOAD_PARTS {
+        let schema = if p.name == PART_DEATH {
+            json!({ "$ref": "#/components/schemas/Death" })
+        } else if p.content_type == "application/json" {
+            json!({ "type": "object", "description": p.description })
+        } else {
+            json!({ "type": "string", "format": "binary", "description": p.description })
+        };
+        properties.insert(p.name.into(), schema);
+        encoding.insert(p.name.into(), json!({ "contentType": p.content_type.replace(' ', "") }));
+    }
+    let mut responses = serde_json::Map::new();
+    for r in STATUS_RULES {
+        let description = format!("{} ({})", r.outcome.describe(), r.note);
+        // Single codes get their own entry, which OpenAPI prefers over the range.
+        if r.codes.start() == r.codes.end() {
+            responses.insert(r.codes.start().to_string(), json!({ "description": description }));
+            continue;
+        }
+        for class in r.codes.start() / 100..=r.codes.end() / 100 {
+            responses.entry(format!("{}XX", class)).or_insert_with(|| json!({ "description": description }));
+        }
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": { "title": "DeathLogger agent uploads", "version": env!("CARGO_PKG_VERSION") },
+        "servers": [{ "url": server }],
+        "paths": {
+            path: {
+                "post": {
+                    "summary": "Upload one death",
+                    "security": [{ "bearer": [] }, {}],
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "multipart/form-data": {
+                                "schema": { "type": "object", "properties": properties },
+                                "encoding": encoding,
+                            }
+                        }
+                    },
+                    "responses": responses,
+                }
+            }
+        },
+        "components": {
+            "schemas": { "Death": death_schema() },
+            "securitySchemes": { "bearer": { "type": "http", "scheme": "bearer" } },
+        },
+    })
+}
+
+/// `deathlogger-agent apidoc [--format markdown|openapi]`
+pub(crate) fn apidoc_command(args: &[String]) -> Result<()> {
+    let format = match args {
+        [] => "markdown",
+        [flag, f] if flag == "--format" => f.as_str(),
+        _ => return Err(anyhow!("Usage: deathlogger-agent apidoc [--format markdown|openapi]")),
+    };
+    let cfg_path = config_path()?;
+    let cfg = if cfg_path.exists() { load_config(&cfg_path)? } else { Config::default() };
+    match format {
+        "markdown" => print!("{}", apidoc_markdown(&cfg)),
+        "openapi" => println!("{}", serde_json::to_string_pretty(&apidoc_openapi(&cfg))?),
+        other => return Err(anyhow!("Unknown apidoc format: {}", other)),
+    }
+    Ok(())
+}
+
+/// Upload one death, unless the server's Retry-After or our own rate limit says
+/// to wait (a `RateLimited` error, which costs no retry attempt). A 401 with an
+/// `[oauth]` token is tried once more with a fresh token. `screenshots` is
+/// nearest first.
+pub(crate) async fn upload(
+    cfg: &Config,
+    http: &Http,
+    media_ids: &mut BTreeMap<String, MediaRef>,
+    death: &DeathPayload,
+    idempotency_key: &str,
+    screenshots: &[&Path],
+) -> Result<Option<UploadReceipt>> {
+    http.check_offline(&cfg.api_url)?;
+    http.check_throttle(&cfg.api_url)?;
+    http.take_upload_slot()?;
+    let death = &stamped(cfg, death);
+    let screenshots: Vec<&Path> = screenshots.iter().copied().filter(|sc| screenshot_sendable(cfg, sc)).collect();
+    let screenshots = &screenshots[..];
+    let mut result = send_death(cfg, http, media_ids, death, idempotency_key, screenshots).await;
+    if is_unauthorized(&result) && http.forget_oauth_token(cfg) {
+        println!("[oauth] {} answered 401; retrying with a new token", cfg.api_url);
+        result = send_death(cfg, http, media_ids, death, idempotency_key, screenshots).await;
+    }
+    if is_expired_challenge(cfg, &result) {
+        println!("[challenge] {} says the nonce expired; uploading again with a new one", cfg.api_url);
+        result = send_death(cfg, http, media_ids, death, idempotency_key, screenshots).await;
+    }
+    http.note_throttle(&cfg.api_url, &result);
+    http.note_offline(&cfg.api_url, result)
+}
+
+/// With `media_url` set the screenshot goes to the media endpoint first and the
+/// death references it by id; if that fails, or the server has forgotten the id
+/// twice in a row, the screenshot is sent inline. The media and resumable paths
+/// carry only the nearest screenshot.
+pub(crate) async fn send_death(
+    cfg: &Config,
+    http: &Http,
+    media_ids: &mut BTreeMap<String, MediaRef>,
+    death: &DeathPayload,
+    idempotency_key: &str,
+    screenshots: &[&Path],
+) -> Result<Option<UploadReceipt>> {
+    let screenshot = screenshots.first().copied();
+    if cfg.upload_flow == UploadFlow::Presigned {
+        return upload_presigned(cfg, http, death, idempotency_key, screenshot).await;
+    }
+    if let Some(sc) = screenshot.filter(|_| resumable_applies(cfg, http)) {
+        return upload_resumable(cfg, http, death, idempotency_key, sc).await;
+    }
+    if let Some(sc) = screenshot.filter(|_| !cfg.media_url.is_empty() && cfg.encrypt_to_public_key.is_empty()) {
+        for attempt in 0..2 {
+            let Some((hash, media_id)) = media_id_for(cfg, http, media_ids, sc).await else { break };
+            let mut with_ref = death.clone();
+            with_ref.screenshot_media_id = Some(media_id);
+            match upload_once(cfg, http, &with_ref, idempotency_key, &[]).await {
+                Err(e) if is_unknown_media(&e) => {
+                    media_ids.remove(&hash);
+                    match attempt {
+                        0 => println!("[media] Server no longer knows the media for {}; uploading it again", sc.display()),
+                        _ => println!("[media] Server lost the media for {} again; sending it inline", sc.display()),
+                    }
+                }
+                other => return other,
+            }
+        }
+    }
+    upload_once(cfg, http, death, idempotency_key, screenshots).await
+}
+
+/// Part name for the `n`th screenshot of a death: `screenshot`, then `screenshot_2`, ...
+pub(crate) fn numbered_part(base: &str, n: usize) -> String {
+    if n == 0 {
+        base.to_string()
+    } else {
+        format!("{}_{}", base, n + 1)
+    }
+}
+
+pub(crate) async fn upload_once(
+    cfg: &Config,
+    http: &Http,
+    death: &DeathPayload,
+    idempotency_key: &str,
+    screenshots: &[&Path],
+) -> Result<Option<UploadReceipt>> {
+    if cfg.upload_format == UploadFormat::Json {
+        return upload_json(cfg, http, death, idempotency_key, screenshots.first().copied()).await;
+    }
+    let death_json = serde_json::to_string(death)?;
+    let key = encryption_key(cfg)?;
+
+    let challenge = match screenshots.first() {
+        Some(_) if !cfg.screenshot_challenge_url.is_empty() => fetch_screenshot_challenge(cfg, http).await,
+        _ => None,
+    };
+    let screenshots: Vec<&Path> =
+        screenshots.iter().copied().filter(|sc| screenshot_fresh_enough(cfg, challenge.as_ref(), sc)).collect();
+
+    let mut shot_parts = vec![];
+    if let Some(challenge) = &challenge {
+        shot_parts.push(FormPart::text(PART_SCREENSHOT_CHALLENGE, challenge.nonce.clone()));
+        if let Some(sc) = screenshots.first() {
+            shot_parts.push(FormPart::text(PART_SCREENSHOT_META, screenshot_meta(sc, death.at)?.to_string()));
+        }
+    }
+    let mut headers = vec![(IDEMPOTENCY_HEADER, idempotency_key.to_string())];
+    if let Some(challenge) = &challenge {
+        headers.push((CHALLENGE_HEADER, challenge.nonce.clone()));
+    }
+    let mut shot_hashes = vec![];
+    for (n, sc) in screenshots.iter().enumerate() {
+        let shot = read_screenshot(cfg, sc)?;
+        shot_hashes.push(format!("{:x}", Sha256::digest(&shot.bytes)));
+        match &key {
+            Some(key) if cfg.encrypt_screenshot => shot_parts.push(FormPart::file(
+                &numbered_part(PART_SCREENSHOT_ENCRYPTED, n),
+                format!("{}.bin", shot.file_name),
+                seal(key, &shot.bytes)?,
+                "application/octet-stream",
+            )),
+            _ => {
+                if n == 0 && cfg.screenshot_thumbnail {
+                    match make_thumbnail(cfg, &shot) {
+                        Ok(thumb) => shot_parts.push(FormPart::file(PART_THUMBNAIL, thumb.file_name, thumb.bytes, thumb.content_type)),
+                        Err(e) => eprintln!("[warn] no thumbnail for {} ({e:#}); sending the death without it", sc.display()),
+                    }
+                }
+                shot_parts.push(FormPart::file(&numbered_part(PART_SCREENSHOT, n), shot.file_name, shot.bytes, shot.content_type))
+            }
+        }
+    }
+
+    headers.extend(signature_headers(cfg, death_json.as_bytes(), &shot_hashes));
+
+    let resp = match &key {
+        None => send_upload_form(cfg, http, PART_DEATH, death_json, shot_parts, &headers).await?,
+        Some(key) => {
+            let sealed_shot = !screenshots.is_empty() && cfg.encrypt_screenshot;
+            let mut parts = vec![
+                FormPart::text(PART_ENVELOPE, encrypted_envelope(death, sealed_shot).to_string()),
+                FormPart::file(PART_DEATH_ENCRYPTED, "death.bin".into(), seal(key, death_json.as_bytes())?, "application/octet-stream"),
+            ];
+            parts.extend(shot_parts);
+            http.send_multipart(NetFeature::Upload, upload_request(cfg, http, &headers).await?, parts).await?
+        }
+    };
+    let status = resp.status();
+    let retry_after = retry_after_of(&resp);
+    let body = resp.text().await.unwrap_or_default();
+    match classify_status(status.as_u16()) {
+        UploadOutcome::Accepted => Ok(parse_receipt(status, &body)),
+        UploadOutcome::RetryLater | UploadOutcome::Rejected => Err(UploadError { status, body, retry_after }.into()),
+    }
+}
+
+/// `upload_format = "json"`: the death is the request body. With
+/// embed_screenshot_base64 the screenshot rides along as `screenshot` (base64),
+/// `screenshot_filename` and `screenshot_content_type`; otherwise it only reaches
+/// the server through media_url.
+pub(crate) async fn upload_json(
+    cfg: &Config,
+    http: &Http,
+    death: &DeathPayload,
+    idempotency_key: &str,
+    screenshot: Option<&Path>,
+) -> Result<Option<UploadReceipt>> {
+    let mut body = serde_json::to_value(death)?;
+    let mut shot_hashes = vec![];
+    if let (Some(sc), true, Some(fields)) = (screenshot, cfg.embed_screenshot_base64, body.as_object_mut()) {
+        let shot = read_screenshot(cfg, sc)?;
+        shot_hashes.push(format!("{:x}", Sha256::digest(&shot.bytes)));
+        fields.insert("screenshot".into(), BASE64.encode(&shot.bytes).into());
+        fields.insert("screenshot_filename".into(), shot.file_name.into());
+        fields.insert("screenshot_content_type".into(), shot.content_type.into());
+    }
+    let json = serde_json::to_string(&body)?;
+    let mut headers = vec![(IDEMPOTENCY_HEADER, idempotency_key.to_string())];
+    headers.extend(signature_headers(cfg, json.as_bytes(), &shot_hashes));
+    let req = upload_request(cfg, http, &headers).await?.header(CONTENT_TYPE, "application/json").body(json);
+    let resp = http.send(NetFeature::Upload, req).await?;
+    let status = resp.status();
+    let retry_after = retry_after_of(&resp);
+    let body = resp.text().await.unwrap_or_default();
+    match classify_status(status.as_u16()) {
+        UploadOutcome::Accepted => Ok(parse_receipt(status, &body)),
+        UploadOutcome::RetryLater | UploadOutcome::Rejected => Err(UploadError { status, body, retry_after }.into()),
+    }
+}
+
+pub(crate) fn is_unauthorized<T>(result: &Result<T>) -> bool {
+    matches!(result, Err(e) if e.downcast_ref::<UploadError>().map(|u| u.status == StatusCode::UNAUTHORIZED).unwrap_or(false))
+}
+
+pub(crate) async fn upload_request(cfg: &Config, http: &Http, headers: &[(&str, String)]) -> Result<reqwest::RequestBuilder> {
+    let mut req = with_schema_header(cfg, http.authorize(cfg, http.post(&cfg.api_url)).await?);
+    for (name, value) in headers {
+        req = req.header(*name, value);
+    }
+    Ok(req)
+}
+
+/// Send an upload form whose JSON part is gzipped when `compress_uploads` is on.
+/// A 400/415 answer to the compressed form switches compression off for the rest
+/// of the session and the form is sent again uncompressed.
+pub(crate) async fn send_upload_form(
+    cfg: &Config,
+    http: &Http,
+    json_name: &str,
+    json: String,
+    others: Vec<FormPart>,
+    headers: &[(&str, String)],
+) -> Result<reqwest::Response> {
+    if cfg.compress_uploads && !http.gzip_refused.load(Ordering::Relaxed) {
+        let mut parts = vec![FormPart::gzip_json(json_name, &json)?];
+        parts.extend(others.iter().cloned());
+        let resp = http.send_multipart(NetFeature::Upload, upload_request(cfg, http, headers).await?, parts).await?;
+        if !matches!(resp.status().as_u16(), 400 | 415) {
+            return Ok(resp);
+        }
+        http.gzip_refused.store(true, Ordering::Relaxed);
+        println!(
+            "[upload] Server answered {} to a compressed upload; sending uncompressed for the rest of this session",
+            resp.status()
+        );
+    }
+    let mut parts = vec![FormPart::text(json_name, json)];
+    parts.extend(others);
+    http.send_multipart(NetFeature::Upload, upload_request(cfg, http, headers).await?, parts).await
+}
+
+/// Name the Discord webhook goes by among the upload targets.
+pub(crate) const DISCORD_TARGET: &str = "discord";
+
+/// Everything a death is sent to: the enabled endpoints and/or the Discord
+/// webhook, as `upload_mode` selects. Each one succeeds or is retried on its own.
+pub(crate) fn upload_targets(cfg: &Config) -> Vec<String> {
+    let mut targets = vec![];
+    if cfg.upload_mode != UploadMode::Discord {
+        targets.extend(api_endpoints(cfg).into_iter().map(|e| e.name));
+    }
+    if cfg.upload_mode != UploadMode::Api && !cfg.discord_webhook_url.is_empty() {
+        targets.push(DISCORD_TARGET.to_string());
+    }
+    targets
+}
+
+/// Send a death to each of `targets`, one result per target. Discord gets only
+/// the nearest of `screenshots`.
+pub(crate) async fn deliver(
+    cfg: &Config,
+    http: &Http,
+    media_ids: &mut BTreeMap<String, MediaRef>,
+    death: &DeathPayload,
+    idempotency_key: &str,
+    screenshots: &[&Path],
+    targets: &[String],
+) -> Vec<(String, Result<Option<UploadReceipt>>)> {
+    let endpoints = api_endpoints(cfg);
+    let mut results = vec![];
+    for target in targets {
+        let result = if target == DISCORD_TARGET {
+            post_discord(cfg, http, death, screenshots.first().copied()).await.map(|_| None)
+        } else {
+            match endpoints.iter().find(|e| &e.name == target) {
+                Some(ep) => upload(&cfg.for_endpoint(ep), http, media_ids, death, idempotency_key, screenshots).await,
+                None => Err(anyhow!("endpoint {} is no longer configured", target)),
+            }
+        };
+        results.push((target.clone(), result));
+    }
+    results
+}
+
+// ---------- Resumable screenshot uploads ----------
+
+pub(crate) const UPLOAD_OFFSET_HEADER: &str = "Upload-Offset";
+/// Failed chunks in a row (no progress in between) before the death goes to the retry queue
+pub(crate) const CHUNK_ATTEMPTS: u32 = 5;
+
+/// Encrypted deaths and challenged screenshots must travel in the same request
+/// as the death, so those always go inline.
+pub(crate) fn resumable_applies(cfg: &Config, http: &Http) -> bool {
+    cfg.resumable_uploads
+        && cfg.upload_format == UploadFormat::Multipart
+        && cfg.encrypt_to_public_key.is_empty()
+        && cfg.screenshot_challenge_url.is_empty()
+        && !http.resumable_refused.load(Ordering::Relaxed)
+}
+
+/// Send the death with a description of its screenshot, then PUT the screenshot
+/// to the URL the server hands back, one chunk at a time. After a failed chunk
+/// the upload continues from the offset the server reports, so a dropped
+/// connection near the end of a 15 MB file only costs the last chunk.
+pub(crate) async fn upload_resumable(cfg: &Config, http: &Http, death: &DeathPayload, idempotency_key: &str, sc: &Path) -> Result<Option<UploadReceipt>> {
+    let death_json = serde_json::to_string(death)?;
+    let shot = read_screenshot(cfg, sc)?;
+    let sha256 = format!("{:x}", Sha256::digest(&shot.bytes));
+    let meta = json!({
+        "file_name": shot.file_name,
+        "content_type": shot.content_type,
+        "size": shot.bytes.len(),
+        "sha256": sha256,
+    });
+    let bytes = shot.bytes;
+    let mut headers = vec![(IDEMPOTENCY_HEADER, idempotency_key.to_string())];
+    headers.extend(signature_headers(cfg, death_json.as_bytes(), &[sha256]));
+    let parts = vec![FormPart::text(PART_SCREENSHOT_RESUMABLE, meta.to_string())];
+    let resp = send_upload_form(cfg, http, PART_DEATH, death_json, parts, &headers).await?;
+    let status = resp.status();
+    let retry_after = retry_after_of(&resp);
+    let body = resp.text().await.unwrap_or_default();
+    if classify_status(status.as_u16()) != UploadOutcome::Accepted {
+        return Err(UploadError { status, body, retry_after }.into());
+    }
+    let receipt = parse_receipt(status, &body);
+    let upload_url = serde_json::from_str::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|v| v.get("upload_url").and_then(|u| u.as_str()).map(String::from));
+    let Some(upload_url) = upload_url else {
+        http.resumable_refused.store(true, Ordering::Relaxed);
+        println!(
+            "[upload] Server accepted the death but offered no screenshot upload URL, so {} was not sent; \
+             sending screenshots inline for the rest of this session (set resumable_uploads = false for this server)",
+            sc.display()
+        );
+        return Ok(receipt);
+    };
+    let url = reqwest::Url::parse(&cfg.api_url)
+        .and_then(|base| base.join(&upload_url))
+        .with_context(|| format!("upload_url {:?}", upload_url))?
+        .to_string();
+
+    let total = bytes.len();
+    // A retry of the whole death lands here again with the same URL; skip what the server has.
+    let mut offset = upload_offset(cfg, http, &url).await.unwrap_or(0).min(total);
+    let mut failures = 0;
+    while offset < total {
+        let end = (offset + cfg.upload_chunk_bytes.max(1)).min(total);
+        match put_chunk(cfg, http, &url, &bytes[offset..end], offset, total).await {
+            Ok(()) => {
+                offset = end;
+                failures = 0;
+            }
+            Err(e) if is_permanent_failure(&e) => return Err(e),
+            Err(e) => {
+                failures += 1;
+                if failures >= CHUNK_ATTEMPTS {
+                    return Err(e.context(format!("screenshot upload stopped at byte {} of {}", offset, total)));
+                }
+                offset = upload_offset(cfg, http, &url).await.unwrap_or(offset).min(total);
+                eprintln!("[upload] Screenshot chunk failed ({e:#}); resuming at byte {} of {}", offset, total);
+            }
+        }
+    }
+    Ok(receipt)
+}
+
+pub(crate) async fn resumable_request(cfg: &Config, http: &Http, method: Method, url: &str) -> Result<reqwest::RequestBuilder> {
+    http.authorize(cfg, http.client.request(method, url)).await
+}
+
+pub(crate) async fn put_chunk(cfg: &Config, http: &Http, url: &str, chunk: &[u8], offset: usize, total: usize) -> Result<()> {
+    let range = format!("bytes {}-{}/{}", offset, offset + chunk.len() - 1, total);
+    let req = resumable_request(cfg, http, Method::PUT, url)
+        .await?
+        .header(CONTENT_RANGE, range)
+        .header(CONTENT_TYPE, "application/octet-stream")
+        .body(chunk.to_vec());
+    let resp = http.send(NetFeature::Upload, req).await?;
+    let status = resp.status();
+    let retry_after = retry_after_of(&resp);
+    match classify_status(status.as_u16()) {
+        UploadOutcome::Accepted => Ok(()),
+        UploadOutcome::RetryLater | UploadOutcome::Rejected => {
+            let body = resp.text().await.unwrap_or_default();
+            Err(UploadError { status, body, retry_after }.into())
+        }
+    }
+}
+
+/// Bytes of the screenshot the server has stored, from `HEAD <upload_url>`.
+pub(crate) async fn upload_offset(cfg: &Config, http: &Http, url: &str) -> Option<usize> {
+    let req = resumable_request(cfg, http, Method::HEAD, url).await.ok()?;
+    let resp = http.send(NetFeature::Upload, req).await.ok()?;
+    resp.headers().get(UPLOAD_OFFSET_HEADER)?.to_str().ok()?.trim().parse().ok()
+}
+
+// ---------- Presigned screenshot uploads ----------
+//
+// With `upload_flow = "presigned"` the death goes as JSON with
+// `screenshot_pending: true` and a description of the screenshot. The server
+// answers with a presigned URL (S3 and the like), the agent PUTs the file there,
+// then confirms at `confirm_url` when the server gave one. Once the death is
+// accepted, the rest of the flow is remembered for this session, so a failed PUT
+// or confirmation is retried without sending the death again.
+
+/// What the server said to do with an accepted death's screenshot.
+#[derive(Debug, Clone)]
+pub(crate) struct PresignedUpload {
+    pub(crate) receipt: Option<UploadReceipt>,
+    pub(crate) put_url: String,
+    pub(crate) content_type: String,
+    /// Extra headers the signature covers (e.g. `x-amz-*`)
+    pub(crate) headers: BTreeMap<String, String>,
+    pub(crate) object_key: Option<String>,
+    /// Absolute; None when the server needs no confirmation
+    pub(crate) confirm_url: Option<String>,
+    pub(crate) stored: bool,
+}
+
+/// The presigned target from the death's response. None when the server
+/// offered no `presigned_url`.
+pub(crate) fn parse_presigned(cfg: &Config, status: StatusCode, body: &str, default_type: &str) -> Result<Option<PresignedUpload>> {
+    let v: serde_json::Value = serde_json::from_str(body).unwrap_or_default();
+    let text = |key: &str| v.get(key).and_then(|x| x.as_str()).filter(|x| !x.is_empty()).map(String::from);
+    let Some(put_url) = text("presigned_url") else { return Ok(None) };
+    let confirm_url = match text("confirm_url") {
+        Some(u) => Some(
+            reqwest::Url::parse(&cfg.api_url)
+                .and_then(|base| base.join(&u))
+                .with_context(|| format!("confirm_url {:?}", u))?
+                .to_string(),
+        ),
+        None => None,
+    };
+    let headers = v
+        .get("headers")
+        .and_then(|h| h.as_object())
+        .map(|h| h.iter().filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_string()))).collect())
+        .unwrap_or_default();
+    Ok(Some(PresignedUpload {
+        receipt: parse_receipt(status, body),
+        put_url,
+        content_type: text("content_type").unwrap_or_else(|| default_type.to_string()),
+        headers,
+        object_key: text("object_key"),
+        confirm_url,
+        stored: false,
+    }))
+}
+
+pub(crate) async fn upload_presigned(
+    cfg: &Config,
+    http: &Http,
+    death: &DeathPayload,
+    idempotency_key: &str,
+    screenshot: Option<&Path>,
+) -> Result<Option<UploadReceipt>> {
+    let shot = screenshot.map(|sc| read_screenshot(cfg, sc)).transpose()?;
+    let sha256 = shot.as_ref().map(|s| format!("{:x}", Sha256::digest(&s.bytes)));
+    let memo_key = format!("{} {}", cfg.api_url, idempotency_key);
+    let remembered = http.presigned.lock().unwrap().get(&memo_key).cloned();
+    let mut target = match remembered {
+        Some(target) => target,
+        None => {
+            let mut body = serde_json::to_value(death)?;
+            if let (Some(shot), Some(sha256), Some(fields)) = (&shot, &sha256, body.as_object_mut()) {
+                fields.insert("screenshot_pending".into(), true.into());
+                fields.insert(
+                    "screenshot".into(),
+                    json!({ "file_name": shot.file_name, "content_type": shot.content_type, "size": shot.bytes.len(), "sha256": sha256 }),
+                );
+            }
+            let json = serde_json::to_string(&body)?;
+            let mut headers = vec![(IDEMPOTENCY_HEADER, idempotency_key.to_string())];
+            headers.extend(signature_headers(cfg, json.as_bytes(), sha256.as_slice()));
+            let req = upload_request(cfg, http, &headers).await?.header(CONTENT_TYPE, "application/json").body(json);
+            let resp = http.send(NetFeature::Upload, req).await?;
+            let status = resp.status();
+            let retry_after = retry_after_of(&resp);
+            let body = resp.text().await.unwrap_or_default();
+            if classify_status(status.as_u16()) != UploadOutcome::Accepted {
+                return Err(UploadError { status, body, retry_after }.into());
+            }
+            let Some(shot) = &shot else { return Ok(parse_receipt(status, &body)) };
+            match parse_presigned(cfg, status, &body, shot.content_type)? {
+                Some(target) => target,
+                None => {
+                    println!(
+                        "[presigned] Server accepted the death but returned no presigned_url, so {} was not sent",
+                        shot.file_name
+                    );
+                    return Ok(parse_receipt(status, &body));
+                }
+            }
+        }
+    };
+    let Some(shot) = shot else {
+        // The screenshot disappeared since the death was accepted.
+        http.presigned.lock().unwrap().remove(&memo_key);
+        return Ok(target.receipt);
+    };
+    let size = shot.bytes.len();
+    http.presigned.lock().unwrap().insert(memo_key.clone(), target.clone());
+
+    if !target.stored {
+        // The URL is signed for exactly these headers, so no auth or [headers] go along.
+        let mut req = http.client.put(&target.put_url).header(CONTENT_TYPE, &target.content_type).body(shot.bytes);
+        for (name, value) in &target.headers {
+            req = req.header(name, value);
+        }
+        let resp = http.send(NetFeature::Media, req).await?;
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.text().await.unwrap_or_default();
+            if matches!(status.as_u16(), 400 | 403) {
+                // Most likely expired: the next attempt sends the death again for a fresh URL.
+                http.presigned.lock().unwrap().remove(&memo_key);
+                return Err(anyhow!("storage refused the presigned PUT ({status}); asking the server for a new URL next time: {body}"));
+            }
+            return Err(anyhow!("presigned PUT of {} failed: {status} - {body}", shot.file_name));
+        }
+        target.stored = true;
+        http.presigned.lock().unwrap().insert(memo_key.clone(), target.clone());
+    }
+
+    if let Some(confirm_url) = &target.confirm_url {
+        let body = json!({ "object_key": target.object_key, "sha256": sha256, "size": size });
+        let req = http
+            .authorize(cfg, http.post(confirm_url))
+            .await?
+            .header(IDEMPOTENCY_HEADER, idempotency_key)
+            .json(&body);
+        let resp = http.send(NetFeature::Upload, req).await?;
+        let status = resp.status();
+        let retry_after = retry_after_of(&resp);
+        if classify_status(status.as_u16()) != UploadOutcome::Accepted {
+            let body = resp.text().await.unwrap_or_default();
+            return Err(UploadError { status, body, retry_after }.into());
+        }
+    }
+    http.presigned.lock().unwrap().remove(&memo_key);
+    Ok(target.receipt)
+}
+
+// ---------- Discord webhook ----------
+
+pub(crate) const DISCORD_FIELD_MAX: usize = 1024;
+pub(crate) const DISCORD_TITLE_MAX: usize = 256;
+pub(crate) const DISCORD_ATTEMPTS: u32 = 3;
+/// Longest rate-limit wait honored before giving up on a post
+pub(crate) const DISCORD_MAX_WAIT_SECS: f64 = 60.0;
+
+/// Cut to at most `max` characters, marking the cut with an ellipsis.
+pub(crate) fn truncate_chars(s: &str, max: usize) -> String {
+    if s.chars().count() <= max {
+        return s.to_string();
+    }
+    let mut out: String = s.chars().take(max.saturating_sub(1)).collect();
+    out.push('…');
+    out
+}
+
+/// Plain text for a JSON value: strings as-is, anything else as compact JSON.
+pub(crate) fn value_text(v: &serde_json::Value) -> String {
+    match v {
+        serde_json::Value::String(s) => s.clone(),
+        serde_json::Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+pub(crate) fn discord_embed(death: &DeathPayload, image: Option<&str>) -> serde_json::Value {
+    let location = death.location.clone().unwrap_or_default();
+    let zone = location.zone.unwrap_or_default();
+    let subzone = location.subzone.unwrap_or_default();
+    let zone = match (zone.is_empty(), subzone.is_empty()) {
+        (false, false) => format!("{} - {}", zone, subzone),
+        (false, true) => zone,
+        (true, _) => subzone,
+    };
+    let killer = match &death.killer {
+        Killer::Info(k) => match (k.name.as_deref(), k.detail.as_deref().filter(|d| !d.is_empty())) {
+            (Some(name), Some(detail)) => format!("{} ({})", name, detail),
+            (Some(name), None) => name.to_string(),
+            (None, detail) => detail.unwrap_or_default().to_string(),
+        },
+        Killer::Raw(v) => value_text(v),
+    };
+    let mut fields = vec![];
+    let mut field = |name: &str, value: String| {
+        if !value.is_empty() {
+            fields.push(json!({ "name": name, "value": truncate_chars(&value, DISCORD_FIELD_MAX), "inline": true }));
+        }
+    };
+    field("Realm", death.realm.clone());
+    field("Level", death.level.map(|l| l.to_string()).unwrap_or_default());
+    field("Class", death.class.clone().unwrap_or_default());
+    field("Zone", zone);
+    field("Killer", killer);
+    field("Note", death.note.clone().unwrap_or_default());
+    let mut embed = json!({
+        "title": truncate_chars(&format!("{} has died", death.player), DISCORD_TITLE_MAX),
+        "timestamp": format_epoch(death.at),
+        "color": 0x8b0000,
+        "fields": fields,
+    });
+    if let Some(name) = image {
+        embed["image"] = json!({ "url": format!("attachment://{}", name) });
+    }
+    embed
+}
+
+/// Post a death to the Discord webhook, waiting out 429s a few times.
+pub(crate) async fn post_discord(cfg: &Config, http: &Http, death: &DeathPayload, screenshot: Option<&Path>) -> Result<()> {
+    let shot = match screenshot.filter(|sc| screenshot_sendable(cfg, sc)) {
+        Some(sc) => {
+            let shot = read_screenshot(cfg, sc)?;
+            Some((shot.file_name, shot.bytes, shot.content_type))
+        }
+        None => None,
+    };
+    let payload = json!({ "embeds": [discord_embed(death, shot.as_ref().map(|(n, _, _)| n.as_str()))] });
+    send_discord(cfg, http, &payload, shot.as_ref()).await
+}
+
+/// One Discord message standing in for a group of deaths during a surge.
+pub(crate) async fn post_discord_summary(cfg: &Config, http: &Http, deaths: &[&DeathPayload]) -> Result<()> {
+    let (Some(first), Some(last)) = (deaths.first(), deaths.last()) else { return Ok(()) };
+    let content = format!(
+        "**{}** died {} times between {} and {}",
+        to_key(&first.player, &first.realm),
+        deaths.len(),
+        format_epoch(first.at),
+        format_epoch(last.at)
+    );
+    send_discord(cfg, http, &json!({ "content": content }), None).await
+}
+
+/// Post to the webhook, waiting out short 429s.
+pub(crate) async fn send_discord(cfg: &Config, http: &Http, payload: &serde_json::Value, shot: Option<&(String, Vec<u8>, &'static str)>) -> Result<()> {
+    for attempt in 1..=DISCORD_ATTEMPTS {
+        let req = http.post(&cfg.discord_webhook_url);
+        let resp = match shot {
+            Some((name, bytes, mime)) => {
+                let parts = vec![
+                    FormPart::text("payload_json", payload.to_string()),
+                    FormPart::file("files[0]", name.clone(), bytes.clone(), mime),
+                ];
+                http.send_multipart(NetFeature::Discord, req, parts).await?
+            }
+            None => http.send(NetFeature::Discord, req.json(payload)).await?,
+        };
+        let status = resp.status();
+        let retry_after = retry_after_of(&resp);
+        if status.is_success() {
+            return Ok(());
+        }
+        let header_wait = resp
+            .headers()
+            .get("retry-after")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<f64>().ok());
+        let body = resp.text().await.unwrap_or_default();
+        if status.as_u16() != 429 || attempt == DISCORD_ATTEMPTS {
+            return Err(UploadError { status, body, retry_after }.into());
+        }
+        // Discord puts the wait in the body (seconds, fractional) and in Retry-After.
+        let wait = serde_json::from_str::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|v| v.get("retry_after").and_then(|r| r.as_f64()))
+            .or(header_wait)
+            .unwrap_or(1.0);
+        if wait > DISCORD_MAX_WAIT_SECS {
+            return Err(UploadError { status, body, retry_after }.into());
+        }
+        println!("[discord] Rate limited; retrying in {:.1}s", wait);
+        tokio::time::sleep(Duration::from_secs_f64(wait.max(0.0))).await;
+    }
+    unreachable!("the last attempt always returns")
+}
+
+// ---------- Media pre-upload ----------
+
+pub(crate) const MEDIA_ATTEMPTS: u32 = 2;
+pub(crate) const MAX_MEDIA_IDS: usize = 200;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct MediaRef {
+    pub(crate) media_id: String,
+    pub(crate) uploaded_at: i64,
+}
+
+/// True when the server answered that a referenced media id doesn't exist.
+pub(crate) fn is_unknown_media(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<UploadError>().map(|e| matches!(e.status.as_u16(), 404 | 410)).unwrap_or(false)
+}
+
+/// The media id for a screenshot: cached by content hash, otherwise uploaded
+/// to `media_url` (a couple of attempts). None sends the screenshot inline instead.
+pub(crate) async fn media_id_for(
+    cfg: &Config,
+    http: &Http,
+    media_ids: &mut BTreeMap<String, MediaRef>,
+    sc: &Path,
+) -> Option<(String, String)> {
+    let shot = read_screenshot(cfg, sc).ok()?;
+    let hash = format!("{:x}", Sha256::digest(&shot.bytes));
+    if let Some(m) = media_ids.get(&hash) {
+        return Some((hash, m.media_id.clone()));
+    }
+    for attempt in 1..=MEDIA_ATTEMPTS {
+        match upload_media(cfg, http, &shot).await {
+            Ok(media_id) => {
+                media_ids.insert(hash.clone(), MediaRef { media_id: media_id.clone(), uploaded_at: Utc::now().timestamp() });
+                while media_ids.len() > MAX_MEDIA_IDS {
+                    let Some(oldest) = media_ids.iter().min_by_key(|(_, m)| m.uploaded_at).map(|(k, _)| k.clone()) else { break };
+                    media_ids.remove(&oldest);
+                }
+                return Some((hash, media_id));
+            }
+            Err(e) => eprintln!("[media] uploading {} failed (attempt {}): {e:#}", sc.display(), attempt),
+        }
+    }
+    println!("[media] Sending {} inline instead", sc.display());
+    None
+}
+
+/// POST one screenshot to `media_url` as the raw request body; the answer must
+/// be `{"media_id": ...}`.
+pub(crate) async fn upload_media(cfg: &Config, http: &Http, shot: &ScreenshotFile) -> Result<String> {
+    let disposition = format!("attachment; filename=\"{}\"", shot.file_name.replace(['"', '\\'], "_"));
+    let req = http
+        .authorize(cfg, http.post(&cfg.media_url))
+        .await?
+        .header(CONTENT_TYPE, shot.content_type)
+        .header(CONTENT_DISPOSITION, disposition)
+        .body(shot.bytes.clone());
+    let resp = http.send(NetFeature::Media, req).await?;
+    let status = resp.status();
+    let retry_after = retry_after_of(&resp);
+    if classify_status(status.as_u16()) != UploadOutcome::Accepted {
+        let body = resp.text().await.unwrap_or_default();
+        return Err(UploadError { status, body, retry_after }.into());
+    }
+    let body: serde_json::Value = resp.json().await.context("media response is not JSON")?;
+    match body.get("media_id") {
+        Some(serde_json::Value::String(id)) if !id.is_empty() => Ok(id.clone()),
+        Some(serde_json::Value::Number(n)) => Ok(n.to_string()),
+        _ => Err(anyhow!("media response has no media_id")),
+    }
+}
+
+// ---------- Idempotency ----------
+
+pub(crate) const IDEMPOTENCY_HEADER: &str = "Idempotency-Key";
+
+/// Hex SHA-256 over the canonical JSON of player, realm, at and killer.
+pub(crate) fn idempotency_key(death: &DeathPayload) -> String {
+    let identity = json!({ "player": death.player, "realm": death.realm, "at": death.at, "killer": death.killer });
+    format!("{:x}", Sha256::digest(canonical_value(&identity).to_string().as_bytes()))
+}
+
+/// The key this death was first sent under, or a new one recorded in state.
+/// Keeping it in state means a later killer_remap change can't split one death
+/// into two keys while it is still unacknowledged.
+pub(crate) fn idempotency_key_for(state: &mut State, char_key: &str, death: &DeathPayload) -> String {
+    match state.idempotency_keys.get(char_key) {
+        Some(k) if k.at == death.at => k.key.clone(),
+        _ => {
+            let key = idempotency_key(death);
+            state.idempotency_keys.insert(char_key.to_string(), IdempotencyKey { at: death.at, key: key.clone() });
+            key
+        }
+    }
+}
+
+/// Forget keys for deaths the watermark has passed and no target still needs.
+pub(crate) fn prune_idempotency_keys(state: &mut State, char_key: &str) {
+    let mark = state.last_uploaded.get(char_key).copied().unwrap_or(0);
+    let Some(at) = state.idempotency_keys.get(char_key).map(|k| k.at) else { return };
+    let retrying = state.retry_queue.iter().any(|r| r.key() == char_key && r.death.at == at);
+    if at <= mark && !retrying {
+        state.idempotency_keys.remove(char_key);
+    }
+}
+
+// ---------- Request signing ----------
+//
+// With `hmac_secret` set, every upload carries
+//
+//   X-DeathLogger-Timestamp: <unix seconds, decimal>
+//   X-DeathLogger-Signature: v1=<lowercase hex HMAC-SHA256(hmac_secret, signed string)>
+//
+// The signed string is four lines joined by "\n" (no trailing newline):
+//
+//   v1
+//   <timestamp, exactly as in the header>
+//   <the `death` (or `deaths`) JSON as sent, before any gzip/encryption, in
+//    canonical form (`payload::canonical_text`: keys sorted at every level, no
+//    whitespace, integral floats written as integers); for bulk uploads the
+//    whole NDJSON body with each line canonical>
+//   <lowercase hex SHA-256 of each screenshot file, comma-separated in part order; empty without one>
+//
+// Servers should recompute it from the received parts, compare in constant time,
+// and reject timestamps too far from their own clock to limit replays.
+
+pub(crate) const SIGNATURE_HEADER: &str = "X-DeathLogger-Signature";
+pub(crate) const TIMESTAMP_HEADER: &str = "X-DeathLogger-Timestamp";
+pub(crate) const SIGNATURE_VERSION: &str = "v1";
+
+pub(crate) fn signed_string(timestamp: i64, json: &[u8], screenshot_sha256: &[String]) -> Vec<u8> {
+    let mut out = format!("{}\n{}\n", SIGNATURE_VERSION, timestamp).into_bytes();
+    out.extend_from_slice(json);
+    out.push(b'\n');
+    out.extend_from_slice(screenshot_sha256.join(",").as_bytes());
+    out
+}
+
+pub(crate) fn sign_upload(secret: &str, timestamp: i64, json: &[u8], screenshot_sha256: &[String]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(&signed_string(timestamp, json, screenshot_sha256));
+    format!("{}={:x}", SIGNATURE_VERSION, mac.finalize().into_bytes())
+}
+
+/// `sign_upload` over the NDJSON body of a bulk upload, one line at a time
+/// rather than from the whole body.
+pub(crate) fn sign_ndjson(secret: &str, timestamp: i64, deaths: &[DeathPayload]) -> Result<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(format!("{}\n{}\n", SIGNATURE_VERSION, timestamp).as_bytes());
+    for death in deaths {
+        mac.update(&canonical_text(&serde_json::to_vec(death)?));
+        mac.update(b"\n");
+    }
+    mac.update(b"\n");
+    Ok(format!("{}={:x}", SIGNATURE_VERSION, mac.finalize().into_bytes()))
+}
+
+/// Timestamp and signature headers for an upload; empty when `hmac_secret` isn't
+/// set. The JSON is signed in canonical form, so a server that re-serializes
+/// what it parsed can still verify it.
+pub(crate) fn signature_headers(cfg: &Config, json: &[u8], screenshot_sha256: &[String]) -> Vec<(&'static str, String)> {
+    if cfg.hmac_secret.is_empty() {
+        return vec![];
+    }
+    let ts = Utc::now().timestamp();
+    vec![
+        (TIMESTAMP_HEADER, ts.to_string()),
+        (SIGNATURE_HEADER, sign_upload(&cfg.hmac_secret, ts, &canonical_text(json), screenshot_sha256)),
+    ]
+}
+
+// ---------- Screenshot freshness challenge ----------
+//
+// Heuristic only: a nonce proves the upload happened after the server issued it,
+// and the reported timestamps/hash let the server apply its own policy. None of
+// it proves the image itself wasn't edited.
+
+pub(crate) const CHALLENGE_HEADER: &str = "X-DeathLogger-Challenge";
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ScreenshotChallenge {
+    pub(crate) nonce: String,
+    /// Oldest screenshot the server will accept, if it says
+    #[serde(default)]
+    pub(crate) max_age_secs: Option<i64>,
+}
+
+/// Ask the server for a nonce, retrying once. None when it can't be had; the
+/// upload then goes ahead without one.
+pub(crate) async fn fetch_screenshot_challenge(cfg: &Config, http: &Http) -> Option<ScreenshotChallenge> {
+    for attempt in 1..=2 {
+        let result = async {
+            let req = http.authorize(cfg, http.post(&cfg.screenshot_challenge_url)).await?;
+            let resp = http.send(NetFeature::Challenge, req).await?.error_for_status()?;
+            Ok::<_, anyhow::Error>(resp.json::<ScreenshotChallenge>().await?)
+        }
+        .await;
+        match result {
+            Ok(c) => return Some(c),
+            Err(e) => eprintln!("[challenge] fetching a screenshot challenge failed (attempt {}): {e:#}", attempt),
+        }
+    }
+    None
+}
+
+/// True when the upload was turned down because its nonce had expired.
+pub(crate) fn is_expired_challenge<T>(cfg: &Config, result: &Result<T>) -> bool {
+    let Err(e) = result else { return false };
+    let Some(u) = e.downcast_ref::<UploadError>().filter(|u| u.status.is_client_error()) else { return false };
+    !cfg.screenshot_challenge_url.is_empty()
+        && serde_json::from_str::<serde_json::Value>(&u.body).is_ok_and(|v| v["error"] == "challenge_expired")
+}
+
+/// False when the server set a maximum age, `enforce_screenshot_max_age` is on,
+/// and the screenshot is older; the death then goes out without it.
+pub(crate) fn screenshot_fresh_enough(cfg: &Config, challenge: Option<&ScreenshotChallenge>, sc: &Path) -> bool {
+    let Some(max_age) = challenge.and_then(|c| c.max_age_secs) else { return true };
+    if !cfg.enforce_screenshot_max_age {
+        return true;
+    }
+    let age = file_size_mtime(sc).map(|(_, mtime)| Utc::now().timestamp() - mtime).unwrap_or(i64::MAX);
+    if age > max_age {
+        println!("[challenge] {} is {}s old (server allows {}s); uploading without it", sc.display(), age, max_age);
+        return false;
+    }
+    true
+}
+
+pub(crate) fn screenshot_meta(sc: &Path, death_at: i64) -> Result<serde_json::Value> {
+    let meta = fs::metadata(sc)?;
+    let epoch = |t: std::io::Result<SystemTime>| {
+        t.ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs() as i64)
+    };
+    Ok(json!({
+        "created": epoch(meta.created()),
+        "modified": epoch(meta.modified()),
+        "sha256": file_sha256(sc)?,
+        "death_to_upload_secs": Utc::now().timestamp() - death_at,
+    }))
+}
+
+/// Upload several deaths in one request: a `deaths` JSON array plus `screenshot_<i>`
+/// parts. Returns one result per death. A 2xx answer may list failed indices as
+/// `{"failed": [1, 3]}`; everything else in the batch counts as stored.
+pub(crate) async fn upload_batch(cfg: &Config, http: &Http, batch: &[StagedDeath]) -> Vec<Result<Option<UploadReceipt>>> {
+    let send = || async {
+        let deaths: Vec<DeathPayload> = batch.iter().map(|s| stamped(cfg, &s.death)).collect();
+        let mut parts = vec![];
+        let mut shot_hashes = vec![];
+        for (i, staged) in batch.iter().enumerate() {
+            let shots = staged.screenshot_paths().into_iter().filter(|p| p.exists() && screenshot_sendable(cfg, p));
+            for (n, sc) in shots.enumerate() {
+                let shot = read_screenshot(cfg, sc)?;
+                shot_hashes.push(format!("{:x}", Sha256::digest(&shot.bytes)));
+                let name = numbered_part(&format!("{}{}", PART_SCREENSHOT_INDEXED, i), n);
+                parts.push(FormPart::file(&name, shot.file_name, shot.bytes, shot.content_type));
+            }
+        }
+        let deaths_json = serde_json::to_string(&deaths)?;
+        let keys: Vec<&str> = batch.iter().map(|s| s.idempotency_key.as_str()).collect();
+        let mut headers = vec![(IDEMPOTENCY_HEADER, format!("{:x}", Sha256::digest(keys.join(",").as_bytes())))];
+        headers.extend(signature_headers(cfg, deaths_json.as_bytes(), &shot_hashes));
+        let resp = send_upload_form(cfg, http, PART_DEATHS, deaths_json, parts, &headers).await?;
+        let status = resp.status();
+        let retry_after = retry_after_of(&resp);
+        let body = resp.text().await.unwrap_or_default();
+        match classify_status(status.as_u16()) {
+            UploadOutcome::Accepted => Ok(batch_failed_indices(&body)),
+            UploadOutcome::RetryLater | UploadOutcome::Rejected => Err(anyhow::Error::from(UploadError { status, body, retry_after })),
+        }
+    };
+    let sent = async {
+        http.check_offline(&cfg.api_url)?;
+        http.check_throttle(&cfg.api_url)?;
+        http.take_upload_slot()?;
+        let sent = send().await;
+        if is_unauthorized(&sent) && http.forget_oauth_token(cfg) {
+            println!("[oauth] {} answered 401; retrying the batch with a new token", cfg.api_url);
+            return send().await;
+        }
+        sent
+    }
+    .await;
+    http.note_throttle(&cfg.api_url, &sent);
+    match http.note_offline(&cfg.api_url, sent) {
+        Ok(failed) => (0..batch.len())
+            .map(|i| {
+                if failed.contains(&i) {
+                    Err(anyhow!("server reported batch entry {} as failed", i))
+                } else {
+                    Ok(None)
+                }
+            })
+            .collect(),
+        Err(e) => {
+            (0..batch.len()).map(|_| Err(copy_error(&e))).collect()
+        }
+    }
+}
+
+/// A copy of an error shared by several deaths. Permanent rejections, rate
+/// limiting and token failures must stay recognizable for each of them.
+pub(crate) fn copy_error(e: &anyhow::Error) -> anyhow::Error {
+    if let Some(u) = e.downcast_ref::<UploadError>() {
+        return UploadError { status: u.status, body: u.body.clone(), retry_after: u.retry_after }.into();
+    }
+    if let Some(limited) = e.downcast_ref::<RateLimited>() {
+        return (*limited).into();
+    }
+    if let Some(oauth) = e.downcast_ref::<OAuthError>() {
+        return OAuthError { url: oauth.url.clone(), reason: oauth.reason.clone() }.into();
+    }
+    anyhow!("{e:#}")
+}
+
+/// Indices listed under "failed" in a batch response body; empty when absent.
+pub(crate) fn batch_failed_indices(body: &str) -> Vec<usize> {
+    serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|v| v.get("failed").and_then(|f| f.as_array()).cloned())
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|i| i.as_u64().map(|i| i as usize))
+        .collect()
+}
+
+// ---------- Bulk backfill ----------
+//
+// With `bulk_api_url` set, deaths in a SavedVariables file older than its newest
+// one that were never sent (a new character's history) go out in one NDJSON
+// request before the newest death, instead of one upload each. The body is
+// streamed from the parsed deaths a line at a time. Backfilled deaths carry no
+// screenshot. The bulk request goes to the primary endpoint; mirrors and Discord
+// get each death through the usual per-death delivery. Every target keeps its
+// own result, so one that isn't accepted falls back to the retry queue for that
+// target only.
+
+pub(crate) const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Backfill needs an API endpoint to send the bulk request to; otherwise every
+/// death goes through the normal path.
+pub(crate) fn backfill_applies(cfg: &Config) -> bool {
+    !cfg.bulk_api_url.is_empty() && cfg.uploads_enabled && cfg.upload_mode != UploadMode::Discord
+}
+
+/// The ones among `deaths` that still need sending, oldest first, with their
+/// entry hashes.
+pub(crate) fn backfill_candidates(cfg: &Config, state: &mut State, deaths: Vec<DeathPayload>) -> Result<Vec<(DeathPayload, String)>> {
+    let mut pending = vec![];
+    for mut death in deaths {
+        let hash = entry_hash(&death);
+        prepare_payload(cfg, &mut death)?;
+        let key = death.key();
+        if !is_new_death(state, &key, death.at, &hash) {
+            continue;
+        }
+        state.metrics.record_detected(&key, death.at);
+        emit(AgentEvent::DeathDetected { character: key.clone(), death: Box::new(death.clone()) });
+        if state.retry_queue.iter().any(|r| r.key() == key && r.death.at == death.at) {
+            continue;
+        }
+        if state.surges.get(&key).is_some_and(|s| s.held.iter().any(|h| h.death.at == death.at)) {
+            continue;
+        }
+        pending.push((death, hash));
+    }
+    Ok(pending)
+}
+
+/// Send `deaths` in one bulk request to the primary endpoint and one by one to
+/// the other targets, and record each death's results like a single upload.
+pub(crate) async fn backfill(cfg: &Config, http: &Http, state: &mut State, deaths: Vec<(DeathPayload, String)>) {
+    if deaths.is_empty() {
+        return;
+    }
+    let (deaths, hashes): (Vec<DeathPayload>, Vec<String>) = deaths.into_iter().unzip();
+    println!("[backfill] Sending {} earlier death(s) to {}", deaths.len(), cfg.bulk_api_url);
+    let started = std::time::Instant::now();
+    let primary = primary_endpoint(cfg);
+    let results = upload_bulk(&cfg.for_endpoint(&primary), http, &deaths).await;
+    let others: Vec<String> = upload_targets(cfg).into_iter().filter(|t| *t != primary.name).collect();
+    let total = deaths.len();
+    let accepted = results.iter().filter(|r| r.is_ok()).count();
+    for ((death, result), hash) in deaths.into_iter().zip(results).zip(hashes) {
+        let mut results = vec![(primary.name.clone(), result)];
+        if !others.is_empty() {
+            let idempotency_key = idempotency_key_for(state, &death.key(), &death);
+            results.extend(deliver(cfg, http, &mut state.media_ids, &death, &idempotency_key, &[], &others).await);
+        }
+        remember_death(state, &death.key(), death.at, hash);
+        record_delivery(state, death, vec![], results, false, 0, started);
+    }
+    if accepted == total {
+        println!("[backfill] All {} accepted", total);
+    } else {
+        println!("[backfill] {} of {} accepted; the rest are queued for retry", accepted, total);
+    }
+    save_state(state).ok();
+}
+
+pub(crate) fn ndjson_line(death: &DeathPayload) -> serde_json::Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(death)?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+/// The NDJSON body, serialized line by line as the request is written.
+pub(crate) fn ndjson_body(deaths: std::sync::Arc<Vec<DeathPayload>>) -> reqwest::Body {
+    let lines = (0..deaths.len()).map(move |i| ndjson_line(&deaths[i]));
+    reqwest::Body::wrap_stream(futures_util::stream::iter(lines))
+}
+
+/// POST `deaths` to `bulk_api_url`, one result per death.
+pub(crate) async fn upload_bulk(cfg: &Config, http: &Http, deaths: &[DeathPayload]) -> Vec<Result<Option<UploadReceipt>>> {
+    let stamped_deaths = std::sync::Arc::new(deaths.iter().map(|d| stamped(cfg, d)).collect::<Vec<_>>());
+    let keys: Vec<String> = deaths.iter().map(idempotency_key).collect();
+    let key = format!("{:x}", Sha256::digest(keys.join(",").as_bytes()));
+    let send = || async {
+        let mut req = http
+            .authorize(cfg, http.post(&cfg.bulk_api_url))
+            .await?
+            .header(CONTENT_TYPE, NDJSON_CONTENT_TYPE)
+            .header(IDEMPOTENCY_HEADER, &key);
+        req = with_schema_header(cfg, req);
+        if !cfg.hmac_secret.is_empty() {
+            let ts = Utc::now().timestamp();
+            let signature = sign_ndjson(&cfg.hmac_secret, ts, &stamped_deaths)?;
+            req = req.header(TIMESTAMP_HEADER, ts.to_string()).header(SIGNATURE_HEADER, signature);
+        }
+        let resp = http.send(NetFeature::Bulk, req.body(ndjson_body(stamped_deaths.clone()))).await?;
+        let status = resp.status();
+        let retry_after = retry_after_of(&resp);
+        let body = resp.text().await.unwrap_or_default();
+        match classify_status(status.as_u16()) {
+            UploadOutcome::Accepted => Ok(body),
+            UploadOutcome::RetryLater | UploadOutcome::Rejected => Err(anyhow::Error::from(UploadError { status, body, retry_after })),
+        }
+    };
+    let sent = async {
+        http.check_offline(&cfg.bulk_api_url)?;
+        http.check_throttle(&cfg.bulk_api_url)?;
+        http.take_upload_slot()?;
+        let sent = send().await;
+        if is_unauthorized(&sent) && http.forget_oauth_token(cfg) {
+            println!("[oauth] {} answered 401; retrying the backfill with a new token", cfg.bulk_api_url);
+            return send().await;
+        }
+        sent
+    }
+    .await;
+    http.note_throttle(&cfg.bulk_api_url, &sent);
+    match http.note_offline(&cfg.bulk_api_url, sent) {
+        Ok(body) => bulk_results(&body, deaths.len()),
+        Err(e) => (0..deaths.len()).map(|_| Err(copy_error(&e))).collect(),
+    }
+}
+
+/// Per-death results from an NDJSON bulk response: line `i` is `{"status": N, ...}`
+/// for death `i`. Deaths without a readable line count as not stored.
+pub(crate) fn bulk_results(body: &str, count: usize) -> Vec<Result<Option<UploadReceipt>>> {
+    let mut lines = body.lines().filter(|l| !l.trim().is_empty());
+    (0..count)
+        .map(|i| {
+            let line = lines.next().ok_or_else(|| anyhow!("bulk response has no line for death {}", i + 1))?;
+            let status = serde_json::from_str::<serde_json::Value>(line)
+                .ok()
+                .and_then(|v| v.get("status")?.as_u64())
+                .and_then(|s| StatusCode::from_u16(u16::try_from(s).ok()?).ok())
+                .ok_or_else(|| anyhow!("bulk response line {} has no valid status: {}", i + 1, line))?;
+            match classify_status(status.as_u16()) {
+                UploadOutcome::Accepted => Ok(parse_receipt(status, line)),
+                UploadOutcome::RetryLater | UploadOutcome::Rejected => {
+                    Err(UploadError { status, body: line.to_string(), retry_after: None }.into())
+                }
+            }
+        })
+        .collect()
+}
+
+// ---------- Retry queue ----------
+
+/// Rejected deaths kept in state for `status`.
+pub(crate) const MAX_FAILED_UPLOADS: usize = 50;
+
+/// Wait before retry number `attempts` (1-based): 5s, 30s, 2m, 5m, then every 15m.
+pub(crate) fn retry_delay_secs(attempts: u32) -> i64 {
+    match attempts {
+        0 | 1 => 5,
+        2 => 30,
+        3 => 120,
+        4 => 300,
+        _ => 900,
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct RetryEntry {
+    pub(crate) death: DeathPayload,
+    pub(crate) screenshot: Option<String>,
+    /// Further screenshots, nearest first (`attach_all_screenshots_in_window`)
+    #[serde(default)]
+    pub(crate) extra_screenshots: Vec<String>,
+    pub(crate) attempts: u32,
+    /// Epoch secs of the next attempt
+    pub(crate) next_at: i64,
+    pub(crate) last_error: String,
+    /// Targets that still need the death; empty (entries from older versions) means all
+    #[serde(default)]
+    pub(crate) targets: Vec<String>,
+    /// Some other target already accepted it (so it is counted as uploaded)
+    #[serde(default)]
+    pub(crate) delivered: bool,
+    /// Sent under this instead of the death's own key (an `annotate` re-send)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) idempotency_key: Option<String>,
+}
+
+impl RetryEntry {
+    pub(crate) fn key(&self) -> String {
+        self.death.key()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct FailedUpload {
+    pub(crate) death: DeathPayload,
+    pub(crate) failed_at: i64,
+    pub(crate) error: String,
+    /// Endpoint name, or "discord"
+    #[serde(default)]
+    pub(crate) target: String,
+}
+
+/// True for errors retrying can't fix: the server answered 4xx (other than 429).
+pub(crate) fn is_permanent_failure(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<UploadError>().map(|e| e.outcome() == UploadOutcome::Rejected).unwrap_or(false)
+}
+
+/// Queue a death for the targets that failed transiently, and record the ones
+/// that rejected it for good. `attempts` is how many tries came before this one.
+pub(crate) fn schedule_retry(
+    state: &mut State,
+    death: DeathPayload,
+    screenshots: Vec<String>,
+    failures: Vec<(String, anyhow::Error)>,
+    delivered: bool,
+    attempts: u32,
+) {
+    let now = Utc::now().timestamp();
+    let key = death.key();
+    let held = attempts;
+    let attempts = attempts + 1;
+    let (permanent, transient): (Vec<_>, Vec<_>) = failures.into_iter().partition(|(_, e)| is_permanent_failure(e));
+    for (target, err) in permanent {
+        println!("[retry] {} rejected the death for {} at {}; not retrying there", target, key, format_epoch(death.at));
+        state.failed_uploads.push(FailedUpload { death: death.clone(), failed_at: now, error: format!("{err:#}"), target });
+        if state.failed_uploads.len() > MAX_FAILED_UPLOADS {
+            state.failed_uploads.remove(0);
+        }
+    }
+    if transient.is_empty() {
+        // Re-parsing the same death would only be rejected again.
+        let mark = state.last_uploaded.entry(key).or_insert(death.at);
+        *mark = (*mark).max(death.at);
+        return;
+    }
+    let targets: Vec<String> = transient.iter().map(|(t, _)| t.clone()).collect();
+    let mut screenshots = screenshots.into_iter();
+    let screenshot = screenshots.next();
+    let extra_screenshots = screenshots.collect();
+    let limited: Vec<RateLimited> = transient.iter().filter_map(|(_, e)| e.downcast_ref::<RateLimited>().copied()).collect();
+    if limited.len() == transient.len() {
+        // Held back before sending: no backoff, and the attempt doesn't count.
+        let Some(longest) = limited.iter().max_by_key(|l| l.wait_secs()) else { return };
+        let wait = longest.wait_secs();
+        let tag = if longest.cause == HoldCause::Offline { "offline" } else { "rate" };
+        println!("[{}] Death for {} at {}: {}", tag, key, format_epoch(death.at), longest);
+        let last_error = longest.to_string();
+        state.retry_queue.push(RetryEntry { death, screenshot, extra_screenshots, attempts: held, next_at: now + wait, last_error, targets, delivered, idempotency_key: None });
+        return;
+    }
+    // A usable Retry-After replaces the backoff.
+    let server_wait = transient.iter().filter_map(|(_, e)| e.downcast_ref::<UploadError>().and_then(|u| u.retry_after)).max();
+    let delay = server_wait.unwrap_or_else(|| retry_delay_secs(attempts));
+    println!(
+        "[retry] Death for {} at {} will be retried on {} in {}s{} (attempt {})",
+        key,
+        format_epoch(death.at),
+        targets.join(", "),
+        delay,
+        if server_wait.is_some() { " as the server asked" } else { "" },
+        attempts + 1
+    );
+    let last_error = transient.iter().map(|(t, e)| format!("{}: {e:#}", t)).collect::<Vec<_>>().join("; ");
+    state.retry_queue.push(RetryEntry { death, screenshot, extra_screenshots, attempts, next_at: now + delay, last_error, targets, delivered, idempotency_key: None });
+}
+
+/// Upload every queued death whose backoff has expired.
+pub(crate) async fn retry_due(cfg: &Config, http: &Http, state: &mut State) {
+    if !cfg.uploads_enabled {
+        return;
+    }
+    let now = Utc::now().timestamp();
+    // Entries stay in the queue until their attempt is recorded, so a cancelled
+    // pass (shutdown grace running out) loses nothing.
+    let mut due: Vec<(String, i64)> = state.retry_queue.iter().filter(|r| r.next_at <= now).map(|r| (r.key(), r.death.at)).collect();
+    // Oldest death first, so a backlog released together keeps its order.
+    due.sort_by_key(|(_, at)| *at);
+    let current = upload_targets(cfg);
+    let position = |queue: &[RetryEntry], key: &str, at: i64| queue.iter().position(|r| r.key() == key && r.death.at == at);
+    for (i, (key, at)) in due.iter().enumerate() {
+        let Some(pos) = position(&state.retry_queue, key, *at) else { continue };
+        let entry = &mut state.retry_queue[pos];
+        // Hold the rest back in one go instead of failing them one by one.
+        if let Some(limited) = http.upload_wait().filter(|_| entry.targets.is_empty() || entry.targets.iter().any(|t| t != DISCORD_TARGET)) {
+            let held = &due[i..];
+            println!("[rate] {} queued upload(s) wait {}s for the upload rate limit", held.len(), limited.wait_secs());
+            for r in state.retry_queue.iter_mut().filter(|r| held.iter().any(|(k, a)| r.death.at == *a && r.key() == *k)) {
+                r.next_at = now + limited.wait_secs();
+            }
+            break;
+        }
+        if let Some(note) = take_pending_note(key, *at) {
+            entry.death.note = Some(note);
+        }
+        let entry = entry.clone();
+        let targets: Vec<String> = if entry.targets.is_empty() {
+            current.clone()
+        } else {
+            entry.targets.iter().filter(|t| current.contains(t)).cloned().collect()
+        };
+        if targets.is_empty() {
+            println!("[retry] Dropping the death for {} at {}: its targets are no longer configured", key, format_epoch(*at));
+            state.retry_queue.remove(pos);
+            continue;
+        }
+        let shots: Vec<&Path> = entry.screenshot.iter().chain(&entry.extra_screenshots).map(Path::new).filter(|p| p.exists()).collect();
+        let started = std::time::Instant::now();
+        let idempotency_key = match &entry.idempotency_key {
+            Some(k) => k.clone(),
+            None => idempotency_key_for(state, key, &entry.death),
+        };
+        let results = deliver(cfg, http, &mut state.media_ids, &entry.death, &idempotency_key, &shots, &targets).await;
+        for (target, result) in &results {
+            if result.is_ok() {
+                println!("[retry] Uploaded death for {} at {} to {} after {} failed attempt(s)", key, format_epoch(*at), target, entry.attempts);
+            }
+        }
+        if let Some(pos) = position(&state.retry_queue, key, *at) {
+            state.retry_queue.remove(pos);
+        }
+        let screenshots = entry.screenshot.into_iter().chain(entry.extra_screenshots).collect();
+        record_delivery(state, entry.death, screenshots, results, entry.delivered, entry.attempts, started);
+        // A re-send that failed again keeps its own key.
+        if let Some(retried) = state.retry_queue.iter_mut().find(|r| r.key() == *key && r.death.at == *at) {
+            retried.idempotency_key = retried.idempotency_key.take().or(entry.idempotency_key);
+        }
+    }
+    save_state(state).ok();
+}
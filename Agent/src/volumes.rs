@@ -0,0 +1,115 @@
+//! Mounted volumes and their serial numbers, for following a WoW install on a
+//! removable drive whose letter changes between sessions.
+
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Volume {
+    /// Root of the volume, e.g. `E:\`
+    pub root: PathBuf,
+    pub serial: Option<u32>,
+}
+
+/// Lists mounted volumes. The system implementation asks Windows; anything else
+/// can stand in for it.
+pub trait VolumeSource {
+    fn volumes(&self) -> Vec<Volume>;
+
+    /// The volume root of `path` and the rest of it, whether or not that volume
+    /// is mounted. By default its drive-letter prefix (`split_volume`).
+    fn split(&self, path: &Path) -> Option<(PathBuf, PathBuf)> {
+        split_volume(path)
+    }
+
+    /// Serial of the volume `path` lives on, when it is mounted.
+    fn serial_for(&self, path: &Path) -> Option<u32> {
+        let (root, _) = self.split(path)?;
+        self.volumes().into_iter().find(|v| v.root == root)?.serial
+    }
+}
+
+pub struct SystemVolumes;
+
+impl VolumeSource for SystemVolumes {
+    fn volumes(&self) -> Vec<Volume> {
+        platform::volumes()
+    }
+
+    fn serial_for(&self, path: &Path) -> Option<u32> {
+        let (root, _) = split_volume(path)?;
+        platform::volume_serial(&root)
+    }
+}
+
+/// Split a drive-letter path into its volume root and the rest: `E:\Games\WoW`
+/// becomes (`E:\`, `Games\WoW`). None for paths without a drive prefix.
+pub fn split_volume(path: &Path) -> Option<(PathBuf, PathBuf)> {
+    let mut components = path.components();
+    let Some(Component::Prefix(prefix)) = components.next() else { return None };
+    let mut root = PathBuf::from(prefix.as_os_str());
+    root.push(std::path::MAIN_SEPARATOR_STR);
+    let rest: PathBuf = components.filter(|c| !matches!(c, Component::RootDir)).collect();
+    Some((root, rest))
+}
+
+/// Display form Windows uses (`dir` prints it the same way), e.g. `1A2B-3C4D`.
+pub fn format_serial(serial: u32) -> String {
+    format!("{:04X}-{:04X}", serial >> 16, serial & 0xffff)
+}
+
+pub fn parse_serial(s: &str) -> Option<u32> {
+    let (hi, lo) = s.trim().split_once('-')?;
+    Some((u32::from_str_radix(hi, 16).ok()? << 16) | u32::from_str_radix(lo, 16).ok()?)
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::Volume;
+    use std::os::windows::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+    use windows_sys::Win32::Storage::FileSystem::{GetLogicalDrives, GetVolumeInformationW};
+
+    pub fn volume_serial(root: &Path) -> Option<u32> {
+        let wide: Vec<u16> = root.as_os_str().encode_wide().chain(std::iter::once(0)).collect();
+        let mut serial = 0u32;
+        let ok = unsafe {
+            GetVolumeInformationW(
+                wide.as_ptr(),
+                std::ptr::null_mut(),
+                0,
+                &mut serial,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                0,
+            )
+        };
+        (ok != 0).then_some(serial)
+    }
+
+    pub fn volumes() -> Vec<Volume> {
+        let mask = unsafe { GetLogicalDrives() };
+        (0..26u8)
+            .filter(|i| mask & (1 << i) != 0)
+            .map(|i| {
+                let root = PathBuf::from(format!("{}:\\", (b'A' + i) as char));
+                let serial = volume_serial(&root);
+                Volume { root, serial }
+            })
+            .collect()
+    }
+}
+
+#[cfg(not(windows))]
+mod platform {
+    use super::Volume;
+    use std::path::Path;
+
+    pub fn volume_serial(_root: &Path) -> Option<u32> {
+        None
+    }
+
+    pub fn volumes() -> Vec<Volume> {
+        vec![]
+    }
+}
@@ -0,0 +1,64 @@
+//! Helpers shared by the integration tests: a mock upload server and a
+//! SavedVariables writer.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+pub type Seen = Arc<Mutex<Vec<(String, String)>>>;
+
+/// Answers every request with 200 and records (method, path).
+pub fn mock_server() -> (String, Seen) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let seen: Seen = Arc::default();
+    let log = seen.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { continue };
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut head = String::new();
+            reader.read_line(&mut head).unwrap();
+            let mut parts = head.split_whitespace();
+            let (method, path) = (parts.next().unwrap_or("").to_string(), parts.next().unwrap_or("").to_string());
+            let (mut length, mut chunked) = (0usize, false);
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                let lower = line.to_ascii_lowercase();
+                if let Some(v) = lower.strip_prefix("content-length:") {
+                    length = v.trim().parse().unwrap();
+                }
+                chunked |= lower.starts_with("transfer-encoding:") && lower.contains("chunked");
+            }
+            if chunked {
+                loop {
+                    let mut size = String::new();
+                    reader.read_line(&mut size).unwrap();
+                    let size = usize::from_str_radix(size.trim(), 16).unwrap();
+                    let mut chunk = vec![0; size + 2];
+                    reader.read_exact(&mut chunk).unwrap();
+                    if size == 0 {
+                        break;
+                    }
+                }
+            } else {
+                reader.read_exact(&mut vec![0; length]).unwrap();
+            }
+            log.lock().unwrap().push((method, path));
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}").ok();
+        }
+    });
+    (url, seen)
+}
+
+pub fn write_sv(path: &Path, player: &str, at: i64) {
+    let lua = format!(
+        "DeathLoggerDB = {{ [\"deaths\"] = {{ {{ [\"player\"] = \"{player}\", [\"realm\"] = \"Realm\", [\"at\"] = {at}, [\"level\"] = 12, [\"class\"] = \"MAGE\" }} }} }}\n"
+    );
+    std::fs::write(path, lua).unwrap();
+}
@@ -0,0 +1,103 @@
+//! The embedding API, driven against a mock upload server and a scratch WoW
+//! folder through public items only.
+
+mod common;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{mock_server, write_sv};
+use deathlogger_agent::{AgentBuilder, AgentEvent};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn started_agent_uploads_and_reports_a_new_death() {
+    let scratch = std::env::temp_dir().join(format!("deathlogger-embedded-{}", std::process::id()));
+    let wow = scratch.join("World of Warcraft");
+    let sv_dir = wow.join("_retail_/WTF/Account/ACC/SavedVariables");
+    std::fs::create_dir_all(&sv_dir).unwrap();
+    std::fs::create_dir_all(wow.join("_retail_/Screenshots")).unwrap();
+    let (url, seen) = mock_server();
+
+    let called = Arc::new(AtomicUsize::new(0));
+    let counter = called.clone();
+    let mut agent = AgentBuilder::new()
+        .config_toml("update_addon_on_start = false\nshutdown_grace_secs = 1\n")
+        .data_dir(scratch.join("data"))
+        .wow_install(&wow, "_retail_")
+        .api_base(format!("{url}/upload"))
+        .on_event(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        })
+        .build()
+        .unwrap();
+    assert!(!agent.status().running);
+    let mut events = agent.subscribe();
+    agent.start().unwrap();
+    assert!(agent.start().is_err(), "a second start is refused");
+    assert!(agent.is_running());
+
+    // Give the watcher a moment to attach before the game writes the file.
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let at = chrono::Utc::now().timestamp();
+    write_sv(&sv_dir.join("DeathLogger.lua"), "Tester", at);
+
+    let mut seen_events = vec![];
+    let wait = tokio::time::timeout(Duration::from_secs(30), async {
+        while let Some(event) = events.next().await {
+            let done = matches!(event, AgentEvent::Uploaded { .. } | AgentEvent::UploadFailed { .. });
+            seen_events.push(event);
+            if done {
+                break;
+            }
+        }
+    });
+    wait.await.expect("no upload within 30s");
+
+    match &seen_events[0] {
+        AgentEvent::DeathDetected { character, death } => {
+            assert_eq!(character, "ACC:Tester@Realm");
+            assert_eq!((death.player(), death.realm(), death.at(), death.level()), ("Tester", "Realm", at, Some(12)));
+            assert_eq!(death.to_json()["class"], "MAGE");
+        }
+        other => panic!("first event was {other:?}"),
+    }
+    match seen_events.last().unwrap() {
+        AgentEvent::Uploaded { character, at: uploaded_at, .. } => {
+            assert_eq!(character, "ACC:Tester@Realm");
+            assert_eq!(*uploaded_at, at);
+        }
+        other => panic!("last event was {other:?}"),
+    }
+    assert!(seen.lock().unwrap().iter().any(|(m, p)| m == "POST" && p == "/upload"));
+    assert!(called.load(Ordering::Relaxed) >= 2);
+
+    let status = agent.status();
+    assert!(status.running);
+    assert!(status.uploads_enabled);
+    assert_eq!(status.wow_dir, wow.join("_retail_"));
+    assert_eq!(status.last_uploaded.get("ACC:Tester@Realm"), Some(&at));
+    assert_eq!(status.retry_queue, 0);
+
+    agent.shutdown().unwrap();
+    assert!(!agent.is_running());
+    assert!(!agent.status().running);
+    // The stream ends with the agent.
+    while events.try_next().is_some() {}
+    assert!(events.next().await.is_none());
+    // State was saved on the way out.
+    assert!(scratch.join("data/state.json").is_file());
+    std::fs::remove_dir_all(&scratch).ok();
+}
+
+#[test]
+fn build_rejects_a_config_that_does_not_parse() {
+    let err = AgentBuilder::new().config_toml("upload_mode = 3").build().err().unwrap();
+    assert!(format!("{err:#}").contains("parsing the config"), "{err:#}");
+}
+
+#[test]
+fn build_rejects_a_config_with_errors() {
+    let err = AgentBuilder::new().config_toml("sv_file_name = \"a/b.lua\"").build().err().unwrap();
+    assert_eq!(err.to_string(), "1 config error(s)");
+}
@@ -0,0 +1,56 @@
+//! An install the game has never been launched from: the agent waits for the
+//! game's own folders instead of creating them, then picks up deaths as usual.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{mock_server, write_sv};
+use deathlogger_agent::{AgentBuilder, AgentEvent};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_fresh_install_is_picked_up_once_the_game_has_run() {
+    let scratch = std::env::temp_dir().join(format!("deathlogger-first-launch-{}", std::process::id()));
+    let branch = scratch.join("World of Warcraft/_retail_");
+    std::fs::create_dir_all(&branch).unwrap();
+    let (url, seen) = mock_server();
+
+    let mut agent = AgentBuilder::new()
+        .config_toml("update_addon_on_start = false\nshutdown_grace_secs = 1\n")
+        .data_dir(scratch.join("data"))
+        .wow_install(scratch.join("World of Warcraft"), "_retail_")
+        .api_base(format!("{url}/upload"))
+        .build()
+        .unwrap();
+    let mut events = agent.subscribe();
+    agent.start().unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(agent.status().waiting_for_first_launch);
+    assert!(!branch.join("WTF").exists() && !branch.join("Screenshots").exists(), "the game's folders are left to the game");
+
+    // First launch and login: the game creates its folders.
+    let sv_dir = branch.join("WTF/Account/ACC/SavedVariables");
+    std::fs::create_dir_all(&sv_dir).unwrap();
+    std::fs::create_dir_all(branch.join("Screenshots")).unwrap();
+    assert!(!agent.status().waiting_for_first_launch);
+    // Watches attach on the next poll, every 10 seconds.
+    tokio::time::sleep(Duration::from_secs(11)).await;
+
+    let at = chrono::Utc::now().timestamp();
+    write_sv(&sv_dir.join("DeathLogger.lua"), "Fresh", at);
+    let uploaded = tokio::time::timeout(Duration::from_secs(30), async {
+        while let Some(event) = events.next().await {
+            match event {
+                AgentEvent::Uploaded { character, at, .. } => return (character, at),
+                AgentEvent::UploadFailed { .. } => panic!("upload failed: {event:?}"),
+                _ => {}
+            }
+        }
+        panic!("the agent stopped");
+    });
+    assert_eq!(uploaded.await.expect("no upload within 30s"), ("ACC:Fresh@Realm".to_string(), at));
+    assert!(seen.lock().unwrap().iter().any(|(m, p)| m == "POST" && p == "/upload"));
+
+    agent.shutdown().unwrap();
+    std::fs::remove_dir_all(&scratch).ok();
+}
@@ -0,0 +1,10 @@
+DeathLoggerDB = {
+	["deaths"] = {
+		{
+			["player"] = "Backy",
+			["realm"] = "Realm",
+			["at"] = 1700000300,
+			["level"] = 40,
+		},
+		{
+			["player
\ No newline at end of file
@@ -0,0 +1,10 @@
+﻿DeathLoggerDB = {
+	["deaths"] = {
+		{
+			["player"] = "Bommy",
+			["realm"] = "Realm",
+			["at"] = 1700000100,
+			["level"] = 12,
+		},
+	},
+}
@@ -0,0 +1,27 @@
+-- Not something the game writes, but a hand-edited file or another addon's
+-- serializer can: tables that contain themselves, shared tables, and a chain
+-- nested far deeper than any real entry.
+local loop = { ["name"] = "loop" }
+loop["self"] = loop
+loop["list"] = { loop, { ["inner"] = loop } }
+local shared = { ["id"] = 7 }
+local deep = {}
+local cur = deep
+for _ = 1, 500 do
+	cur["next"] = {}
+	cur = cur["next"]
+end
+DeathLoggerDB = {
+	["deaths"] = {
+		{
+			["player"] = "Cyclic",
+			["realm"] = "Realm",
+			["at"] = 1700000900,
+			["level"] = 33,
+			["loop"] = loop,
+			["pair"] = { ["a"] = shared, ["b"] = shared },
+			["deep"] = deep,
+		},
+	},
+}
+DeathLoggerDB["deaths"][1]["me"] = DeathLoggerDB["deaths"][1]
@@ -0,0 +1,10 @@
+DeathLoggerDB = {
+	["deaths"] = {
+		{
+			["player"] = "Quillon-Firemaw",
+			["realm"] = "",
+			["at"] = 1700000100,
+			["level"] = 30,
+		},
+	},
+}
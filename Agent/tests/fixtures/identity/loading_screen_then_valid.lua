@@ -0,0 +1,16 @@
+DeathLoggerDB = {
+	["deaths"] = {
+		{
+			["player"] = "",
+			["realm"] = "",
+			["at"] = 1700000100,
+			["level"] = 30,
+		},
+		{
+			["player"] = "Quillon",
+			["realm"] = "Firemaw",
+			["at"] = 1700000200,
+			["level"] = 30,
+		},
+	},
+}
@@ -0,0 +1,11 @@
+DeathLoggerDB = {
+	["deaths"] = {
+		{
+			["player"] = "Brokeny",
+			["realm"] = "Realm",
+			["at"] = 1700000200,
+			["level"] = 20,
+			["killer"] = { ["sourceName"] = "", },
+		},
+	},
+}
@@ -0,0 +1,41 @@
+-- Entries in an array, oldest first.
+DeathLoggerDB = {
+	["deaths"] = {
+		{
+			["player"] = "Quillon",
+			["realm"] = "Firemaw",
+			["at"] = 1700000100,
+			["level"] = 12,
+			["killer"] = {
+				["name"] = "Hogger",
+			},
+		},
+		{
+			["player"] = "Quillon",
+			["realm"] = "Firemaw",
+			["at"] = 1700000200,
+			["level"] = 14,
+			["killer"] = {
+				["name"] = "Defias Bandit",
+			},
+		},
+		{
+			["player"] = "Brakka",
+			["realm"] = "Firemaw",
+			["at"] = 1700000300,
+			["level"] = 9,
+			["killer"] = {
+				["name"] = "Murloc Raider",
+			},
+		},
+		{
+			["player"] = "Quillon",
+			["realm"] = "Firemaw",
+			["at"] = 1700000400,
+			["level"] = 20,
+			["killer"] = {
+				["name"] = "Falling",
+			},
+		},
+	},
+}
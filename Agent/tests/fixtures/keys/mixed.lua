@@ -0,0 +1,41 @@
+-- Array entries and string-keyed ones in one table; the array is not in time order either.
+DeathLoggerDB = {
+	["deaths"] = {
+		[2] = {
+			["player"] = "Quillon",
+			["realm"] = "Firemaw",
+			["at"] = 1700000400,
+			["level"] = 20,
+			["killer"] = {
+				["name"] = "Falling",
+			},
+		},
+		["Player-4454-0B7C1F32-1700000300"] = {
+			["player"] = "Brakka",
+			["realm"] = "Firemaw",
+			["at"] = 1700000300,
+			["level"] = 9,
+			["killer"] = {
+				["name"] = "Murloc Raider",
+			},
+		},
+		[1] = {
+			["player"] = "Quillon",
+			["realm"] = "Firemaw",
+			["at"] = 1700000200,
+			["level"] = 14,
+			["killer"] = {
+				["name"] = "Defias Bandit",
+			},
+		},
+		["1700000100"] = {
+			["player"] = "Quillon",
+			["realm"] = "Firemaw",
+			["at"] = 1700000100,
+			["level"] = 12,
+			["killer"] = {
+				["name"] = "Hogger",
+			},
+		},
+	},
+}
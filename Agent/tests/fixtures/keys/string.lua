@@ -0,0 +1,41 @@
+-- Entries keyed by GUID or by their timestamp, in no particular order.
+DeathLoggerDB = {
+	["deaths"] = {
+		["Player-4454-0B7C1F32-1700000300"] = {
+			["player"] = "Brakka",
+			["realm"] = "Firemaw",
+			["at"] = 1700000300,
+			["level"] = 9,
+			["killer"] = {
+				["name"] = "Murloc Raider",
+			},
+		},
+		["Player-4454-0A12D9E0-1700000400"] = {
+			["player"] = "Quillon",
+			["realm"] = "Firemaw",
+			["at"] = 1700000400,
+			["level"] = 20,
+			["killer"] = {
+				["name"] = "Falling",
+			},
+		},
+		["1700000100"] = {
+			["player"] = "Quillon",
+			["realm"] = "Firemaw",
+			["at"] = 1700000100,
+			["level"] = 12,
+			["killer"] = {
+				["name"] = "Hogger",
+			},
+		},
+		["Player-4454-0A12D9E0-1700000200"] = {
+			["player"] = "Quillon",
+			["realm"] = "Firemaw",
+			["at"] = 1700000200,
+			["level"] = 14,
+			["killer"] = {
+				["name"] = "Defias Bandit",
+			},
+		},
+	},
+}
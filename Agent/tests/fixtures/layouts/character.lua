@@ -0,0 +1,10 @@
+DeathLoggerDB = {
+	["deaths"] = {
+		{
+			["player"] = "Brakka",
+			["realm"] = "Firemaw",
+			["at"] = 1700000200,
+			["level"] = 45,
+		},
+	},
+}
@@ -0,0 +1,18 @@
+-- Coordinates as C_Map.GetPlayerMapPosition returns them: fractions of the map.
+DeathLoggerDB = {
+	["deaths"] = {
+		{
+			["player"] = "Located",
+			["realm"] = "Realm",
+			["at"] = 1700001100,
+			["location"] = {
+				["zone"] = "Elwynn Forest",
+				["subzone"] = "Goldshire",
+				["mapID"] = 37,
+				["x"] = 0.4213,
+				["y"] = 0.6579,
+				["facing"] = 1.5,
+			},
+		},
+	},
+}
@@ -0,0 +1,19 @@
+-- A nested {x, y} array in percent, plus keys the agent doesn't know.
+DeathLoggerDB = {
+	["deaths"] = {
+		{
+			["player"] = "Located",
+			["realm"] = "Realm",
+			["at"] = 1700001100,
+			["location"] = {
+				["zone"] = "Elwynn Forest",
+				["subzone"] = "Goldshire",
+				["map_id"] = "37",
+				["coords"] = { 42.13, 65.79 },
+				["facing"] = 1.5,
+				["instance"] = "none",
+				["continent"] = 13,
+			},
+		},
+	},
+}
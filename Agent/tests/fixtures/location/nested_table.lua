@@ -0,0 +1,20 @@
+-- A nested {x=, y=} position table.
+DeathLoggerDB = {
+	["deaths"] = {
+		{
+			["player"] = "Located",
+			["realm"] = "Realm",
+			["at"] = 1700001100,
+			["location"] = {
+				["zoneText"] = "Elwynn Forest",
+				["subZoneText"] = "Goldshire",
+				["mapId"] = 37,
+				["pos"] = {
+					["x"] = 0.4213,
+					["y"] = 0.6579,
+				},
+				["facing"] = 1.5,
+			},
+		},
+	},
+}
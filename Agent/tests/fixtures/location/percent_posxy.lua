@@ -0,0 +1,18 @@
+-- posX/posY already in percent, other key spellings, and a float map id.
+DeathLoggerDB = {
+	["deaths"] = {
+		{
+			["player"] = "Located",
+			["realm"] = "Realm",
+			["at"] = 1700001100,
+			["location"] = {
+				["zoneName"] = "Elwynn Forest",
+				["subZone"] = "Goldshire",
+				["uiMapID"] = 37.0,
+				["posX"] = 42.13,
+				["posY"] = 65.79,
+				["facing"] = 1.5,
+			},
+		},
+	},
+}
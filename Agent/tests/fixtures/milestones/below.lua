@@ -0,0 +1,19 @@
+DeathLoggerDB = {
+	["deaths"] = {
+	},
+	["levels"] = {
+		["Quillon-Firemaw"] = {
+			["player"] = "Quillon",
+			["realm"] = "Firemaw",
+			["level"] = 58,
+			["at"] = 1700000100,
+			["timePlayed"] = 1500000,
+		},
+		["Alt-Firemaw"] = {
+			["player"] = "Alt",
+			["realm"] = "Firemaw",
+			["level"] = 12,
+			["at"] = 1700000000,
+		},
+	},
+}
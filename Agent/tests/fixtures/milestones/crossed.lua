@@ -0,0 +1,19 @@
+DeathLoggerDB = {
+	["deaths"] = {
+	},
+	["levels"] = {
+		["Quillon-Firemaw"] = {
+			["player"] = "Quillon",
+			["realm"] = "Firemaw",
+			["level"] = 60,
+			["at"] = 1700000200,
+			["timePlayed"] = 1600000,
+		},
+		["Alt-Firemaw"] = {
+			["player"] = "Alt",
+			["realm"] = "Firemaw",
+			["level"] = 12,
+			["at"] = 1700000000,
+		},
+	},
+}
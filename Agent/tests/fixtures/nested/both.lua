@@ -0,0 +1,21 @@
+-- Mid-upgrade: the flat table is still there next to the per-character one.
+DeathLoggerDB = {
+	["deaths"] = {
+		{
+			["player"] = "Quillon",
+			["realm"] = "Firemaw",
+			["at"] = 1700000050,
+			["level"] = 10,
+		},
+	},
+	["characters"] = {
+		["Quillon-Firemaw"] = {
+			["deaths"] = {
+				{
+					["at"] = 1700000100,
+					["level"] = 12,
+				},
+			},
+		},
+	},
+}
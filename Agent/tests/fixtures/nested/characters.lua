@@ -0,0 +1,34 @@
+-- Newer addon layout: one deaths table per character, entries without player/realm.
+DeathLoggerDB = {
+	["version"] = "2.0.0",
+	["characters"] = {
+		["Quillon-Firemaw"] = {
+			["deaths"] = {
+				{
+					["at"] = 1700000100,
+					["level"] = 12,
+				},
+				{
+					["at"] = 1700000300,
+					["level"] = 15,
+				},
+				{
+					["level"] = 16,
+				},
+			},
+		},
+		["Brakka-Firemaw"] = {
+			["deaths"] = {
+				{
+					["player"] = "Brakka",
+					["realm"] = "Firemaw",
+					["at"] = 1700000200,
+					["level"] = 30,
+				},
+			},
+		},
+		["Empty-Firemaw"] = {
+			["level"] = 5,
+		},
+	},
+}
@@ -0,0 +1,35 @@
+-- Whole numbers written as floats (older serializers do), integers past 2^53,
+-- and a real fraction that must stay one.
+DeathLoggerDB = {
+	["deaths"] = {
+		{
+			["player"] = "Numbers",
+			["realm"] = "Realm",
+			["at"] = 1700001000.0,
+			["level"] = 60.0,
+			["moneyCopper"] = 1234567.0,
+			["moneyGold"] = 123.0,
+			["moneySilver"] = 45,
+			["moneyCopperOnly"] = 67.0,
+			["bags"] = {
+				{
+					["id"] = 19019.0,
+					["count"] = 1.0,
+					["bonusIDs"] = { 6652.0, 7756, -1.0 },
+					["guid"] = 9007199254740993,
+				},
+				{
+					["id"] = 2589,
+					["count"] = 20,
+					["weight"] = 0.5,
+				},
+			},
+			["equipped"] = {
+				{
+					["slot"] = 16.0,
+					["id"] = 1.9019e4,
+				},
+			},
+		},
+	},
+}
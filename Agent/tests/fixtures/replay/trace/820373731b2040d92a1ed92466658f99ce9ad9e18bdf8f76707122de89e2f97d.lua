@@ -0,0 +1 @@
+DeathLoggerDB = { ["deaths"] = { { ["player"] = "Traced", ["realm"] = "Realm", ["at"] = 1700000100, ["level"] = 30 }, { ["player"] = "Traced", ["realm"] = "Realm", ["at"] = 1700000900, ["level"] = 30 } } }
@@ -0,0 +1,22 @@
+DeathLoggerDB = {
+	["deaths"] = {
+		{
+			["player"] = "Resetti",
+			["realm"] = "Realm",
+			["at"] = 1700000100,
+			["level"] = 20,
+		},
+		{
+			["player"] = "Resetti",
+			["realm"] = "Realm",
+			["at"] = 1700000200,
+			["level"] = 21,
+		},
+		{
+			["player"] = "Resetti",
+			["realm"] = "Realm",
+			["at"] = 1700000300,
+			["level"] = 22,
+		},
+	},
+}
@@ -0,0 +1,4 @@
+DeathLoggerDB = {
+	["deaths"] = {
+	},
+}
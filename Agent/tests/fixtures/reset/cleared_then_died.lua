@@ -0,0 +1,10 @@
+DeathLoggerDB = {
+	["deaths"] = {
+		{
+			["player"] = "Resetti",
+			["realm"] = "Realm",
+			["at"] = 1700000400,
+			["level"] = 22,
+		},
+	},
+}
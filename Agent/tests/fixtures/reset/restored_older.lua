@@ -0,0 +1,10 @@
+DeathLoggerDB = {
+	["deaths"] = {
+		{
+			["player"] = "Resetti",
+			["realm"] = "Realm",
+			["at"] = 1700000200,
+			["level"] = 21,
+		},
+	},
+}
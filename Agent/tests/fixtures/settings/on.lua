@@ -0,0 +1,20 @@
+
+DeathLoggerDB = {
+	["version"] = "1.3.0",
+	["settings"] = {
+		["screenshotOn"] = true,
+		["maxEntries"] = 50,
+		["minimapIcon"] = {
+			["hide"] = true,
+		},
+	},
+	["deaths"] = {
+		{
+			["player"] = "Thornwick",
+			["realm"] = "Silvermoon",
+			["at"] = 1712403651,
+			["level"] = 31,
+			["class"] = "ROGUE",
+		}, -- [1]
+	},
+}
@@ -0,0 +1,32 @@
+
+DeathLoggerDB = {
+	["deaths"] = {
+		{
+			["at"] = 1715550000,
+			["level"] = 60,
+			["class"] = "PRIEST",
+			["location"] = {
+				["zone"] = "Blackrock Depths",
+				["subzone"] = "Grim Guzzler",
+				["mapID"] = 1584,
+			},
+			["killer"] = {
+				["sourceName"] = "Plugger Spazzring",
+				["sourceGUID"] = "Creature-0-4395-230-2244-9499-000002B8A1",
+				["subevent"] = "SPELL_DAMAGE",
+				["spellID"] = 13241,
+				["spellName"] = "Throw Mug",
+				["amount"] = 640,
+				["overkill"] = 212,
+				["detail"] = "Throw Mug",
+			},
+			["bags"] = {},
+			["equipped"] = {},
+			["moneyCopper"] = 4830012,
+			["instanceID"] = 230,
+			["instanceName"] = "Blackrock Depths",
+			["instanceDifficulty"] = "Normal",
+			["mapDifficultyID"] = 1,
+		}, -- [1]
+	},
+}
@@ -0,0 +1,99 @@
+
+DeathLoggerDB = {
+	["deaths"] = {
+		{
+			["player"] = "Mirelle",
+			["realm"] = "Nek'Rosh",
+			["at"] = 1700001000,
+			["level"] = 12,
+			["class"] = "MAGE",
+			["location"] = {
+				["zone"] = "Westfall",
+				["subzone"] = "The Jangolode Mine",
+				["mapID"] = 1436,
+				["x"] = 42.1,
+				["y"] = 19.75,
+			},
+			["killer"] = {
+				["sourceName"] = "Defias Trapper",
+				["sourceGUID"] = "Creature-0-5250-0-11-504-00001F2E3D",
+				["subevent"] = "SPELL_DAMAGE",
+				["spellID"] = 5019,
+				["spellName"] = "Shoot",
+				["amount"] = 41,
+				["overkill"] = 9,
+				["timestamp"] = 1700000999.873,
+				["detail"] = "Shoot",
+			},
+			["bags"] = {},
+			["equipped"] = {},
+			["moneyGold"] = 0,
+			["moneySilver"] = 5,
+			["moneyCopperOnly"] = 3,
+			["instanceID"] = 0,
+			["instanceName"] = "Eastern Kingdoms",
+			["instanceDifficulty"] = "",
+			["mapDifficultyID"] = 0,
+		}, -- [1]
+		{
+			["player"] = "Grushk",
+			["realm"] = "Nek'Rosh",
+			["at"] = 1700002500,
+			["level"] = 38,
+			["class"] = "HUNTER",
+			["location"] = {
+				["zone"] = "Thousand Needles",
+				["subzone"] = "",
+				["mapID"] = 1441,
+				["x"] = 67,
+				["y"] = 88.2,
+			},
+			["killer"] = {
+				["sourceName"] = "Environment: Falling",
+				["subevent"] = "ENVIRONMENTAL_DAMAGE",
+				["environmentalType"] = "Falling",
+				["spellName"] = "Falling",
+				["amount"] = 1310,
+				["overkill"] = 466,
+				["timestamp"] = 1700002499.1,
+				["detail"] = "Environmental (Falling)",
+			},
+			["bags"] = {},
+			["equipped"] = {},
+			["moneyCopper"] = 1520344,
+			["moneyGold"] = 152,
+			["moneySilver"] = 3,
+			["moneyCopperOnly"] = 44,
+			["instanceID"] = 1,
+			["instanceName"] = "Kalimdor",
+			["instanceDifficulty"] = "",
+			["mapDifficultyID"] = 0,
+		}, -- [2]
+		{
+			["player"] = "Mirelle",
+			["realm"] = "Nek'Rosh",
+			["at"] = 1700009000,
+			["level"] = 1,
+			["class"] = "MAGE",
+			["location"] = {
+				["zone"] = "Northshire",
+				["subzone"] = "",
+				["mapID"] = 1429,
+			},
+			["killer"] = {
+				["sourceName"] = "Unknown",
+				["detail"] = "No recent damage events",
+			},
+			["bags"] = {},
+			["equipped"] = {},
+			["moneyCopper"] = 0,
+			["moneyGold"] = 0,
+			["moneySilver"] = 0,
+			["moneyCopperOnly"] = 0,
+			["instanceID"] = 0,
+			["instanceName"] = "Eastern Kingdoms",
+			["instanceDifficulty"] = "",
+			["mapDifficultyID"] = 0,
+		}, -- [3]
+	},
+}
@@ -0,0 +1,69 @@
+
+DeathLoggerDB = {
+	["version"] = "1.3.0",
+	["deaths"] = {
+		{
+			["moneyCopperOnly"] = 17,
+			["equipped"] = {
+				{
+					["hyperlink"] = "|cff1eff00|Hitem:2575::::::::24:::::::::|h[Red Linen Shirt]|h|r",
+					["slot"] = 4,
+				}, -- [1]
+				{
+					["hyperlink"] = "|cffffffff|Hitem:25::::::::24:::::::::|h[Worn Shortsword]|h|r",
+					["slot"] = 16,
+				}, -- [2]
+			},
+			["instanceName"] = "Elwynn Forest",
+			["moneyGold"] = 3,
+			["realm"] = "Silvermoon",
+			["instanceDifficulty"] = "",
+			["specID"] = 71,
+			["class"] = "WARRIOR",
+			["instanceID"] = 0,
+			["mapDifficultyID"] = 0,
+			["moneySilver"] = 42,
+			["player"] = "Torvald",
+			["at"] = 1712403651,
+			["level"] = 24,
+			["killer"] = {
+				["sourceGUID"] = "Creature-0-3783-0-17-448-00004A1B2C",
+				["detail"] = "Melee",
+				["amount"] = 312,
+				["timestamp"] = 1712403651.417,
+				["subevent"] = "SWING_DAMAGE",
+				["spellName"] = "Melee",
+				["overkill"] = 87,
+				["sourceName"] = "Hogger",
+			},
+			["location"] = {
+				["y"] = 78.41,
+				["x"] = 26.53,
+				["subzone"] = "Forest's Edge",
+				["zone"] = "Elwynn Forest",
+				["mapID"] = 37,
+			},
+			["bags"] = {
+				{
+					["bagID"] = 0,
+					["slots"] = {
+						{
+							["itemID"] = 117,
+							["quality"] = 1,
+							["stackCount"] = 6,
+							["icon"] = 133972,
+							["hyperlink"] = "|cffffffff|Hitem:117::::::::24:::::::::|h[Tough Jerky]|h|r",
+							["slot"] = 1,
+						}, -- [1]
+					},
+				}, -- [1]
+				{
+					["bagID"] = 1,
+					["slots"] = {
+					},
+				}, -- [2]
+			},
+			["moneyCopper"] = 34217,
+		}, -- [1]
+	},
+}
@@ -0,0 +1,18 @@
+DeathLoggerDB = {
+	["deaths"] = {
+		{
+			["player"] = "Fieldy",
+			["realm"] = "Realm",
+			["at"] = 1700000000,
+			["level"] = 31,
+			["class"] = "ROGUE",
+			["guild"] = "Tide",
+			["hardcore"] = true,
+			["selfFound"] = {
+				["since"] = 1699990000,
+				["rules"] = { "no_trade", "no_mail" },
+			},
+			["playedTime"] = 123456,
+		},
+	},
+}
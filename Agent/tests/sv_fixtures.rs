@@ -0,0 +1,58 @@
+//! Anonymized SavedVariables files from real installs, and the exact payloads
+//! the agent builds from them.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use deathlogger_agent::{parse_deaths, parse_deaths_file};
+use serde_json::Value;
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/sv").join(name)
+}
+
+fn expected(lua: &Path) -> Value {
+    let json = lua.with_extension("json");
+    let text = fs::read_to_string(&json).unwrap_or_else(|e| panic!("{}: {e}", json.display()));
+    serde_json::from_str(&text).unwrap()
+}
+
+fn assert_fixture(name: &str) {
+    let path = fixture(name);
+    let from_file = serde_json::to_value(parse_deaths_file(&path).unwrap()).unwrap();
+    assert_eq!(from_file, expected(&path), "{name}");
+}
+
+#[test]
+fn retail_death_with_bags_and_melee_killer() {
+    assert_fixture("retail_melee.lua");
+}
+
+#[test]
+fn classic_history_oldest_first() {
+    assert_fixture("classic_history.lua");
+}
+
+#[test]
+fn character_file_takes_identity_from_its_path() {
+    assert_fixture("WTF/Account/ANON1234/Firemaw/Quillon/SavedVariables/DeathLogger.lua");
+}
+
+#[test]
+fn content_and_file_parse_the_same() {
+    let path = fixture("classic_history.lua");
+    let content = fs::read_to_string(&path).unwrap();
+    let from_str = serde_json::to_value(parse_deaths(&content).unwrap()).unwrap();
+    assert_eq!(from_str, expected(&path));
+}
+
+#[test]
+fn content_without_deaths_is_empty() {
+    assert!(parse_deaths("DeathLoggerDB = { [\"deaths\"] = {} }").unwrap().is_empty());
+    assert!(parse_deaths("SomeOtherAddonDB = {}").unwrap().is_empty());
+}
+
+#[test]
+fn content_that_is_not_savedvariables_is_an_error() {
+    assert!(parse_deaths("DeathLoggerDB = {").is_err());
+}